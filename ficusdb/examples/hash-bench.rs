@@ -0,0 +1,41 @@
+use ficusdb::KeccakImpl;
+use std::env;
+use std::time::Instant;
+
+// Encoded sizes of a short leaf, a short node over a hash and a full branch.
+const NODE_SIZES: [usize; 3] = [40, 70, 532];
+
+/// Keccak256 throughput of each implementation compiled in, on inputs the
+/// size of encoded trie nodes; `calc_hash` digests one per dirty node on
+/// every commit. Build with `--release --features tiny-keccak` to compare
+/// against `tiny-keccak`.
+fn main() {
+    let rounds = env::args()
+        .nth(1)
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(1_000_000usize);
+    let impls = [
+        KeccakImpl::Sha3,
+        #[cfg(feature = "tiny-keccak")]
+        KeccakImpl::TinyKeccak,
+    ];
+    for size in NODE_SIZES {
+        let mut input: Vec<u8> = (0..size).map(|i| i as u8).collect();
+        for keccak_impl in impls {
+            let hasher = keccak_impl.hasher();
+            let timer = Instant::now();
+            for _ in 0..rounds {
+                let digest = hasher.digest(&input);
+                input[..digest.len()].copy_from_slice(&digest);
+            }
+            let elapsed = timer.elapsed().as_secs_f64();
+            println!(
+                "{:?}\t{}B\t{:.0} hashes/s\t{:.1} MB/s",
+                keccak_impl,
+                size,
+                rounds as f64 / elapsed,
+                (rounds * size) as f64 / elapsed / 1e6
+            );
+        }
+    }
+}
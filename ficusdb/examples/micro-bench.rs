@@ -4,7 +4,7 @@ use rand::Rng;
 use rand_distr::{Distribution, Exp};
 use std::env;
 use std::fs::{File, OpenOptions};
-use std::io::{BufRead, BufReader, Read, Write, Seek, SeekFrom};
+use std::io::{BufRead, BufReader, Seek, SeekFrom, Write};
 use std::time::Instant;
 
 fn open_db(dbpath: &str, cachesize: usize) -> DB {
@@ -25,7 +25,6 @@ fn random_bytes(len: usize) -> Vec<u8> {
 fn bench_init(db: &mut DB, wlpath: &str, verpath: &str, batch_size: usize, val_size: usize) {
     let workload_buf = BufReader::new(File::open(wlpath).unwrap());
     let mut wb = db.new_writebatch();
-    let mut in_batch = 0usize;
     let mut timer = Instant::now();
     let mut total_ops = 0usize;
     let mut final_root = 0;
@@ -41,12 +40,9 @@ fn bench_init(db: &mut DB, wlpath: &str, verpath: &str, batch_size: usize, val_s
         }
         let val = random_bytes(val_size);
         wb.insert(key.as_bytes(), &val);
-        in_batch += 1;
 
-        if in_batch >= batch_size {
-            final_root = wb.commit();
-            wb = db.new_writebatch();
-            in_batch = 0;
+        if wb.len() >= batch_size {
+            final_root = wb.commit().unwrap();
             let elapsed = timer.elapsed().as_secs_f64();
             let trpt = batch_size as f64 / elapsed;
             total_ops += batch_size;
@@ -56,8 +52,8 @@ fn bench_init(db: &mut DB, wlpath: &str, verpath: &str, batch_size: usize, val_s
             db.print_stats();
         }
     }
-    if in_batch > 0 {
-        final_root = wb.commit();
+    if !wb.is_empty() {
+        final_root = wb.commit().unwrap();
         println!("final_root: {}", final_root);
     }
     let mut verfile = OpenOptions::new()
@@ -70,7 +66,15 @@ fn bench_init(db: &mut DB, wlpath: &str, verpath: &str, batch_size: usize, val_s
     verfile.flush().unwrap();
 }
 
-fn bench_put(db: &mut DB, wlpath: &str, verpath: &str, batch_size: usize, val_size: usize, versions: usize) {
+fn bench_put(
+    db: &mut DB,
+    wlpath: &str,
+    verpath: &str,
+    batch_size: usize,
+    val_size: usize,
+    versions: usize,
+    nosync: bool,
+) {
     let mut verfile = OpenOptions::new()
         .read(true)
         .write(true)
@@ -101,7 +105,11 @@ fn bench_put(db: &mut DB, wlpath: &str, verpath: &str, batch_size: usize, val_si
 
         if in_batch >= batch_size {
             let t_commit = Instant::now();
-            let root = wb.commit();
+            let root = if nosync {
+                wb.commit_nosync().unwrap()
+            } else {
+                wb.commit().unwrap()
+            };
             t_ops += t_commit.elapsed().as_secs_f64();
             let trpt = batch_size as f64 / t_ops;
             total_ops += batch_size;
@@ -113,7 +121,7 @@ fn bench_put(db: &mut DB, wlpath: &str, verpath: &str, batch_size: usize, val_si
             wb = db.new_writebatch();
             in_batch = 0;
             t_ops = 0.0;
-            
+
             #[cfg(feature = "stats")]
             db.print_stats();
             n_batch += 1;
@@ -123,11 +131,16 @@ fn bench_put(db: &mut DB, wlpath: &str, verpath: &str, batch_size: usize, val_si
         }
     }
     if in_batch > 0 {
-        let root = wb.commit();
+        let root = wb.commit().unwrap();
         verfile.seek(SeekFrom::End(0)).unwrap();
         verfile.write_all(&root.to_le_bytes()).unwrap();
         verfile.flush().unwrap();
     }
+    if nosync {
+        let t_sync = Instant::now();
+        db.sync();
+        println!("sync:\t{:.3}", t_sync.elapsed().as_secs_f64());
+    }
 }
 
 fn bench_get(db: &mut DB, wlpath: &str, batch_size: usize) {
@@ -162,21 +175,8 @@ fn bench_get(db: &mut DB, wlpath: &str, batch_size: usize) {
     }
 }
 
-fn load_versions(verpath: &str) -> Vec<u64> {
-    let mut versions = Vec::new();
-    let mut verfile = OpenOptions::new().read(true).open(verpath).unwrap();
-    let len = verfile.metadata().unwrap().len() / 8;
-    for _ in 0..len {
-        let mut buf = [0u8; 8];
-        verfile.read_exact(&mut buf).unwrap();
-        versions.push(u64::from_le_bytes(buf));
-    }
-    versions.reverse();
-    versions
-}
-
-fn bench_vget(db: &mut DB, wlpath: &str, verpath: &str, batch_size: usize) {
-    let vers = load_versions(verpath);
+fn bench_vget(db: &mut DB, wlpath: &str, batch_size: usize) {
+    let n_versions = db.version_count();
     let exp = Exp::new(10.0).unwrap();
     let mut rng = rand::rng();
     let workload_buf = BufReader::new(File::open(wlpath).unwrap());
@@ -193,10 +193,10 @@ fn bench_vget(db: &mut DB, wlpath: &str, verpath: &str, batch_size: usize) {
         if key.is_empty() {
             continue;
         }
-        let veridx = exp.sample(&mut rng) as usize % vers.len();
+        let veridx = exp.sample(&mut rng) as usize % n_versions;
         let t_start = Instant::now();
-        db.open_root(vers[veridx]);
-        let _val = db.get(key.as_bytes());
+        let root = db.version_root(n_versions - 1 - veridx).unwrap();
+        let _val = db.get_at(root, key.as_bytes());
         t_ops += t_start.elapsed().as_secs_f64();
         in_batch += 1;
 
@@ -216,7 +216,7 @@ fn main() {
     let args: Vec<String> = env::args().collect();
     if args.len() < 5 {
         eprintln!(
-            "usage: {} <init|get|vget|put> <dbpath> <workload_path> <cache_mb> [batch_size] [val_size]",
+            "usage: {} <init|get|vget|put|put-nosync> <dbpath> <workload_path> <cache_mb> [batch_size] [val_size]",
             args.get(0).map(|s| s.as_str()).unwrap_or("micro-bench")
         );
         std::process::exit(2);
@@ -233,30 +233,32 @@ fn main() {
         .get(6)
         .and_then(|s| s.parse::<usize>().ok())
         .unwrap_or(10000);
-    
 
     let mut db = open_db(dbpath, cache_size);
 
     if op == "init" {
         let val_size = args
-        .get(7)
-        .and_then(|s| s.parse::<usize>().ok())
-        .unwrap_or(200);
+            .get(7)
+            .and_then(|s| s.parse::<usize>().ok())
+            .unwrap_or(200);
         bench_init(&mut db, wlpath, verpath, batch_size, val_size);
     } else if op == "get" {
         bench_get(&mut db, wlpath, batch_size);
     } else if op == "vget" {
-        bench_vget(&mut db, wlpath, verpath, batch_size);
-    } else if op == "put" {
+        bench_vget(&mut db, wlpath, batch_size);
+    } else if op == "put" || op == "put-nosync" {
         let val_size = args
-        .get(7)
-        .and_then(|s| s.parse::<usize>().ok())
-        .unwrap_or(200);
+            .get(7)
+            .and_then(|s| s.parse::<usize>().ok())
+            .unwrap_or(200);
         let versions = args
-        .get(8)
-        .and_then(|s| s.parse::<usize>().ok())
-        .unwrap_or(10);
-        bench_put(&mut db, wlpath, verpath, batch_size, val_size, versions);
+            .get(8)
+            .and_then(|s| s.parse::<usize>().ok())
+            .unwrap_or(10);
+        let nosync = op == "put-nosync";
+        bench_put(
+            &mut db, wlpath, verpath, batch_size, val_size, versions, nosync,
+        );
     } else {
         eprintln!("unknown op: {}", op);
         std::process::exit(2);
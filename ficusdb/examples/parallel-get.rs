@@ -0,0 +1,68 @@
+use ficusdb::{DB, DBConfig};
+use std::env;
+use std::thread;
+use std::time::Instant;
+
+const GETS_PER_THREAD: usize = 200_000;
+
+fn key(i: u64) -> [u8; 8] {
+    i.wrapping_mul(0x9e37_79b9_7f4a_7c15).to_be_bytes()
+}
+
+/// Point reads of one committed root from 1, 2, 4, ... threads, each with
+/// its own snapshot. Reads hitting the node cache only lock a cache shard, so
+/// throughput should grow with the thread count up to the number of cores.
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let dbpath = &args[1];
+    let nkeys = args
+        .get(2)
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(100_000u64);
+    let max_threads = args
+        .get(3)
+        .and_then(|s| s.parse().ok())
+        .unwrap_or_else(|| thread::available_parallelism().map_or(1, |n| n.get()));
+
+    let cfg = DBConfig::builder()
+        .truncate(true)
+        .cache_size(1024 * 1024 * 1024)
+        .db_value_cache_size(0)
+        .build();
+    let db = DB::open(dbpath, cfg);
+    let mut wb = db.new_writebatch();
+    for i in 0..nkeys {
+        wb.insert(&key(i), &i.to_le_bytes());
+    }
+    let root = wb.commit().unwrap();
+
+    // warm the node cache
+    let snapshot = db.snapshot_at(root);
+    for i in 0..nkeys {
+        assert!(snapshot.get(&key(i)).is_some());
+    }
+
+    let mut threads = 1;
+    while threads <= max_threads {
+        let snapshots: Vec<_> = (0..threads).map(|_| db.snapshot_at(root)).collect();
+        let timer = Instant::now();
+        thread::scope(|s| {
+            for (t, snapshot) in snapshots.iter().enumerate() {
+                s.spawn(move || {
+                    let mut i = t as u64;
+                    for _ in 0..GETS_PER_THREAD {
+                        i = i
+                            .wrapping_mul(6364136223846793005)
+                            .wrapping_add(1442695040888963407)
+                            % nkeys;
+                        assert!(snapshot.get(&key(i)).is_some());
+                    }
+                });
+            }
+        });
+        let elapsed = timer.elapsed().as_secs_f64();
+        let trpt = (threads * GETS_PER_THREAD) as f64 / elapsed;
+        println!("get:\t{}\t{:.3}\t{:.3}", threads, elapsed, trpt);
+        threads *= 2;
+    }
+}
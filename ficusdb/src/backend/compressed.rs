@@ -0,0 +1,206 @@
+use super::SyncMode;
+use crate::merkle::{Backend, CleanPtr};
+
+use std::collections::BTreeMap;
+use std::io;
+
+const HEADER_SIZE: u64 = 8;
+
+/// Append-only backend that zstd-compresses each written blob.
+///
+/// Callers see an uncompressed address space: `tail()` and the pointers
+/// passed to `read`/`write` are logical offsets, so `NodeStore` can keep
+/// appending at `tail()` and reading at any offset inside a node. Each
+/// `write` is stored in `inner` as `[uncompressed_len: u32 LE]
+/// [compressed_len: u32 LE][zstd frame]`. The logical-to-physical index is
+/// kept in memory and rebuilt by scanning the blob headers on open.
+pub struct CompressedBackend<B: Backend> {
+    inner: B,
+    level: i32,
+    // logical start -> (physical ptr, compressed len, uncompressed len)
+    index: BTreeMap<CleanPtr, (CleanPtr, u32, u32)>,
+    tail: CleanPtr,
+    // last blob decompressed, by logical start
+    last: Option<(CleanPtr, Vec<u8>)>,
+}
+
+impl<B: Backend> CompressedBackend<B> {
+    pub fn new(mut inner: B, level: i32) -> Self {
+        let mut index = BTreeMap::new();
+        let mut tail = 0;
+        let mut phys = 0;
+        while phys + HEADER_SIZE <= inner.tail() {
+            let header = inner.read(phys, HEADER_SIZE as usize);
+            let ulen = u32::from_le_bytes(header[..4].try_into().unwrap());
+            let clen = u32::from_le_bytes(header[4..].try_into().unwrap());
+            index.insert(tail, (phys, clen, ulen));
+            tail += ulen as CleanPtr;
+            phys += HEADER_SIZE + clen as CleanPtr;
+        }
+        Self {
+            inner,
+            level,
+            index,
+            tail,
+            last: None,
+        }
+    }
+
+    /// The decompressed blob starting at logical offset `start`. Fails for
+    /// a blob that doesn't decompress to its recorded length, e.g. one torn
+    /// off the end of the file by a crash.
+    fn blob(&mut self, start: CleanPtr) -> io::Result<&[u8]> {
+        if self.last.as_ref().is_none_or(|(ptr, _)| *ptr != start) {
+            let (phys, clen, ulen) = self.index[&start];
+            let compressed = self.inner.try_read(phys + HEADER_SIZE, clen as usize)?;
+            let blob = zstd::bulk::decompress(&compressed, ulen as usize)
+                .ok()
+                .filter(|blob| blob.len() == ulen as usize)
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("corrupt compressed blob at offset {phys}"),
+                    )
+                })?;
+            self.last = Some((start, blob));
+        }
+        Ok(&self.last.as_ref().unwrap().1)
+    }
+}
+
+impl<B: Backend> Backend for CompressedBackend<B> {
+    fn tail(&self) -> CleanPtr {
+        self.tail
+    }
+
+    fn read(&mut self, ptr: CleanPtr, len: usize) -> Vec<u8> {
+        self.try_read(ptr, len).unwrap_or_else(|e| panic!("{e}"))
+    }
+
+    fn try_read(&mut self, ptr: CleanPtr, len: usize) -> io::Result<Vec<u8>> {
+        let end = (ptr + len as CleanPtr).min(self.tail);
+        let mut buf = Vec::with_capacity(len);
+        let mut cur = ptr;
+        while cur < end {
+            let (&start, &(_, _, ulen)) = self.index.range(..=cur).next_back().unwrap();
+            let off = (cur - start) as usize;
+            let n = (end - cur).min(ulen as CleanPtr - off as CleanPtr) as usize;
+            buf.extend_from_slice(&self.blob(start)?[off..off + n]);
+            cur += n as CleanPtr;
+        }
+        Ok(buf)
+    }
+
+    fn write(&mut self, ptr: CleanPtr, data: &[u8]) {
+        assert_eq!(ptr, self.tail, "CompressedBackend only supports appends");
+        if data.is_empty() {
+            return;
+        }
+        let compressed = zstd::bulk::compress(data, self.level).unwrap();
+        let mut buf = Vec::with_capacity(HEADER_SIZE as usize + compressed.len());
+        buf.extend((data.len() as u32).to_le_bytes());
+        buf.extend((compressed.len() as u32).to_le_bytes());
+        buf.extend(compressed);
+        let phys = self.inner.tail();
+        self.inner.write(phys, &buf);
+        let clen = (buf.len() as u64 - HEADER_SIZE) as u32;
+        self.index.insert(ptr, (phys, clen, data.len() as u32));
+        self.tail += data.len() as CleanPtr;
+        self.last = Some((ptr, data.to_vec()));
+    }
+
+    fn flush(&mut self) {
+        self.inner.flush();
+    }
+
+    fn sync(&mut self, mode: SyncMode) -> io::Result<()> {
+        self.inner.sync(mode)
+    }
+
+    fn cached_bytes(&self) -> usize {
+        self.last.as_ref().map_or(0, |(_, blob)| blob.len()) + self.inner.cached_bytes()
+    }
+
+    #[cfg(feature = "stats")]
+    fn print_stats(&mut self) {
+        self.inner.print_stats();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CompressedBackend;
+    use crate::backend::PageCachedFile;
+    use crate::merkle::Backend;
+    use std::fs;
+    use std::path::PathBuf;
+
+    fn unique_temp_path(name: &str) -> PathBuf {
+        let mut p = std::env::temp_dir();
+        let pid = std::process::id();
+        let n = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        p.push(format!("ficusdb-{name}-{pid}-{n}.dat"));
+        p
+    }
+
+    #[test]
+    fn compressed_reads_span_blobs_and_survive_reopen() {
+        let path = unique_temp_path("compressed");
+        let blobs: Vec<Vec<u8>> = (0..20u8).map(|i| vec![i; 100 + i as usize]).collect();
+        let mut ptrs = Vec::new();
+        {
+            let file = PageCachedFile::new(path.to_str().unwrap(), 1 << 16);
+            let mut b = CompressedBackend::new(file, 3);
+            for blob in &blobs {
+                let ptr = b.tail();
+                b.write(ptr, blob);
+                ptrs.push(ptr);
+            }
+            assert_eq!(b.read(ptrs[3] + 10, 5), vec![3; 5]);
+            b.flush();
+        }
+        let logical: u64 = blobs.iter().map(|b| b.len() as u64).sum();
+        assert!(fs::metadata(&path).unwrap().len() < logical);
+
+        let file = PageCachedFile::new(path.to_str().unwrap(), 1 << 16);
+        let mut b = CompressedBackend::new(file, 3);
+        assert_eq!(b.tail(), logical);
+        for (ptr, blob) in ptrs.iter().zip(&blobs) {
+            assert_eq!(&b.read(*ptr, blob.len()), blob);
+        }
+        // A read crossing from one blob into the next, and one past the tail.
+        let mut expected = vec![4; 2];
+        expected.extend([5; 3]);
+        assert_eq!(b.read(ptrs[5] - 2, 5), expected);
+        assert_eq!(b.read(logical - 1, 10), vec![19]);
+        drop(b);
+        let _ = fs::remove_file(path);
+    }
+
+    #[test]
+    fn compressed_try_read_reports_a_torn_blob() {
+        let path = unique_temp_path("compressed-torn");
+        {
+            let file = PageCachedFile::new(path.to_str().unwrap(), 1 << 16);
+            let mut b = CompressedBackend::new(file, 3);
+            b.write(0, &[1; 200]);
+            b.write(200, &(0..=255).collect::<Vec<u8>>());
+            b.flush();
+        }
+        let len = fs::metadata(&path).unwrap().len();
+        let file = fs::OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(len - 4).unwrap();
+        drop(file);
+
+        let file = PageCachedFile::new(path.to_str().unwrap(), 1 << 16);
+        let mut b = CompressedBackend::new(file, 3);
+        assert_eq!(b.try_read(0, 200).unwrap(), vec![1; 200]);
+        let err = b.try_read(200, 10).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        drop(b);
+        let _ = fs::remove_file(path);
+    }
+}
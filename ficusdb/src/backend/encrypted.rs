@@ -0,0 +1,272 @@
+use super::{PAGE_SIZE, SyncMode};
+use crate::merkle::{Backend, CleanPtr};
+
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use lru::LruCache;
+use std::io;
+use std::num::NonZeroUsize;
+
+const BLOCK_SIZE: usize = PAGE_SIZE;
+// generation: u64 LE, plaintext length: u32 LE
+const HEADER_SIZE: usize = 12;
+const TAG_SIZE: usize = 16;
+const STRIDE: u64 = (HEADER_SIZE + BLOCK_SIZE + TAG_SIZE) as u64;
+const DECRYPTED_BLOCKS: usize = 64;
+
+struct Block {
+    generation: u64,
+    data: Vec<u8>,
+}
+
+/// Backend that XChaCha20-Poly1305-encrypts `inner` in fixed-size blocks.
+///
+/// Callers see the plaintext address space. Logical block `i` covers
+/// `[i * BLOCK_SIZE, (i + 1) * BLOCK_SIZE)` and is stored at `i * STRIDE` in
+/// `inner` as `[generation][len][ciphertext + tag]`, so any sub-range read
+/// decrypts only the blocks it touches. The nonce is the block index plus
+/// its generation, which is bumped on every rewrite (appends into the tail
+/// block, in-place AHA updates), so a nonce is never reused under one key.
+/// The length is bound as associated data. A wrong key or a tampered block
+/// fails `try_read` with `InvalidData` (and panics `read`) instead of
+/// returning garbage.
+pub struct EncryptedBackend<B: Backend> {
+    inner: B,
+    cipher: XChaCha20Poly1305,
+    tail: CleanPtr,
+    blocks: LruCache<u64, Block>,
+}
+
+impl<B: Backend> EncryptedBackend<B> {
+    /// Returns an `InvalidData` error if `inner` is non-empty and its last
+    /// block does not authenticate under `key`.
+    pub fn try_new(inner: B, key: &[u8; 32]) -> io::Result<Self> {
+        let mut backend = Self {
+            inner,
+            cipher: XChaCha20Poly1305::new(key.into()),
+            tail: 0,
+            blocks: LruCache::new(NonZeroUsize::new(DECRYPTED_BLOCKS).unwrap()),
+        };
+        let phys_tail = backend.inner.tail();
+        if phys_tail > 0 {
+            let last = (phys_tail - 1) / STRIDE;
+            let len = backend.block(last)?.data.len();
+            backend.tail = last * BLOCK_SIZE as u64 + len as u64;
+        }
+        Ok(backend)
+    }
+
+    fn nonce(idx: u64, generation: u64) -> XNonce {
+        let mut nonce = XNonce::default();
+        nonce[..8].copy_from_slice(&idx.to_le_bytes());
+        nonce[8..16].copy_from_slice(&generation.to_le_bytes());
+        nonce
+    }
+
+    fn block(&mut self, idx: u64) -> io::Result<&mut Block> {
+        if !self.blocks.contains(&idx) {
+            let block = self.load_block(idx)?;
+            self.blocks.put(idx, block);
+        }
+        Ok(self.blocks.get_mut(&idx).unwrap())
+    }
+
+    fn load_block(&mut self, idx: u64) -> io::Result<Block> {
+        let phys = idx * STRIDE;
+        if phys >= self.inner.tail() {
+            return Ok(Block {
+                generation: 0,
+                data: Vec::new(),
+            });
+        }
+        let failed = || {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "encrypted block {idx} failed authentication (wrong key or corrupted file)"
+                ),
+            )
+        };
+        let header = self.inner.try_read(phys, HEADER_SIZE)?;
+        if header.len() < HEADER_SIZE {
+            return Err(failed());
+        }
+        let generation = u64::from_le_bytes(header[..8].try_into().unwrap());
+        let len = u32::from_le_bytes(header[8..].try_into().unwrap());
+        if len as usize > BLOCK_SIZE {
+            return Err(failed());
+        }
+        let ciphertext = self
+            .inner
+            .try_read(phys + HEADER_SIZE as u64, len as usize + TAG_SIZE)?;
+        let payload = Payload {
+            msg: &ciphertext,
+            aad: &len.to_le_bytes(),
+        };
+        let data = self
+            .cipher
+            .decrypt(&Self::nonce(idx, generation), payload)
+            .map_err(|_| failed())?;
+        Ok(Block { generation, data })
+    }
+
+    fn store_block(&mut self, idx: u64) {
+        let block = self.blocks.get(&idx).unwrap();
+        let len = block.data.len() as u32;
+        let payload = Payload {
+            msg: &block.data,
+            aad: &len.to_le_bytes(),
+        };
+        let ciphertext = self
+            .cipher
+            .encrypt(&Self::nonce(idx, block.generation), payload)
+            .unwrap();
+        let mut buf = Vec::with_capacity(HEADER_SIZE + ciphertext.len());
+        buf.extend(block.generation.to_le_bytes());
+        buf.extend(len.to_le_bytes());
+        buf.extend(ciphertext);
+        self.inner.write(idx * STRIDE, &buf);
+    }
+}
+
+impl<B: Backend> Backend for EncryptedBackend<B> {
+    fn tail(&self) -> CleanPtr {
+        self.tail
+    }
+
+    fn read(&mut self, ptr: CleanPtr, len: usize) -> Vec<u8> {
+        self.try_read(ptr, len).unwrap_or_else(|e| panic!("{e}"))
+    }
+
+    fn try_read(&mut self, ptr: CleanPtr, len: usize) -> io::Result<Vec<u8>> {
+        let end = (ptr + len as CleanPtr).min(self.tail);
+        let mut buf = Vec::with_capacity(len);
+        let mut cur = ptr;
+        while cur < end {
+            let idx = cur / BLOCK_SIZE as u64;
+            let off = (cur % BLOCK_SIZE as u64) as usize;
+            let n = (end - cur).min((BLOCK_SIZE - off) as u64) as usize;
+            buf.extend_from_slice(&self.block(idx)?.data[off..off + n]);
+            cur += n as CleanPtr;
+        }
+        Ok(buf)
+    }
+
+    fn write(&mut self, ptr: CleanPtr, data: &[u8]) {
+        if ptr > self.tail {
+            // Materialize the gap so every block below the tail authenticates.
+            let gap = vec![0; (ptr - self.tail) as usize];
+            self.write(self.tail, &gap);
+        }
+        let end = ptr + data.len() as CleanPtr;
+        let mut cur = ptr;
+        while cur < end {
+            let idx = cur / BLOCK_SIZE as u64;
+            let off = (cur % BLOCK_SIZE as u64) as usize;
+            let n = (end - cur).min((BLOCK_SIZE - off) as u64) as usize;
+            let src = &data[(cur - ptr) as usize..(cur - ptr) as usize + n];
+            let block = self.block(idx).unwrap_or_else(|e| panic!("{e}"));
+            if block.data.len() < off + n {
+                block.data.resize(off + n, 0);
+            }
+            block.data[off..off + n].copy_from_slice(src);
+            block.generation += 1;
+            self.store_block(idx);
+            cur += n as CleanPtr;
+        }
+        self.tail = self.tail.max(end);
+    }
+
+    fn flush(&mut self) {
+        self.inner.flush();
+    }
+
+    fn sync(&mut self, mode: SyncMode) -> io::Result<()> {
+        self.inner.sync(mode)
+    }
+
+    fn cached_bytes(&self) -> usize {
+        self.blocks.iter().map(|(_, b)| b.data.len()).sum::<usize>() + self.inner.cached_bytes()
+    }
+
+    #[cfg(feature = "stats")]
+    fn print_stats(&mut self) {
+        self.inner.print_stats();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BLOCK_SIZE, EncryptedBackend, HEADER_SIZE};
+    use crate::backend::PageCachedFile;
+    use crate::merkle::Backend;
+    use std::fs;
+    use std::path::PathBuf;
+
+    fn unique_temp_path(name: &str) -> PathBuf {
+        let mut p = std::env::temp_dir();
+        let pid = std::process::id();
+        let n = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        p.push(format!("ficusdb-{name}-{pid}-{n}.dat"));
+        p
+    }
+
+    #[test]
+    fn encrypted_overwrites_and_cross_block_reads_survive_reopen() {
+        let path = unique_temp_path("encrypted");
+        let key = [7u8; 32];
+        let data: Vec<u8> = (0..BLOCK_SIZE * 2 + 100).map(|i| (i % 251) as u8).collect();
+        {
+            let file = PageCachedFile::new(path.to_str().unwrap(), BLOCK_SIZE * 4);
+            let mut b = EncryptedBackend::try_new(file, &key).unwrap();
+            // Append in node-sized pieces, then overwrite a range in place.
+            for chunk in data.chunks(300) {
+                let tail = b.tail();
+                b.write(tail, chunk);
+            }
+            b.write(BLOCK_SIZE as u64 - 2, b"spans");
+            b.flush();
+        }
+        let raw = fs::read(&path).unwrap();
+        assert!(!raw.windows(5).any(|w| w == b"spans"));
+
+        let mut expected = data.clone();
+        expected[BLOCK_SIZE - 2..BLOCK_SIZE + 3].copy_from_slice(b"spans");
+        let file = PageCachedFile::new(path.to_str().unwrap(), BLOCK_SIZE * 4);
+        let mut b = EncryptedBackend::try_new(file, &key).unwrap();
+        assert_eq!(b.tail(), data.len() as u64);
+        assert_eq!(b.read(0, data.len() + 10), expected);
+        assert_eq!(b.read(BLOCK_SIZE as u64 - 2, 5), b"spans".to_vec());
+        drop(b);
+        let _ = fs::remove_file(path);
+    }
+
+    #[test]
+    fn encrypted_wrong_key_and_tampered_block_are_invalid_data() {
+        let path = unique_temp_path("encrypted-invalid");
+        {
+            let file = PageCachedFile::new(path.to_str().unwrap(), BLOCK_SIZE * 4);
+            let mut b = EncryptedBackend::try_new(file, &[1; 32]).unwrap();
+            b.write(0, &vec![5; BLOCK_SIZE * 2]);
+            b.flush();
+        }
+        let file = PageCachedFile::new(path.to_str().unwrap(), BLOCK_SIZE * 4);
+        let err = EncryptedBackend::try_new(file, &[2; 32]).err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+
+        // Flip a ciphertext byte of block 0; the last block still opens.
+        let mut raw = fs::read(&path).unwrap();
+        raw[HEADER_SIZE + 10] ^= 1;
+        fs::write(&path, raw).unwrap();
+        let file = PageCachedFile::new(path.to_str().unwrap(), BLOCK_SIZE * 4);
+        let mut b = EncryptedBackend::try_new(file, &[1; 32]).unwrap();
+        assert_eq!(b.try_read(BLOCK_SIZE as u64, 8).unwrap(), vec![5; 8]);
+        let err = b.try_read(0, 8).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        drop(b);
+        let _ = fs::remove_file(path);
+    }
+}
@@ -1,41 +1,92 @@
 #![allow(dead_code)]
 use super::{PAGE_BITS, PAGE_SIZE};
+use crate::metrics::Metrics;
 
 use lru::LruCache;
 use std::collections::HashMap;
 use std::fs::{File, OpenOptions};
+use std::io;
 use std::num::NonZeroUsize;
 use std::os::unix::fs::FileExt;
+use std::sync::Arc;
+use std::time::Instant;
 
 type Page = [u8; PAGE_SIZE];
 
+/// How hard a flush pushes written bytes to stable storage.
+///
+/// A flush always hands dirty pages to the OS, which is enough to survive a
+/// process crash. Surviving power loss needs a sync as well, which costs a
+/// device round trip per file on every commit. `Data` skips metadata such as
+/// mtime that is not needed to read the data back; `Full` syncs everything.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SyncMode {
+    #[default]
+    None,
+    /// `fdatasync`
+    Data,
+    /// `fsync`
+    Full,
+}
+
+impl SyncMode {
+    pub(crate) fn sync(self, file: &File) -> io::Result<()> {
+        match self {
+            SyncMode::None => Ok(()),
+            SyncMode::Data => file.sync_data(),
+            SyncMode::Full => file.sync_all(),
+        }
+    }
+}
+
 pub struct PageCachedFile {
     file: File,
     file_tail: u64,
     buff_tail: u64,
     clean: LruCache<u64, Page>,
     dirty: HashMap<u64, Page>,
+    metrics: Option<Arc<dyn Metrics>>,
     #[cfg(feature = "stats")]
     stats: PageCachedFileStats,
 }
 
 impl PageCachedFile {
     pub fn new(path: &str, cache_size: usize) -> Self {
+        Self::try_new(path, cache_size).unwrap()
+    }
+
+    /// Like `new`, but return the error if the file cannot be opened or
+    /// created.
+    pub fn try_new(path: &str, cache_size: usize) -> io::Result<Self> {
         let file = OpenOptions::new()
             .read(true)
             .write(true)
             .create(true)
-            .open(path)
-            .unwrap();
-        let file_tail = file.metadata().unwrap().len();
-        Self {
+            .open(path)?;
+        let file_tail = file.metadata()?.len();
+        Ok(Self {
             file,
             file_tail,
             buff_tail: file_tail,
             clean: LruCache::new(NonZeroUsize::new((cache_size / PAGE_SIZE).max(1)).unwrap()),
             dirty: HashMap::new(),
+            metrics: None,
             #[cfg(feature = "stats")]
             stats: PageCachedFileStats::new(),
+        })
+    }
+
+    pub fn set_metrics(&mut self, metrics: Option<Arc<dyn Metrics>>) {
+        self.metrics = metrics;
+    }
+
+    fn count_page(&self, hit: bool) {
+        if let Some(m) = &self.metrics {
+            if hit {
+                m.on_page_hit()
+            } else {
+                m.on_page_miss()
+            }
         }
     }
 
@@ -55,6 +106,7 @@ impl PageCachedFile {
             {
                 self.stats.hit += 1;
             }
+            self.count_page(true);
             return self.dirty.get(&pid).unwrap();
         }
         if !self.clean.contains(&pid) {
@@ -67,11 +119,13 @@ impl PageCachedFile {
                 self.stats.miss += 1;
                 self.stats.load += load_timer.elapsed().as_secs_f64();
             }
+            self.count_page(false);
         } else {
             #[cfg(feature = "stats")]
             {
                 self.stats.hit += 1;
             }
+            self.count_page(true);
         }
         self.clean.get(&pid).unwrap()
     }
@@ -84,6 +138,7 @@ impl PageCachedFile {
                     {
                         self.stats.hit += 1;
                     }
+                    self.count_page(true);
                     page
                 }
                 None => {
@@ -95,6 +150,7 @@ impl PageCachedFile {
                         self.stats.miss += 1;
                         self.stats.load += load_timer.elapsed().as_secs_f64();
                     }
+                    self.count_page(false);
                     page
                 }
             };
@@ -139,8 +195,7 @@ impl PageCachedFile {
     }
 
     pub fn flush(&mut self) {
-        #[cfg(feature = "stats")]
-        let flush_timer = std::time::Instant::now();
+        let flush_timer = Instant::now();
         for (pid, page) in self.dirty.drain() {
             let ptr = pid << PAGE_BITS;
             self.file.write_at(&page, ptr).unwrap();
@@ -154,12 +209,91 @@ impl PageCachedFile {
         {
             self.stats.write += flush_timer.elapsed().as_secs_f64();
         }
+        if let Some(m) = &self.metrics {
+            m.on_flush(flush_timer.elapsed());
+        }
+    }
+
+    /// Sync flushed bytes to stable storage. Pages still dirty in the cache
+    /// are not covered; call `flush` first.
+    pub fn sync(&mut self, mode: SyncMode) -> io::Result<()> {
+        let timer = Instant::now();
+        mode.sync(&self.file)?;
+        if let Some(m) = &self.metrics
+            && mode != SyncMode::None
+        {
+            m.on_sync(mode, timer.elapsed());
+        }
+        Ok(())
     }
 
     pub fn tail(&self) -> u64 {
         self.buff_tail
     }
 
+    /// Allocate disk space for `bytes` more bytes past the tail, so that
+    /// appends fill preallocated extents instead of growing the file a flush
+    /// at a time. The file length and `tail` are unchanged.
+    ///
+    /// Uses `fallocate` with `FALLOC_FL_KEEP_SIZE` on Linux and does nothing
+    /// on file systems that don't support it. Other platforms fall back to
+    /// doing nothing too: growing the file with `set_len` would move the
+    /// tail that `new` reads back from the file length.
+    pub fn reserve(&mut self, bytes: u64) -> io::Result<()> {
+        if bytes == 0 {
+            return Ok(());
+        }
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::fd::AsRawFd;
+            // SAFETY: the descriptor is owned by `self.file` and stays open
+            // for the call; fallocate takes no pointers.
+            let ret = unsafe {
+                libc::fallocate(
+                    self.file.as_raw_fd(),
+                    libc::FALLOC_FL_KEEP_SIZE,
+                    self.buff_tail as libc::off_t,
+                    bytes as libc::off_t,
+                )
+            };
+            if ret != 0 {
+                let err = io::Error::last_os_error();
+                if err.raw_os_error() != Some(libc::EOPNOTSUPP) {
+                    return Err(err);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Cut the file down to `len` bytes, flushing pending writes first.
+    /// Cached pages past `len` are dropped, so later reads and appends see
+    /// the shorter file.
+    pub fn truncate(&mut self, len: u64) {
+        self.flush();
+        if len >= self.file_tail {
+            return;
+        }
+        self.file.set_len(len).unwrap();
+        self.file_tail = len;
+        self.buff_tail = len;
+        let first = len >> PAGE_BITS;
+        let stale: Vec<u64> = self
+            .clean
+            .iter()
+            .map(|(pid, _)| *pid)
+            .filter(|pid| *pid >= first)
+            .collect();
+        for pid in stale {
+            self.clean.pop(&pid);
+        }
+    }
+
+    /// Memory held by clean and dirty pages.
+    pub fn cached_bytes(&self) -> usize {
+        (self.clean.len() + self.dirty.len()) * PAGE_SIZE
+    }
+
     #[cfg(feature = "stats")]
     pub fn print_stats(&mut self) {
         self.stats.cache_size = self.clean.len() * PAGE_SIZE;
@@ -259,6 +393,36 @@ mod tests {
         let _ = fs::remove_file(path);
     }
 
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn reserve_allocates_blocks_without_moving_the_tail() {
+        use std::os::unix::fs::MetadataExt;
+        let path = unique_temp_path("reserve");
+        {
+            let mut f = PageCachedFile::new(path.to_str().unwrap(), PAGE_SIZE * 2);
+            f.write(0, b"abc");
+            f.flush();
+            let blocks = fs::metadata(&path).unwrap().blocks();
+            f.reserve(1 << 20).unwrap();
+            assert_eq!(f.tail(), 3);
+            let meta = fs::metadata(&path).unwrap();
+            assert_eq!(meta.len(), 3);
+            // st_blocks counts 512-byte units
+            assert!(meta.blocks() * 512 >= (1 << 20), "{} blocks", meta.blocks());
+            assert!(meta.blocks() > blocks);
+
+            f.write(3, b"def");
+            f.flush();
+            assert_eq!(fs::metadata(&path).unwrap().len(), 6);
+        }
+        {
+            let mut f2 = PageCachedFile::new(path.to_str().unwrap(), PAGE_SIZE * 2);
+            assert_eq!(f2.tail(), 6);
+            assert_eq!(f2.read(0, 10), b"abcdef".to_vec());
+        }
+        let _ = fs::remove_file(path);
+    }
+
     #[test]
     fn overwrite_then_flush_persists_overwrite() {
         let path = unique_temp_path("overwrite");
@@ -1,6 +1,14 @@
+#[cfg(feature = "compression")]
+mod compressed;
+#[cfg(feature = "encryption")]
+mod encrypted;
 mod file;
 
 const PAGE_BITS: usize = 12;
 const PAGE_SIZE: usize = 1 << PAGE_BITS;
 
-pub use file::PageCachedFile;
+#[cfg(feature = "compression")]
+pub use compressed::CompressedBackend;
+#[cfg(feature = "encryption")]
+pub use encrypted::EncryptedBackend;
+pub use file::{PageCachedFile, SyncMode};
@@ -1,13 +1,90 @@
 #![allow(dead_code)]
 
-use crate::backend::PageCachedFile;
-use crate::merkle::{AggregatedHashArray, Backend, CleanPtr, Merkle, NodeStore, Value};
-use lru_mem::LruCache;
-use std::collections::HashMap;
+#[cfg(feature = "compression")]
+use crate::backend::CompressedBackend;
+#[cfg(feature = "encryption")]
+use crate::backend::EncryptedBackend;
+use crate::backend::{PageCachedFile, SyncMode};
+use crate::merkle::{
+    AggregatedHashArray, Backend, CachePolicy, CleanPtr, Cursor, Hasher, KeccakImpl, Merkle,
+    NodeHeaderError, NodeStore, NodeView, RangeProof, SortedBuilder, Value, check_node_header,
+};
+use crate::metrics::Metrics;
+use crate::wal::Wal;
+use lru_mem::{HeapSize, LruCache};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
 use std::mem::size_of;
+use std::panic::RefUnwindSafe;
 use std::sync::{Arc, Mutex};
 use typed_builder::TypedBuilder;
 
+/// Values read through a `DB`, keyed by the root they were read at so that
+/// entries never leak across `open_root` or commits.
+type ValueCache = LruCache<(CleanPtr, Vec<u8>), Option<SharedValue>>;
+
+/// Called with each new root and the keys its commit changed; see
+/// `DB::on_commit`.
+type CommitHook = Box<dyn Fn(CleanPtr, &[Vec<u8>]) + Send>;
+
+/// The `on_commit` callback, shared by a DB's handles and batches, and the
+/// commits it has not been told about yet because their roots aren't
+/// durable.
+#[derive(Default)]
+struct CommitHooks {
+    cb: Option<CommitHook>,
+    pending: Vec<(CleanPtr, Vec<Vec<u8>>)>,
+}
+
+impl CommitHooks {
+    fn is_set(&self) -> bool {
+        self.cb.is_some()
+    }
+
+    /// Report a commit, or hold it back until `report_pending` if its root
+    /// is not durable yet. A durable commit syncs every root before it, so
+    /// the held-back commits are reported first, in order.
+    fn report(&mut self, root_cptr: CleanPtr, keys: Vec<Vec<u8>>, durable: bool) {
+        if self.cb.is_none() {
+            return;
+        }
+        self.pending.push((root_cptr, keys));
+        if durable {
+            self.report_pending();
+        }
+    }
+
+    /// Report the commits held back since the last durable one.
+    fn report_pending(&mut self) {
+        let pending = std::mem::take(&mut self.pending);
+        if let Some(cb) = &self.cb {
+            for (root_cptr, keys) in pending {
+                cb(root_cptr, &keys);
+            }
+        }
+    }
+}
+
+/// Folds merge operands, oldest first, into the value they apply to;
+/// see `DBConfig::merge_fn`. `RefUnwindSafe` keeps `DB` and `WriteBatch`
+/// unwind-safe, as they were before `merge_fn` existed, so they can still
+/// be used inside `catch_unwind` without `AssertUnwindSafe`.
+type MergeFn = Arc<dyn Fn(Option<&[u8]>, &[&[u8]]) -> Vec<u8> + Send + Sync + RefUnwindSafe>;
+
+/// A cached value, shared with the callers of `DB::get_arc`. The cache
+/// counts the whole allocation while it holds the value; once evicted, a
+/// value that callers still hold uses memory that no cache accounts for.
+#[derive(Clone)]
+struct SharedValue(Arc<Vec<u8>>);
+
+impl HeapSize for SharedValue {
+    fn heap_size(&self) -> usize {
+        // the Arc's counters and the Vec live in one allocation
+        2 * size_of::<usize>() + size_of::<Vec<u8>>() + self.0.capacity()
+    }
+}
+
 #[derive(TypedBuilder)]
 pub struct DBConfig {
     #[builder(default = false)]
@@ -22,53 +99,877 @@ pub struct DBConfig {
     pub aha_lens: Vec<u8>,
     #[builder(default = 16 * 1024 * 1024)]
     pub db_value_cache_size: usize,
+    /// Crate computing Keccak256 unless `hasher` is set; read by `DB::open`.
+    /// See `KeccakImpl` for the default.
+    #[builder(default)]
+    pub keccak_impl: KeccakImpl,
+    /// A hash function to use instead of Keccak256, overriding
+    /// `keccak_impl`.
+    #[builder(default, setter(strip_option))]
+    pub hasher: Option<Arc<dyn Hasher>>,
+    /// Receives node, cache, page and commit events. Unset by default.
+    #[builder(default, setter(strip_option))]
+    pub metrics: Option<Arc<dyn Metrics>>,
+    /// Once a `WriteBatch` stages more than this many key and value bytes,
+    /// its writes are applied to the trie without committing. They are then
+    /// visible to reads before `commit`. 0 disables the limit.
+    #[builder(default = 0)]
+    pub max_batch_bytes: usize,
+    /// Resolves `WriteBatch::merge`: called at commit with the key's value
+    /// (`None` if absent) and the batch's operands for it, in order, and
+    /// returns the value to insert. Unset by default, which makes `merge`
+    /// panic. It must be `RefUnwindSafe`: wrap a closure that captures a
+    /// `Cell` or `RefCell` in `AssertUnwindSafe` if a panic can't leave it
+    /// half-updated.
+    #[builder(default, setter(strip_option))]
+    pub merge_fn: Option<MergeFn>,
+    /// zstd level for node bytes. Needs the `compression` feature. A DB
+    /// directory must always be opened with the same choice, since the node
+    /// file layout differs; opening scans every blob header in the file.
+    #[builder(default, setter(strip_option))]
+    pub compression_level: Option<i32>,
+    /// Encrypts the node and AHA files at rest. Needs the `encryption`
+    /// feature. Opening with a different key fails with
+    /// `OpenError::Decrypt` rather than returning garbage. The root file is
+    /// left in plaintext.
+    #[builder(default, setter(strip_option))]
+    pub encryption_key: Option<[u8; 32]>,
+    /// Log each commit to `{path}/wal` before writing it. On open, a commit
+    /// whose root pointer was not fully written is rolled back, so the latest
+    /// root always advances atomically across a process crash. Cannot be
+    /// combined with `encryption_key`, whose node file a rollback would tear.
+    #[builder(default = false)]
+    pub wal: bool,
+    /// Whether commits and `flush` also sync the node, AHA, root and WAL
+    /// files, so that a published root survives power loss and not just a
+    /// process crash. `None` is fastest and `Full` is safest; see
+    /// `SyncMode`.
+    #[builder(default)]
+    pub sync_mode: SyncMode,
+    /// Values longer than this many bytes are appended to `{path}/blobs`,
+    /// and the trie keeps only a reference to them. Roots are the same as
+    /// with inline values. A directory that has a blob file always reads
+    /// it, whether or not the threshold is set. Unset by default.
+    #[builder(default, setter(strip_option))]
+    pub inline_threshold: Option<usize>,
+    /// Keep the sorted 8-byte hashes of all keys of every committed root in
+    /// `{path}/key_summary`, for `DB::key_hashes`. Each commit logs the
+    /// hashes of the keys it added and removed, with a full snapshot now
+    /// and then. Off by default.
+    #[builder(default = false)]
+    pub key_summary: bool,
+    /// How copy-on-write uses the clean-node cache; see `CachePolicy`.
+    #[builder(default)]
+    pub cache_policy: CachePolicy,
+    /// Abort writes that copy a branch whose AHA array does not match it,
+    /// instead of reading the children's hashes from the node file:
+    /// `WriteBatch::commit` then fails with `CommitError::Corrupt`, also
+    /// for a mismatch met by an earlier auto-flush of the batch. Meant for
+    /// testing and for deployments that prefer stopping over running on a
+    /// damaged AHA. The mismatch reaches `Metrics::on_aha_mismatch` either
+    /// way.
+    ///
+    /// Freed AHA slots are not reused in this mode, so that older roots
+    /// opened with `open_root` or `rollback_to` keep matching arrays; the
+    /// AHA files grow with every commit instead.
+    #[builder(default = false)]
+    pub strict_aha: bool,
+    /// Append the keys each commit changed, with the new root hash, to
+    /// `{path}/changelog`, for followers to apply with
+    /// `DB::replay_changelog`. Off by default.
+    #[builder(default = false)]
+    pub changelog: bool,
+    /// Preallocate this many bytes of disk space past the end of the node
+    /// file on open, for bulk loads that append many nodes; see
+    /// `PageCachedFile::reserve`. 0, the default, preallocates nothing.
+    #[builder(default = 0)]
+    pub preallocate_bytes: u64,
+    /// Make the node file a function of the writes alone, so that two runs
+    /// committing the same batches produce identical files. Batches apply
+    /// their staged writes in key order rather than hash map order, which
+    /// decides which untouched nodes a commit copies and rewrites, and each
+    /// depth of a commit's nodes is written sorted by hash. The sorts cost a
+    /// few percent of commit time on large batches. Off by default.
+    #[builder(default = false)]
+    pub deterministic_layout: bool,
+}
+
+fn encrypted(
+    path: &str,
+    file: PageCachedFile,
+    key: Option<&[u8; 32]>,
+) -> Result<Box<dyn Backend>, OpenError> {
+    match key {
+        None => Ok(Box::new(file)),
+        #[cfg(feature = "encryption")]
+        Some(key) => match EncryptedBackend::try_new(file, key) {
+            Ok(backend) => Ok(Box::new(backend)),
+            Err(source) => Err(OpenError::Decrypt {
+                path: path.to_string(),
+                source,
+            }),
+        },
+        #[cfg(not(feature = "encryption"))]
+        Some(_) => unreachable!("{path}: encryption_key is refused on open"),
+    }
+}
+
+// "FICUSRT" and the format version as one ASCII digit
+const ROOT_MAGIC: &[u8; 8] = b"FICUSRT1";
+// root pointer, then the root hash zero-padded to `ROOT_HASH_LEN` bytes
+const ROOT_HASH_LEN: usize = 32;
+const ROOT_RECORD: u64 = (size_of::<CleanPtr>() + ROOT_HASH_LEN) as u64;
+
+/// The root file: one record per commit, in commit order.
+///
+/// A root file starts with `ROOT_MAGIC`, and each record holds the root
+/// pointer followed by the root hash, so that roots can be found by hash.
+/// Files written before hashes were kept have no header and hold bare
+/// pointers; they stay in that format and their hashes are computed from the
+/// trie when needed. Either way the pointer opens the record, which is where
+/// the WAL checks for it. A header of another format version is an error,
+/// and so is a file that ends partway through a record.
+struct RootFile {
+    file: PageCachedFile,
+    // `ROOT_MAGIC` length, or 0 for a file of bare pointers
+    start: u64,
+    record: u64,
+    // first root appended since the last `sync_roots`, and its offset
+    unsynced: Option<(CleanPtr, u64)>,
 }
 
+impl RootFile {
+    fn open(path: &str, cache_size: usize) -> Result<Self, OpenError> {
+        let mut file =
+            PageCachedFile::try_new(path, cache_size).map_err(|source| OpenError::Create {
+                path: path.to_string(),
+                source,
+            })?;
+        if file.tail() == 0 {
+            file.write(0, ROOT_MAGIC);
+            file.flush();
+        }
+        let magic = file.read(0, ROOT_MAGIC.len());
+        let (start, record) = if magic == ROOT_MAGIC {
+            (ROOT_MAGIC.len() as u64, ROOT_RECORD)
+        } else if magic[..7] == ROOT_MAGIC[..7] {
+            return Err(OpenError::Version {
+                path: path.to_string(),
+                file: "root file",
+                found: (magic[7] as char).to_string(),
+                expected: (ROOT_MAGIC[7] as char).to_string(),
+            });
+        } else {
+            (0, size_of::<CleanPtr>() as u64)
+        };
+        // With a WAL, a torn last record has already been cut off.
+        let len = file.tail();
+        if len < start || (len - start) % record != 0 {
+            return Err(OpenError::RootFileLength {
+                path: path.to_string(),
+                len,
+                record,
+            });
+        }
+        Ok(Self {
+            file,
+            start,
+            record,
+            unsynced: None,
+        })
+    }
+
+    fn has_hashes(&self) -> bool {
+        self.start > 0
+    }
+
+    fn len(&self) -> usize {
+        ((self.file.tail() - self.start) / self.record) as usize
+    }
+
+    /// Offset the next record is written at.
+    fn next_offset(&self) -> u64 {
+        self.start + self.len() as u64 * self.record
+    }
+
+    fn root_ptr(&mut self, version: usize) -> Option<CleanPtr> {
+        if version >= self.len() {
+            return None;
+        }
+        let offset = self.start + version as u64 * self.record;
+        let buf = self.file.read(offset, size_of::<CleanPtr>());
+        Some(CleanPtr::from_le_bytes(buf.try_into().unwrap()))
+    }
+
+    /// Stored hash of the `version`-th root, zero-padded to `ROOT_HASH_LEN`.
+    /// `None` for a file of bare pointers.
+    fn root_hash(&mut self, version: usize) -> Option<Vec<u8>> {
+        if !self.has_hashes() || version >= self.len() {
+            return None;
+        }
+        let offset = self.start + version as u64 * self.record + size_of::<CleanPtr>() as u64;
+        Some(self.file.read(offset, ROOT_HASH_LEN))
+    }
+
+    fn append(&mut self, root_cptr: CleanPtr, root_hash: &[u8]) {
+        let mut buf = root_cptr.to_le_bytes().to_vec();
+        if self.has_hashes() {
+            buf.extend(padded_root_hash(root_hash));
+        }
+        let offset = self.next_offset();
+        self.file.write(offset, &buf);
+        self.unsynced.get_or_insert((root_cptr, offset));
+    }
+
+    /// Latest version whose root is `root_cptr`.
+    fn find_version(&mut self, root_cptr: CleanPtr) -> Option<usize> {
+        (0..self.len())
+            .rev()
+            .find(|&version| self.root_ptr(version) == Some(root_cptr))
+    }
+
+    /// Drop every record after the first `versions`.
+    fn truncate(&mut self, versions: usize) {
+        let end = self.start + versions as u64 * self.record;
+        self.file.truncate(end);
+        if self.unsynced.is_some_and(|(_, offset)| offset >= end) {
+            self.unsynced = None;
+        }
+    }
+
+    fn flush(&mut self) {
+        self.file.flush();
+    }
+
+    fn sync(&mut self, mode: SyncMode) -> io::Result<()> {
+        self.file.sync(mode)
+    }
+}
+
+fn padded_root_hash(root_hash: &[u8]) -> Vec<u8> {
+    let mut hash = root_hash.to_vec();
+    hash.resize(ROOT_HASH_LEN, 0);
+    hash
+}
+
+/// Make the nodes of every root appended since the last call durable, then
+/// write the roots to the root file. Until then the roots only live in the
+/// root file's page cache, which is never written back on its own. If a
+/// sync fails, the roots stay unsynced for the next call to retry.
+fn sync_roots(
+    node_store: &Mutex<NodeStore>,
+    root_file: &Mutex<RootFile>,
+    wal: Option<&Arc<Mutex<Wal>>>,
+    sync_mode: SyncMode,
+) -> io::Result<()> {
+    let unsynced = root_file.lock().unwrap().unsynced.take();
+    let synced = (|| {
+        // The WAL checks the first of the roots, so that a crash rolls back
+        // all of them along with their nodes.
+        if let (Some(wal), Some((root_cptr, root_offset))) = (wal, unsynced) {
+            let mut wal = wal.lock().unwrap();
+            wal.log(root_cptr, root_offset)?;
+            wal.sync(sync_mode)?;
+        }
+
+        // Ensure node bytes are durable before publishing the new root pointers.
+        let mut store = node_store.lock().unwrap();
+        store.flush();
+        store.sync(sync_mode)?;
+        drop(store);
+
+        let mut root_file = root_file.lock().unwrap();
+        root_file.flush();
+        root_file.sync(sync_mode)
+    })();
+    if synced.is_err() && unsynced.is_some() {
+        // Ours is the first unsynced root, ahead of any appended since.
+        root_file.lock().unwrap().unsynced = unsynced;
+    }
+    synced
+}
+
+/// Append `root_cptr` and its hash to the root file, then make it durable
+/// with `sync_roots` unless the caller groups it with later commits.
+fn publish_root(
+    node_store: &Mutex<NodeStore>,
+    root_file: &Mutex<RootFile>,
+    wal: Option<&Arc<Mutex<Wal>>>,
+    sync_mode: SyncMode,
+    root_cptr: CleanPtr,
+    root_hash: &[u8],
+    durable: bool,
+) -> io::Result<()> {
+    root_file.lock().unwrap().append(root_cptr, root_hash);
+    if durable {
+        sync_roots(node_store, root_file, wal, sync_mode)?;
+    }
+    Ok(())
+}
+
+/// Sorted 8-byte key hashes per committed root.
+///
+/// `{path}/key_summary` is a log with one record per root, keyed by the
+/// root hash: a snapshot of all its hashes, or the hashes added and removed
+/// since the record of the root before it. A commit takes its change from
+/// `Merkle::diff`, so it reads only the changed part of the trie, and
+/// appends a delta, unless the deltas since the last snapshot would then
+/// outgrow one; so a commit writes O(changed keys) amortized, and a root is
+/// rebuilt from about twice its keys at most. A root hash already in the log
+/// is not written again. The log is not synced: a record cut short by a
+/// crash ends it, and a latest root without a record gets a snapshot on
+/// open.
+struct KeySummary {
+    file: File,
+    end: u64,
+    hasher: Arc<dyn Hasher>,
+    index: HashMap<Vec<u8>, SummaryRecord>,
+    root_cptr: CleanPtr,
+    root_hash: Vec<u8>,
+    // the hashes at `root_hash`, with how often each occurs
+    hashes: BTreeMap<[u8; 8], u32>,
+    len: usize,
+}
+
+struct SummaryRecord {
+    offset: u64,
+    // root hash of the record a delta applies to; `None` for a snapshot
+    parent: Option<Vec<u8>>,
+    // hashes and records to read to rebuild the root, past the snapshot;
+    // `usize::MAX` if the chain is broken
+    chain: usize,
+}
+
+impl KeySummary {
+    fn open(path: &str, merkle: &Merkle, hasher: Arc<dyn Hasher>) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(format!("{}/key_summary", path))?;
+        let mut summary = Self {
+            file,
+            end: 0,
+            hasher,
+            index: HashMap::new(),
+            root_cptr: merkle.root_cptr(),
+            root_hash: merkle.hash(),
+            hashes: BTreeMap::new(),
+            len: 0,
+        };
+        summary.scan()?;
+        let root_hash = summary.root_hash.clone();
+        match summary.read(&root_hash) {
+            Ok(Some(hashes)) => summary.set_hashes(hashes),
+            _ => {
+                summary.set_hashes(Self::hashes_of(merkle, summary.hasher.as_ref()));
+                summary.append_snapshot()?;
+            }
+        }
+        Ok(summary)
+    }
+
+    /// Index the records of the log and cut off a record cut short.
+    fn scan(&mut self) -> io::Result<()> {
+        let len = self.file.metadata()?.len();
+        let mut r = BufReader::new(&self.file);
+        r.seek(SeekFrom::Start(0))?;
+        while let Ok(body_len) = read_u32(&mut r) {
+            let end = self.end + 4 + body_len as u64;
+            if end > len {
+                break;
+            }
+            let mut body = (&mut r).take(body_len as u64);
+            let Ok((root, parent)) = Self::read_head(&mut body) else {
+                break;
+            };
+            let hashes = body.limit() as usize / 8;
+            let chain = match &parent {
+                None => 0,
+                Some(parent) => match self.index.get(parent) {
+                    Some(record) => record.chain.saturating_add(hashes + 1),
+                    None => usize::MAX,
+                },
+            };
+            let offset = self.end;
+            self.index.insert(
+                root,
+                SummaryRecord {
+                    offset,
+                    parent,
+                    chain,
+                },
+            );
+            r.seek(SeekFrom::Start(end))?;
+            self.end = end;
+        }
+        drop(r);
+        if self.end < len {
+            self.file.set_len(self.end)?;
+        }
+        Ok(())
+    }
+
+    fn read_head(r: &mut impl Read) -> io::Result<(Vec<u8>, Option<Vec<u8>>)> {
+        let root = read_frame(r)?;
+        let mut tag = [0u8; 1];
+        r.read_exact(&mut tag)?;
+        let parent = match tag[0] {
+            0 => None,
+            1 => Some(read_frame(r)?),
+            _ => return Err(io::Error::from(io::ErrorKind::InvalidData)),
+        };
+        Ok((root, parent))
+    }
+
+    /// Rebuild the hashes of `root_hash` from the log, or `None` if it has
+    /// no record or its chain is broken.
+    fn read(&mut self, root_hash: &[u8]) -> io::Result<Option<BTreeMap<[u8; 8], u32>>> {
+        let mut offsets = Vec::new();
+        let mut at = root_hash;
+        loop {
+            let Some(record) = self.index.get(at) else {
+                return Ok(None);
+            };
+            if record.chain == usize::MAX {
+                return Ok(None);
+            }
+            offsets.push(record.offset);
+            match &record.parent {
+                Some(parent) => at = parent,
+                None => break,
+            }
+        }
+        let invalid = || io::Error::from(io::ErrorKind::InvalidData);
+        let mut hashes = BTreeMap::new();
+        for offset in offsets.into_iter().rev() {
+            self.file.seek(SeekFrom::Start(offset))?;
+            let body = read_frame(&mut self.file)?;
+            let mut r = body.as_slice();
+            let (_, parent) = Self::read_head(&mut r)?;
+            let added = match parent {
+                Some(_) => read_u32(&mut r)? as usize,
+                None => usize::MAX,
+            };
+            if r.len() % 8 != 0 {
+                return Err(invalid());
+            }
+            for (i, hash) in r.chunks(8).enumerate() {
+                let hash: [u8; 8] = hash.try_into().unwrap();
+                if i < added {
+                    *hashes.entry(hash).or_insert(0) += 1;
+                } else {
+                    let count = hashes.get_mut(&hash).ok_or_else(invalid)?;
+                    *count -= 1;
+                    if *count == 0 {
+                        hashes.remove(&hash);
+                    }
+                }
+            }
+        }
+        Ok(Some(hashes))
+    }
+
+    fn set_hashes(&mut self, hashes: BTreeMap<[u8; 8], u32>) {
+        self.len = hashes.values().map(|&n| n as usize).sum();
+        self.hashes = hashes;
+    }
+
+    /// The sorted hashes of `root_hash`, if the log has them.
+    fn hashes_for(&mut self, root_hash: &[u8]) -> Option<Vec<[u8; 8]>> {
+        if root_hash == self.root_hash {
+            return Some(Self::expand(&self.hashes));
+        }
+        self.read(root_hash)
+            .ok()
+            .flatten()
+            .map(|h| Self::expand(&h))
+    }
+
+    fn expand(hashes: &BTreeMap<[u8; 8], u32>) -> Vec<[u8; 8]> {
+        hashes
+            .iter()
+            .flat_map(|(hash, &n)| std::iter::repeat_n(*hash, n as usize))
+            .collect()
+    }
+
+    fn key_hash(hasher: &dyn Hasher, key: &[u8]) -> [u8; 8] {
+        let mut hash = [0u8; 8];
+        let digest = hasher.digest(key);
+        let n = digest.len().min(8);
+        hash[..n].copy_from_slice(&digest[..n]);
+        hash
+    }
+
+    fn hashes_of(merkle: &Merkle, hasher: &dyn Hasher) -> BTreeMap<[u8; 8], u32> {
+        let mut hashes = BTreeMap::new();
+        for (key, _) in merkle.iter() {
+            *hashes.entry(Self::key_hash(hasher, &key)).or_insert(0) += 1;
+        }
+        hashes
+    }
+
+    /// Move the summary to `root_cptr`, whose hash is `root_hash`, and log
+    /// it. Any `Merkle` over the store will do for `merkle`; it is only
+    /// used to diff the two roots. The summary moves even if the log cannot
+    /// be written; the root is then rebuilt by walking its keys.
+    fn advance(
+        &mut self,
+        merkle: &Merkle,
+        root_cptr: CleanPtr,
+        root_hash: &[u8],
+    ) -> io::Result<()> {
+        let old_root = std::mem::replace(&mut self.root_cptr, root_cptr);
+        if root_hash == self.root_hash {
+            return Ok(());
+        }
+        let mut added = Vec::new();
+        let mut removed = Vec::new();
+        for (key, old, new) in merkle.diff(old_root, root_cptr) {
+            let hash = Self::key_hash(self.hasher.as_ref(), &key);
+            match (old, new) {
+                (None, Some(_)) => {
+                    *self.hashes.entry(hash).or_insert(0) += 1;
+                    added.push(hash);
+                }
+                (Some(_), None) => {
+                    let count = self.hashes.get_mut(&hash).unwrap();
+                    *count -= 1;
+                    if *count == 0 {
+                        self.hashes.remove(&hash);
+                    }
+                    removed.push(hash);
+                }
+                _ => {}
+            }
+        }
+        self.len = self.len + added.len() - removed.len();
+        let parent = std::mem::replace(&mut self.root_hash, root_hash.to_vec());
+        if self.index.contains_key(root_hash) {
+            return Ok(());
+        }
+        let chain = self.index.get(&parent).map_or(usize::MAX, |record| {
+            record.chain.saturating_add(added.len() + removed.len() + 1)
+        });
+        if chain > self.len {
+            return self.append_snapshot();
+        }
+        let mut body = Vec::new();
+        write_frame(&mut body, root_hash)?;
+        body.push(1);
+        write_frame(&mut body, &parent)?;
+        body.extend((added.len() as u32).to_le_bytes());
+        body.extend(added.iter().chain(&removed).flatten());
+        self.append(body, Some(parent), chain)
+    }
+
+    fn append_snapshot(&mut self) -> io::Result<()> {
+        let mut body = Vec::with_capacity(self.root_hash.len() + 5 + self.len * 8);
+        write_frame(&mut body, &self.root_hash)?;
+        body.push(0);
+        body.extend(Self::expand(&self.hashes).iter().flatten());
+        self.append(body, None, 0)
+    }
+
+    fn append(&mut self, body: Vec<u8>, parent: Option<Vec<u8>>, chain: usize) -> io::Result<()> {
+        let mut record = Vec::with_capacity(4 + body.len());
+        write_frame(&mut record, &body)?;
+        if let Err(e) = self.file.write_all(&record) {
+            // drop what was written, so later records still parse
+            let _ = self.file.set_len(self.end);
+            return Err(e);
+        }
+        let offset = self.end;
+        self.end += record.len() as u64;
+        self.index.insert(
+            self.root_hash.clone(),
+            SummaryRecord {
+                offset,
+                parent,
+                chain,
+            },
+        );
+        Ok(())
+    }
+}
+
+/// Per-commit change records for `DB::replay_changelog`.
+///
+/// A record is a u32 length and a body: the new root hash as a u32 length
+/// and the bytes, then per changed key a tag byte, `1` followed by the key
+/// and the value or `2` followed by the key, each as a u32 length and the
+/// bytes. The changes are what `Merkle::diff` reports between the previous
+/// and the new root, so they include writes a batch auto-flushed and do
+/// not depend on the node layout. A record cut short by a crash ends the
+/// log.
+struct Changelog {
+    file: File,
+}
+
+impl Changelog {
+    fn open(path: &str) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(format!("{}/changelog", path))?;
+        Ok(Self { file })
+    }
+
+    /// Append the record of moving from `old_root` to `new_root`. Any
+    /// `Merkle` over the store will do for `merkle`.
+    fn append(
+        &mut self,
+        merkle: &Merkle,
+        old_root: CleanPtr,
+        new_root: CleanPtr,
+        root_hash: &[u8],
+    ) {
+        let mut body = Vec::new();
+        write_frame(&mut body, root_hash).unwrap();
+        for (key, _, new) in merkle.diff(old_root, new_root) {
+            match new {
+                Some(value) => {
+                    body.push(1);
+                    write_frame(&mut body, &key).unwrap();
+                    write_frame(&mut body, &value.value).unwrap();
+                }
+                None => {
+                    body.push(2);
+                    write_frame(&mut body, &key).unwrap();
+                }
+            }
+        }
+        let mut record = Vec::with_capacity(4 + body.len());
+        write_frame(&mut record, &body).unwrap();
+        self.file.write_all(&record).unwrap();
+    }
+
+    fn sync(&self, mode: SyncMode) -> io::Result<()> {
+        mode.sync(&self.file)
+    }
+}
+
+const EXPORT_MAGIC: &[u8; 8] = b"FICUSEXP";
+const EXPORT_VERSION: u32 = 1;
+// Key and value bytes `DB::import` inserts at a time without `max_batch_bytes`.
+const IMPORT_CHUNK_BYTES: usize = 64 * 1024 * 1024;
+
+fn write_frame(w: &mut impl Write, data: &[u8]) -> io::Result<()> {
+    w.write_all(&(data.len() as u32).to_le_bytes())?;
+    w.write_all(data)
+}
+
+fn read_u32(r: &mut impl Read) -> io::Result<u32> {
+    let mut buf = [0u8; 4];
+    r.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+// Bytes a frame is read into up front; a longer one grows as its bytes
+// arrive, so a corrupt length can't allocate 4 GiB by itself.
+const MAX_FRAME_PREALLOC: usize = 64 * 1024;
+
+fn read_frame(r: &mut impl Read) -> io::Result<Vec<u8>> {
+    let len = read_u32(r)? as usize;
+    let mut buf = Vec::with_capacity(len.min(MAX_FRAME_PREALLOC));
+    r.take(len as u64).read_to_end(&mut buf)?;
+    if buf.len() != len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(buf)
+}
+
+/// A key-value store over one Merkle trie.
+///
+/// Cloning a `DB` gives another handle over the same files, caches and
+/// trie, for use on another thread. Handles share the current root: a
+/// commit or `open_root` through one is seen by all of them. For reads
+/// that must stay at one root, use `snapshot_at` instead.
+#[derive(Clone)]
 pub struct DB {
     node_store: Arc<Mutex<NodeStore>>,
     merkle: Arc<Mutex<Merkle>>,
-    root_file: Arc<Mutex<PageCachedFile>>,
-    db_value_cache: Option<Arc<Mutex<LruCache<Vec<u8>, Option<Vec<u8>>>>>>,
+    root_file: Arc<Mutex<RootFile>>,
+    db_value_cache: Option<Arc<Mutex<ValueCache>>>,
+    max_batch_bytes: usize,
+    merge_fn: Option<MergeFn>,
+    wal: Option<Arc<Mutex<Wal>>>,
+    sync_mode: SyncMode,
+    key_summary: Option<Arc<Mutex<KeySummary>>>,
+    changelog: Option<Arc<Mutex<Changelog>>>,
+    on_commit: Arc<Mutex<CommitHooks>>,
+    keccak_impl: Option<KeccakImpl>,
 }
 
 impl DB {
     pub fn open(path: &str, cfg: DBConfig) -> Self {
+        Self::try_open(path, cfg).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Like `open`, but return an error instead of panicking when the
+    /// directory or its files cannot be created or read, the node or root
+    /// file is of another format version or malformed, a file does not
+    /// decrypt under `encryption_key`, or `cfg` needs a feature this build
+    /// lacks.
+    pub fn try_open(path: &str, cfg: DBConfig) -> Result<Self, OpenError> {
+        Self::open_with_root(path, cfg, None)
+    }
+
+    /// Open the files at `path` like `open`, but start at `root_cptr`
+    /// instead of the last published root; 0 starts from an empty trie.
+    /// Published roots stay available to `open_root`.
+    pub fn open_at(path: &str, cfg: DBConfig, root_cptr: CleanPtr) -> Self {
+        Self::open_with_root(path, cfg, Some(root_cptr)).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    fn open_with_root(
+        path: &str,
+        cfg: DBConfig,
+        root_cptr: Option<CleanPtr>,
+    ) -> Result<Self, OpenError> {
+        let create = |path: &str| {
+            let path = path.to_string();
+            move |source| OpenError::Create { path, source }
+        };
+        let open_file = |path: &str, cache_size| {
+            PageCachedFile::try_new(path, cache_size).map_err(create(path))
+        };
+        let keccak_impl = cfg.hasher.is_none().then_some(cfg.keccak_impl);
+        let hasher = cfg
+            .hasher
+            .clone()
+            .unwrap_or_else(|| cfg.keccak_impl.hasher());
+        #[cfg(not(feature = "encryption"))]
+        if cfg.encryption_key.is_some() {
+            return Err(OpenError::Config(
+                "`encryption_key` requires the `encryption` feature",
+            ));
+        }
+        #[cfg(not(feature = "compression"))]
+        if cfg.compression_level.is_some() {
+            return Err(OpenError::Config(
+                "`compression_level` requires the `compression` feature",
+            ));
+        }
+        if cfg.wal && cfg.encryption_key.is_some() {
+            return Err(OpenError::Config(
+                "`wal` cannot be combined with `encryption_key`",
+            ));
+        }
         if cfg.truncate {
             let _ = std::fs::remove_file(path);
         }
-        let _ = std::fs::create_dir_all(path);
-        let node_path = format!("{}/node", path);
-        let node_file = PageCachedFile::new(&node_path, cfg.page_cache_size);
-        let aha = if cfg.aha_lens.is_empty() {
-            None
+        std::fs::create_dir_all(path).map_err(create(path))?;
+        let wal = if cfg.wal {
+            let wal_path = format!("{}/wal", path);
+            let mut wal = Wal::open(path).map_err(create(&wal_path))?;
+            wal.recover().map_err(|source| OpenError::Io {
+                path: wal_path,
+                source,
+            })?;
+            Some(Arc::new(Mutex::new(wal)))
         } else {
-            let mut ahas: Vec<(u8, Box<dyn Backend>)> = Vec::new();
-            for len in cfg.aha_lens {
-                let aha_path = format!("{}/aha_{}", path, len);
-                let aha_file = PageCachedFile::new(&aha_path, cfg.aha_cache_size);
-                ahas.push((len, Box::new(aha_file)));
-            }
-            Some(AggregatedHashArray::new(ahas))
+            None
         };
+        let node_path = format!("{}/node", path);
+        let mut node_file = open_file(&node_path, cfg.page_cache_size)?;
+        node_file.set_metrics(cfg.metrics.clone());
+        node_file
+            .reserve(cfg.preallocate_bytes)
+            .map_err(create(&node_path))?;
+        let aha =
+            if cfg.aha_lens.is_empty() {
+                None
+            } else {
+                let mut ahas: Vec<(u8, Box<dyn Backend>)> = Vec::new();
+                for len in cfg.aha_lens {
+                    let aha_path = format!("{}/aha_{}", path, len);
+                    let mut aha_file = open_file(&aha_path, cfg.aha_cache_size)?;
+                    aha_file.set_metrics(cfg.metrics.clone());
+                    ahas.push((
+                        len,
+                        encrypted(&aha_path, aha_file, cfg.encryption_key.as_ref())?,
+                    ));
+                }
+                let free_path = format!("{}/aha_free", path);
+                let free_file = open_file(&free_path, cfg.aha_cache_size)?;
+                Some(
+                    AggregatedHashArray::new(ahas, hasher.output_len()).with_recycle_store(
+                        encrypted(&free_path, free_file, cfg.encryption_key.as_ref())?,
+                    ),
+                )
+            };
+        let node_backend = encrypted(&node_path, node_file, cfg.encryption_key.as_ref())?;
+        let mut node_backend: Box<dyn Backend> = match cfg.compression_level {
+            None => node_backend,
+            #[cfg(feature = "compression")]
+            Some(level) => Box::new(CompressedBackend::new(node_backend, level)),
+            #[cfg(not(feature = "compression"))]
+            Some(_) => unreachable!("compression_level is refused on open"),
+        };
+        check_node_header(&mut node_backend).map_err(|source| match source {
+            NodeHeaderError::Version { found, expected } => OpenError::Version {
+                path: node_path.clone(),
+                file: "node file",
+                found: found.to_string(),
+                expected: expected.to_string(),
+            },
+            source => OpenError::NodeFile {
+                path: node_path.clone(),
+                source,
+            },
+        })?;
         let node_store = Arc::new(Mutex::new(NodeStore::new(
-            Box::new(node_file),
+            node_backend,
             cfg.cache_size,
             aha,
+            hasher,
         )));
+        node_store.lock().unwrap().set_metrics(cfg.metrics);
+        node_store
+            .lock()
+            .unwrap()
+            .set_cache_policy(cfg.cache_policy);
+        node_store.lock().unwrap().set_strict_aha(cfg.strict_aha);
+        node_store
+            .lock()
+            .unwrap()
+            .set_deterministic_layout(cfg.deterministic_layout);
+        let blob_path = format!("{}/blobs", path);
+        if cfg.inline_threshold.is_some() || std::path::Path::new(&blob_path).exists() {
+            let blob_file = open_file(&blob_path, cfg.page_cache_size)?;
+            node_store.lock().unwrap().set_blob_store(
+                encrypted(&blob_path, blob_file, cfg.encryption_key.as_ref())?,
+                cfg.inline_threshold.unwrap_or(usize::MAX),
+            );
+        }
 
         let root_path = format!("{}/root", path);
-        let mut root_file = PageCachedFile::new(&root_path, cfg.aha_cache_size);
-        let root_cptr = if root_file.tail() as u64 >= size_of::<CleanPtr>() as u64 {
-            let buf = root_file.read(
-                root_file.tail() - size_of::<CleanPtr>() as u64,
-                size_of::<CleanPtr>(),
-            );
-            CleanPtr::from_le_bytes(buf.try_into().unwrap())
+        let mut root_file = RootFile::open(&root_path, cfg.aha_cache_size)?;
+        let root_cptr = root_cptr.unwrap_or_else(|| match root_file.len() {
+            0 => 0,
+            n => root_file.root_ptr(n - 1).unwrap(),
+        });
+        let merkle = Merkle::new(node_store.clone(), root_cptr);
+        let key_summary = if cfg.key_summary {
+            let hasher = node_store.lock().unwrap().hasher();
+            let summary =
+                KeySummary::open(path, &merkle, hasher).map_err(|source| OpenError::Io {
+                    path: format!("{}/key_summary", path),
+                    source,
+                })?;
+            Some(Arc::new(Mutex::new(summary)))
         } else {
-            0
+            None
         };
-        let merkle = Merkle::new(node_store.clone(), root_cptr);
-        Self {
+        let changelog = if cfg.changelog {
+            let changelog =
+                Changelog::open(path).map_err(create(&format!("{}/changelog", path)))?;
+            Some(Arc::new(Mutex::new(changelog)))
+        } else {
+            None
+        };
+        Ok(Self {
             node_store,
             merkle: Arc::new(Mutex::new(merkle)),
             root_file: Arc::new(Mutex::new(root_file)),
@@ -77,7 +978,15 @@ impl DB {
             } else {
                 None
             },
-        }
+            max_batch_bytes: cfg.max_batch_bytes,
+            merge_fn: cfg.merge_fn,
+            wal,
+            sync_mode: cfg.sync_mode,
+            key_summary,
+            changelog,
+            on_commit: Arc::new(Mutex::new(CommitHooks::default())),
+            keccak_impl,
+        })
     }
 
     pub fn open_root(&mut self, root_cptr: CleanPtr) {
@@ -85,37 +994,581 @@ impl DB {
             return;
         }
         *self.merkle.lock().unwrap() = Merkle::new(self.node_store.clone(), root_cptr);
-        // Prevent stale values from a different root snapshot.
-        if let Some(cache) = &self.db_value_cache {
-            cache.lock().unwrap().clear();
+    }
+
+    /// Drop every version published after the latest one at `root_cptr`
+    /// and make it the current root, e.g. to undo blocks lost in a reorg.
+    /// Returns false, changing nothing, if `root_cptr` was never published.
+    ///
+    /// Only the root file shrinks: nodes written for the dropped versions
+    /// stay in the node file, unreferenced. Like `open_root`, this drops
+    /// writes that a batch has auto-flushed but not committed.
+    pub fn rollback_to(&mut self, root_cptr: CleanPtr) -> bool {
+        // Truncating writes the root file out, so its roots' nodes go first.
+        self.sync();
+        let mut root_file = self.root_file.lock().unwrap();
+        let Some(version) = root_file.find_version(root_cptr) else {
+            return false;
+        };
+        root_file.truncate(version + 1);
+        root_file.sync(self.sync_mode).unwrap();
+        if let Some(wal) = &self.wal {
+            let mut wal = wal.lock().unwrap();
+            wal.clear().unwrap();
+            wal.sync(self.sync_mode).unwrap();
+        }
+        drop(root_file);
+        let old_root = self.merkle.lock().unwrap().root_cptr();
+        self.open_root(root_cptr);
+        if let Some(changelog) = &self.changelog {
+            let merkle = self.merkle.lock().unwrap();
+            let mut changelog = changelog.lock().unwrap();
+            changelog.append(&merkle, old_root, root_cptr, &merkle.hash());
+            changelog.sync(self.sync_mode).unwrap();
         }
+        true
+    }
+
+    /// Number of roots published by commits so far.
+    pub fn version_count(&self) -> usize {
+        self.root_file.lock().unwrap().len()
+    }
+
+    /// Root pointer published by the `version`-th commit (0-based).
+    pub fn version_root(&self, version: usize) -> Option<CleanPtr> {
+        self.root_file.lock().unwrap().root_ptr(version)
+    }
+
+    /// Open the most recently published root whose hash is `root_hash`.
+    /// Returns the root opened, or `None` if no commit published that hash.
+    ///
+    /// Hashes are read from the root file. For a root file written before
+    /// hashes were stored, they are computed from each published root, newest
+    /// first, which reads every root node on the way.
+    pub fn open_root_hash(&mut self, root_hash: &[u8]) -> Option<CleanPtr> {
+        let wanted = padded_root_hash(root_hash);
+        let root_cptr = (0..self.version_count()).rev().find_map(|version| {
+            let mut root_file = self.root_file.lock().unwrap();
+            let root_cptr = root_file.root_ptr(version)?;
+            let hash = match root_file.root_hash(version) {
+                Some(hash) => hash,
+                None => {
+                    drop(root_file);
+                    padded_root_hash(&Merkle::new(self.node_store.clone(), root_cptr).hash())
+                }
+            };
+            (hash == wanted).then_some(root_cptr)
+        })?;
+        self.open_root(root_cptr);
+        Some(root_cptr)
+    }
+
+    /// Open the root of the `version`-th commit (0-based). Returns the root
+    /// opened, or `None` if there is no such version.
+    pub fn open_version(&mut self, version: usize) -> Option<CleanPtr> {
+        let root_cptr = self.version_root(version)?;
+        self.open_root(root_cptr);
+        Some(root_cptr)
+    }
+
+    /// Open the root committed `n` versions before the latest one; `n = 0`
+    /// is the latest.
+    pub fn open_version_from_tip(&mut self, n: usize) -> Option<CleanPtr> {
+        let version = self.version_count().checked_sub(n + 1)?;
+        self.open_version(version)
     }
 
     pub fn hash(&self) -> Vec<u8> {
         self.merkle.lock().unwrap().hash()
     }
 
+    /// Root hash that committing exactly `entries` to an empty DB with this
+    /// DB's hasher would give, computed on a throwaway in-memory trie. This
+    /// DB is not touched. For repeated keys the last entry wins.
+    pub fn root_for(&self, entries: &[(Vec<u8>, Vec<u8>)]) -> Vec<u8> {
+        let hasher = self.node_store.lock().unwrap().hasher();
+        let entries: Vec<_> = entries
+            .iter()
+            .map(|(key, value)| (key.clone(), Value::new(value.clone(), Vec::new())))
+            .collect();
+        Merkle::root_for_with(&entries, hasher)
+    }
+
+    /// The crate this DB computes Keccak256 with, as `DBConfig::keccak_impl`
+    /// chose it when the DB was opened, or `None` if `DBConfig::hasher`
+    /// replaced Keccak256.
+    pub fn keccak_impl(&self) -> Option<KeccakImpl> {
+        self.keccak_impl
+    }
+
+    /// Describe the committed node at `ptr`, such as a root from
+    /// `version_root`, for tools that walk or render the trie. `None` for an
+    /// unreadable pointer. A root of 0 is the empty trie and should not be
+    /// inspected.
+    pub fn inspect_node(&self, ptr: CleanPtr) -> Option<NodeView> {
+        self.node_store.lock().unwrap().inspect_node(ptr)
+    }
+
+    /// Read-only view pinned at `root_cptr`, independent of `open_root`.
+    pub fn snapshot_at(&self, root_cptr: CleanPtr) -> Snapshot {
+        Snapshot {
+            merkle: Merkle::new(self.node_store.clone(), root_cptr),
+        }
+    }
+
+    /// The value of `key` at the committed root `root_cptr`, e.g. one from
+    /// `version_root`. Neither the current root nor the value cache is
+    /// touched.
+    pub fn get_at(&self, root_cptr: CleanPtr, key: &[u8]) -> Option<Vec<u8>> {
+        self.snapshot_at(root_cptr).get(key)
+    }
+
+    /// Entries with keys in `[start, end]` at the current committed root, and
+    /// the nodes proving they are all of them; see `Merkle::range_proof` and
+    /// `verify_range_proof`.
+    pub fn range_proof(&self, start: &[u8], end: &[u8]) -> RangeProof<Vec<u8>> {
+        let root_cptr = self.merkle.lock().unwrap().root_cptr();
+        let (entries, proof) = self.snapshot_at(root_cptr).merkle.range_proof(start, end);
+        let entries = entries.into_iter().map(|(key, v)| (key, v.value)).collect();
+        (entries, proof)
+    }
+
+    /// In-memory writes layered over the current committed root; see
+    /// `Overlay`.
+    pub fn overlay(&self) -> Overlay {
+        let root_cptr = self.merkle.lock().unwrap().root_cptr();
+        Overlay {
+            base: self.snapshot_at(root_cptr),
+            pending: HashMap::new(),
+            batch: self.new_writebatch(),
+        }
+    }
+
+    /// Panics on a node that cannot be read or decoded; see `try_get`.
     pub fn get(&mut self, key: &[u8]) -> Option<Vec<u8>> {
-        if let Some(cache) = &self.db_value_cache {
+        self.get_arc(key).map(Arc::unwrap_or_clone)
+    }
+
+    /// Like `get`, but returns an error for a node on the path of `key`
+    /// that cannot be read or decoded, as a corrupt node file could hold,
+    /// so that a server can log the key and carry on.
+    pub fn try_get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>, DbError> {
+        Ok(self.try_get_arc(key)?.map(Arc::unwrap_or_clone))
+    }
+
+    /// Like `get`, but a value served by the value cache is shared with the
+    /// cache instead of copied, so repeated reads of a hot key make no new
+    /// allocation. Without the value cache, this is `get`.
+    pub fn get_arc(&mut self, key: &[u8]) -> Option<Arc<Vec<u8>>> {
+        self.try_get_arc(key).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// `get_arc` with the errors of `try_get`. Failed reads are not cached.
+    pub fn try_get_arc(&mut self, key: &[u8]) -> Result<Option<Arc<Vec<u8>>>, DbError> {
+        let find = |merkle: &Merkle| match merkle.try_find_at_fault(key) {
+            Ok(value) => Ok(value.map(|v| Arc::new(v.value))),
+            Err((ptr, _)) => Err(DbError::Decode { ptr }),
+        };
+        // Hold the merkle lock so the root and the lookup stay consistent.
+        let merkle = self.merkle.lock().unwrap();
+        // Writes auto-flushed by a batch are not part of any root yet.
+        if let Some(cache) = self.db_value_cache.as_ref().filter(|_| !merkle.is_dirty()) {
+            let cache_key = (merkle.root_cptr(), key.to_vec());
             let mut cache = cache.lock().unwrap();
-            if let Some(v) = cache.get(key) {
-                return v.clone();
+            if let Some(v) = cache.get(&cache_key) {
+                return Ok(v.as_ref().map(|v| v.0.clone()));
+            }
+
+            let computed = find(&merkle)?;
+            let _ = cache.insert(cache_key, computed.clone().map(SharedValue));
+            return Ok(computed);
+        }
+
+        find(&merkle)
+    }
+
+    /// The value of `key` together with the extra bytes stored by
+    /// `WriteBatch::insert_with_extra` (empty if none were set). Bypasses the
+    /// value cache, which only holds values.
+    pub fn get_with_extra(&mut self, key: &[u8]) -> Option<(Vec<u8>, Vec<u8>)> {
+        let merkle = self.merkle.lock().unwrap();
+        merkle.find(key).map(|v| (v.value, v.extra))
+    }
+
+    /// Return the value of `key`, or insert the value computed by `f` and
+    /// return it.
+    ///
+    /// A miss commits a new root holding just this write, which also makes
+    /// it the current root, so this is meant for low-frequency use such as
+    /// memoization rather than bulk loading.
+    pub fn get_or_insert_with(&mut self, key: &[u8], f: impl FnOnce() -> Vec<u8>) -> Vec<u8> {
+        if let Some(value) = self.get(key) {
+            return value;
+        }
+        let value = f();
+        let mut wb = self.new_writebatch();
+        wb.insert(key, &value);
+        wb.commit()
+            .expect("batch without compare_and_set cannot conflict");
+        value
+    }
+
+    /// Look up `keys` ahead of use so later `get`s are served from the value
+    /// cache, or at least from the node cache. A hint only: results are
+    /// unchanged.
+    pub fn prefetch(&mut self, keys: &[&[u8]]) {
+        let merkle = self.merkle.lock().unwrap();
+        let cache = self.db_value_cache.as_ref().filter(|_| !merkle.is_dirty());
+        let mut cache = cache.map(|cache| cache.lock().unwrap());
+        for key in keys {
+            let cache_key = (merkle.root_cptr(), key.to_vec());
+            if cache.as_mut().is_some_and(|c| c.contains(&cache_key)) {
+                continue;
+            }
+            let value = merkle.find(key).map(|v| SharedValue(Arc::new(v.value)));
+            if let Some(cache) = cache.as_mut() {
+                let _ = cache.insert(cache_key, value);
+            }
+        }
+    }
+
+    /// Number of keys in the current root.
+    pub fn len(&mut self) -> usize {
+        self.merkle.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&mut self) -> bool {
+        self.merkle.lock().unwrap().is_empty()
+    }
+
+    pub fn contains(&mut self, key: &[u8]) -> bool {
+        self.merkle.lock().unwrap().contains(key)
+    }
+
+    /// Return all committed key-value pairs whose key starts with `prefix`,
+    /// in ascending key order.
+    pub fn scan_prefix(&mut self, prefix: &[u8]) -> Vec<(Vec<u8>, Vec<u8>)> {
+        self.merkle
+            .lock()
+            .unwrap()
+            .scan_prefix(prefix)
+            .into_iter()
+            .map(|(key, value)| (key, value.value))
+            .collect()
+    }
+
+    /// Number of values at the committed root per power-of-two size class:
+    /// key `b` counts values of `b..2 * b` bytes, and key 0 empty values.
+    /// See `value_size_histogram_with`.
+    pub fn value_size_histogram(&mut self) -> BTreeMap<usize, usize> {
+        let bounds: Vec<usize> = (0..usize::BITS).map(|bit| 1 << bit).collect();
+        self.value_size_histogram_with(&bounds)
+    }
+
+    /// Number of values at the committed root per size bucket. `bounds` are
+    /// ascending bucket lower bounds: a value of `n` bytes is counted under
+    /// the largest bound `<= n`, or under 0 if there is none. Extra bytes
+    /// are not counted in the size, and empty buckets are left out.
+    ///
+    /// Walks every value of the root as of the call; later commits and
+    /// uncommitted writes are not seen.
+    pub fn value_size_histogram_with(&mut self, bounds: &[usize]) -> BTreeMap<usize, usize> {
+        let root_cptr = self.merkle.lock().unwrap().root_cptr();
+        let snapshot = Merkle::new(self.node_store.clone(), root_cptr);
+        let mut histogram = BTreeMap::new();
+        for (_, value) in snapshot.iter() {
+            let size = value.value.len();
+            let bucket = match bounds.partition_point(|bound| *bound <= size) {
+                0 => 0,
+                i => bounds[i - 1],
+            };
+            *histogram.entry(bucket).or_insert(0) += 1;
+        }
+        histogram
+    }
+
+    /// Current memory use of the DB's caches.
+    pub fn cache_stats(&self) -> CacheStats {
+        let store = self.node_store.lock().unwrap();
+        CacheStats {
+            clean_nodes_bytes: store.node_cache_bytes(),
+            value_cache_entries: self
+                .db_value_cache
+                .as_ref()
+                .map_or(0, |cache| cache.lock().unwrap().len()),
+            page_cache_bytes: store.page_cache_bytes(),
+        }
+    }
+
+    /// Set the value cache budget to `new_bytes`, evicting least recently
+    /// used entries down to it. A DB opened without a value cache gets one;
+    /// write batches created before that keep committing without it, which
+    /// only costs them cache warming.
+    pub fn resize_value_cache(&mut self, new_bytes: usize) {
+        match &self.db_value_cache {
+            Some(cache) => cache.lock().unwrap().set_max_size(new_bytes),
+            None => self.db_value_cache = Some(Arc::new(Mutex::new(LruCache::new(new_bytes)))),
+        }
+    }
+
+    /// Sorted 8-byte hashes, under the DB's hasher, of every key at the
+    /// current committed root: an exact summary of the key set that is much
+    /// smaller than the keys, e.g. for diffing it against another replica's.
+    ///
+    /// With `DBConfig::key_summary` set this is read from the summary of
+    /// the root, including an older one opened with `open_root`; otherwise,
+    /// or for a root committed without the option, every key is visited.
+    pub fn key_hashes(&self) -> Vec<[u8; 8]> {
+        let merkle = self.merkle.lock().unwrap();
+        let committed = Merkle::new(self.node_store.clone(), merkle.root_cptr());
+        drop(merkle);
+        if let Some(summary) = &self.key_summary
+            && let Some(hashes) = summary.lock().unwrap().hashes_for(&committed.hash())
+        {
+            return hashes;
+        }
+        let hasher = self.node_store.lock().unwrap().hasher();
+        KeySummary::expand(&KeySummary::hashes_of(&committed, hasher.as_ref()))
+    }
+
+    /// Call `cb` after every successful commit, once its root is durably
+    /// published, with the new root and the sorted keys the commit inserted
+    /// or removed, e.g. to evict them from a cache kept above the DB. An
+    /// import reports every key that differs from the previous root.
+    /// Commits made with `WriteBatch::commit_nosync` are reported, in
+    /// order, by the next durable commit or `sync`; a crash before then
+    /// loses them unreported along with their roots.
+    /// Replaces any earlier callback; batches already created and other
+    /// handles of this DB use the new one too. `cb` must not call
+    /// `on_commit` itself.
+    pub fn on_commit(&mut self, cb: CommitHook) {
+        self.on_commit.lock().unwrap().cb = Some(cb);
+    }
+
+    /// A cursor over the committed root at the time of the call, positioned
+    /// before the first key. Later commits and `open_root` don't affect it.
+    pub fn cursor(&self) -> Cursor {
+        let root_cptr = self.merkle.lock().unwrap().root_cptr();
+        Cursor::new(self.node_store.clone(), root_cptr)
+    }
+
+    /// Write every committed key-value pair at the current root to `w`, in
+    /// ascending key order.
+    ///
+    /// The format does not depend on the node layout: the magic `FICUSEXP`
+    /// and a u32 format version, then per entry a `1` tag byte followed by
+    /// the key and the value, each as a u32 length and the bytes, and finally
+    /// a `0` tag byte and the entry count as a u64. Integers are little
+    /// endian.
+    pub fn export(&mut self, w: &mut impl Write) -> io::Result<()> {
+        let root_cptr = self.merkle.lock().unwrap().root_cptr();
+        let committed = Merkle::new(self.node_store.clone(), root_cptr);
+        w.write_all(EXPORT_MAGIC)?;
+        w.write_all(&EXPORT_VERSION.to_le_bytes())?;
+        let mut count = 0u64;
+        for (key, value) in committed.iter() {
+            w.write_all(&[1])?;
+            write_frame(w, &key)?;
+            write_frame(w, &value.value)?;
+            count += 1;
+        }
+        w.write_all(&[0])?;
+        w.write_all(&count.to_le_bytes())?;
+        w.flush()
+    }
+
+    /// Build a new trie from an `export` stream, commit it as the latest
+    /// root and open it. Entries already in this DB are not carried over.
+    /// The trie is built bottom-up in one pass, reading the stream in chunks
+    /// of `DBConfig::max_batch_bytes`, or 64 MiB without that limit, so
+    /// memory is bounded by a chunk and the right edge of the trie rather
+    /// than the export. An invalid stream leaves the DB at its old root,
+    /// with the nodes of the chunks before the error unreferenced in the
+    /// node file. If only the key summary cannot be written, the import is
+    /// still committed and open, and the error is returned.
+    pub fn import(&mut self, r: &mut impl Read) -> io::Result<CleanPtr> {
+        let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+        let mut magic = [0u8; 8];
+        r.read_exact(&mut magic)?;
+        if &magic != EXPORT_MAGIC {
+            return Err(invalid("not a ficusdb export"));
+        }
+        if read_u32(r)? != EXPORT_VERSION {
+            return Err(invalid("unsupported export version"));
+        }
+        let chunk_bytes = match self.max_batch_bytes {
+            0 => IMPORT_CHUNK_BYTES,
+            max => max,
+        };
+        let old_root = self.merkle.lock().unwrap().root_cptr();
+        let mut builder = SortedBuilder::new(self.node_store.clone());
+        let mut chunk: Vec<(Vec<u8>, Value)> = Vec::new();
+        let (mut chunk_len, mut count) = (0, 0u64);
+        // last key of the chunks already added to the builder
+        let mut inserted_up_to: Option<Vec<u8>> = None;
+        loop {
+            let mut tag = [0u8; 1];
+            r.read_exact(&mut tag)?;
+            match tag[0] {
+                1 => {
+                    let key = read_frame(r)?;
+                    let value = read_frame(r)?;
+                    let prev = chunk.last().map(|(k, _)| k).or(inserted_up_to.as_ref());
+                    if prev.is_some_and(|prev| *prev >= key) {
+                        return Err(invalid("export keys are not in ascending order"));
+                    }
+                    chunk_len += key.len() + value.len();
+                    chunk.push((key, Value::new(value, Vec::new())));
+                    count += 1;
+                    if chunk_len >= chunk_bytes {
+                        builder.extend(&chunk);
+                        inserted_up_to = chunk.pop().map(|(k, _)| k);
+                        chunk.clear();
+                        chunk_len = 0;
+                    }
+                }
+                0 => break,
+                _ => return Err(invalid("bad export entry tag")),
+            }
+        }
+        let mut expected = [0u8; 8];
+        r.read_exact(&mut expected)?;
+        if u64::from_le_bytes(expected) != count {
+            return Err(invalid("export entry count mismatch"));
+        }
+        builder.extend(&chunk);
+        let root_cptr = builder.finish();
+        let fresh = Merkle::new(self.node_store.clone(), root_cptr);
+        publish_root(
+            &self.node_store,
+            &self.root_file,
+            self.wal.as_ref(),
+            self.sync_mode,
+            root_cptr,
+            &fresh.hash(),
+            true,
+        )?;
+        let root_hash = fresh.hash();
+        let summarized = match &self.key_summary {
+            Some(summary) => summary
+                .lock()
+                .unwrap()
+                .advance(&fresh, root_cptr, &root_hash),
+            None => Ok(()),
+        };
+        if let Some(changelog) = &self.changelog {
+            let mut changelog = changelog.lock().unwrap();
+            changelog.append(&fresh, old_root, root_cptr, &root_hash);
+            changelog.sync(self.sync_mode)?;
+        }
+        let mut hooks = self.on_commit.lock().unwrap();
+        if hooks.is_set() {
+            let keys: Vec<_> = fresh
+                .diff(old_root, root_cptr)
+                .into_iter()
+                .map(|(key, _, _)| key)
+                .collect();
+            hooks.report(root_cptr, keys, true);
+        }
+        drop(hooks);
+        *self.merkle.lock().unwrap() = fresh;
+        summarized?;
+        Ok(root_cptr)
+    }
+
+    /// Apply the changelog of the DB at `path` to this DB, one commit per
+    /// record, starting after the latest record for the root hash
+    /// `from_root`, which should be this DB's current root. With the empty
+    /// root hash and no such record, every record is applied. Returns the
+    /// number of records applied.
+    ///
+    /// Fails with `NotFound` if no record has `from_root`, and with
+    /// `InvalidData` if a commit does not reach its record's root hash;
+    /// records applied before that stay committed.
+    pub fn replay_changelog(&mut self, path: &str, from_root: &[u8]) -> io::Result<usize> {
+        let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+        let log = std::fs::read(format!("{}/changelog", path))?;
+        let mut r = log.as_slice();
+        let mut records = Vec::new();
+        // a record cut short by a crash ends the log
+        while let Ok(body) = read_frame(&mut r) {
+            records.push(body);
+        }
+        let root_of = |body: &[u8]| read_frame(&mut &body[..]);
+        let empty = self.node_store.lock().unwrap().hasher().empty_node_hash();
+        let start = match records
+            .iter()
+            .rposition(|body| root_of(body).is_ok_and(|hash| hash == from_root))
+        {
+            Some(i) => i + 1,
+            None if from_root == empty => 0,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "root is not in the changelog",
+                ));
             }
+        };
 
-            let computed = self.merkle.lock().unwrap().find(key).map(|v| v.value);
-            let _ = cache.insert(key.to_vec(), computed.clone());
-            return computed;
+        for body in &records[start..] {
+            let mut r = body.as_slice();
+            let root_hash = read_frame(&mut r)?;
+            let mut batch = self.new_writebatch();
+            while !r.is_empty() {
+                let mut tag = [0u8; 1];
+                r.read_exact(&mut tag)?;
+                let key = read_frame(&mut r)?;
+                match tag[0] {
+                    1 => batch.insert(&key, &read_frame(&mut r)?),
+                    2 => batch.remove(&key),
+                    _ => return Err(invalid("bad changelog entry tag")),
+                }
+            }
+            batch
+                .commit()
+                .expect("a batch without compare_and_set always commits");
+            if self.hash() != root_hash {
+                return Err(invalid("replayed record does not reach its root hash"));
+            }
         }
+        Ok(records.len() - start)
+    }
 
-        self.merkle.lock().unwrap().find(key).map(|v| v.value)
+    /// The entry with the largest key at the current root, e.g. the latest
+    /// record when keys are big-endian sequence numbers.
+    pub fn last(&self) -> Option<(Vec<u8>, Vec<u8>)> {
+        let merkle = self.merkle.lock().unwrap();
+        merkle
+            .iter_rev()
+            .next()
+            .map(|(key, value)| (key, value.value))
+    }
+
+    /// Start a transaction for writes spanning many operations; see `Txn`.
+    pub fn begin(&self) -> Txn {
+        Txn {
+            batch: self.new_writebatch(),
+        }
     }
 
     pub fn new_writebatch(&self) -> WriteBatch {
         WriteBatch {
             merkle: self.merkle.clone(),
             staging: HashMap::new(),
+            staged_bytes: 0,
+            max_batch_bytes: self.max_batch_bytes,
+            merges: HashMap::new(),
+            merge_fn: self.merge_fn.clone(),
             root_file: self.root_file.clone(),
             node_store: self.node_store.clone(),
+            wal: self.wal.clone(),
+            sync_mode: self.sync_mode,
+            key_summary: self.key_summary.clone(),
+            changelog: self.changelog.clone(),
+            on_commit: self.on_commit.clone(),
+            changed: Vec::new(),
+            expected: Vec::new(),
+            failed: None,
             committed: false,
             db_value_cache: if let Some(cache) = &self.db_value_cache {
                 Some(cache.clone())
@@ -125,9 +1578,32 @@ impl DB {
         }
     }
 
+    /// Make every root committed so far durable, including the ones from
+    /// `WriteBatch::commit_nosync`, with one flush and sync of the node and
+    /// root files. Panics if a sync fails; see `try_sync`.
+    pub fn sync(&mut self) {
+        self.try_sync().unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Like `sync`, but return the error of a failed sync. The roots stay
+    /// unsynced, so that a later call retries them.
+    pub fn try_sync(&mut self) -> io::Result<()> {
+        sync_roots(
+            &self.node_store,
+            &self.root_file,
+            self.wal.as_ref(),
+            self.sync_mode,
+        )?;
+        if let Some(changelog) = &self.changelog {
+            changelog.lock().unwrap().sync(self.sync_mode)?;
+        }
+        self.on_commit.lock().unwrap().report_pending();
+        Ok(())
+    }
+
+    /// Same as `sync`.
     pub fn flush(&mut self) {
-        self.root_file.lock().unwrap().flush();
-        self.node_store.lock().unwrap().flush();
+        self.sync();
     }
 
     #[cfg(feature = "stats")]
@@ -142,46 +1618,605 @@ impl Drop for DB {
     }
 }
 
+/// Memory held by a DB's caches, from `DB::cache_stats`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Decoded nodes in the clean-node cache.
+    pub clean_nodes_bytes: usize,
+    /// Values in the DB value cache, which is sized in bytes but counted
+    /// here by entry.
+    pub value_cache_entries: usize,
+    /// Pages of the node and blob files held in memory.
+    pub page_cache_bytes: usize,
+}
+
+/// A read-only handle over a committed root.
+///
+/// Each snapshot owns its own `Merkle` over the shared node store, so
+/// snapshots at different roots coexist and are unaffected by
+/// `DB::open_root`. Reads bypass the DB value cache.
+pub struct Snapshot {
+    merkle: Merkle,
+}
+
+impl Snapshot {
+    pub fn root_cptr(&self) -> CleanPtr {
+        self.merkle.root_cptr()
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.merkle.find(key).map(|v| v.value)
+    }
+
+    pub fn hash(&self) -> Vec<u8> {
+        self.merkle.hash()
+    }
+}
+
+/// Writes kept in memory on top of a committed root, for read, modify,
+/// read loops.
+///
+/// Reads see the overlay's own writes first and fall through to the root
+/// the overlay was created at, like a `Snapshot`. Nothing reaches the DB
+/// until `commit`, which applies the writes as one `WriteBatch` on top of
+/// the DB's latest root, which may have moved on since. Dropping the
+/// overlay discards its writes.
+pub struct Overlay {
+    base: Snapshot,
+    // `None` is a deletion.
+    pending: HashMap<Vec<u8>, Option<Vec<u8>>>,
+    batch: WriteBatch,
+}
+
+impl Overlay {
+    /// The committed root this overlay reads through to.
+    pub fn base_root(&self) -> CleanPtr {
+        self.base.root_cptr()
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        match self.pending.get(key) {
+            Some(value) => value.clone(),
+            None => self.base.get(key),
+        }
+    }
+
+    pub fn insert(&mut self, key: &[u8], value: &[u8]) {
+        self.pending.insert(key.to_vec(), Some(value.to_vec()));
+    }
+
+    pub fn remove(&mut self, key: &[u8]) {
+        self.pending.insert(key.to_vec(), None);
+    }
+
+    /// Number of keys written or removed in this overlay.
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// Apply the writes to the DB and publish a new root.
+    pub fn commit(mut self) -> CleanPtr {
+        for (key, value) in self.pending.drain() {
+            match value {
+                Some(value) => self.batch.insert(&key, &value),
+                None => self.batch.remove(&key),
+            }
+        }
+        self.batch
+            .commit()
+            .expect("an overlay has no compare-and-set expectations")
+    }
+}
+
+/// Writes gathered from many logical operations, and from other
+/// `WriteBatch`es, for one commit.
+///
+/// Reads see the transaction's own writes. `stage` applies the writes so
+/// far to the trie's dirty nodes without writing anything to disk, and can
+/// be called any number of times; `commit` then does a single trie commit
+/// and publishes one root. Like a batch's auto-flushed writes, staged
+/// writes live in the DB's current trie, so other handles read them and
+/// `DB::open_root` drops them.
+pub struct Txn {
+    batch: WriteBatch,
+}
+
+impl Txn {
+    pub fn insert(&mut self, key: &[u8], value: &[u8]) {
+        self.batch.insert(key, value);
+    }
+
+    pub fn remove(&mut self, key: &[u8]) {
+        self.batch.remove(key);
+    }
+
+    /// The value of `key` as this transaction would commit it.
+    pub fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.batch.get(key)
+    }
+
+    /// Take over the writes and `compare_and_set` expectations of `batch`
+    /// instead of committing it on its own. Later writes win.
+    pub fn append(&mut self, mut batch: WriteBatch) {
+        for (key, value) in batch.staging.drain() {
+            self.batch.stage(key, value);
+        }
+        for (key, operands) in batch.merges.drain() {
+            for operand in operands {
+                self.batch.merge(&key, &operand);
+            }
+        }
+        self.batch.expected.append(&mut batch.expected);
+        self.batch.changed.append(&mut batch.changed);
+    }
+
+    /// Apply the writes so far to the trie's dirty nodes.
+    pub fn stage(&mut self) {
+        self.batch.flush_staging();
+    }
+
+    /// Commit everything written or staged as one new root.
+    pub fn commit(mut self) -> Result<CleanPtr, CommitError> {
+        self.batch.commit()
+    }
+}
+
 pub struct WriteBatch {
     merkle: Arc<Mutex<Merkle>>,
-    staging: HashMap<Vec<u8>, Vec<u8>>,
-    root_file: Arc<Mutex<PageCachedFile>>,
+    // `None` stages a deletion.
+    staging: HashMap<Vec<u8>, Option<Value>>,
+    staged_bytes: usize,
+    max_batch_bytes: usize,
+    // `merge` operands per key, oldest first, resolved at commit
+    merges: HashMap<Vec<u8>, Vec<Vec<u8>>>,
+    merge_fn: Option<MergeFn>,
+    root_file: Arc<Mutex<RootFile>>,
     node_store: Arc<Mutex<NodeStore>>,
-    db_value_cache: Option<Arc<Mutex<LruCache<Vec<u8>, Option<Vec<u8>>>>>>,
+    db_value_cache: Option<Arc<Mutex<ValueCache>>>,
+    wal: Option<Arc<Mutex<Wal>>>,
+    sync_mode: SyncMode,
+    key_summary: Option<Arc<Mutex<KeySummary>>>,
+    changelog: Option<Arc<Mutex<Changelog>>>,
+    on_commit: Arc<Mutex<CommitHooks>>,
+    // keys auto-flushed into the merkle, kept for `on_commit`
+    changed: Vec<Vec<u8>>,
+    // `compare_and_set` preconditions, checked at commit
+    expected: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+    // why an auto-flush could not apply its writes, reported at commit
+    failed: Option<io::Error>,
     committed: bool,
 }
 
+/// Why a `DB` read failed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DbError {
+    /// The committed node at `ptr` on the key's path could not be read or
+    /// decoded, or does not fit the trie's shape.
+    Decode { ptr: CleanPtr },
+}
+
+impl std::fmt::Display for DbError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DbError::Decode { ptr } => write!(f, "cannot decode node {ptr}"),
+        }
+    }
+}
+
+impl std::error::Error for DbError {}
+
+/// Why `DB::try_open` could not open a DB.
+#[derive(Debug)]
+pub enum OpenError {
+    /// The DB directory, or a file in it, could not be created or opened.
+    Create { path: String, source: io::Error },
+    /// The root file does not end on a record boundary.
+    RootFileLength { path: String, len: u64, record: u64 },
+    /// `file` was written in a format version this build does not read.
+    Version {
+        path: String,
+        file: &'static str,
+        found: String,
+        expected: String,
+    },
+    /// The node file does not start with a valid format header.
+    NodeFile {
+        path: String,
+        source: NodeHeaderError,
+    },
+    /// A file does not authenticate under `encryption_key`: the key is
+    /// wrong or the file is corrupt.
+    Decrypt { path: String, source: io::Error },
+    /// A file could not be read or written while opening, e.g. to roll
+    /// back a torn commit logged in the WAL.
+    Io { path: String, source: io::Error },
+    /// `DBConfig` asks for something this build cannot open.
+    Config(&'static str),
+}
+
+impl std::fmt::Display for OpenError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OpenError::Create { path, source } => write!(f, "{path}: cannot create: {source}"),
+            OpenError::RootFileLength { path, len, record } => write!(
+                f,
+                "{path}: length {len} is not a whole number of {record}-byte records"
+            ),
+            OpenError::Version {
+                path,
+                file,
+                found,
+                expected,
+            } => write!(
+                f,
+                "{path}: {file} format version {found} is not supported (expected {expected})"
+            ),
+            OpenError::NodeFile { path, source } => write!(f, "{path}: {source}"),
+            OpenError::Decrypt { path, source } => write!(f, "{path}: cannot decrypt: {source}"),
+            OpenError::Io { path, source } => write!(f, "{path}: {source}"),
+            OpenError::Config(reason) => write!(f, "invalid config: {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for OpenError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            OpenError::Create { source, .. } => Some(source),
+            OpenError::NodeFile { source, .. } => Some(source),
+            OpenError::Decrypt { source, .. } => Some(source),
+            OpenError::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+/// Why `WriteBatch::commit` rejected a batch.
+#[derive(Debug)]
+pub enum CommitError {
+    /// The committed value of `key` did not match its `compare_and_set`
+    /// expectation.
+    CasConflict { key: Vec<u8> },
+    /// The batch was applied and its root is current, as after
+    /// `commit_nosync`, but syncing it to stable storage failed. A later
+    /// `DB::try_sync` retries.
+    Sync(io::Error),
+    /// A node the batch had to copy could not be read, or a branch's AHA
+    /// array did not match it under `DBConfig::strict_aha`. Nothing was
+    /// applied, including writes an auto-flush had applied, and the batch
+    /// is cleared.
+    Corrupt(io::Error),
+    /// The batch was applied and synced, but its root could not be added
+    /// to the key summary of `DBConfig::key_summary`; `DB::key_hashes`
+    /// walks its keys instead.
+    KeySummary(io::Error),
+}
+
+impl std::fmt::Display for CommitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CommitError::CasConflict { key } => {
+                write!(f, "compare-and-set conflict on key 0x{}", hex::encode(key))
+            }
+            CommitError::Sync(source) => write!(f, "cannot sync the commit: {source}"),
+            CommitError::Corrupt(source) => write!(f, "cannot apply the batch: {source}"),
+            CommitError::KeySummary(source) => {
+                write!(f, "cannot add the commit to the key summary: {source}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for CommitError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CommitError::Sync(source)
+            | CommitError::Corrupt(source)
+            | CommitError::KeySummary(source) => Some(source),
+            _ => None,
+        }
+    }
+}
+
 impl WriteBatch {
     pub fn insert(&mut self, key: &[u8], value: &[u8]) {
-        self.staging.insert(key.to_vec(), value.to_vec());
+        self.stage(key.to_vec(), Some(Value::new(value.to_vec(), Vec::new())));
     }
 
-    pub fn commit(&mut self) -> CleanPtr {
-        let root_cptr = {
-            let mut merkle = self.merkle.lock().unwrap();
-            if let Some(cache) = &self.db_value_cache {
-                let mut cache = cache.lock().unwrap();
-                for (key, value) in self.staging.drain() {
-                    merkle.insert(&key, Value::new(value.clone(), Vec::new()));
-                    let _ = cache.insert(key, Some(value));
+    /// Like `insert`, also storing `extra` next to the value. `extra` is not
+    /// part of the value that `DB::get` returns; read it back with
+    /// `DB::get_with_extra`.
+    pub fn insert_with_extra(&mut self, key: &[u8], value: &[u8], extra: &[u8]) {
+        self.stage(
+            key.to_vec(),
+            Some(Value::new(value.to_vec(), extra.to_vec())),
+        );
+    }
+
+    pub fn remove(&mut self, key: &[u8]) {
+        self.stage(key.to_vec(), None);
+    }
+
+    /// Stage `operand` to be folded into the value of `key` by
+    /// `DBConfig::merge_fn` when the batch commits. The value is read at
+    /// commit, under the same lock as the writes, so concurrent batches
+    /// merging into one key do not lose each other's updates. Merging into
+    /// a key with a staged insert or removal folds into that at once; a
+    /// later insert or removal replaces the pending operands. Operands
+    /// count towards `staged_bytes`, and an auto-flush folds them into the
+    /// trie like the other staged writes.
+    ///
+    /// Panics if the DB has no `merge_fn`.
+    pub fn merge(&mut self, key: &[u8], operand: &[u8]) {
+        let merge_fn = self
+            .merge_fn
+            .clone()
+            .expect("WriteBatch::merge needs DBConfig::merge_fn");
+        if let Some(staged) = self.staging.get(key) {
+            let value = merge_fn(staged.as_ref().map(|v| v.value.as_slice()), &[operand]);
+            self.insert(key, &value);
+            return;
+        }
+        let operands = self.merges.entry(key.to_vec()).or_default();
+        if operands.is_empty() {
+            self.staged_bytes += key.len();
+        }
+        operands.push(operand.to_vec());
+        self.staged_bytes += operand.len();
+        if self.max_batch_bytes > 0 && self.staged_bytes > self.max_batch_bytes {
+            self.flush_staging();
+        }
+    }
+
+    /// The value of `key` as this batch would commit it: a staged write
+    /// wins, and otherwise the trie is read, including writes auto-flushed
+    /// from this batch, with any pending `merge` operands folded in.
+    pub fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        match self.staging.get(key) {
+            Some(staged) => staged.as_ref().map(|v| v.value.clone()),
+            None => {
+                let merkle = self.merkle.lock().unwrap();
+                match self.merges.get(key) {
+                    Some(operands) => Some(self.fold(&merkle, key, operands).value),
+                    None => merkle.find(key).map(|v| v.value),
                 }
-            } else {
-                for (key, value) in self.staging.drain() {
-                    merkle.insert(&key, Value::new(value.clone(), Vec::new()));
+            }
+        }
+    }
+
+    /// Number of keys with a staged insert, removal or merge. Writes
+    /// already applied to the trie by an auto-flush are not counted.
+    pub fn len(&self) -> usize {
+        self.staging.len() + self.merges.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.staging.is_empty() && self.merges.is_empty()
+    }
+
+    /// Drop the staged writes without committing; the batch can be reused.
+    /// Writes already applied to the trie by an auto-flush are kept.
+    pub fn clear(&mut self) {
+        self.staging.clear();
+        self.merges.clear();
+        self.expected.clear();
+        self.staged_bytes = 0;
+    }
+
+    /// Stage `new` for `key`, but only if the committed value of `key` is
+    /// still `expected` (`None` for absent) when the batch commits. If any
+    /// expectation fails, `commit` applies nothing from the batch.
+    pub fn compare_and_set(&mut self, key: &[u8], expected: Option<&[u8]>, new: &[u8]) {
+        self.expected
+            .push((key.to_vec(), expected.map(|v| v.to_vec())));
+        self.insert(key, new);
+    }
+
+    /// Key, value and merge operand bytes currently held in the batch.
+    pub fn staged_bytes(&self) -> usize {
+        self.staged_bytes
+    }
+
+    fn stage(&mut self, key: Vec<u8>, value: Option<Value>) {
+        let value_len = |v: &Option<Value>| v.as_ref().map_or(0, |v| v.value.len() + v.extra.len());
+        let key_len = key.len();
+        if let Some(operands) = self.merges.remove(&key) {
+            self.staged_bytes -= key_len + operands.iter().map(Vec::len).sum::<usize>();
+        }
+        self.staged_bytes += key_len + value_len(&value);
+        if let Some(old) = self.staging.insert(key, value) {
+            self.staged_bytes -= key_len + value_len(&old);
+        }
+        if self.max_batch_bytes > 0 && self.staged_bytes > self.max_batch_bytes {
+            self.flush_staging();
+        }
+    }
+
+    /// Apply the staged writes, with pending merge operands folded in, to
+    /// the trie's dirty nodes, without committing them.
+    fn flush_staging(&mut self) {
+        let sorted = self.node_store.lock().unwrap().deterministic_layout();
+        let mut merkle = self.merkle.lock().unwrap();
+        for (key, operands) in std::mem::take(&mut self.merges) {
+            let value = self.fold(&merkle, &key, &operands);
+            self.staging.insert(key, Some(value));
+        }
+        if self.on_commit.lock().unwrap().is_set() {
+            self.changed.extend(self.staging.keys().cloned());
+        }
+        let staged = Self::drain_staging(&mut self.staging, sorted);
+        if self.failed.is_none() {
+            self.failed = Self::apply(&mut merkle, staged).err();
+        }
+        self.staged_bytes = 0;
+    }
+
+    /// Insert or delete each of `staged` in `merkle`, stopping at the first
+    /// node that cannot be copied.
+    fn apply(merkle: &mut Merkle, staged: Vec<(Vec<u8>, Option<Value>)>) -> io::Result<()> {
+        for (key, value) in staged {
+            match value {
+                Some(value) => merkle.try_insert(&key, value)?,
+                None => {
+                    merkle.try_delete(&key)?;
                 }
             }
-            merkle.commit()
-        };
+        }
+        Ok(())
+    }
 
-        // Ensure node bytes are durable before publishing the new root pointer.
-        self.node_store.lock().unwrap().flush();
+    /// The value `operands` fold `key` into, starting from its value in
+    /// `merkle`. Extra bytes stored with the old value are kept.
+    fn fold(&self, merkle: &Merkle, key: &[u8], operands: &[Vec<u8>]) -> Value {
+        let merge_fn = self.merge_fn.as_ref().expect("operands need a merge_fn");
+        let old = merkle.find(key);
+        let operands: Vec<&[u8]> = operands.iter().map(|op| op.as_slice()).collect();
+        let value = merge_fn(old.as_ref().map(|v| v.value.as_slice()), &operands);
+        Value::new(value, old.map_or_else(Vec::new, |v| v.extra))
+    }
 
-        let mut root_file = self.root_file.lock().unwrap();
-        let tail = root_file.tail() as u64;
-        root_file.write(tail, &root_cptr.to_le_bytes());
-        root_file.flush();
+    /// Take the staged writes, in key order if `sorted`. The order they are
+    /// applied in decides which untouched nodes get copied on the way, so
+    /// `DBConfig::deterministic_layout` needs a fixed one.
+    fn drain_staging(
+        staging: &mut HashMap<Vec<u8>, Option<Value>>,
+        sorted: bool,
+    ) -> Vec<(Vec<u8>, Option<Value>)> {
+        let mut staged: Vec<_> = staging.drain().collect();
+        if sorted {
+            staged.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        }
+        staged
+    }
+
+    /// Stage deletions for every committed or staged key starting with
+    /// `prefix`. Later inserts in this batch take precedence.
+    pub fn remove_prefix(&mut self, prefix: &[u8]) {
+        let committed = self.merkle.lock().unwrap().scan_prefix(prefix);
+        let staged: Vec<Vec<u8>> = self
+            .staging
+            .keys()
+            .filter(|key| key.starts_with(prefix))
+            .cloned()
+            .collect();
+        for key in committed.into_iter().map(|(key, _)| key).chain(staged) {
+            self.stage(key, None);
+        }
+    }
+
+    /// Apply the staged writes and publish a new root. Fails without
+    /// applying anything, and clears the batch, if a `compare_and_set`
+    /// expectation does not hold against the committed root; writes already
+    /// applied by an auto-flush are discarded too. Fails with
+    /// `CommitError::Corrupt` in the same way if a node the writes copy
+    /// cannot be read, and with `CommitError::Sync` if the new root cannot
+    /// be synced.
+    pub fn commit(&mut self) -> Result<CleanPtr, CommitError> {
+        self.commit_with(true)
+    }
 
+    /// Like `commit`, but leave the new root and its nodes in memory until
+    /// the next durable commit or `DB::sync`, so that a group of commits
+    /// pays for one flush and sync instead of one each. The root is current
+    /// at once for every handle. A crash before the group is synced loses
+    /// all of its roots, and the DB reopens at the last synced one.
+    /// `DB::on_commit` hears of the commit only once it has been synced.
+    pub fn commit_nosync(&mut self) -> Result<CleanPtr, CommitError> {
+        self.commit_with(false)
+    }
+
+    fn commit_with(&mut self, durable: bool) -> Result<CleanPtr, CommitError> {
+        self.staged_bytes = 0;
+        let sorted = self.node_store.lock().unwrap().deterministic_layout();
+        let (old_root, root_cptr, root_hash) = {
+            let mut merkle = self.merkle.lock().unwrap();
+            let old_root = merkle.root_cptr();
+            if !self.expected.is_empty() {
+                let committed = Merkle::new(self.node_store.clone(), merkle.root_cptr());
+                let conflict = self
+                    .expected
+                    .drain(..)
+                    .find(|(key, expected)| committed.find(key).map(|v| v.value) != *expected);
+                if let Some((key, _)) = conflict {
+                    self.staging.clear();
+                    self.merges.clear();
+                    self.changed.clear();
+                    merkle.discard();
+                    return Err(CommitError::CasConflict { key });
+                }
+            }
+            for (key, operands) in std::mem::take(&mut self.merges) {
+                let value = self.fold(&merkle, &key, &operands);
+                self.staging.insert(key, Some(value));
+            }
+            if self.on_commit.lock().unwrap().is_set() {
+                self.changed.extend(self.staging.keys().cloned());
+            }
+            let staged = Self::drain_staging(&mut self.staging, sorted);
+            let cached = self.db_value_cache.is_some().then(|| staged.clone());
+            let applied = match self.failed.take() {
+                Some(e) => Err(e),
+                None => Self::apply(&mut merkle, staged),
+            };
+            if let Err(e) = applied {
+                self.merges.clear();
+                self.changed.clear();
+                merkle.discard();
+                return Err(CommitError::Corrupt(e));
+            }
+            let root_cptr = merkle.commit();
+            if let (Some(cache), Some(staged)) = (&self.db_value_cache, cached) {
+                // The staged values are only known to be current at the new root.
+                let mut cache = cache.lock().unwrap();
+                for (key, value) in staged {
+                    let _ = cache.insert(
+                        (root_cptr, key),
+                        value.map(|v| SharedValue(Arc::new(v.value))),
+                    );
+                }
+            }
+            (old_root, root_cptr, merkle.hash())
+        };
+
+        let mut synced = publish_root(
+            &self.node_store,
+            &self.root_file,
+            self.wal.as_ref(),
+            self.sync_mode,
+            root_cptr,
+            &root_hash,
+            durable,
+        );
+        let mut summarized = Ok(());
+        if let Some(summary) = &self.key_summary {
+            let merkle = self.merkle.lock().unwrap();
+            summarized = summary
+                .lock()
+                .unwrap()
+                .advance(&merkle, root_cptr, &root_hash);
+        }
+        if let Some(changelog) = &self.changelog {
+            let merkle = self.merkle.lock().unwrap();
+            let mut changelog = changelog.lock().unwrap();
+            changelog.append(&merkle, old_root, root_cptr, &root_hash);
+            if durable && synced.is_ok() {
+                synced = changelog.sync(self.sync_mode);
+            }
+        }
+        let mut changed = std::mem::take(&mut self.changed);
+        changed.sort();
+        changed.dedup();
+        self.on_commit
+            .lock()
+            .unwrap()
+            .report(root_cptr, changed, durable && synced.is_ok());
         self.committed = true;
-        root_cptr
+        synced.map_err(CommitError::Sync)?;
+        summarized.map_err(CommitError::KeySummary)?;
+        Ok(root_cptr)
     }
 }
@@ -1,12 +1,31 @@
 mod backend;
 mod db;
 mod merkle;
+mod metrics;
 mod statedb;
 #[cfg(feature = "stats")]
 mod stats;
+#[cfg(feature = "serde")]
+mod typed;
+mod wal;
 
-pub use db::{DB, DBConfig, WriteBatch};
-pub use statedb::{StateDB, StateDBConfig};
+pub use backend::SyncMode;
+pub use db::{
+    CacheStats, CommitError, DB, DBConfig, DbError, OpenError, Overlay, Snapshot, Txn, WriteBatch,
+};
+#[cfg(feature = "tiny-keccak")]
+pub use merkle::TinyKeccak256Hasher;
+pub use merkle::{
+    CachePolicy, ChildView, Cursor, Hasher, IntegrityError, Keccak256Hasher, KeccakImpl,
+    NibblePath, NodeHeaderError, NodeView, verify_range_proof,
+};
+pub use metrics::Metrics;
+pub use statedb::{
+    AccountChange, AccountInfo, GenesisAccount, InsufficientBalance, StateDB, StateDBConfig,
+    StateReadView,
+};
+#[cfg(feature = "serde")]
+pub use typed::{TypedDB, TypedWriteBatch};
 
 use crate::backend::PageCachedFile;
 use crate::merkle::CleanPtr;
@@ -28,6 +47,14 @@ impl merkle::Backend for PageCachedFile {
         PageCachedFile::flush(self);
     }
 
+    fn sync(&mut self, mode: SyncMode) -> std::io::Result<()> {
+        PageCachedFile::sync(self, mode)
+    }
+
+    fn cached_bytes(&self) -> usize {
+        PageCachedFile::cached_bytes(self)
+    }
+
     #[cfg(feature = "stats")]
     fn print_stats(&mut self) {
         PageCachedFile::print_stats(self);
@@ -3,20 +3,45 @@ use super::CleanPtr;
 use super::backend::Backend;
 #[cfg(feature = "stats")]
 use super::stats::AHAStats;
+use crate::backend::SyncMode;
+use sha3::{Digest, Keccak256};
+use std::io::{Error, ErrorKind};
 #[cfg(feature = "stats")]
 use std::time::Instant;
 
+/// Longest reference item stored inline instead of hashed (RLP < 32 bytes).
+const MAX_INLINE_REF: usize = 31;
+
+/// Hash arrays of branch nodes, in tiers by array length. A node refers to
+/// its array by length and slot index; slot `i` of a tier starts at byte
+/// `i * stride`, whatever the tier backend's length.
 pub struct AggregatedHashArray {
     backends: Vec<Box<dyn Backend>>,
     aha_len: Vec<u8>,
+    // Worst-case bytes per stored item, including its length byte.
+    entry_bytes: usize,
     recycled: Vec<Vec<CleanPtr>>,
     pending_recycle: Vec<Vec<CleanPtr>>,
+    // where `recycled` is saved on flush, if anywhere
+    recycle_store: Option<Box<dyn Backend>>,
+    // whether replaced arrays free their slots for reuse
+    recycle: bool,
     #[cfg(feature = "stats")]
     stats: AHAStats,
 }
 
 impl AggregatedHashArray {
-    pub fn new(mut ahas: Vec<(u8, Box<dyn Backend>)>) -> Self {
+    /// `hash_len` is the digest size of the trie hasher. Each stored item is
+    /// [u8 length] + [reference item], where a reference item is either an
+    /// inlined node (at most 31 bytes) or RLP(hash), so every array slot is
+    /// sized for the larger of the two.
+    pub fn new(mut ahas: Vec<(u8, Box<dyn Backend>)>, hash_len: usize) -> Self {
+        let hashed_ref = rlp::encode(&vec![0u8; hash_len].as_slice()).len();
+        let max_ref = hashed_ref.max(MAX_INLINE_REF);
+        assert!(
+            max_ref <= u8::MAX as usize,
+            "reference items must fit a one-byte length"
+        );
         let mut backends = Vec::new();
         let mut aha_len = Vec::new();
         let mut recycled = Vec::new();
@@ -30,13 +55,66 @@ impl AggregatedHashArray {
         Self {
             backends,
             aha_len,
+            entry_bytes: 1 + max_ref,
             recycled,
             pending_recycle,
+            recycle_store: None,
+            recycle: true,
             #[cfg(feature = "stats")]
             stats: AHAStats::new(),
         }
     }
 
+    /// Stop freeing the slots of replaced arrays, and reusing freed ones,
+    /// so that every array a published root refers to stays as written.
+    /// The AHA files then grow with every commit, like the node file.
+    /// Slots already on the saved free lists stay there.
+    pub fn set_recycle(&mut self, recycle: bool) {
+        self.recycle = recycle;
+    }
+
+    /// Save the recycle lists to `store` on every `flush`, so that slots
+    /// freed before a reopen are still reused after it. The lists saved by
+    /// the last flush are loaded now. Lists that are missing, torn or saved
+    /// for different tiers are ignored, which only leaks their slots.
+    pub fn with_recycle_store(mut self, mut store: Box<dyn Backend>) -> Self {
+        let saved = store.read(0, store.tail() as usize);
+        if let Some(recycled) = self.decode_recycled(&saved) {
+            self.recycled = recycled;
+        }
+        self.recycle_store = Some(store);
+        self
+    }
+
+    fn decode_recycled(&self, buf: &[u8]) -> Option<Vec<Vec<CleanPtr>>> {
+        let mut off = 0;
+        let mut next = || {
+            let word = buf.get(off..off + 8)?;
+            off += 8;
+            Some(u64::from_le_bytes(word.try_into().unwrap()))
+        };
+        if next()? != self.aha_len.len() as u64 {
+            return None;
+        }
+        let mut recycled = Vec::new();
+        for idx in 0..self.aha_len.len() {
+            let slots = self.slot_count(idx);
+            let count = next()?;
+            let mut list = Vec::new();
+            for _ in 0..count {
+                let slot = next()?;
+                // a slot past the tier's end was not saved for this file
+                if slot >= slots {
+                    return None;
+                }
+                list.push(slot);
+            }
+            recycled.push(list);
+        }
+        let checksum = Keccak256::digest(&buf[..off]);
+        (buf.get(off..off + 8)? == &checksum[..8]).then_some(recycled)
+    }
+
     #[inline(always)]
     fn aha_index(&self, len: u8) -> usize {
         for i in 0..self.aha_len.len() {
@@ -47,73 +125,111 @@ impl AggregatedHashArray {
         self.aha_len.len()
     }
 
+    /// Bytes per slot of tier `idx`.
+    #[inline(always)]
+    fn stride(&self, idx: usize) -> usize {
+        self.aha_len[idx] as usize * self.entry_bytes
+    }
+
+    /// Slots in use or freed in tier `idx`. A backend whose length is not
+    /// a multiple of the stride, e.g. a pre-sized or shared file, has its
+    /// partial last slot counted as used.
+    fn slot_count(&self, idx: usize) -> CleanPtr {
+        self.backends[idx]
+            .tail()
+            .div_ceil(self.stride(idx) as CleanPtr)
+    }
+
     #[inline(always)]
-    fn new_cptr(&mut self, idx: usize) -> CleanPtr {
-        match self.recycled[idx].pop() {
-            Some(cptr) => {
+    fn new_slot(&mut self, idx: usize) -> CleanPtr {
+        let reused = if self.recycle {
+            self.recycled[idx].pop()
+        } else {
+            None
+        };
+        match reused {
+            Some(slot) => {
                 #[cfg(feature = "stats")]
                 {
                     self.stats.reused += 1;
                 }
-                cptr
+                slot
             }
             None => {
                 #[cfg(feature = "stats")]
                 {
                     self.stats.new += 1;
                 }
-                self.backends[idx].tail()
+                self.slot_count(idx)
             }
         }
     }
 
-    pub fn read_aha(&mut self, aha_len: u8, aha_ptr: CleanPtr) -> Vec<Vec<u8>> {
+    /// Read back `aha_len` reference items stored in slot `aha_ptr`. Fails if
+    /// the stored lengths run past the data the backend returns, e.g. on a
+    /// truncated AHA file.
+    pub fn read_aha(&mut self, aha_len: u8, aha_ptr: CleanPtr) -> Result<Vec<Vec<u8>>, Error> {
         let idx = self.aha_index(aha_len);
-        // Each stored item is: [u8 length] + [reference-item bytes].
-        // Reference items are either:
-        // - inlined canonical RLP (<32 bytes)  => at most 31 bytes
-        // - RLP(keccak256(rlp))               => 33 bytes (0xa0 + 32-byte hash)
-        // So worst-case is 1 + 33 = 34 bytes per entry.
-        let max_bytes = (self.aha_len[idx] as usize) * (33 + 1);
+        if idx >= self.aha_len.len() {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "AHA length exceeds every tier",
+            ));
+        }
+        let max_bytes = self.stride(idx);
         let backend = &mut self.backends[idx];
-        let buf = backend.read(aha_ptr, max_bytes);
+        let buf = backend.read(aha_ptr * max_bytes as CleanPtr, max_bytes);
         let mut off = 0;
         let mut hashs = Vec::new();
         for _ in 0..aha_len as usize {
-            let len = u8::from_le_bytes(buf[off..off + 1].try_into().unwrap());
-            let hash = buf[off + 1..off + 1 + len as usize].to_vec();
+            let Some(&len) = buf.get(off) else {
+                return Err(Error::new(
+                    ErrorKind::UnexpectedEof,
+                    "AHA entry length is missing",
+                ));
+            };
+            let Some(hash) = buf.get(off + 1..off + 1 + len as usize) else {
+                return Err(Error::new(
+                    ErrorKind::UnexpectedEof,
+                    "AHA entry is truncated",
+                ));
+            };
             off += 1 + len as usize;
-            hashs.push(hash);
+            hashs.push(hash.to_vec());
         }
-        hashs
+        Ok(hashs)
     }
 
+    /// Store `hashs` in a slot of the smallest tier that fits them and free
+    /// the node's previous slot, if any, at the next commit. Returns the new
+    /// slot, or `None` if the node gets no array: when `hashs` is empty, or
+    /// longer than the largest tier, in which case its children's hashes are
+    /// read from the children themselves.
     pub fn write_aha(
         &mut self,
         mut hashs: Vec<Vec<u8>>,
         old_len: u8,
         old_cptr: CleanPtr,
-    ) -> CleanPtr {
+    ) -> Option<CleanPtr> {
 
-        if old_len > 0 {
+        if old_len > 0 && self.recycle {
             let idx = self.aha_index(old_len);
             self.pending_recycle[idx].push(old_cptr);
         }
         if hashs.is_empty() {
-            return old_cptr;
+            return None;
         }
         // Select backend tier by *array length* (number of hashes), not by hash byte length.
         let idx = self.aha_index(hashs.len() as u8);
         if idx >= self.aha_len.len() {
-            return 0;
-        }
-        // Each stored item is: [u8 length] + [reference-item bytes].
-        // Reference items are either:
-        // - inlined canonical RLP (<32 bytes)  => at most 31 bytes
-        // - RLP(keccak256(rlp))               => 33 bytes (0xa0 + 32-byte hash)
-        // So worst-case is 1 + 33 = 34 bytes per entry.
-        let max_bytes = (self.aha_len[idx] as usize) * (33 + 1);
-        let new_cptr = self.new_cptr(idx);
+            #[cfg(feature = "stats")]
+            {
+                self.stats.overflow += 1;
+            }
+            return None;
+        }
+        let max_bytes = self.stride(idx);
+        let new_slot = self.new_slot(idx);
         
         let mut encoded = Vec::new();
         for hash in hashs.drain(..) {
@@ -123,17 +239,16 @@ impl AggregatedHashArray {
         debug_assert!(encoded.len() <= max_bytes);
         encoded.resize(max_bytes, 0);
 
-        debug_assert!(new_cptr % (max_bytes as CleanPtr) == 0);
         let backend = &mut self.backends[idx];
         #[cfg(feature = "stats")]
         let timer = Instant::now();
-        backend.write(new_cptr, &encoded);
+        backend.write(new_slot * max_bytes as CleanPtr, &encoded);
         #[cfg(feature = "stats")]
         {
             self.stats.t_write += timer.elapsed().as_secs_f64();
         }
         
-        new_cptr
+        Some(new_slot)
     }
 
     pub fn commit(&mut self) {
@@ -150,6 +265,17 @@ impl AggregatedHashArray {
         for backend in &mut self.backends {
             backend.flush();
         }
+        if let Some(store) = &mut self.recycle_store {
+            store.write(0, &encode_recycled(&self.recycled));
+            store.flush();
+        }
+    }
+
+    pub fn sync(&mut self, mode: SyncMode) -> std::io::Result<()> {
+        for backend in self.backends.iter_mut().chain(&mut self.recycle_store) {
+            backend.sync(mode)?;
+        }
+        Ok(())
     }
 
     #[cfg(feature = "stats")]
@@ -159,3 +285,19 @@ impl AggregatedHashArray {
         self.stats.reset();
     }
 }
+
+/// Tier count, then per tier the list length and its slots, then a
+/// checksum of all that; all u64 LE but the checksum.
+fn encode_recycled(recycled: &[Vec<CleanPtr>]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    buf.extend((recycled.len() as u64).to_le_bytes());
+    for list in recycled {
+        buf.extend((list.len() as u64).to_le_bytes());
+        for cptr in list {
+            buf.extend(cptr.to_le_bytes());
+        }
+    }
+    let checksum = Keccak256::digest(&buf);
+    buf.extend(&checksum[..8]);
+    buf
+}
@@ -1,10 +1,61 @@
 use super::CleanPtr;
+use crate::backend::SyncMode;
+use std::io;
 
-pub trait Backend {
+pub trait Backend: Send {
     fn tail(&self) -> CleanPtr;
     fn read(&mut self, ptr: CleanPtr, len: usize) -> Vec<u8>;
+    /// Like `read`, but return an error for stored bytes that can't be
+    /// decoded, such as a torn compressed blob, where `read` panics.
+    fn try_read(&mut self, ptr: CleanPtr, len: usize) -> io::Result<Vec<u8>> {
+        Ok(self.read(ptr, len))
+    }
     fn write(&mut self, ptr: CleanPtr, data: &[u8]);
     fn flush(&mut self);
+    /// Sync flushed bytes to stable storage. In-memory backends have nothing
+    /// to sync.
+    fn sync(&mut self, _mode: SyncMode) -> io::Result<()> {
+        Ok(())
+    }
+    /// Bytes this backend holds in memory for caching and buffering.
+    fn cached_bytes(&self) -> usize {
+        0
+    }
     #[cfg(feature = "stats")]
     fn print_stats(&mut self);
 }
+
+impl<B: Backend + ?Sized> Backend for Box<B> {
+    fn tail(&self) -> CleanPtr {
+        (**self).tail()
+    }
+
+    fn read(&mut self, ptr: CleanPtr, len: usize) -> Vec<u8> {
+        (**self).read(ptr, len)
+    }
+
+    fn try_read(&mut self, ptr: CleanPtr, len: usize) -> io::Result<Vec<u8>> {
+        (**self).try_read(ptr, len)
+    }
+
+    fn write(&mut self, ptr: CleanPtr, data: &[u8]) {
+        (**self).write(ptr, data)
+    }
+
+    fn flush(&mut self) {
+        (**self).flush()
+    }
+
+    fn sync(&mut self, mode: SyncMode) -> io::Result<()> {
+        (**self).sync(mode)
+    }
+
+    fn cached_bytes(&self) -> usize {
+        (**self).cached_bytes()
+    }
+
+    #[cfg(feature = "stats")]
+    fn print_stats(&mut self) {
+        (**self).print_stats()
+    }
+}
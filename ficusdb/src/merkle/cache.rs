@@ -0,0 +1,72 @@
+use super::CleanPtr;
+use super::node::Node;
+
+use lru_mem::{HeapSize, LruCache};
+use std::mem::size_of;
+use std::sync::{Arc, Mutex};
+
+/// Number of independently locked shards in a `ShardedCache`.
+pub const CACHE_SHARDS: usize = 16;
+
+/// A cached node, shared with readers that are still walking it after it was
+/// evicted.
+struct CachedNode(Arc<Node>);
+
+impl HeapSize for CachedNode {
+    fn heap_size(&self) -> usize {
+        size_of::<Node>() + self.0.heap_size()
+    }
+}
+
+/// Clean-node cache split into `CACHE_SHARDS` LRU caches, each behind its own
+/// lock and each holding an equal share of the memory budget.
+///
+/// A node goes to the shard picked by a multiplicative hash of its
+/// `CleanPtr`. Nodes are appended at increasing offsets, so the hash spreads
+/// the nodes of one subtree across shards and readers walking different
+/// paths rarely wait on each other. Recency is tracked per shard.
+pub struct ShardedCache {
+    shards: Box<[Mutex<LruCache<CleanPtr, CachedNode>>]>,
+}
+
+impl ShardedCache {
+    pub fn new(cache_size: usize) -> Self {
+        let shards = (0..CACHE_SHARDS)
+            .map(|_| Mutex::new(LruCache::new(cache_size / CACHE_SHARDS)))
+            .collect();
+        Self { shards }
+    }
+
+    fn shard(&self, cptr: CleanPtr) -> &Mutex<LruCache<CleanPtr, CachedNode>> {
+        let h = cptr.wrapping_mul(0x9e37_79b9_7f4a_7c15) >> 32;
+        &self.shards[h as usize % CACHE_SHARDS]
+    }
+
+    /// The cached node at `cptr`, marking it most recently used.
+    pub fn get(&self, cptr: CleanPtr) -> Option<Arc<Node>> {
+        let mut shard = self.shard(cptr).lock().unwrap();
+        shard.get(&cptr).map(|node| node.0.clone())
+    }
+
+    /// Cache `node`. A node larger than its shard's budget is not kept.
+    pub fn insert(&self, cptr: CleanPtr, node: Arc<Node>) {
+        let _ = self
+            .shard(cptr)
+            .lock()
+            .unwrap()
+            .insert(cptr, CachedNode(node));
+    }
+
+    pub fn remove(&self, cptr: CleanPtr) -> Option<Arc<Node>> {
+        let mut shard = self.shard(cptr).lock().unwrap();
+        shard.remove(&cptr).map(|node| node.0)
+    }
+
+    /// Memory held by all shards, in bytes.
+    pub fn current_size(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.lock().unwrap().current_size())
+            .sum()
+    }
+}
@@ -0,0 +1,245 @@
+use super::node::{Child, NodePtr, NodeType, Value};
+use super::store::{NodeReader, NodeStore};
+use super::{CleanPtr, NBRANCH, utils};
+
+use std::sync::{Arc, Mutex};
+
+// Children are ordered by position: the value slot (NBRANCH) sorts before
+// nibbles 0..15, since a key that ends at a branch is a prefix of the others.
+fn nibble_at(pos: usize) -> usize {
+    if pos == 0 { NBRANCH } else { pos - 1 }
+}
+
+fn pos_of(nibble: u8) -> usize {
+    if nibble as usize == NBRANCH {
+        0
+    } else {
+        nibble as usize + 1
+    }
+}
+
+fn clean(child: &Child) -> CleanPtr {
+    match child.ptr() {
+        NodePtr::Clean(cptr) => cptr,
+        NodePtr::Dirty(_) => unreachable!("committed nodes only have clean children"),
+    }
+}
+
+/// A branch or short node on the path to the current entry.
+struct Frame {
+    ptr: CleanPtr,
+    // position of the child taken; always 0 for short nodes
+    pos: usize,
+    // path length before this node
+    start: usize,
+}
+
+#[derive(Clone, Copy)]
+enum Position {
+    Start,
+    Before,
+    After,
+    End,
+}
+
+/// Streaming cursor over the keys of one committed root.
+///
+/// The cursor sits between two entries: `next` returns the entry after it
+/// and moves past it, `prev` returns the entry before it and moves back. It
+/// starts before the first key. Only the path to the current entry is kept,
+/// as a stack of frames, and nodes are read through the node cache as the
+/// cursor moves.
+pub struct Cursor {
+    reader: NodeReader,
+    root_cptr: CleanPtr,
+    stack: Vec<Frame>,
+    nibbles: Vec<u8>,
+    // value at the end of `stack`
+    value: Option<Value>,
+    at: Position,
+}
+
+impl Cursor {
+    pub fn new(store: Arc<Mutex<NodeStore>>, root_cptr: CleanPtr) -> Self {
+        Self {
+            reader: store.lock().unwrap().reader(),
+            root_cptr,
+            stack: Vec::new(),
+            nibbles: Vec::new(),
+            value: None,
+            at: Position::Start,
+        }
+    }
+
+    /// Position the cursor before the first key `>= key`.
+    pub fn seek(&mut self, key: &[u8]) {
+        self.stack.clear();
+        self.nibbles.clear();
+        self.value = None;
+        let found = self.root_cptr != 0 && self.seek_from(&utils::to_path(key));
+        self.at = if found {
+            Position::Before
+        } else {
+            Position::End
+        };
+    }
+
+    pub fn prev(&mut self) -> Option<(Vec<u8>, Vec<u8>)> {
+        let found = match self.at {
+            Position::After => true,
+            Position::Before => self.step(false),
+            Position::End => self.restart(false),
+            Position::Start => false,
+        };
+        if !found {
+            self.at = Position::Start;
+            return None;
+        }
+        self.at = Position::Before;
+        Some(self.entry())
+    }
+
+    fn entry(&self) -> (Vec<u8>, Vec<u8>) {
+        // a complete path always ends with the NBRANCH terminator
+        let key = utils::from_nibbles(&self.nibbles[..self.nibbles.len() - 1]).collect();
+        (key, self.value.as_ref().unwrap().value.clone())
+    }
+
+    fn restart(&mut self, first: bool) -> bool {
+        self.stack.clear();
+        self.nibbles.clear();
+        if self.root_cptr == 0 {
+            return false;
+        }
+        self.descend(self.root_cptr, first);
+        true
+    }
+
+    /// Walk down from `ptr` to its first (or last) entry.
+    fn descend(&mut self, mut ptr: CleanPtr, first: bool) {
+        loop {
+            let start = self.nibbles.len();
+            let (pos, child) = match self.reader.get_clean(ptr).get_inner() {
+                NodeType::Value(vnode) => {
+                    self.value = Some(vnode.clone());
+                    return;
+                }
+                NodeType::Short(snode) => {
+                    self.nibbles.extend_from_slice(&snode.path);
+                    (0, clean(&snode.child))
+                }
+                NodeType::Branch(bnode) => {
+                    let has_child = |pos: &usize| bnode.children[nibble_at(*pos)].is_some();
+                    let pos = if first {
+                        (0..=NBRANCH).find(has_child)
+                    } else {
+                        (0..=NBRANCH).rev().find(has_child)
+                    }
+                    .expect("branch without children");
+                    self.nibbles.push(nibble_at(pos) as u8);
+                    (pos, clean(bnode.children[nibble_at(pos)].as_ref().unwrap()))
+                }
+            };
+            self.stack.push(Frame { ptr, pos, start });
+            ptr = child;
+        }
+    }
+
+    /// Move from the current entry to the next (or previous) one. Returns
+    /// false, leaving the stack empty, when there is none.
+    fn step(&mut self, forward: bool) -> bool {
+        while let Some(frame) = self.stack.last_mut() {
+            self.nibbles.truncate(frame.start);
+            if let NodeType::Branch(bnode) = self.reader.get_clean(frame.ptr).get_inner() {
+                let has_child = |pos: &usize| bnode.children[nibble_at(*pos)].is_some();
+                let sibling = if forward {
+                    (frame.pos + 1..=NBRANCH).find(has_child)
+                } else {
+                    (0..frame.pos).rev().find(has_child)
+                };
+                if let Some(pos) = sibling {
+                    let child = clean(bnode.children[nibble_at(pos)].as_ref().unwrap());
+                    frame.pos = pos;
+                    self.nibbles.push(nibble_at(pos) as u8);
+                    self.descend(child, forward);
+                    return true;
+                }
+            }
+            self.stack.pop();
+        }
+        false
+    }
+
+    /// Descend along `path`, stopping at the first entry not below it.
+    fn seek_from(&mut self, path: &[u8]) -> bool {
+        let mut ptr = self.root_cptr;
+        let mut i = 0;
+        loop {
+            let start = self.nibbles.len();
+            match self.reader.get_clean(ptr).get_inner() {
+                NodeType::Value(vnode) => {
+                    self.value = Some(vnode.clone());
+                    return true;
+                }
+                NodeType::Short(snode) => {
+                    let ord = snode
+                        .path
+                        .iter()
+                        .map(|n| pos_of(*n))
+                        .cmp(path[i..].iter().take(snode.path.len()).map(|n| pos_of(*n)));
+                    let (len, child) = (snode.path.len(), clean(&snode.child));
+                    self.nibbles.extend_from_slice(&snode.path);
+                    self.stack.push(Frame { ptr, pos: 0, start });
+                    match ord {
+                        std::cmp::Ordering::Equal => {
+                            ptr = child;
+                            i += len;
+                        }
+                        std::cmp::Ordering::Greater => {
+                            self.descend(child, true);
+                            return true;
+                        }
+                        std::cmp::Ordering::Less => return self.step(true),
+                    }
+                }
+                NodeType::Branch(bnode) => {
+                    let nibble = path[i];
+                    let child = bnode.children[nibble as usize].as_ref().map(clean);
+                    self.nibbles.push(nibble);
+                    self.stack.push(Frame {
+                        ptr,
+                        pos: pos_of(nibble),
+                        start,
+                    });
+                    match child {
+                        Some(child) => {
+                            ptr = child;
+                            i += 1;
+                        }
+                        // no entry on this path; take the next sibling
+                        None => return self.step(true),
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Iterator for Cursor {
+    type Item = (Vec<u8>, Vec<u8>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let found = match self.at {
+            Position::Before => true,
+            Position::After => self.step(true),
+            Position::Start => self.restart(true),
+            Position::End => false,
+        };
+        if !found {
+            self.at = Position::End;
+            return None;
+        }
+        self.at = Position::After;
+        Some(self.entry())
+    }
+}
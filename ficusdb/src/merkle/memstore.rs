@@ -1,6 +1,7 @@
 use crate::merkle::CleanPtr;
 use crate::merkle::backend::Backend;
 
+/// Backend keeping the node bytes in a `Vec`, for throwaway tries.
 pub struct MemStore {
     data: Vec<u8>,
 }
@@ -14,8 +15,10 @@ impl MemStore {
         self.data.len()
     }
 
+    /// Reads past the end are cut short, as with `PageCachedFile`.
     pub fn read(&mut self, ptr: usize, len: usize) -> Vec<u8> {
-        self.data[ptr..ptr + len].to_vec()
+        let end = (ptr + len).min(self.data.len());
+        self.data[ptr.min(end)..end].to_vec()
     }
 
     pub fn write(&mut self, ptr: usize, data: &[u8]) {
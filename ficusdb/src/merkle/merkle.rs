@@ -1,32 +1,49 @@
 #![allow(dead_code)]
 
+use super::memstore::MemStore;
 use super::node::*;
+use super::proof;
 #[cfg(feature = "stats")]
 use super::stats::MerkleStats;
-use super::store::NodeStore;
+use super::hasher::{Hasher, Keccak256Hasher};
+use super::store::{CachePolicy, NodeReader, NodeStore};
 use super::utils;
 use super::{CleanPtr, DirtyPtr, NBRANCH};
-#[cfg(feature = "stats")]
+use rayon::prelude::*;
+use std::fmt::Write;
+use std::io::{Error, ErrorKind};
 use std::time::Instant;
 
-use sha3::{Digest, Keccak256};
-use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
 
+// Levels are split into parallel jobs of at least this many nodes; hashing a
+// single node is too cheap to be worth a job of its own.
+const PAR_HASH_MIN_LEN: usize = 64;
+
 pub struct Merkle {
     store: Arc<Mutex<NodeStore>>,
+    // committed nodes are read through this without locking `store`
+    reader: NodeReader,
     root_cptr: CleanPtr,
     root_dptr: Option<DirtyPtr>,
+    // key count of the committed root it was computed for
+    len_cache: Mutex<Option<(CleanPtr, usize)>>,
+    // committed root kept by `pin_root`; dropped when the root changes
+    root_node: Option<Arc<Node>>,
     #[cfg(feature = "stats")]
     stats: Arc<Mutex<MerkleStats>>,
 }
 
 impl Merkle {
     pub fn new(store: Arc<Mutex<NodeStore>>, root_ptr: CleanPtr) -> Self {
+        let reader = store.lock().unwrap().reader();
         Self {
             store,
+            reader,
             root_cptr: root_ptr,
             root_dptr: None,
+            len_cache: Mutex::new(None),
+            root_node: None,
             #[cfg(feature = "stats")]
             stats: Arc::new(Mutex::new(MerkleStats::new())),
         }
@@ -36,23 +53,192 @@ impl Merkle {
         self.root_cptr
     }
 
+    /// Keep the committed root node, so lookups start from it instead of
+    /// going through the node cache. For tries read many times at one root,
+    /// like storage tries; the node is dropped on the next commit.
+    pub fn pin_root(&mut self) {
+        if self.root_cptr != 0 {
+            self.root_node = self.reader.try_get_clean(self.root_cptr).ok();
+        }
+    }
+
+    /// Whether there are changes not yet committed.
+    pub fn is_dirty(&self) -> bool {
+        self.root_dptr.is_some()
+    }
+
+    /// Number of uncommitted nodes held in the store's dirty arena. The
+    /// arena is shared, so this includes the dirty nodes of every trie over
+    /// the same store, e.g. the storage tries of a `StateDB`.
+    pub fn dirty_count(&self) -> usize {
+        self.store.lock().unwrap().dirty_count()
+    }
+
+    /// Number of keys under the committed root; uncommitted changes are not
+    /// counted. The count is cached until the committed root changes.
+    pub fn len(&self) -> usize {
+        let mut len_cache = self.len_cache.lock().unwrap();
+        if let Some((root_cptr, len)) = *len_cache
+            && root_cptr == self.root_cptr
+        {
+            return len;
+        }
+        let len = if self.root_cptr == 0 {
+            0
+        } else {
+            Self::count_values(&mut self.store.lock().unwrap(), self.root_cptr)
+        };
+        *len_cache = Some((self.root_cptr, len));
+        len
+    }
+
+    /// Whether the committed root is empty.
+    pub fn is_empty(&self) -> bool {
+        self.root_cptr == 0
+    }
+
+    fn count_values(store: &mut NodeStore, root_cptr: CleanPtr) -> usize {
+        let clean = |child: &Child| match child.ptr() {
+            NodePtr::Clean(cptr) => cptr,
+            NodePtr::Dirty(_) => unreachable!("committed nodes only have clean children"),
+        };
+        let mut count = 0;
+        let mut stack = vec![root_cptr];
+        while let Some(cptr) = stack.pop() {
+            match store.get_clean(cptr).get_inner() {
+                NodeType::Value(_) => count += 1,
+                NodeType::Short(snode) => stack.push(clean(&snode.child)),
+                NodeType::Branch(bnode) => stack.extend(bnode.children.iter().flatten().map(clean)),
+            }
+        }
+        count
+    }
+
+    /// Root hash of the empty trie under the store's hasher; see
+    /// `Hasher::empty_node_hash`.
+    pub fn empty_root(&self) -> Vec<u8> {
+        self.store.lock().unwrap().hasher().empty_node_hash()
+    }
+
     pub fn hash(&self) -> Vec<u8> {
-        let mut store = self.store.lock().unwrap();
         if self.root_cptr == 0 {
-            return Keccak256::digest(&[0x80u8]).to_vec();
+            return self.empty_root();
         }
-        // Ethereum-style root hash is Keccak256(RLP(root_node_canonical)).
-        let mut root_node = store.get_clean(self.root_cptr).clone();
+        let mut store = self.store.lock().unwrap();
+        let hasher = store.hasher();
+        // Ethereum-style root hash is H(RLP(root_node_canonical)).
+        let mut root_node = Node::clone(&store.get_clean(self.root_cptr));
         store.load_children_hash(&mut root_node);
         let root_rlp = root_node
             .rlp_encode()
             .expect("canonical root RLP encoding must succeed");
-        Keccak256::digest(&root_rlp).to_vec()
+        hasher.digest(&root_rlp)
+    }
+
+    /// Root hash of a trie holding exactly `entries`, under Keccak-256.
+    ///
+    /// The trie is built in memory and dropped, so this is a way to compute
+    /// an expected root without touching a live store. Entries may come in
+    /// any order; for repeated keys the last entry wins.
+    pub fn root_for(entries: &[(Vec<u8>, Value)]) -> Vec<u8> {
+        Self::root_for_with(entries, Arc::new(Keccak256Hasher))
+    }
+
+    /// `root_for` under `hasher`.
+    pub fn root_for_with(entries: &[(Vec<u8>, Value)], hasher: Arc<dyn Hasher>) -> Vec<u8> {
+        let store = NodeStore::new(Box::new(MemStore::new()), 0, None, hasher);
+        let mut merkle = Merkle::new(Arc::new(Mutex::new(store)), 0);
+        for (key, val) in entries {
+            merkle.insert(key, val.clone());
+        }
+        merkle.commit();
+        merkle.hash()
     }
 
+    /// Panics on a node that cannot be read or breaks the trie's shape;
+    /// see `try_find`.
     pub fn find(&self, key: &[u8]) -> Option<Value> {
+        self.try_find(key).unwrap()
+    }
+
+    /// Like `find`, but returns an error for an unreadable or malformed
+    /// node on the path of `key`, as a corrupt node file could hold.
+    pub fn try_find(&self, key: &[u8]) -> Result<Option<Value>, Error> {
+        self.lookup(key, Value::clone).map_err(|(_, e)| e)
+    }
+
+    /// Like `try_find`, but the error also names the committed node that
+    /// failed: the one that could not be read, or the last one read before
+    /// a malformed node.
+    pub(crate) fn try_find_at_fault(&self, key: &[u8]) -> Result<Option<Value>, (CleanPtr, Error)> {
+        self.lookup(key, Value::clone)
+    }
+
+    /// Whether `key` is present, without copying its value.
+    pub fn contains(&self, key: &[u8]) -> bool {
+        self.lookup(key, |_| ()).unwrap().is_some()
+    }
+
+    /// Drop all uncommitted changes and go back to the committed root. When
+    /// no other trie holds dirty nodes, the whole dirty arena is reclaimed
+    /// right away, as on commit, including slots this trie reused below
+    /// where it started and slots left behind by tries committed meanwhile.
+    pub fn discard(&mut self) {
+        if self.root_dptr.take().is_none() {
+            return;
+        }
+        let mut store = self.store.lock().unwrap();
+        store.release_dirty();
+        if store.dirty_tries() == 0 {
+            store.clear_dirty();
+        }
+    }
+
+    /// A new trie over the same store and committed root, starting with a
+    /// copy of this trie's uncommitted changes. Later changes to either trie
+    /// are not seen by the other. Committed nodes are shared; only dirty
+    /// nodes are copied, into fresh slots of the store's arena.
+    pub fn fork(&self) -> Merkle {
+        let mut fork = Merkle::new(self.store.clone(), self.root_cptr);
+        if let Some(root_dptr) = self.root_dptr {
+            let mut store = self.store.lock().unwrap();
+            store.acquire_dirty();
+            fork.root_dptr = Some(Self::copy_dirty(&mut store, root_dptr));
+        }
+        fork
+    }
+
+    fn copy_dirty(store: &mut NodeStore, dptr: DirtyPtr) -> DirtyPtr {
+        let Some(mut node) = store.get_dirty(dptr).cloned() else {
+            return store.add_dirty(None);
+        };
+        let children: Vec<&mut Child> = match &mut node.0 {
+            NodeType::Branch(bnode) => bnode.children.iter_mut().flatten().collect(),
+            NodeType::Short(snode) => vec![&mut snode.child],
+            NodeType::Value(_) => Vec::new(),
+        };
+        for child in children {
+            if let Child::Ptr(NodePtr::Dirty(child_dptr)) = child {
+                *child_dptr = Self::copy_dirty(store, *child_dptr);
+            }
+        }
+        store.add_dirty(Some(node))
+    }
+
+    /// Walk the path of `key` and apply `f` to the value node if it exists.
+    ///
+    /// Every path ends with the `NBRANCH` terminator, and only a value node
+    /// may follow it. Nodes that break this can only come from a corrupt
+    /// file (dirty nodes are copies of clean ones), so they are reported as
+    /// errors, like unreadable nodes, rather than asserted on. Errors come
+    /// with the pointer of the last committed node read.
+    fn lookup<R>(
+        &self,
+        key: &[u8],
+        f: impl FnOnce(&Value) -> R,
+    ) -> Result<Option<R>, (CleanPtr, Error)> {
         if self.root_cptr == 0 && self.root_dptr.is_none() {
-            return None;
+            return Ok(None);
         }
         #[cfg(feature = "stats")]
         let timer = Instant::now();
@@ -60,24 +246,42 @@ impl Merkle {
             Some(dptr) => NodePtr::Dirty(dptr),
             None => NodePtr::Clean(self.root_cptr),
         };
-        let mut store = self.store.lock().unwrap();
+        // A committed trie is read without the store lock, so lookups from
+        // several tries over one store run in parallel.
+        let mut store = self.root_dptr.map(|_| self.store.lock().unwrap());
         let path = utils::to_path(key);
         let mut i = 0;
         let mut ptrs = Vec::new();
+        let mut at = self.root_cptr;
         while i <= path.len() {
+            let clean_node;
             let cur_node = match cur_ptr {
                 NodePtr::Clean(cptr) => {
-                    ptrs.push(cptr);
-                    store.get_clean(cptr)
+                    at = cptr;
+                    let loaded = match (&self.root_node, &mut store) {
+                        (Some(root), _) if cptr == self.root_cptr => Ok(root.clone()),
+                        (_, Some(store)) => {
+                            ptrs.push(cptr);
+                            store.try_get_clean(cptr)
+                        }
+                        (_, None) => {
+                            ptrs.push(cptr);
+                            self.reader.try_get_clean(cptr)
+                        }
+                    };
+                    clean_node = loaded.map_err(|e| (cptr, e))?;
+                    &*clean_node
                 }
-                NodePtr::Dirty(dptr) => match store.get_dirty(dptr) {
+                NodePtr::Dirty(dptr) => match store.as_mut().unwrap().get_dirty(dptr) {
                     Some(n) => n,
                     None => break,
                 },
             };
             match cur_node.get_inner() {
                 NodeType::Branch(bnode) => {
-                    assert!(i < path.len());
+                    if i == path.len() {
+                        return Err((at, malformed("branch node past the end of a key")));
+                    }
                     let bidx = path[i] as usize;
                     cur_ptr = match &bnode.children[bidx] {
                         Some(Child::Ptr(ptr)) => *ptr,
@@ -87,7 +291,9 @@ impl Merkle {
                     i += 1;
                 }
                 NodeType::Short(snode) => {
-                    assert!(i < path.len());
+                    if i == path.len() {
+                        return Err((at, malformed("short node past the end of a key")));
+                    }
                     let shared_len = snode.common_prefix_len(&path[i..]);
                     if shared_len == snode.path.len() {
                         cur_ptr = match &snode.child {
@@ -100,20 +306,27 @@ impl Merkle {
                     }
                 }
                 NodeType::Value(vnode) => {
-                    assert!(i == path.len());
+                    if i != path.len() {
+                        return Err((at, malformed("value node before the end of a key")));
+                    }
                     #[cfg(feature = "stats")]
                     {
                         let mut stats = self.stats.lock().unwrap();
                         stats.get += 1;
                         stats.t_get += timer.elapsed().as_secs_f64();
                     }
-                    return Some(vnode.clone());
+                    return Ok(Some(f(vnode)));
                 }
             }
         }
-        #[cfg(not(feature = "lru"))]
-        while let Some(cptr) = ptrs.pop() {
-            store.get_clean(cptr);
+        if self.reader.cache_policy() == CachePolicy::Take {
+            // keep the path recently used; these were read just now
+            while let Some(cptr) = ptrs.pop() {
+                let _ = match &mut store {
+                    Some(store) => store.try_get_clean(cptr),
+                    None => self.reader.try_get_clean(cptr),
+                };
+            }
         }
         #[cfg(feature = "stats")]
         {
@@ -121,21 +334,627 @@ impl Merkle {
             stats.get += 1;
             stats.t_get += timer.elapsed().as_secs_f64();
         }
-        None
+        Ok(None)
+    }
+
+    /// Return all key-value pairs whose key starts with `prefix`, in
+    /// ascending key order. Uncommitted changes are visible.
+    pub fn scan_prefix(&self, prefix: &[u8]) -> Vec<(Vec<u8>, Value)> {
+        let mut out = Vec::new();
+        let Some(mut cur_ptr) = self.root_ptr() else {
+            return out;
+        };
+        let mut store = self.store.lock().unwrap();
+        let prefix: Vec<u8> = utils::to_nibbles(prefix).collect();
+        let mut nibbles = Vec::new();
+        let mut i = 0;
+        loop {
+            let Some(cur_node) = Self::load_node(&mut store, cur_ptr) else {
+                return out;
+            };
+            if i == prefix.len() {
+                // the whole subtree is covered by the prefix
+                Self::collect_values(&mut store, cur_node, &mut nibbles, &mut out);
+                return out;
+            }
+            match cur_node.get_inner() {
+                NodeType::Branch(bnode) => {
+                    let bidx = prefix[i] as usize;
+                    cur_ptr = match &bnode.children[bidx] {
+                        Some(child) => child.ptr(),
+                        None => return out,
+                    };
+                    nibbles.push(prefix[i]);
+                    i += 1;
+                }
+                NodeType::Short(snode) => {
+                    let shared_len = snode.common_prefix_len(&prefix[i..]);
+                    if shared_len == prefix.len() - i {
+                        // the prefix ends inside (or at the end of) the short
+                        // node path, so everything below it matches
+                        Self::collect_values(&mut store, cur_node, &mut nibbles, &mut out);
+                        return out;
+                    } else if shared_len == snode.path.len() {
+                        nibbles.extend_from_slice(&snode.path);
+                        i += shared_len;
+                        cur_ptr = snode.child.ptr();
+                    } else {
+                        return out;
+                    }
+                }
+                NodeType::Value(_) => return out,
+            }
+        }
+    }
+
+    fn root_ptr(&self) -> Option<NodePtr> {
+        match self.root_dptr {
+            Some(dptr) => Some(NodePtr::Dirty(dptr)),
+            None if self.root_cptr == 0 => None,
+            None => Some(NodePtr::Clean(self.root_cptr)),
+        }
+    }
+
+    fn load_node(store: &mut NodeStore, ptr: NodePtr) -> Option<Node> {
+        match ptr {
+            NodePtr::Clean(cptr) => Some(Node::clone(&store.get_clean(cptr))),
+            NodePtr::Dirty(dptr) => store.get_dirty(dptr).cloned(),
+        }
+    }
+
+    /// Depth-first walk of the subtree rooted at `node` whose path so far is
+    /// `nibbles`, appending every value in ascending key order.
+    fn collect_values(
+        store: &mut NodeStore,
+        node: Node,
+        nibbles: &mut Vec<u8>,
+        out: &mut Vec<(Vec<u8>, Value)>,
+    ) {
+        match node.0 {
+            NodeType::Value(vnode) => {
+                // a complete path always ends with the NBRANCH terminator
+                assert!(nibbles.last() == Some(&(NBRANCH as u8)));
+                let key = utils::from_nibbles(&nibbles[..nibbles.len() - 1]).collect();
+                out.push((key, vnode));
+            }
+            NodeType::Short(snode) => {
+                let len = nibbles.len();
+                nibbles.extend_from_slice(&snode.path);
+                if let Some(child) = Self::load_node(store, snode.child.ptr()) {
+                    Self::collect_values(store, child, nibbles, out);
+                }
+                nibbles.truncate(len);
+            }
+            NodeType::Branch(bnode) => {
+                // the value slot is a key that ends here, so it sorts first
+                let order = std::iter::once(NBRANCH).chain(0..NBRANCH);
+                for idx in order {
+                    let Some(child) = &bnode.children[idx] else {
+                        continue;
+                    };
+                    if let Some(child) = Self::load_node(store, child.ptr()) {
+                        nibbles.push(idx as u8);
+                        Self::collect_values(store, child, nibbles, out);
+                        nibbles.pop();
+                    }
+                }
+            }
+        }
+    }
+
+    /// The subtries of the committed root that start after `depth` nibbles,
+    /// as (nibble prefix, reference item) pairs in ascending key order, for
+    /// splitting the key space into chunks that verify on their own.
+    ///
+    /// A short node that spans `depth` is split there, and the reference
+    /// item is that of the short node holding the rest of its path. A key
+    /// that ends above `depth` is reported at its value node, with its whole
+    /// path, ending in the terminator 16, as the prefix.
+    pub fn subtrie_roots(&self, depth: usize) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let clean = |child: &Child| match child.ptr() {
+            NodePtr::Clean(cptr) => cptr,
+            NodePtr::Dirty(_) => unreachable!("committed nodes only have clean children"),
+        };
+        let mut roots = Vec::new();
+        if self.root_cptr == 0 {
+            return roots;
+        }
+        let mut store = self.store.lock().unwrap();
+        let hasher = store.hasher();
+        let mut stack = vec![(Vec::new(), self.root_cptr)];
+        while let Some((prefix, cptr)) = stack.pop() {
+            let node = store.get_clean(cptr);
+            if prefix.len() == depth {
+                roots.push((prefix, node.hash()));
+                continue;
+            }
+            match node.get_inner() {
+                NodeType::Value(_) => roots.push((prefix, node.hash())),
+                NodeType::Short(snode) => {
+                    let child = clean(&snode.child);
+                    let take = depth - prefix.len();
+                    if snode.path.len() <= take {
+                        stack.push(([&prefix[..], &snode.path].concat(), child));
+                        continue;
+                    }
+                    let child_ref = store.get_clean(child).hash();
+                    let mut rest =
+                        Short::new(snode.path[take..].to_vec(), Child::Hash(child, child_ref));
+                    let reference = rest.calc_hash(hasher.as_ref()).unwrap();
+                    roots.push(([&prefix[..], &snode.path[..take]].concat(), reference));
+                }
+                NodeType::Branch(bnode) => {
+                    // popped in key order: the value slot, then nibbles 0..15
+                    for i in (0..NBRANCH).rev().chain([NBRANCH]) {
+                        if let Some(child) = &bnode.children[i] {
+                            stack.push(([&prefix[..], &[i as u8]].concat(), clean(child)));
+                        }
+                    }
+                }
+            }
+        }
+        roots
+    }
+
+    /// All key-value pairs of the committed root with keys in `[start, end]`,
+    /// in ascending key order, with the nodes that prove nothing in between
+    /// was left out: the canonical RLP of the nodes on the paths of `start`
+    /// and of the last returned key, or of `end` if none was found. Nodes
+    /// small enough to be embedded in their parent are not listed. Check the
+    /// result with `verify_range_proof`.
+    pub fn range_proof(&self, start: &[u8], end: &[u8]) -> RangeProof<Value> {
+        let mut entries = Vec::new();
+        let mut proof = Vec::new();
+        if self.root_cptr == 0 || start > end {
+            return (entries, proof);
+        }
+        let mut store = self.store.lock().unwrap();
+        let (left, right) = (utils::to_path(start), utils::to_path(end));
+        Self::collect_range(
+            &mut store,
+            self.root_cptr,
+            &mut Vec::new(),
+            Some(&left),
+            Some(&right),
+            &mut entries,
+        );
+        let last = entries.last().map_or(right, |(key, _)| utils::to_path(key));
+        for path in [left, last] {
+            Self::prove_path(&mut store, self.root_cptr, &path, &mut proof);
+        }
+        (entries, proof)
+    }
+
+    fn collect_range(
+        store: &mut NodeStore,
+        cptr: CleanPtr,
+        nibbles: &mut Vec<u8>,
+        left: Option<&[u8]>,
+        right: Option<&[u8]>,
+        out: &mut Vec<(Vec<u8>, Value)>,
+    ) {
+        let mut visit =
+            |store: &mut NodeStore, nibbles: &mut Vec<u8>, seg: &[u8], child: &Child| {
+                let (Some(left), Some(right)) = (
+                    proof::follow(seg, left, true),
+                    proof::follow(seg, right, false),
+                ) else {
+                    return;
+                };
+                let NodePtr::Clean(child) = child.ptr() else {
+                    unreachable!("committed nodes only have clean children");
+                };
+                nibbles.extend_from_slice(seg);
+                Self::collect_range(store, child, nibbles, left, right, out);
+                nibbles.truncate(nibbles.len() - seg.len());
+            };
+        let node = store.get_clean(cptr);
+        match node.get_inner() {
+            NodeType::Value(vnode) => {
+                let key = utils::from_nibbles(&nibbles[..nibbles.len() - 1]).collect();
+                out.push((key, vnode.clone()));
+            }
+            NodeType::Short(snode) => visit(store, nibbles, &snode.path, &snode.child),
+            NodeType::Branch(bnode) => {
+                for i in std::iter::once(NBRANCH).chain(0..NBRANCH) {
+                    if let Some(child) = &bnode.children[i] {
+                        visit(store, nibbles, &[i as u8], child);
+                    }
+                }
+            }
+        }
+    }
+
+    // Push the canonical RLP of the root and of every hashed node on `path`.
+    fn prove_path(store: &mut NodeStore, root: CleanPtr, path: &[u8], proof: &mut Vec<Vec<u8>>) {
+        let mut cptr = root;
+        let mut depth = 0;
+        loop {
+            let mut node = Node::clone(&store.get_clean(cptr));
+            store.load_children_hash(&mut node);
+            let next = match node.get_inner() {
+                NodeType::Value(_) => break,
+                NodeType::Short(snode) => {
+                    let raw = snode.rlp_encode().unwrap();
+                    if (cptr == root || raw.len() >= 32) && !proof.contains(&raw) {
+                        proof.push(raw);
+                    }
+                    path[depth..].starts_with(&snode.path).then(|| {
+                        depth += snode.path.len();
+                        &snode.child
+                    })
+                }
+                NodeType::Branch(bnode) => {
+                    let raw = bnode.rlp_encode().unwrap();
+                    if (cptr == root || raw.len() >= 32) && !proof.contains(&raw) {
+                        proof.push(raw);
+                    }
+                    let child = path
+                        .get(depth)
+                        .and_then(|i| bnode.children[*i as usize].as_ref());
+                    depth += 1;
+                    child
+                }
+            };
+            match next.map(Child::ptr) {
+                Some(NodePtr::Clean(child)) => cptr = child,
+                _ => break,
+            }
+        }
+    }
+
+    /// Iterate over all key-value pairs in ascending key order. Uncommitted
+    /// changes are visible. Nodes are loaded lazily as the iterator advances.
+    pub fn iter(&self) -> Iter<'_> {
+        Iter::new(self, false)
+    }
+
+    /// Like `iter`, but in descending key order, so the first item is the
+    /// largest key. Branch children are visited from 15 down to 0, then the
+    /// value slot, which holds a key that is a prefix of the others.
+    pub fn iter_rev(&self) -> Iter<'_> {
+        Iter::new(self, true)
+    }
+
+    /// List the keys that differ between two committed roots as
+    /// `(key, old value, new value)`, in ascending key order. Subtrees with
+    /// the same pointer or reference hash on both sides are skipped.
+    pub fn diff(
+        &self,
+        old_root: CleanPtr,
+        new_root: CleanPtr,
+    ) -> Vec<(Vec<u8>, Option<Value>, Option<Value>)> {
+        let mut out = Vec::new();
+        let mut store = self.store.lock().unwrap();
+        let cursor = |root: CleanPtr| (root != 0).then_some((root, 0));
+        Self::diff_nodes(
+            &mut store,
+            cursor(old_root),
+            cursor(new_root),
+            &mut Vec::new(),
+            &mut out,
+        );
+        out
+    }
+
+    fn diff_nodes(
+        store: &mut NodeStore,
+        old: Option<DiffCursor>,
+        new: Option<DiffCursor>,
+        nibbles: &mut Vec<u8>,
+        out: &mut Vec<(Vec<u8>, Option<Value>, Option<Value>)>,
+    ) {
+        if old == new {
+            return;
+        }
+        if let (Some((old_cptr, old_skip)), Some((new_cptr, new_skip))) = (old, new) {
+            let old_node = store.get_clean(old_cptr);
+            let is_value = matches!(old_node.get_inner(), NodeType::Value(_));
+            let old_hash = old_node.hash();
+            if !is_value && old_skip == new_skip && old_hash == store.get_clean(new_cptr).hash() {
+                return;
+            }
+        }
+        let old = old.map(|cursor| Self::diff_expand(store, cursor));
+        let new = new.map(|cursor| Self::diff_expand(store, cursor));
+        match (old, new) {
+            (Some(DiffNode::Slots(old)), Some(DiffNode::Slots(new))) => {
+                Self::diff_slots(store, *old, *new, nibbles, out)
+            }
+            (Some(DiffNode::Slots(old)), None) => {
+                Self::diff_slots(store, *old, [None; NBRANCH + 1], nibbles, out)
+            }
+            (None, Some(DiffNode::Slots(new))) => {
+                Self::diff_slots(store, [None; NBRANCH + 1], *new, nibbles, out)
+            }
+            (old, new) => {
+                let leaf = |node| match node {
+                    Some(DiffNode::Leaf(value)) => Some(value),
+                    Some(DiffNode::Slots(_)) => unreachable!("values only follow the terminator"),
+                    None => None,
+                };
+                let (old, new) = (leaf(old), leaf(new));
+                if let (Some(o), Some(n)) = (&old, &new)
+                    && o.value == n.value
+                    && o.extra == n.extra
+                {
+                    return;
+                }
+                // drop the terminator
+                let path = &nibbles[..nibbles.len() - 1];
+                out.push((utils::from_nibbles(path).collect(), old, new));
+            }
+        }
     }
 
+    fn diff_slots(
+        store: &mut NodeStore,
+        old: [Option<DiffCursor>; NBRANCH + 1],
+        new: [Option<DiffCursor>; NBRANCH + 1],
+        nibbles: &mut Vec<u8>,
+        out: &mut Vec<(Vec<u8>, Option<Value>, Option<Value>)>,
+    ) {
+        // the value slot is a key that ends here, so it sorts first
+        for idx in std::iter::once(NBRANCH).chain(0..NBRANCH) {
+            nibbles.push(idx as u8);
+            Self::diff_nodes(store, old[idx], new[idx], nibbles, out);
+            nibbles.pop();
+        }
+    }
+
+    /// View the node under `cursor` as a value or as branch slots; a short
+    /// node becomes a single slot for its next nibble.
+    fn diff_expand(store: &mut NodeStore, (mut cptr, mut skip): DiffCursor) -> DiffNode {
+        let clean = |child: &Child| match child.ptr() {
+            NodePtr::Clean(cptr) => cptr,
+            NodePtr::Dirty(_) => unreachable!("committed nodes only have clean children"),
+        };
+        loop {
+            let mut slots = [None; NBRANCH + 1];
+            match store.get_clean(cptr).get_inner() {
+                NodeType::Value(vnode) => return DiffNode::Leaf(vnode.clone()),
+                NodeType::Branch(bnode) => {
+                    for (idx, child) in bnode.children.iter().enumerate() {
+                        slots[idx] = child.as_ref().map(|child| (clean(child), 0));
+                    }
+                }
+                NodeType::Short(snode) if skip == snode.path.len() => {
+                    (cptr, skip) = (clean(&snode.child), 0);
+                    continue;
+                }
+                NodeType::Short(snode) => {
+                    slots[snode.path[skip] as usize] = Some((cptr, skip + 1));
+                }
+            }
+            return DiffNode::Slots(Box::new(slots));
+        }
+    }
+
+    /// Graphviz DOT rendering of the committed trie, for debugging. Branch
+    /// edges are labeled with their nibble (`v` for the value slot), short
+    /// nodes with their path in hex nibbles (`T` for the terminator), and
+    /// value nodes with up to 8 bytes of hex. Nodes deeper than `max_depth`
+    /// are drawn as a `...` placeholder; the root is at depth 0. Uncommitted
+    /// changes are not shown.
+    pub fn to_dot(&self, max_depth: usize) -> String {
+        let mut dot = String::from("digraph trie {\n    node [fontname=monospace];\n");
+        if self.root_cptr != 0 {
+            let mut store = self.store.lock().unwrap();
+            Self::node_to_dot(&mut store, self.root_cptr, 0, max_depth, &mut dot);
+        }
+        dot.push_str("}\n");
+        dot
+    }
+
+    fn node_to_dot(
+        store: &mut NodeStore,
+        cptr: CleanPtr,
+        depth: usize,
+        max_depth: usize,
+        dot: &mut String,
+    ) {
+        let id = format!("n{cptr}");
+        if depth > max_depth {
+            writeln!(dot, "    {id} [shape=plaintext, label=\"...\"];").unwrap();
+            return;
+        }
+        match store.inspect_node(cptr) {
+            None => writeln!(dot, "    {id} [shape=octagon, label=\"unreadable\"];").unwrap(),
+            Some(NodeView::Branch { children }) => {
+                writeln!(dot, "    {id} [shape=box, label=\"branch\"];").unwrap();
+                for (slot, child) in children.iter().enumerate() {
+                    let Some(child) = child else {
+                        continue;
+                    };
+                    let label = if slot == NBRANCH {
+                        "v".to_string()
+                    } else {
+                        format!("{slot:x}")
+                    };
+                    writeln!(dot, "    {id} -> n{} [label=\"{label}\"];", child.ptr).unwrap();
+                    Self::node_to_dot(store, child.ptr, depth + 1, max_depth, dot);
+                }
+            }
+            Some(NodeView::Short { path, child }) => {
+                writeln!(dot, "    {id} [shape=ellipse, label=\"short {path}\"];").unwrap();
+                writeln!(dot, "    {id} -> n{};", child.ptr).unwrap();
+                Self::node_to_dot(store, child.ptr, depth + 1, max_depth, dot);
+            }
+            Some(NodeView::Value { value, .. }) => {
+                let mut label = hex::encode(&value[..value.len().min(8)]);
+                if value.len() > 8 {
+                    label.push_str("...");
+                }
+                writeln!(dot, "    {id} [shape=note, label=\"0x{label}\"];").unwrap();
+            }
+        }
+    }
+
+    /// Check the committed trie: every node is readable, its stored hash
+    /// matches the recomputed one, loaded child references match their
+    /// children, and the node shapes are canonical. Returns the first
+    /// offending node.
+    pub fn verify_integrity(&self) -> Result<(), IntegrityError> {
+        if self.root_cptr == 0 {
+            return Ok(());
+        }
+        let mut store = self.store.lock().unwrap();
+        let hasher = store.hasher();
+        Self::verify_node(&mut store, hasher.as_ref(), self.root_cptr).map(|_| ())
+    }
+
+    /// Verify the subtree at `cptr`, returning the node's reference item and
+    /// its kind.
+    fn verify_node(
+        store: &mut NodeStore,
+        hasher: &dyn Hasher,
+        cptr: CleanPtr,
+    ) -> Result<(Vec<u8>, Shape), IntegrityError> {
+        let malformed = |reason| IntegrityError::Malformed { ptr: cptr, reason };
+        let mut node = match store.try_get_clean(cptr) {
+            Ok(node) => Node::clone(&node),
+            Err(e) => {
+                return Err(IntegrityError::Unreadable {
+                    ptr: cptr,
+                    reason: e.to_string(),
+                });
+            }
+        };
+        let stored_hash = node.hash();
+        match node.get_inner_mut() {
+            NodeType::Value(_) => return Ok((stored_hash, Shape::Value)),
+            NodeType::Short(snode) => {
+                let Some(&last) = snode.path.last() else {
+                    return Err(malformed("short node with an empty path"));
+                };
+                if snode.path[..snode.path.len() - 1].contains(&(NBRANCH as u8)) {
+                    return Err(malformed("terminator inside a short node path"));
+                }
+                let shape = Self::verify_child(store, hasher, cptr, 0, &mut snode.child)?;
+                match (last as usize == NBRANCH, shape) {
+                    (true, Shape::Value) | (false, Shape::Branch) => {}
+                    (_, Shape::Short) => {
+                        return Err(malformed("short node points to a short node"));
+                    }
+                    (true, _) => return Err(malformed("leaf short node without a value")),
+                    (false, _) => return Err(malformed("value without a terminator")),
+                }
+            }
+            NodeType::Branch(bnode) => {
+                if bnode.children.iter().flatten().count() < 2 {
+                    return Err(malformed("branch node with fewer than two children"));
+                }
+                for (idx, child) in bnode.children.iter_mut().enumerate() {
+                    let Some(child) = child else {
+                        continue;
+                    };
+                    let shape = Self::verify_child(store, hasher, cptr, idx, child)?;
+                    if (idx == NBRANCH) != (shape == Shape::Value) {
+                        return Err(malformed("value outside the branch value slot"));
+                    }
+                }
+            }
+        }
+        let shape = match node.get_inner() {
+            NodeType::Short(_) => Shape::Short,
+            _ => Shape::Branch,
+        };
+        match node.calc_hash(hasher) {
+            Ok(hash) if hash == stored_hash => Ok((hash, shape)),
+            _ => Err(IntegrityError::HashMismatch { ptr: cptr }),
+        }
+    }
+
+    /// Verify the child in `slot` of the node at `parent`, checking a loaded
+    /// reference against the recomputed one, and replace it with the
+    /// recomputed reference.
+    fn verify_child(
+        store: &mut NodeStore,
+        hasher: &dyn Hasher,
+        parent: CleanPtr,
+        slot: usize,
+        child: &mut Child,
+    ) -> Result<Shape, IntegrityError> {
+        let NodePtr::Clean(child_cptr) = child.ptr() else {
+            return Err(IntegrityError::Malformed {
+                ptr: parent,
+                reason: "committed node with a dirty child",
+            });
+        };
+        let (child_hash, shape) = Self::verify_node(store, hasher, child_cptr)?;
+        if let Child::Hash(_, loaded) = child
+            && *loaded != child_hash
+        {
+            return Err(IntegrityError::ChildHashMismatch { ptr: parent, slot });
+        }
+        *child = Child::Hash(child_cptr, child_hash);
+        Ok(shape)
+    }
+
+    /// Panics on a node that cannot be read or breaks the trie's shape;
+    /// see `try_insert`.
     pub fn insert(&mut self, key: &[u8], val: Value) {
+        self.try_insert(key, val).unwrap()
+    }
+
+    /// Like `insert`, but returns an error for an unreadable or malformed
+    /// node on the path of `key`. The trie keeps its previous contents then:
+    /// nodes copied on the way down are unchanged and the value is dropped.
+    ///
+    /// The remaining asserts are logic invariants that no file content can
+    /// break, so they are only checked in debug builds.
+    pub fn try_insert(&mut self, key: &[u8], val: Value) -> Result<(), Error> {
+        self.upsert(key, val, false).map(|_| ())
+    }
+
+    /// Insert `val` at `key` and return the value it replaces, like
+    /// `HashMap::insert`. A committed previous value is read from the
+    /// store, which `insert` avoids.
+    pub fn replace(&mut self, key: &[u8], val: Value) -> Option<Value> {
+        self.upsert(key, val, true).unwrap()
+    }
+
+    /// Take the value node `old` out of the trie, returning its value if
+    /// `want` is set.
+    fn take_value(
+        store: &mut NodeStore,
+        old: Option<Child>,
+        want: bool,
+    ) -> Result<Option<Value>, Error> {
+        match old {
+            Some(Child::Ptr(NodePtr::Dirty(dptr))) => {
+                // the old value node was only referenced here
+                let node = store.take_dirty(dptr);
+                store.free_dirty(dptr);
+                Ok(node.and_then(|node| match node.0 {
+                    NodeType::Value(v) if want => Some(v),
+                    _ => None,
+                }))
+            }
+            Some(Child::Ptr(NodePtr::Clean(cptr)) | Child::Hash(cptr, _)) if want => {
+                match store.try_get_clean(cptr)?.get_inner() {
+                    NodeType::Value(v) => Ok(Some(v.clone())),
+                    _ => Err(malformed("terminator leads to a non-value node")),
+                }
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn upsert(&mut self, key: &[u8], val: Value, want_prev: bool) -> Result<Option<Value>, Error> {
         #[cfg(feature = "stats")]
         let timer = Instant::now();
         let mut store = self.store.lock().unwrap();
         let root_dptr = match &self.root_dptr {
             Some(dptr) => *dptr,
             None => {
-                if self.root_cptr == 0 {
+                let root_dptr = if self.root_cptr == 0 {
                     store.add_dirty(None)
                 } else {
-                    store.cow_clean(self.root_cptr)
-                }
+                    store.try_cow_clean(self.root_cptr)?
+                };
+                store.acquire_dirty();
+                root_dptr
             }
         };
         // Track the dirty root so reads/commit see uncommitted changes.
@@ -146,6 +965,7 @@ impl Merkle {
         let mut i = 0;
 
         let val_dptr = store.add_dirty(Some(Node(NodeType::Value(val))));
+        let mut prev = None;
 
         while i < path.len() {
             match store.take_dirty(cur_dptr) {
@@ -153,13 +973,18 @@ impl Merkle {
                     // reach a empty pointer with non-empty remaining path
                     // insert a short node for path compression and its value
                     let subpath = path[i..].to_vec();
-                    assert!(subpath.len() > 0);
+                    debug_assert!(!subpath.is_empty());
                     let snode = Short::new(subpath, Child::Ptr(NodePtr::Dirty(val_dptr)));
                     store.put_dirty(cur_dptr, Some(Node(NodeType::Short(snode))));
                     break;
                 }
                 Some(mut cur_node) => match &mut cur_node.get_inner_mut() {
-                    NodeType::Value(_) => unreachable!(),
+                    NodeType::Value(_) => {
+                        // only the terminator may lead to a value node
+                        store.put_dirty(cur_dptr, Some(cur_node));
+                        store.free_dirty(val_dptr);
+                        return Err(malformed("value node before the end of a key"));
+                    }
                     NodeType::Branch(bnode) => {
                         let bidx = path[i] as usize;
                         i += 1;
@@ -167,17 +992,27 @@ impl Merkle {
                         // insert the value to the current branch node
                         if i == path.len() {
                             // the last index of the path must be NBRANCH
-                            assert!(bidx == NBRANCH);
-                            bnode.children[bidx] = Some(Child::Ptr(NodePtr::Dirty(val_dptr)));
+                            debug_assert!(bidx == NBRANCH);
+                            let old =
+                                bnode.children[bidx].replace(Child::Ptr(NodePtr::Dirty(val_dptr)));
                             store.put_dirty(cur_dptr, Some(cur_node));
+                            prev = Self::take_value(&mut store, old, want_prev)?;
                             break;
                         } else {
                             // get the next node pointer (DirtyPtr)
                             let child_dptr = match &bnode.children[bidx] {
-                                Some(Child::Ptr(NodePtr::Dirty(dptr))) => *dptr,
-                                Some(Child::Ptr(NodePtr::Clean(cptr))) => store.cow_clean(*cptr),
-                                Some(Child::Hash(cptr, _)) => store.cow_clean(*cptr),
-                                None => store.add_dirty(None),
+                                Some(Child::Ptr(NodePtr::Dirty(dptr))) => Ok(*dptr),
+                                Some(Child::Ptr(NodePtr::Clean(cptr)))
+                                | Some(Child::Hash(cptr, _)) => store.try_cow_clean(*cptr),
+                                None => Ok(store.add_dirty(None)),
+                            };
+                            let child_dptr = match child_dptr {
+                                Ok(dptr) => dptr,
+                                Err(e) => {
+                                    store.put_dirty(cur_dptr, Some(cur_node));
+                                    store.free_dirty(val_dptr);
+                                    return Err(e);
+                                }
                             };
                             bnode.children[bidx] = Some(Child::Ptr(NodePtr::Dirty(child_dptr)));
                             store.put_dirty(cur_dptr, Some(cur_node));
@@ -189,23 +1024,31 @@ impl Merkle {
                         i += shared_len;
                         if i == path.len() && shared_len == snode.path.len() {
                             // the short node path is exact the remaining path
-                            snode.child = Child::Ptr(NodePtr::Dirty(val_dptr));
+                            let old = std::mem::replace(
+                                &mut snode.child,
+                                Child::Ptr(NodePtr::Dirty(val_dptr)),
+                            );
                             store.put_dirty(cur_dptr, Some(cur_node));
+                            prev = Self::take_value(&mut store, Some(old), want_prev)?;
                             break;
                         } else if i < path.len() && shared_len == snode.path.len() {
                             // the short node path matches a prefix of remaining
                             // path, get the next node pointer and continue the
                             // tree traversal
-                            assert!(i + 1 < path.len());
                             let child_dptr = match snode.child {
-                                Child::Ptr(NodePtr::Dirty(dptr)) => dptr,
-                                Child::Ptr(NodePtr::Clean(cptr)) => {
-                                    // the node is not loaded nor CoW yet
-                                    store.cow_clean(cptr)
+                                Child::Ptr(NodePtr::Dirty(dptr)) => Ok(dptr),
+                                // the node is not loaded nor CoW yet, or only
+                                // its hash is loaded
+                                Child::Ptr(NodePtr::Clean(cptr)) | Child::Hash(cptr, _) => {
+                                    store.try_cow_clean(cptr)
                                 }
-                                Child::Hash(cptr, _) => {
-                                    // the hash is loaded, but the node is not CoW
-                                    store.cow_clean(cptr)
+                            };
+                            let child_dptr = match child_dptr {
+                                Ok(dptr) => dptr,
+                                Err(e) => {
+                                    store.put_dirty(cur_dptr, Some(cur_node));
+                                    store.free_dirty(val_dptr);
+                                    return Err(e);
                                 }
                             };
                             snode.child = Child::Ptr(NodePtr::Dirty(child_dptr));
@@ -215,7 +1058,12 @@ impl Merkle {
                             // partial short node path matches a partial prefix of
                             // remaining path, a branch node (maybe and a short
                             // node) is created BEFORE the current short node
-                            assert!(i < path.len() && shared_len < snode.path.len());
+                            if i == path.len() {
+                                // the short node continues past the terminator
+                                store.put_dirty(cur_dptr, Some(cur_node));
+                                store.free_dirty(val_dptr);
+                                return Err(malformed("short node past the end of a key"));
+                            }
                             let shared_prefix = snode.path[..shared_len].to_vec();
                             // branch index of the short node after the branch node
                             let bidx_snode = snode.path[shared_len] as usize;
@@ -244,7 +1092,7 @@ impl Merkle {
                             } else {
                                 // the remaining path reaches the NBRANCH
                                 // attach the value to the branch node
-                                assert!(bidx_path == NBRANCH);
+                                debug_assert!(bidx_path == NBRANCH);
                                 Child::Ptr(NodePtr::Dirty(val_dptr))
                             });
                             let branch = Node(NodeType::Branch(bnode));
@@ -275,15 +1123,76 @@ impl Merkle {
             stats.put += 1;
             stats.t_put += timer.elapsed().as_secs_f64();
         }
+        Ok(prev)
+    }
+
+    /// Bulk-load `entries`, sorted by ascending key, and commit the result.
+    ///
+    /// Into an empty trie, the trie is built bottom-up by a `SortedBuilder`,
+    /// so no dirty nodes are created. A non-empty trie falls back to
+    /// `insert` plus `commit`. For repeated keys the last entry wins.
+    pub fn insert_sorted(&mut self, entries: &[(Vec<u8>, Value)]) -> CleanPtr {
+        if self.root_cptr != 0 || self.root_dptr.is_some() {
+            for (key, val) in entries {
+                self.insert(key, val.clone());
+            }
+            return self.commit();
+        }
+        let mut builder = SortedBuilder::new(self.store.clone());
+        builder.extend(entries);
+        self.root_cptr = builder.finish();
+        self.root_node = None;
+        self.root_cptr
+    }
+
+    /// Hash and append a node whose children are all persisted.
+    fn persist(store: &mut NodeStore, hasher: &dyn Hasher, mut node: Node) -> Child {
+        let hash = node.calc_hash(hasher).unwrap();
+        store.write_aha(&mut node);
+        Child::Hash(store.add_node(node), hash)
     }
 
     /// Delete a key from the trie.
     ///
     /// Returns `true` if the key existed and was removed, `false` otherwise.
+    /// Panics on a node that cannot be read; see `try_delete`.
     pub fn delete(&mut self, key: &[u8]) -> bool {
+        self.try_delete(key).unwrap()
+    }
+
+    /// Like `delete`, but returns an error for a node on the path of `key`
+    /// that cannot be read, or fails AHA validation in strict mode. As with
+    /// `try_insert`, the trie keeps its previous contents then.
+    pub fn try_delete(&mut self, key: &[u8]) -> Result<bool, Error> {
+        let store = self.store.clone();
+        let mut store = store.lock().unwrap();
+        self.delete_locked(&mut store, key)
+    }
+
+    /// Delete `keys` under one store lock and return how many were present.
+    ///
+    /// Keys are deleted in sorted order, so keys sharing a prefix come one
+    /// after another and each reuses the path nodes the previous one already
+    /// copied into the arena instead of copying them from the store again.
+    pub fn delete_batch(&mut self, keys: &[&[u8]]) -> usize {
+        let mut keys = keys.to_vec();
+        keys.sort_unstable();
+        keys.dedup();
+        let store = self.store.clone();
+        let mut store = store.lock().unwrap();
+        let mut removed = 0;
+        for key in keys {
+            if self.delete_locked(&mut store, key).unwrap() {
+                removed += 1;
+            }
+        }
+        removed
+    }
+
+    fn delete_locked(&mut self, store: &mut NodeStore, key: &[u8]) -> Result<bool, Error> {
         // Fast path: nothing committed and nothing dirty.
         if self.root_cptr == 0 && self.root_dptr.is_none() {
-            return false;
+            return Ok(false);
         }
 
         #[cfg(feature = "stats")]
@@ -295,31 +1204,32 @@ impl Merkle {
         // when the key does not exist.
         let prev_root_dptr = self.root_dptr;
 
-        let mut store = self.store.lock().unwrap();
         let root_dptr = match self.root_dptr {
             Some(dptr) => dptr,
             None => {
-                if self.root_cptr == 0 {
+                let root_dptr = if self.root_cptr == 0 {
                     // Create a placeholder dirty root; it will be populated by delete_rec
                     // or left as None if nothing gets deleted.
                     store.add_dirty(None)
                 } else {
-                    store.cow_clean(self.root_cptr)
-                }
+                    store.try_cow_clean(self.root_cptr)?
+                };
+                store.acquire_dirty();
+                root_dptr
             }
         };
 
         // Tentatively track a dirty root so reads see in-flight changes.
         self.root_dptr = Some(root_dptr);
 
-        let (new_root_opt, removed) =
-            Self::delete_rec(&mut store, NodePtr::Dirty(root_dptr), &path, 0);
+        let (new_root_opt, removed) = Self::delete_rec(store, NodePtr::Dirty(root_dptr), &path, 0)?;
 
         if !removed {
             // Revert to prior state if this delete was a no-op on a clean tree.
             // (If we were already dirty, keep the dirty root.)
             if prev_root_dptr.is_none() {
                 self.root_dptr = None;
+                store.release_dirty();
             }
             #[cfg(feature = "stats")]
             {
@@ -327,7 +1237,7 @@ impl Merkle {
                 stats.del += 1;
                 stats.t_del += timer.elapsed().as_secs_f64();
             }
-            return false;
+            return Ok(false);
         }
 
         match new_root_opt {
@@ -342,7 +1252,7 @@ impl Merkle {
             }
             Some(NodePtr::Clean(cptr)) => {
                 // Should be rare, but keep representation consistent: move to dirty.
-                let new_dptr = store.cow_clean(cptr);
+                let new_dptr = store.try_cow_clean(cptr)?;
                 self.root_dptr = Some(new_dptr);
             }
         }
@@ -353,22 +1263,25 @@ impl Merkle {
             stats.del += 1;
             stats.t_del += timer.elapsed().as_secs_f64();
         }
-        true
+        Ok(true)
     }
 
+    /// Delete `path` below `ptr`. Returns the new subtree and whether
+    /// anything was removed. An error comes from copying a node on the way
+    /// down, before anything is removed, and leaves every node in place.
     fn delete_rec(
         store: &mut NodeStore,
         ptr: NodePtr,
         path: &[u8],
         depth: usize,
-    ) -> (Option<NodePtr>, bool) {
+    ) -> Result<(Option<NodePtr>, bool), Error> {
         let NodePtr::Dirty(dptr) = ptr else {
             unreachable!("delete_rec must only be called with dirty pointers");
         };
 
         let Some(mut node) = store.take_dirty(dptr) else {
             // Empty subtree.
-            return (None, false);
+            return Ok((None, false));
         };
 
         match node.get_inner_mut() {
@@ -376,66 +1289,74 @@ impl Merkle {
                 if depth == path.len() {
                     // Remove this value node.
                     store.put_dirty(dptr, None);
-                    return (None, true);
+                    return Ok((None, true));
                 }
                 store.put_dirty(dptr, Some(node));
-                (Some(NodePtr::Dirty(dptr)), false)
+                Ok((Some(NodePtr::Dirty(dptr)), false))
             }
             NodeType::Short(snode) => {
                 let remain = &path[depth..];
                 let shared = snode.common_prefix_len(remain);
                 if shared != snode.path.len() {
                     store.put_dirty(dptr, Some(node));
-                    return (Some(NodePtr::Dirty(dptr)), false);
+                    return Ok((Some(NodePtr::Dirty(dptr)), false));
                 }
 
                 let new_depth = depth + shared;
-                let child_ptr = match snode.child.clone() {
-                    Child::Ptr(NodePtr::Dirty(cdptr)) => NodePtr::Dirty(cdptr),
-                    Child::Ptr(NodePtr::Clean(cptr)) => NodePtr::Dirty(store.cow_clean(cptr)),
-                    Child::Hash(cptr, _) => NodePtr::Dirty(store.cow_clean(cptr)),
+                let child_ptr = match &snode.child {
+                    Child::Ptr(NodePtr::Dirty(cdptr)) => Ok(*cdptr),
+                    Child::Ptr(NodePtr::Clean(cptr)) | Child::Hash(cptr, _) => {
+                        store.try_cow_clean(*cptr)
+                    }
+                };
+                let child_ptr = match child_ptr {
+                    Ok(cdptr) => NodePtr::Dirty(cdptr),
+                    Err(e) => {
+                        store.put_dirty(dptr, Some(node));
+                        return Err(e);
+                    }
                 };
 
                 // Ensure the short node points to the dirty child we will traverse/mutate.
                 snode.child = Child::Ptr(child_ptr);
 
-                let (new_child_opt, removed) = Self::delete_rec(store, child_ptr, path, new_depth);
+                let (new_child_opt, removed) =
+                    match Self::delete_rec(store, child_ptr, path, new_depth) {
+                        Ok(deleted) => deleted,
+                        Err(e) => {
+                            store.put_dirty(dptr, Some(node));
+                            return Err(e);
+                        }
+                    };
                 if !removed {
                     store.put_dirty(dptr, Some(node));
-                    return (Some(NodePtr::Dirty(dptr)), false);
+                    return Ok((Some(NodePtr::Dirty(dptr)), false));
                 }
 
                 let Some(new_child_ptr) = new_child_opt else {
                     // Child removed => this short node is removed as well.
                     store.put_dirty(dptr, None);
-                    return (None, true);
+                    return Ok((None, true));
                 };
 
                 snode.child = Child::Ptr(new_child_ptr);
 
                 // If this is an extension (no terminator nibble), merge consecutive shorts:
                 // extension + (extension|leaf) => single short with concatenated path.
-                if snode.path.last().copied() != Some(NBRANCH as u8) {
-                    let child_node = match new_child_ptr {
-                        NodePtr::Dirty(cdptr) => store.get_dirty(cdptr).cloned(),
-                        NodePtr::Clean(cptr) => Some(store.get_clean(cptr).clone()),
-                    };
-
-                    if let Some(Node(NodeType::Short(child_snode))) = child_node {
-                        let mut merged_path = snode.path.clone();
-                        merged_path.extend_from_slice(&child_snode.path);
-                        snode.path = merged_path;
-                        snode.child = child_snode.child;
-                    }
+                if snode.path.last().copied() != Some(NBRANCH as u8)
+                    && let Some((child_path, grandchild)) = Self::take_short(store, new_child_ptr)
+                {
+                    snode.path.extend_from_slice(&child_path);
+                    snode.child = grandchild;
                 }
 
                 store.put_dirty(dptr, Some(node));
-                (Some(NodePtr::Dirty(dptr)), true)
+                Ok((Some(NodePtr::Dirty(dptr)), true))
             }
             NodeType::Branch(bnode) => {
                 if depth >= path.len() {
                     store.put_dirty(dptr, Some(node));
-                    return (Some(NodePtr::Dirty(dptr)), false);
+                    return Ok((Some(NodePtr::Dirty(dptr)), false));
                 }
 
                 let bidx = path[depth] as usize;
@@ -443,7 +1364,7 @@ impl Merkle {
 
                 let Some(child) = bnode.children[bidx].take() else {
                     store.put_dirty(dptr, Some(node));
-                    return (Some(NodePtr::Dirty(dptr)), false);
+                    return Ok((Some(NodePtr::Dirty(dptr)), false));
                 };
 
                 // Special-case the branch "value slot" (index 16) when we're at end of key.
@@ -453,29 +1374,36 @@ impl Merkle {
                         (child, None, true)
                     } else {
                         // Ensure the child is dirty before descending.
-                        let (child_ptr, child_updated) = match child {
-                            Child::Ptr(NodePtr::Dirty(cdptr)) => {
-                                (NodePtr::Dirty(cdptr), Child::Ptr(NodePtr::Dirty(cdptr)))
-                            }
-                            Child::Ptr(NodePtr::Clean(cptr)) => {
-                                let cdptr = store.cow_clean(cptr);
-                                (NodePtr::Dirty(cdptr), Child::Ptr(NodePtr::Dirty(cdptr)))
+                        let cdptr = match &child {
+                            Child::Ptr(NodePtr::Dirty(cdptr)) => Ok(*cdptr),
+                            Child::Ptr(NodePtr::Clean(cptr)) | Child::Hash(cptr, _) => {
+                                store.try_cow_clean(*cptr)
                             }
-                            Child::Hash(cptr, _) => {
-                                let cdptr = store.cow_clean(cptr);
-                                (NodePtr::Dirty(cdptr), Child::Ptr(NodePtr::Dirty(cdptr)))
+                        };
+                        let cdptr = match cdptr {
+                            Ok(cdptr) => cdptr,
+                            Err(e) => {
+                                bnode.children[bidx] = Some(child);
+                                store.put_dirty(dptr, Some(node));
+                                return Err(e);
                             }
                         };
-                        let (new_child_opt, removed) =
-                            Self::delete_rec(store, child_ptr, path, next_depth);
-                        (child_updated, new_child_opt, removed)
+                        let child_updated = Child::Ptr(NodePtr::Dirty(cdptr));
+                        match Self::delete_rec(store, NodePtr::Dirty(cdptr), path, next_depth) {
+                            Ok((new_child_opt, removed)) => (child_updated, new_child_opt, removed),
+                            Err(e) => {
+                                bnode.children[bidx] = Some(child_updated);
+                                store.put_dirty(dptr, Some(node));
+                                return Err(e);
+                            }
+                        }
                     };
 
                 if !removed {
                     // Put the child back unchanged.
                     bnode.children[bidx] = Some(child_for_restore);
                     store.put_dirty(dptr, Some(node));
-                    return (Some(NodePtr::Dirty(dptr)), false);
+                    return Ok((Some(NodePtr::Dirty(dptr)), false));
                 }
 
                 bnode.children[bidx] = new_child_opt.map(|p| Child::Ptr(p));
@@ -485,21 +1413,23 @@ impl Merkle {
                 // - Single entry:
                 //   - only value => leaf with empty path (represented as short path [16])
                 //   - only one child and no value => short node (possibly merged)
-                let mut present: Vec<usize> = Vec::new();
-                for i in 0..NBRANCH + 1 {
-                    if bnode.children[i].is_some() {
-                        present.push(i);
-                    }
-                }
-
-                match present.len() {
-                    0 => {
+                let mut present = bnode
+                    .children
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, c)| c.is_some());
+                let only = match (present.next(), present.next()) {
+                    (None, _) => {
                         store.put_dirty(dptr, None);
-                        (None, true)
+                        return Ok((None, true));
                     }
-                    1 => {
-                        let only = present[0];
-                        let only_child = bnode.children[only].clone().unwrap();
+                    (Some((only, _)), None) => Some(only),
+                    _ => None,
+                };
+
+                match only {
+                    Some(only) => {
+                        let only_child = bnode.children[only].take().unwrap();
 
                         // If the only entry is the value slot, collapse to a leaf short node.
                         let mut new_path: Vec<u8> = vec![only as u8];
@@ -507,91 +1437,150 @@ impl Merkle {
 
                         if only != NBRANCH {
                             // For non-value branches, attempt to merge [only] with a child short.
-                            let child_ptr = match &new_child {
-                                Child::Ptr(p) => *p,
-                                Child::Hash(cptr, _) => NodePtr::Clean(*cptr),
-                            };
-                            let child_node = match child_ptr {
-                                NodePtr::Dirty(cdptr) => store.get_dirty(cdptr).cloned(),
-                                NodePtr::Clean(cptr) => Some(store.get_clean(cptr).clone()),
-                            };
-                            if let Some(Node(NodeType::Short(child_snode))) = child_node {
-                                new_path.extend_from_slice(&child_snode.path);
-                                new_child = child_snode.child;
+                            if let Some((child_path, grandchild)) =
+                                Self::take_short(store, new_child.ptr())
+                            {
+                                new_path.extend_from_slice(&child_path);
+                                new_child = grandchild;
                             }
                         }
 
                         let new_snode = Short::new(new_path, new_child);
                         store.put_dirty(dptr, Some(Node(NodeType::Short(new_snode))));
-                        (Some(NodePtr::Dirty(dptr)), true)
+                        Ok((Some(NodePtr::Dirty(dptr)), true))
                     }
-                    _ => {
+                    None => {
                         store.put_dirty(dptr, Some(node));
-                        (Some(NodePtr::Dirty(dptr)), true)
+                        Ok((Some(NodePtr::Dirty(dptr)), true))
                     }
                 }
             }
         }
     }
 
+    /// If `ptr` is a short node, return its path and child so a parent can
+    /// absorb it. A dirty short is moved out of the store, since it is
+    /// unreachable once merged; a clean one is only read.
+    fn take_short(store: &mut NodeStore, ptr: NodePtr) -> Option<(Vec<u8>, Child)> {
+        match ptr {
+            NodePtr::Dirty(dptr) => {
+                if !matches!(store.get_dirty(dptr), Some(Node(NodeType::Short(_)))) {
+                    return None;
+                }
+                match store.take_dirty(dptr) {
+                    Some(Node(NodeType::Short(snode))) => Some((snode.path, snode.child)),
+                    _ => unreachable!(),
+                }
+            }
+            NodePtr::Clean(cptr) => match store.get_clean(cptr).get_inner() {
+                NodeType::Short(snode) => Some((snode.path.clone(), snode.child.clone())),
+                _ => None,
+            },
+        }
+    }
+
+    /// Hash and persist the dirty nodes and make the new root current.
+    /// Every commit with changes, including one deleting the trie to empty,
+    /// is reported to `Metrics::on_commit`; committing a clean trie is not.
     pub fn commit(&mut self) -> CleanPtr {
-        #[cfg(feature = "stats")]
-        let commit_timer = Instant::now();
-        let root_dptr = match &self.root_dptr {
-            Some(dptr) => *dptr,
-            None => return self.root_cptr,
-        };
+        let timer = Instant::now();
+        let dirty = self.root_dptr.is_some();
+        match self.prepare_commit() {
+            Some(mut pending) => {
+                pending.hash();
+                self.finish_commit(pending)
+            }
+            None => {
+                if dirty {
+                    self.report_commit(timer);
+                }
+                self.root_cptr
+            }
+        }
+    }
+
+    /// Tell the metrics hook about a commit started at `started`.
+    pub(crate) fn report_commit(&self, started: Instant) {
+        if let Some(m) = self.store.lock().unwrap().metrics() {
+            m.on_commit(started.elapsed());
+        }
+    }
+
+    /// First commit phase: take this trie's dirty nodes out of the store and
+    /// load the hashes of their clean children, so that hashing can proceed
+    /// without holding the store lock.
+    ///
+    /// Returns `None` if there is nothing left to hash, i.e. the trie is clean
+    /// or was deleted to empty (which is committed right away).
+    pub fn prepare_commit(&mut self) -> Option<PendingCommit> {
+        let started = Instant::now();
+        let root_dptr = self.root_dptr?;
 
-        // If the dirty root is explicitly empty, this commit is deleting the trie to empty.
         let mut store = self.store.lock().unwrap();
+        // If the dirty root is explicitly empty, this commit is deleting the trie to empty.
         if store.get_dirty(root_dptr).is_none() {
             self.root_cptr = 0;
             self.root_dptr = None;
+            self.root_node = None;
+            store.release_dirty();
             store.commit();
-            #[cfg(feature = "stats")]
-            {
-                let mut stats = self.stats.lock().unwrap();
-                stats.t_commit += commit_timer.elapsed().as_secs_f64();
-            }
-            return 0;
+            return None;
         }
 
-        let mut ptr_map: HashMap<DirtyPtr, (CleanPtr, Vec<u8>)> = HashMap::new();
-        let mut nodes = Self::commit_order(&mut store, root_dptr);
+        let (mut nodes, dirty_children) = Self::commit_order(&mut store, root_dptr);
+        for node in &mut nodes {
+            store.load_children_hash(node);
+        }
+        Some(PendingCommit {
+            started,
+            hasher: store.hasher(),
+            hashes: Vec::new(),
+            nodes,
+            dirty_children,
+            #[cfg(feature = "stats")]
+            t_hash: 0.0,
+        })
+    }
+
+    /// Last commit phase: persist the hashed nodes bottom-up, make the
+    /// new root current and report the commit, timed from
+    /// `prepare_commit`, to `Metrics::on_commit`. Nodes go out in reverse BFS order, or with
+    /// `NodeStore::set_deterministic_layout` depth by depth, deepest first,
+    /// each depth sorted by reference item.
+    pub fn finish_commit(&mut self, mut pending: PendingCommit) -> CleanPtr {
+        #[cfg(feature = "stats")]
+        let commit_timer = Instant::now();
+        let mut store = self.store.lock().unwrap();
         #[cfg(feature = "stats")]
         let mut stats = self.stats.lock().unwrap();
         #[cfg(feature = "stats")]
         let tc_node = Instant::now();
-        while let Some((dptr, mut node)) = nodes.pop() {
 
-            match &mut node.get_inner_mut() {
-                
-                NodeType::Branch(bnode) => {
-                    for i in 0..NBRANCH + 1 {
-                        if let Some(Child::Ptr(NodePtr::Dirty(child_dptr))) = &bnode.children[i] {
-                            let (cptr, hash) = ptr_map.remove(child_dptr).unwrap();
-                            bnode.children[i] = Some(Child::Hash(cptr, hash));
-                        }
-                    }
-                }
-                NodeType::Short(snode) => {
-                    if let Child::Ptr(NodePtr::Dirty(child_dptr)) = snode.child.clone() {
-                        let (cptr, hash) = ptr_map.remove(&child_dptr).unwrap();
-                        snode.child = Child::Hash(cptr, hash);
-                    }
-                }
-                NodeType::Value(_) => {}
-            }
-
-            #[cfg(feature = "stats")]
-            let hash_timer = Instant::now();
-
-            store.load_children_hash(&mut node);
-            let hash = node.calc_hash().unwrap();
-
-            #[cfg(feature = "stats")]
-            {
-                stats.tcn_hash += hash_timer.elapsed().as_secs_f64();
+        let order: Vec<usize> = if store.deterministic_layout() {
+            let hashes = &pending.hashes;
+            pending
+                .levels()
+                .into_iter()
+                .rev()
+                .flat_map(|(lo, hi)| {
+                    let mut level: Vec<usize> = (lo..hi).collect();
+                    level.sort_by(|&a, &b| hashes[a].cmp(&hashes[b]));
+                    level
+                })
+                .collect()
+        } else {
+            (0..pending.nodes.len()).rev().collect()
+        };
+        let mut nodes: Vec<Option<Node>> = std::mem::take(&mut pending.nodes)
+            .into_iter()
+            .map(Some)
+            .collect();
+        let mut cptrs: Vec<CleanPtr> = vec![0; nodes.len()];
+        for i in order {
+            let mut node = nodes[i].take().unwrap();
+            for &(slot, j) in &pending.dirty_children[i] {
+                let hash = std::mem::take(&mut pending.hashes[j]);
+                set_child(&mut node, slot, Child::Hash(cptrs[j], hash));
             }
 
             #[cfg(feature = "stats")]
@@ -599,29 +1588,32 @@ impl Merkle {
 
             store.write_aha(&mut node);
 
-            #[cfg(feature = "stats")] {
+            #[cfg(feature = "stats")]
+            {
                 stats.tcn_add += add_timer.elapsed().as_secs_f64();
             }
 
             #[cfg(feature = "stats")]
             let store_timer = Instant::now();
 
-            let cptr = store.add_node(node);
+            cptrs[i] = store.add_node(node);
 
-            #[cfg(feature = "stats")] {
+            #[cfg(feature = "stats")]
+            {
                 stats.tcn_store += store_timer.elapsed().as_secs_f64();
-            }   
-            
-            ptr_map.insert(dptr, (cptr, hash));
+            }
         }
-        
 
-        let (cptr, _hash) = ptr_map.remove(&root_dptr).unwrap();
+        let cptr = cptrs[0];
         self.root_cptr = cptr;
         self.root_dptr = None;
+        self.root_node = None;
+        store.release_dirty();
 
-        #[cfg(feature = "stats")] {
+        #[cfg(feature = "stats")]
+        {
             stats.tc_node += tc_node.elapsed().as_secs_f64();
+            stats.tcn_hash += pending.t_hash;
         }
 
         #[cfg(feature = "stats")]
@@ -630,45 +1622,55 @@ impl Merkle {
         #[cfg(feature = "stats")]
         {
             stats.tc_store += tc_store.elapsed().as_secs_f64();
-            stats.t_commit += commit_timer.elapsed().as_secs_f64();
+            stats.t_commit += commit_timer.elapsed().as_secs_f64() + pending.t_hash;
+        }
+        if let Some(m) = store.metrics() {
+            m.on_commit(pending.started.elapsed());
         }
         cptr
     }
 
-    fn commit_order(store: &mut NodeStore, root_dptr: DirtyPtr) -> Vec<(DirtyPtr, Node)> {
+    /// Take all dirty nodes reachable from `root_dptr` in BFS order, so every
+    /// parent precedes its children. Alongside each node, return its dirty
+    /// children as `(child slot, index into the returned nodes)`.
+    fn commit_order(
+        store: &mut NodeStore,
+        root_dptr: DirtyPtr,
+    ) -> (Vec<Node>, Vec<Vec<(usize, usize)>>) {
         let mut nodes = Vec::new();
-        nodes.push((root_dptr, store.take_dirty(root_dptr).unwrap()));
+        let mut dirty_children = Vec::new();
+        nodes.push(store.take_dirty(root_dptr).unwrap());
 
         let mut i = 0;
         while i < nodes.len() {
             // Collect child pointers first so we don't hold an immutable borrow
             // of `nodes[i]` while pushing into `nodes`.
-            let dirty_children: Vec<DirtyPtr> = {
-                let (_, cur_node) = &nodes[i];
-                match cur_node.get_inner() {
-                    NodeType::Branch(bnode) => {
-                        let mut out = Vec::new();
-                        for idx in 0..NBRANCH + 1 {
-                            if let Some(Child::Ptr(NodePtr::Dirty(dptr))) = &bnode.children[idx] {
-                                out.push(*dptr);
-                            }
+            let children: Vec<(usize, DirtyPtr)> = match nodes[i].get_inner() {
+                NodeType::Branch(bnode) => {
+                    let mut out = Vec::new();
+                    for idx in 0..NBRANCH + 1 {
+                        if let Some(Child::Ptr(NodePtr::Dirty(dptr))) = &bnode.children[idx] {
+                            out.push((idx, *dptr));
                         }
-                        out
                     }
-                    NodeType::Short(snode) => match &snode.child {
-                        Child::Ptr(NodePtr::Dirty(dptr)) => vec![*dptr],
-                        _ => Vec::new(),
-                    },
-                    NodeType::Value(_) => Vec::new(),
+                    out
                 }
+                NodeType::Short(snode) => match &snode.child {
+                    Child::Ptr(NodePtr::Dirty(dptr)) => vec![(0, *dptr)],
+                    _ => Vec::new(),
+                },
+                NodeType::Value(_) => Vec::new(),
             };
 
             i += 1;
-            for dptr in dirty_children {
-                nodes.push((dptr, store.take_dirty(dptr).unwrap()));
+            let mut out = Vec::with_capacity(children.len());
+            for (slot, dptr) in children {
+                out.push((slot, nodes.len()));
+                nodes.push(store.take_dirty(dptr).unwrap());
             }
+            dirty_children.push(out);
         }
-        nodes
+        (nodes, dirty_children)
     }
 
     #[cfg(feature = "stats")]
@@ -679,3 +1681,378 @@ impl Merkle {
         self.store.lock().unwrap().print_stats();
     }
 }
+
+impl Drop for Merkle {
+    fn drop(&mut self) {
+        if self.root_dptr.is_some()
+            && let Ok(mut store) = self.store.lock()
+        {
+            store.release_dirty();
+        }
+    }
+}
+
+/// Builds a trie bottom-up from entries in ascending key order, which may
+/// come in any number of slices, and commits it as one root. Only the right
+/// edge of the trie, the path to the last entry, is held in memory: every
+/// subtree left of it is written to the store once a later key shows that
+/// it is complete.
+pub(crate) struct SortedBuilder {
+    store: Arc<Mutex<NodeStore>>,
+    // branch nodes on the path to `last` with their depth; the slot that
+    // path goes through is still empty
+    spine: Vec<(usize, Branch)>,
+    // path and value of the last entry, not written yet
+    last: Option<(Vec<u8>, Value)>,
+    started: Instant,
+}
+
+// The part of the right edge below the innermost open branch: either the
+// last entry itself, or a persisted node sitting at the given depth.
+enum Tail {
+    Leaf(Value),
+    Node(usize, Child),
+}
+
+impl SortedBuilder {
+    pub(crate) fn new(store: Arc<Mutex<NodeStore>>) -> Self {
+        Self {
+            store,
+            spine: Vec::new(),
+            last: None,
+            started: Instant::now(),
+        }
+    }
+
+    /// Add `entries`, which must be in ascending key order and come after
+    /// the entries added before. For repeated keys the last entry wins.
+    pub(crate) fn extend(&mut self, entries: &[(Vec<u8>, Value)]) {
+        let store = self.store.clone();
+        let mut store = store.lock().unwrap();
+        let hasher = store.hasher();
+        for (key, val) in entries {
+            let path = utils::to_path(key);
+            let Some((last, _)) = &self.last else {
+                self.last = Some((path, val.clone()));
+                continue;
+            };
+            if *last == path {
+                self.last.as_mut().unwrap().1 = val.clone();
+                continue;
+            }
+            // without the terminator, nibble order is key order
+            assert!(
+                last[..last.len() - 1] < path[..path.len() - 1],
+                "insert_sorted requires keys in ascending order"
+            );
+            // the terminator makes the paths differ before either ends
+            let shared = last.iter().zip(&path).take_while(|(a, b)| a == b).count();
+            let (last, last_val) = self.last.replace((path, val.clone())).unwrap();
+
+            // nothing after `key` can go below the branch at `shared`, so
+            // the right edge under it is complete
+            let tail = self.close(&mut store, hasher.as_ref(), &last, last_val, shared + 1);
+            let child = Self::subtree(&mut store, hasher.as_ref(), &last, tail, shared + 1);
+            let bidx = last[shared] as usize;
+            match self.spine.last_mut() {
+                Some((depth, bnode)) if *depth == shared => bnode.children[bidx] = Some(child),
+                _ => {
+                    let mut bnode = Branch::new();
+                    bnode.children[bidx] = Some(child);
+                    self.spine.push((shared, bnode));
+                }
+            }
+        }
+    }
+
+    /// Write the right edge and return the root, reporting the build to
+    /// `Metrics::on_commit`. Without entries, returns the empty root 0.
+    pub(crate) fn finish(mut self) -> CleanPtr {
+        let Some((path, val)) = self.last.take() else {
+            return 0;
+        };
+        let store = self.store.clone();
+        let mut store = store.lock().unwrap();
+        let hasher = store.hasher();
+        let tail = self.close(&mut store, hasher.as_ref(), &path, val, 0);
+        let Child::Hash(cptr, _) = Self::subtree(&mut store, hasher.as_ref(), &path, tail, 0)
+        else {
+            unreachable!();
+        };
+        if let Some(m) = store.metrics() {
+            m.on_commit(self.started.elapsed());
+        }
+        cptr
+    }
+
+    /// Persist the open branches at depth `keep` or deeper, innermost
+    /// first, with `path` and its value `val` below them.
+    fn close(
+        &mut self,
+        store: &mut NodeStore,
+        hasher: &dyn Hasher,
+        path: &[u8],
+        val: Value,
+        keep: usize,
+    ) -> Tail {
+        let mut tail = Tail::Leaf(val);
+        while let Some((depth, _)) = self.spine.last()
+            && *depth >= keep
+        {
+            let (depth, mut bnode) = self.spine.pop().unwrap();
+            let child = Self::subtree(store, hasher, path, tail, depth + 1);
+            bnode.children[path[depth] as usize] = Some(child);
+            let node = Node(NodeType::Branch(bnode));
+            tail = Tail::Node(depth, Merkle::persist(store, hasher, node));
+        }
+        tail
+    }
+
+    /// The child for `path` from nibble `depth` on, given what lies below.
+    fn subtree(
+        store: &mut NodeStore,
+        hasher: &dyn Hasher,
+        path: &[u8],
+        tail: Tail,
+        depth: usize,
+    ) -> Child {
+        match tail {
+            Tail::Leaf(val) => {
+                let child = Merkle::persist(store, hasher, Node(NodeType::Value(val)));
+                if depth == path.len() {
+                    // the key ends at the branch above
+                    return child;
+                }
+                let snode = Short::new(path[depth..].to_vec(), child);
+                Merkle::persist(store, hasher, Node(NodeType::Short(snode)))
+            }
+            Tail::Node(at, child) if at > depth => {
+                let snode = Short::new(path[depth..at].to_vec(), child);
+                Merkle::persist(store, hasher, Node(NodeType::Short(snode)))
+            }
+            Tail::Node(_, child) => child,
+        }
+    }
+}
+
+/// Lazy depth-first walk over a `Merkle`, returned by `Merkle::iter` and
+/// `Merkle::iter_rev`.
+pub struct Iter<'a> {
+    merkle: &'a Merkle,
+    // subtrees still to visit, with their path so far; the top is next
+    stack: Vec<(NodePtr, Vec<u8>)>,
+    rev: bool,
+}
+
+impl<'a> Iter<'a> {
+    fn new(merkle: &'a Merkle, rev: bool) -> Self {
+        let stack = merkle
+            .root_ptr()
+            .map(|ptr| (ptr, Vec::new()))
+            .into_iter()
+            .collect();
+        Self { merkle, stack, rev }
+    }
+}
+
+impl Iterator for Iter<'_> {
+    type Item = (Vec<u8>, Value);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut store = self.merkle.store.lock().unwrap();
+        while let Some((ptr, mut nibbles)) = self.stack.pop() {
+            let Some(node) = Merkle::load_node(&mut store, ptr) else {
+                continue;
+            };
+            match node.0 {
+                NodeType::Value(vnode) => {
+                    // a complete path always ends with the NBRANCH terminator
+                    assert!(nibbles.last() == Some(&(NBRANCH as u8)));
+                    let key = utils::from_nibbles(&nibbles[..nibbles.len() - 1]).collect();
+                    return Some((key, vnode));
+                }
+                NodeType::Short(snode) => {
+                    nibbles.extend_from_slice(&snode.path);
+                    self.stack.push((snode.child.ptr(), nibbles));
+                }
+                NodeType::Branch(bnode) => {
+                    // push in reverse visiting order so the first child is on top
+                    let order: Vec<usize> = if self.rev {
+                        std::iter::once(NBRANCH).chain(0..NBRANCH).collect()
+                    } else {
+                        (0..NBRANCH).rev().chain(std::iter::once(NBRANCH)).collect()
+                    };
+                    for idx in order {
+                        if let Some(child) = &bnode.children[idx] {
+                            let mut path = nibbles.clone();
+                            path.push(idx as u8);
+                            self.stack.push((child.ptr(), path));
+                        }
+                    }
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Dirty nodes of one trie taken out of the store by
+/// `Merkle::prepare_commit`.
+///
+/// Hashing a `PendingCommit` needs neither the store nor the trie, so the
+/// pending commits of independent tries can be hashed concurrently.
+pub struct PendingCommit {
+    started: Instant,
+    hasher: Arc<dyn Hasher>,
+    // BFS order: parents precede children
+    nodes: Vec<Node>,
+    dirty_children: Vec<Vec<(usize, usize)>>,
+    hashes: Vec<Vec<u8>>,
+    #[cfg(feature = "stats")]
+    t_hash: f64,
+}
+
+impl PendingCommit {
+    /// Compute the reference item of every node bottom-up. Until
+    /// `Merkle::finish_commit` assigns clean pointers, dirty children are
+    /// referenced by their index into `nodes`.
+    ///
+    /// Nodes only depend on their children, so the nodes of one depth are
+    /// hashed in parallel, deepest first. `nodes` is in BFS order, which
+    /// makes every depth a contiguous range whose children all lie past
+    /// its end. The order nodes are written in is left to `finish_commit`,
+    /// so the on-disk layout is the same as with serial hashing.
+    pub fn hash(&mut self) {
+        #[cfg(feature = "stats")]
+        let hash_timer = Instant::now();
+        self.hashes = vec![Vec::new(); self.nodes.len()];
+        let hasher = self.hasher.as_ref();
+        for (lo, hi) in self.levels().into_iter().rev() {
+            let (done, below) = self.hashes.split_at_mut(hi);
+            let below = &*below;
+            self.nodes[lo..hi]
+                .par_iter_mut()
+                .zip(&mut done[lo..])
+                .zip(&self.dirty_children[lo..hi])
+                .with_min_len(PAR_HASH_MIN_LEN)
+                .for_each(|((node, hash), children)| {
+                    for &(slot, j) in children {
+                        set_child(
+                            node,
+                            slot,
+                            Child::Hash(j as CleanPtr, below[j - hi].clone()),
+                        );
+                    }
+                    *hash = node.calc_hash(hasher).unwrap();
+                });
+        }
+        #[cfg(feature = "stats")]
+        {
+            self.t_hash += hash_timer.elapsed().as_secs_f64();
+        }
+    }
+
+    /// `[lo, hi)` index range of each depth of `nodes`, root first.
+    fn levels(&self) -> Vec<(usize, usize)> {
+        let mut levels = Vec::new();
+        let (mut lo, mut hi) = (0, 1.min(self.nodes.len()));
+        while lo < hi {
+            levels.push((lo, hi));
+            // the children of a level follow it directly
+            let next = self.dirty_children[lo..hi]
+                .iter()
+                .flatten()
+                .map(|&(_, j)| j + 1)
+                .max()
+                .unwrap_or(hi);
+            (lo, hi) = (hi, next);
+        }
+        levels
+    }
+
+    /// Root hash of the trie these nodes commit to. Only valid after `hash`.
+    pub fn root_hash(&self) -> Vec<u8> {
+        let root_rlp = self.nodes[0]
+            .rlp_encode()
+            .expect("pending root RLP encoding must succeed");
+        self.hasher.digest(&root_rlp)
+    }
+}
+
+/// Why a committed trie failed `Merkle::verify_integrity`, and where.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IntegrityError {
+    /// The node could not be read or decoded.
+    Unreadable { ptr: CleanPtr, reason: String },
+    /// The node's stored hash differs from its recomputed hash.
+    HashMismatch { ptr: CleanPtr },
+    /// A loaded child reference in `slot` differs from the child's hash.
+    ChildHashMismatch { ptr: CleanPtr, slot: usize },
+    /// The node breaks a structural invariant of the trie.
+    Malformed { ptr: CleanPtr, reason: &'static str },
+}
+
+impl IntegrityError {
+    /// The offending node.
+    pub fn ptr(&self) -> CleanPtr {
+        match self {
+            IntegrityError::Unreadable { ptr, .. }
+            | IntegrityError::HashMismatch { ptr }
+            | IntegrityError::ChildHashMismatch { ptr, .. }
+            | IntegrityError::Malformed { ptr, .. } => *ptr,
+        }
+    }
+}
+
+impl std::fmt::Display for IntegrityError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IntegrityError::Unreadable { ptr, reason } => {
+                write!(f, "node {ptr} is unreadable: {reason}")
+            }
+            IntegrityError::HashMismatch { ptr } => write!(f, "node {ptr} has a stale hash"),
+            IntegrityError::ChildHashMismatch { ptr, slot } => {
+                write!(f, "node {ptr} has a stale reference to child {slot}")
+            }
+            IntegrityError::Malformed { ptr, reason } => {
+                write!(f, "node {ptr} is malformed: {reason}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for IntegrityError {}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Shape {
+    Value,
+    Short,
+    Branch,
+}
+
+/// A position in a committed trie: the node at the pointer with the first
+/// `skip` nibbles of its path (if it is a short node) already consumed.
+type DiffCursor = (CleanPtr, usize);
+
+/// Entries of a key range and the proof nodes; see `Merkle::range_proof`.
+pub(crate) type RangeProof<V> = (Vec<(Vec<u8>, V)>, Vec<Vec<u8>>);
+
+enum DiffNode {
+    Leaf(Value),
+    Slots(Box<[Option<DiffCursor>; NBRANCH + 1]>),
+}
+
+/// A node whose shape no valid trie can have, as read from a corrupt file.
+fn malformed(what: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("corrupt trie: {}", what))
+}
+
+/// Set child `slot` of `node`: a branch index, or 0 for the child of a short
+/// node.
+fn set_child(node: &mut Node, slot: usize, child: Child) {
+    match node.get_inner_mut() {
+        NodeType::Branch(bnode) => bnode.children[slot] = Some(child),
+        NodeType::Short(snode) => snode.child = child,
+        NodeType::Value(_) => unreachable!("value nodes have no children"),
+    }
+}
@@ -1,7 +1,13 @@
 mod aha;
 mod backend;
+mod cache;
+mod cursor;
+mod hasher;
+mod memstore;
 mod merkle;
 mod node;
+mod path;
+mod proof;
 mod store;
 #[cfg(test)]
 mod tests;
@@ -17,6 +23,13 @@ const NBRANCH: usize = 16;
 
 pub use aha::AggregatedHashArray;
 pub use backend::Backend;
-pub use merkle::Merkle;
-pub use node::Value;
-pub use store::NodeStore;
+pub use cursor::Cursor;
+#[cfg(feature = "tiny-keccak")]
+pub use hasher::TinyKeccak256Hasher;
+pub use hasher::{Hasher, Keccak256Hasher, KeccakImpl};
+pub use merkle::{IntegrityError, Merkle};
+pub(crate) use merkle::{RangeProof, SortedBuilder};
+pub use node::{ChildView, NodeView, Value};
+pub use path::NibblePath;
+pub use proof::verify_range_proof;
+pub use store::{CachePolicy, NodeHeaderError, NodeStore, check_node_header};
@@ -1,10 +1,11 @@
 #![allow(dead_code)]
 
+use super::hasher::Hasher;
+use super::path::NibblePath;
 use super::utils;
 use super::{CleanPtr, DirtyPtr, NBRANCH};
 
 use lru_mem::HeapSize;
-use sha3::{Digest, Keccak256};
 use std::io::{Error, ErrorKind};
 use std::mem::size_of;
 
@@ -13,6 +14,8 @@ const HASH_SIZE: usize = 32;
 const BRANCH_NODE_TYPE: u8 = 0x0;
 const SHORT_NODE_TYPE: u8 = 0x1;
 const VALUE_NODE_TYPE: u8 = 0x2;
+// A value node whose value bytes are kept in the blob file.
+const BLOB_VALUE_NODE_TYPE: u8 = 0x3;
 
 #[derive(Copy, Clone)]
 pub enum NodePtr {
@@ -59,6 +62,32 @@ pub enum NodeType {
 #[derive(Clone)]
 pub struct Node(pub NodeType);
 
+/// Read-only description of a committed node, for tooling such as trie
+/// visualizers. Child pointers can be passed back to `inspect_node`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NodeView {
+    /// One slot per nibble, then the value slot at index 16.
+    Branch {
+        children: Vec<Option<ChildView>>,
+    },
+    Short {
+        path: NibblePath,
+        child: ChildView,
+    },
+    Value {
+        value: Vec<u8>,
+        extra: Vec<u8>,
+    },
+}
+
+/// A child's pointer and its reference item: the child's RLP if shorter
+/// than 32 bytes, otherwise the RLP of its hash.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChildView {
+    pub ptr: CleanPtr,
+    pub hash: Vec<u8>,
+}
+
 //=============== Implementations ===============
 
 impl Node {
@@ -86,11 +115,45 @@ impl Node {
     }
 
     pub fn decode(data: &[u8]) -> Result<Self, Error> {
-        if let Ok(inner) = rlp::decode::<NodeType>(data) {
-            Ok(Self(inner))
-        } else {
-            Err(Error::new(ErrorKind::Other, "Invalid RLP"))
+        match rlp::decode::<NodeType>(data) {
+            Ok(inner) => Ok(Self(inner)),
+            Err(e) => Err(Error::new(ErrorKind::Other, format!("Invalid RLP: {e}"))),
+        }
+    }
+
+    /// Encode a value node whose value is not stored inline but as `len`
+    /// bytes at `blob_ptr` in the blob file.
+    pub fn encode_blob_value(blob_ptr: CleanPtr, len: usize, extra: &[u8]) -> Vec<u8> {
+        let mut s = RlpStream::new_list(2);
+        s.append(&BLOB_VALUE_NODE_TYPE);
+        s.begin_list(3)
+            .append(&blob_ptr)
+            .append(&(len as u64))
+            .append_list(extra);
+        s.out().to_vec()
+    }
+
+    /// Like `decode`, but a value node written by `encode_blob_value` gets
+    /// its value back from `read_blob(blob_ptr, len)`.
+    pub fn decode_with_blobs(
+        data: &[u8],
+        read_blob: impl FnOnce(CleanPtr, usize) -> Result<Vec<u8>, Error>,
+    ) -> Result<Self, Error> {
+        let invalid =
+            |e: DecoderError| Error::new(ErrorKind::InvalidData, format!("Invalid RLP: {e}"));
+        let s = Rlp::new(data);
+        if s.val_at::<u8>(0).map_err(invalid)? != BLOB_VALUE_NODE_TYPE {
+            return Self::decode(data);
+        }
+        let blob = s.at(1).map_err(invalid)?;
+        let blob_ptr: CleanPtr = blob.val_at(0).map_err(invalid)?;
+        let len: u64 = blob.val_at(1).map_err(invalid)?;
+        let extra = blob.list_at(2).map_err(invalid)?;
+        let value = read_blob(blob_ptr, len as usize)?;
+        if value.len() != len as usize {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "blob is truncated"));
         }
+        Ok(Self(NodeType::Value(Value { value, extra })))
     }
 
     /// Canonical trie RLP used for hashing.
@@ -103,11 +166,20 @@ impl Node {
     }
 
     /// Calculates and stores the trie reference item for this node.
-    pub fn calc_hash(&mut self) -> Result<Vec<u8>, Error> {
+    pub fn calc_hash(&mut self, hasher: &dyn Hasher) -> Result<Vec<u8>, Error> {
         match &mut self.0 {
             NodeType::Value(v) => Ok(rlp::encode(&v.value).to_vec()),
-            NodeType::Branch(b) => b.calc_hash(),
-            NodeType::Short(s) => s.calc_hash(),
+            NodeType::Branch(b) => b.calc_hash(hasher),
+            NodeType::Short(s) => s.calc_hash(hasher),
+        }
+    }
+}
+
+impl Child {
+    pub fn ptr(&self) -> NodePtr {
+        match self {
+            Child::Ptr(ptr) => *ptr,
+            Child::Hash(cptr, _) => NodePtr::Clean(*cptr),
         }
     }
 }
@@ -141,12 +213,12 @@ impl Branch {
         Ok(encoder.out().to_vec())
     }
 
-    pub fn calc_hash(&mut self) -> Result<Vec<u8>, Error> {
+    pub fn calc_hash(&mut self, hasher: &dyn Hasher) -> Result<Vec<u8>, Error> {
         let raw = self.rlp_encode()?;
         let out = if raw.len() < HASH_SIZE {
             raw
         } else {
-            let hash = Keccak256::digest(&raw);
+            let hash = hasher.digest(&raw);
             rlp::encode(&hash.as_slice()).to_vec()
         };
         self.hash = out.clone();
@@ -186,12 +258,12 @@ impl Short {
         Ok(s.out().to_vec())
     }
 
-    pub fn calc_hash(&mut self) -> Result<Vec<u8>, Error> {
+    pub fn calc_hash(&mut self, hasher: &dyn Hasher) -> Result<Vec<u8>, Error> {
         let raw = self.rlp_encode()?;
         let out = if raw.len() < HASH_SIZE {
             raw
         } else {
-            let hash = Keccak256::digest(&raw);
+            let hash = hasher.digest(&raw);
             rlp::encode(&hash.as_slice()).to_vec()
         };
         self.hash = out.clone();
@@ -296,8 +368,14 @@ impl Encodable for Branch {
 impl Decodable for Branch {
     fn decode(s: &Rlp) -> Result<Self, DecoderError> {
         let hash = s.list_at(0)?;
-        let children: [Option<Child>; NBRANCH + 1] = s.list_at(1)?.try_into().unwrap_or_default();
-        let aha_len = s.val_at(2)?;
+        let children: Vec<Option<Child>> = s.list_at(1)?;
+        let children: [Option<Child>; NBRANCH + 1] = children
+            .try_into()
+            .map_err(|_| DecoderError::RlpIncorrectListLen)?;
+        let aha_len: u8 = s.val_at(2)?;
+        if aha_len as usize > NBRANCH + 1 {
+            return Err(DecoderError::Custom("aha_len exceeds the branch width"));
+        }
         let aha_ptr = s.val_at(3)?;
         Ok(Self {
             hash,
@@ -341,7 +419,7 @@ impl Decodable for NodeType {
             BRANCH_NODE_TYPE => NodeType::Branch(s.val_at(1)?),
             SHORT_NODE_TYPE => NodeType::Short(s.val_at(1)?),
             VALUE_NODE_TYPE => NodeType::Value(s.val_at(1)?),
-            _ => unreachable!(),
+            _ => return Err(DecoderError::Custom("unknown node type")),
         })
     }
 }
@@ -0,0 +1,88 @@
+use super::{NBRANCH, utils};
+
+use std::fmt;
+
+/// A trie path in nibbles, as short nodes store it: a path that ends at a
+/// value ends with the terminator 16.
+#[derive(Clone, PartialEq, Eq, Hash, Default)]
+pub struct NibblePath(Vec<u8>);
+
+impl NibblePath {
+    /// The terminal path of `key`: two nibbles per byte, high nibble
+    /// first, then the terminator.
+    pub fn from_key(key: &[u8]) -> Self {
+        Self(utils::to_path(key))
+    }
+
+    /// Wrap raw nibbles, which may end with the terminator.
+    pub fn from_nibbles(nibbles: Vec<u8>) -> Self {
+        let len = nibbles.len() - (nibbles.last() == Some(&(NBRANCH as u8))) as usize;
+        assert!(
+            nibbles[..len].iter().all(|n| (*n as usize) < NBRANCH),
+            "nibbles are below 16, apart from a trailing terminator"
+        );
+        Self(nibbles)
+    }
+
+    /// Decode the hex-prefix (compact) encoding used in short nodes' RLP.
+    pub fn from_compact(compact: &[u8]) -> Self {
+        Self(utils::from_compact(compact))
+    }
+
+    pub fn to_compact(&self) -> Vec<u8> {
+        utils::to_compact(&self.0)
+    }
+
+    /// The key bytes the nibbles spell, or `None` for an odd number of
+    /// nibbles, which only a path into the middle of a key has.
+    pub fn to_key(&self) -> Option<Vec<u8>> {
+        let nibbles = self.nibbles();
+        if nibbles.len() % 2 == 1 {
+            return None;
+        }
+        Some(utils::from_nibbles(nibbles).collect())
+    }
+
+    pub fn is_terminal(&self) -> bool {
+        self.0.last() == Some(&(NBRANCH as u8))
+    }
+
+    /// The nibbles without the terminator.
+    pub fn nibbles(&self) -> &[u8] {
+        &self.0[..self.0.len() - self.is_terminal() as usize]
+    }
+
+    /// The path as stored, terminator included.
+    pub fn as_slice(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// Length of the longest common prefix with `other`, in nibbles; the
+    /// terminator only matches a terminator.
+    pub fn common_prefix(&self, other: &NibblePath) -> usize {
+        self.0
+            .iter()
+            .zip(&other.0)
+            .take_while(|(a, b)| a == b)
+            .count()
+    }
+}
+
+/// Lowercase hex nibbles, with `T` for the terminator, e.g. `3a0T`.
+impl fmt::Display for NibblePath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for n in self.nibbles() {
+            write!(f, "{n:x}")?;
+        }
+        if self.is_terminal() {
+            write!(f, "T")?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for NibblePath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "NibblePath({self})")
+    }
+}
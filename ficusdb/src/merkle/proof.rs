@@ -0,0 +1,300 @@
+use super::hasher::Hasher;
+use super::{NBRANCH, utils};
+
+use rlp::{Rlp, RlpStream};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+
+// Reference items of at least this many bytes are hashes; shorter nodes are
+// embedded in their parent.
+const HASH_SIZE: usize = 32;
+
+/// A trie rebuilt from proof nodes and range entries.
+enum PNode {
+    Empty,
+    // reference item of a subtree the proof does not open
+    Ref(Vec<u8>),
+    Short(Vec<u8>, Box<PNode>),
+    Branch(Box<[PNode; NBRANCH + 1]>),
+    Value(Vec<u8>),
+}
+
+fn invalid(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, msg.to_string())
+}
+
+// The value slot sorts before nibbles 0..15, like the key it holds.
+fn pos(nibble: u8) -> usize {
+    if nibble as usize == NBRANCH {
+        0
+    } else {
+        nibble as usize + 1
+    }
+}
+
+/// Where the path segment `seg` lies relative to the bound path `bound`:
+/// `None` if beyond it (left of a left bound, right of a right bound),
+/// `Some(None)` if entirely within it, and `Some(Some(rest))` if `seg`
+/// follows the bound, which continues with `rest`. A missing bound is no
+/// bound at all.
+pub(crate) fn follow<'a>(
+    seg: &[u8],
+    bound: Option<&'a [u8]>,
+    left: bool,
+) -> Option<Option<&'a [u8]>> {
+    let Some(bound) = bound else {
+        return Some(None);
+    };
+    let n = seg.len().min(bound.len());
+    let ord = seg[..n]
+        .iter()
+        .map(|n| pos(*n))
+        .cmp(bound[..n].iter().map(|n| pos(*n)));
+    match (ord, left) {
+        (Ordering::Equal, _) => Some(Some(&bound[n..])),
+        (Ordering::Greater, true) | (Ordering::Less, false) => Some(None),
+        _ => None,
+    }
+}
+
+fn decode_node(raw: &[u8]) -> Result<PNode, Error> {
+    let bad = |_| invalid("proof node is not valid RLP");
+    let rlp = Rlp::new(raw);
+    match rlp.item_count().map_err(bad)? {
+        17 => {
+            let mut children: Box<[PNode; NBRANCH + 1]> =
+                Box::new(std::array::from_fn(|_| PNode::Empty));
+            for (i, child) in children.iter_mut().enumerate() {
+                let item = rlp.at(i).map_err(bad)?;
+                if item.is_empty() {
+                    continue;
+                }
+                *child = if i == NBRANCH {
+                    PNode::Value(item.data().map_err(bad)?.to_vec())
+                } else {
+                    PNode::Ref(item.as_raw().to_vec())
+                };
+            }
+            Ok(PNode::Branch(children))
+        }
+        2 => {
+            let compact = rlp.at(0).and_then(|p| p.data()).map_err(bad)?;
+            if compact.is_empty() {
+                return Err(invalid("short node has an empty path"));
+            }
+            let path = utils::from_compact(compact);
+            let item = rlp.at(1).map_err(bad)?;
+            let child = if path.last() == Some(&(NBRANCH as u8)) {
+                PNode::Value(item.data().map_err(bad)?.to_vec())
+            } else {
+                PNode::Ref(item.as_raw().to_vec())
+            };
+            Ok(PNode::Short(path, Box::new(child)))
+        }
+        _ => Err(invalid("proof node is neither a branch nor a short node")),
+    }
+}
+
+/// The node behind a reference item: embedded in it, or found in the proof
+/// by hash.
+fn open(reference: &[u8], proof: &HashMap<Vec<u8>, &[u8]>) -> Result<PNode, Error> {
+    let rlp = Rlp::new(reference);
+    if rlp.is_list() {
+        return decode_node(reference);
+    }
+    let hash = rlp
+        .data()
+        .map_err(|_| invalid("child reference is not valid RLP"))?;
+    match proof.get(hash) {
+        Some(raw) => decode_node(raw),
+        None => Err(invalid("proof is missing a node on a boundary path")),
+    }
+}
+
+/// Open the nodes along the two bound paths and empty every subtree
+/// between them, including the bound keys themselves.
+fn prune(
+    node: &mut PNode,
+    left: Option<&[u8]>,
+    right: Option<&[u8]>,
+    proof: &HashMap<Vec<u8>, &[u8]>,
+) -> Result<(), Error> {
+    if left.is_none() && right.is_none() {
+        *node = PNode::Empty;
+        return Ok(());
+    }
+    if let PNode::Ref(reference) = node {
+        *node = open(reference, proof)?;
+    }
+    match node {
+        PNode::Empty => {}
+        PNode::Ref(_) => unreachable!("opened above"),
+        // only reached at the end of a bound path, i.e. at a bound key
+        PNode::Value(_) => *node = PNode::Empty,
+        PNode::Short(path, child) => {
+            let (Some(left), Some(right)) = (follow(path, left, true), follow(path, right, false))
+            else {
+                return Ok(());
+            };
+            prune(child, left, right, proof)?;
+            if matches!(**child, PNode::Empty) {
+                *node = PNode::Empty;
+            }
+        }
+        PNode::Branch(children) => {
+            for (i, child) in children.iter_mut().enumerate() {
+                let seg = [i as u8];
+                if let (Some(left), Some(right)) =
+                    (follow(&seg, left, true), follow(&seg, right, false))
+                {
+                    prune(child, left, right, proof)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn leaf(path: &[u8], value: Vec<u8>) -> PNode {
+    if path.is_empty() {
+        PNode::Value(value)
+    } else {
+        PNode::Short(path.to_vec(), Box::new(PNode::Value(value)))
+    }
+}
+
+fn insert(node: &mut PNode, path: &[u8], value: Vec<u8>) -> Result<(), Error> {
+    let split = match node {
+        PNode::Empty => {
+            *node = leaf(path, value);
+            return Ok(());
+        }
+        PNode::Value(old) if path.is_empty() => {
+            *old = value;
+            return Ok(());
+        }
+        PNode::Branch(children) => {
+            return insert(&mut children[path[0] as usize], &path[1..], value);
+        }
+        PNode::Short(short, child) => {
+            let common = short.iter().zip(path).take_while(|(a, b)| a == b).count();
+            if common == short.len() {
+                return insert(child, &path[common..], value);
+            }
+            let mut children: Box<[PNode; NBRANCH + 1]> =
+                Box::new(std::array::from_fn(|_| PNode::Empty));
+            let old = std::mem::replace(&mut **child, PNode::Empty);
+            children[short[common] as usize] = if common + 1 == short.len() {
+                old
+            } else {
+                PNode::Short(short[common + 1..].to_vec(), Box::new(old))
+            };
+            children[path[common] as usize] = leaf(&path[common + 1..], value);
+            let branch = PNode::Branch(children);
+            if common == 0 {
+                branch
+            } else {
+                PNode::Short(short[..common].to_vec(), Box::new(branch))
+            }
+        }
+        _ => return Err(invalid("an entry lies outside the proven range")),
+    };
+    *node = split;
+    Ok(())
+}
+
+/// Canonical RLP of a branch or short node.
+fn encode(node: &PNode, hasher: &dyn Hasher) -> Vec<u8> {
+    match node {
+        PNode::Short(path, child) => {
+            let mut s = RlpStream::new_list(2);
+            s.append(&utils::to_compact(path));
+            s.append_raw(&reference(child, hasher), 1);
+            s.out().to_vec()
+        }
+        PNode::Branch(children) => {
+            let mut s = RlpStream::new_list(NBRANCH + 1);
+            for child in children.iter() {
+                match child {
+                    PNode::Empty => s.append_empty_data(),
+                    child => s.append_raw(&reference(child, hasher), 1),
+                };
+            }
+            s.out().to_vec()
+        }
+        _ => unreachable!("only branch and short nodes are encoded"),
+    }
+}
+
+fn reference(node: &PNode, hasher: &dyn Hasher) -> Vec<u8> {
+    match node {
+        PNode::Empty => rlp::NULL_RLP.to_vec(),
+        PNode::Ref(reference) => reference.clone(),
+        PNode::Value(value) => rlp::encode(value).to_vec(),
+        node => {
+            let raw = encode(node, hasher);
+            if raw.len() < HASH_SIZE {
+                raw
+            } else {
+                rlp::encode(&hasher.digest(&raw).as_slice()).to_vec()
+            }
+        }
+    }
+}
+
+/// Check that `entries` are all the key-value pairs of the trie with root
+/// hash `root_hash` whose keys lie in `[start, last]`, where `last` is the
+/// last entry's key, or `end` if there are no entries; see
+/// `Merkle::range_proof`.
+///
+/// The trie is rebuilt from the nodes of `proof` on the paths of `start`
+/// and `last`, with everything between the two paths replaced by
+/// `entries`. It hashes to `root_hash` only if no key in the range was left
+/// out, added or changed.
+pub fn verify_range_proof(
+    hasher: &dyn Hasher,
+    root_hash: &[u8],
+    start: &[u8],
+    end: &[u8],
+    entries: &[(Vec<u8>, Vec<u8>)],
+    proof: &[Vec<u8>],
+) -> Result<(), Error> {
+    if start > end {
+        return Err(invalid("range starts after it ends"));
+    }
+    if entries.windows(2).any(|pair| pair[0].0 >= pair[1].0) {
+        return Err(invalid("entries are not in ascending key order"));
+    }
+    if let (Some((first, _)), Some((last, _))) = (entries.first(), entries.last())
+        && (first.as_slice() < start || last.as_slice() > end)
+    {
+        return Err(invalid("an entry lies outside the requested range"));
+    }
+    let proof: HashMap<Vec<u8>, &[u8]> = proof
+        .iter()
+        .map(|raw| (hasher.digest(raw), raw.as_slice()))
+        .collect();
+    let mut root = match proof.get(root_hash) {
+        Some(raw) => decode_node(raw)?,
+        None if root_hash == hasher.empty_node_hash() => PNode::Empty,
+        None => return Err(invalid("proof is missing the root node")),
+    };
+
+    let left = utils::to_path(start);
+    let right = utils::to_path(entries.last().map_or(end, |(key, _)| key));
+    prune(&mut root, Some(&left), Some(&right), &proof)?;
+    for (key, value) in entries {
+        insert(&mut root, &utils::to_path(key), value.clone())?;
+    }
+
+    let hash = match root {
+        PNode::Empty => hasher.empty_node_hash(),
+        PNode::Short(..) | PNode::Branch(_) => hasher.digest(&encode(&root, hasher)),
+        _ => return Err(invalid("rebuilt root is not a node")),
+    };
+    if hash != root_hash {
+        return Err(invalid("range does not match the root hash"));
+    }
+    Ok(())
+}
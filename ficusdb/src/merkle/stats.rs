@@ -72,6 +72,7 @@ pub struct AHAStats {
     pub reused: usize,
     pub new: usize,
     pub recycled: usize,
+    pub overflow: usize,
     pub t_write: f64,
 }
 
@@ -81,18 +82,20 @@ impl AHAStats {
             reused: 0,
             new: 0,
             recycled: 0,
+            overflow: 0,
             t_write: 0.0,
         }
     }
 
     pub fn print_stats(&mut self) {
-        println!("aha:\treused\tnew\trecycled\tt_write");
-        println!("\t{}\t{}\t{}\t{:.2}", self.reused, self.new, self.recycled, self.t_write);
+        println!("aha:\treused\tnew\trecycled\toverflow\tt_write");
+        println!("\t{}\t{}\t{}\t{}\t{:.2}", self.reused, self.new, self.recycled, self.overflow, self.t_write);
     }
     pub fn reset(&mut self) {
         self.reused = 0;
         self.new = 0;
         self.recycled = 0;
+        self.overflow = 0;
         self.t_write = 0.0;
     }
 }
@@ -2,25 +2,126 @@
 
 use super::aha::AggregatedHashArray;
 use super::backend::Backend;
-use super::node::{Child, Node, NodePtr, NodeType};
+use super::cache::ShardedCache;
+use super::hasher::Hasher;
+use super::node::{Child, ChildView, Node, NodePtr, NodeType, NodeView};
+use super::path::NibblePath;
+use super::utils::{self, MAX_VARINT_LEN};
 use super::{CleanPtr, DirtyPtr, NBRANCH};
+use crate::backend::SyncMode;
+use crate::metrics::Metrics;
 
 #[cfg(feature = "stats")]
 use super::stats::StoreStats;
-use lru_mem::LruCache;
 use std::io::{Error, ErrorKind};
-use std::mem::size_of;
+use std::sync::{Arc, Mutex};
 #[cfg(feature = "stats")]
 use std::time::Instant;
 
-type EncodedLen = u16;
+/// What copy-on-write and lookups do with the clean-node cache.
+///
+/// `Take` moves a node out of the cache when it is copied for writing, so
+/// no clone is made, and lookups touch the nodes on their path again so
+/// they stay recently used. That saves clones but churns the cache: the
+/// old node is gone until it is read again. `Clone` copies the cached node
+/// and leaves it in place, which keeps the cache warm for readers at the
+/// cost of one clone per modified node. The default is `Take`, or `Clone`
+/// with the `lru` feature.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CachePolicy {
+    Take,
+    Clone,
+}
+
+impl Default for CachePolicy {
+    fn default() -> Self {
+        if cfg!(feature = "lru") {
+            CachePolicy::Clone
+        } else {
+            CachePolicy::Take
+        }
+    }
+}
+
+// A node file starts with `NODE_MAGIC` and the format version as a
+// little-endian u32, zero-padded to `NODE_HEADER_LEN` bytes. Nodes are
+// appended after it, so no node lives at `CleanPtr` 0, the empty root.
+// Version 2 stores AHA slot indices rather than byte offsets in branches.
+const NODE_MAGIC: &[u8; 8] = b"FICUSNOD";
+const NODE_FORMAT_VERSION: u32 = 2;
+const NODE_HEADER_LEN: usize = 16;
+
+/// Write the format header to an empty node backend, or check the one it
+/// starts with. Fails for files of another format version and for files
+/// without a header, e.g. ones written before versioning or read with
+/// different compression settings.
+pub fn check_node_header(backend: &mut dyn Backend) -> Result<(), NodeHeaderError> {
+    if backend.tail() == 0 {
+        let mut header = NODE_MAGIC.to_vec();
+        header.extend(NODE_FORMAT_VERSION.to_le_bytes());
+        header.resize(NODE_HEADER_LEN, 0);
+        backend.write(0, &header);
+        backend.flush();
+        return Ok(());
+    }
+    if backend.tail() < NODE_HEADER_LEN as CleanPtr {
+        return Err(NodeHeaderError::Torn);
+    }
+    let header = backend.read(0, NODE_HEADER_LEN);
+    if header[..NODE_MAGIC.len()] != NODE_MAGIC[..] {
+        return Err(NodeHeaderError::Missing);
+    }
+    let version = u32::from_le_bytes(header[8..12].try_into().unwrap());
+    if version != NODE_FORMAT_VERSION {
+        return Err(NodeHeaderError::Version {
+            found: version,
+            expected: NODE_FORMAT_VERSION,
+        });
+    }
+    Ok(())
+}
+
+/// Why `check_node_header` rejected a node file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NodeHeaderError {
+    Torn,
+    Missing,
+    Version { found: u32, expected: u32 },
+}
+
+impl std::fmt::Display for NodeHeaderError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NodeHeaderError::Torn => write!(f, "node file header is torn"),
+            NodeHeaderError::Missing => write!(f, "not a node file: no format header"),
+            NodeHeaderError::Version { found, expected } => write!(
+                f,
+                "node file format version {found} is not supported (expected {expected})"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for NodeHeaderError {}
 
 pub struct NodeStore {
+    // One arena shared by every trie over this store. Tries never share
+    // slots (`Merkle::fork` copies), so the arena is only reset once no trie
+    // holds dirty nodes; `dirty_tries` counts the ones that do.
     dirty: Vec<Option<Node>>,
-    clean: LruCache<CleanPtr, Node>,
+    dirty_tries: usize,
+    // Vacated slots that `add_dirty` hands out again; see `free_dirty`.
+    free: Vec<DirtyPtr>,
+    reader: NodeReader,
+    // values longer than this go to the blob store, if there is one
+    inline_threshold: usize,
 
-    backend: Box<dyn Backend>,
     aha: Option<AggregatedHashArray>,
+    // fail, rather than fall back, when an AHA array does not validate
+    strict_aha: bool,
+    // write each commit's nodes in an order set by their hashes
+    deterministic_layout: bool,
+    hasher: Arc<dyn Hasher>,
     #[cfg(feature = "stats")]
     stats: StoreStats,
 }
@@ -30,87 +131,222 @@ impl NodeStore {
         backend: Box<dyn Backend>,
         cache_size: usize,
         aha: Option<AggregatedHashArray>,
+        hasher: Arc<dyn Hasher>,
     ) -> Self {
         Self {
             dirty: Vec::new(),
-            clean: LruCache::new(cache_size),
-            backend,
+            dirty_tries: 0,
+            free: Vec::new(),
+            reader: NodeReader {
+                cache: Arc::new(ShardedCache::new(cache_size)),
+                backend: Arc::new(Mutex::new(backend)),
+                blobs: None,
+                metrics: None,
+                policy: CachePolicy::default(),
+            },
+            inline_threshold: usize::MAX,
             aha,
+            strict_aha: false,
+            deterministic_layout: false,
+            hasher,
             #[cfg(feature = "stats")]
             stats: StoreStats::new(),
         }
     }
 
-    // ===== store =====
-    fn get_node(&mut self, ptr: CleanPtr) -> Result<Node, Error> {
-        let len_buf = self.backend.read(ptr, size_of::<EncodedLen>());
-        if len_buf.len() != size_of::<EncodedLen>() {
-            return Err(Error::new(ErrorKind::Other, "Invalid encoded length"));
+    pub fn hasher(&self) -> Arc<dyn Hasher> {
+        self.hasher.clone()
+    }
+
+    /// Set before handing out readers; readers taken earlier keep the old
+    /// metrics.
+    pub fn set_metrics(&mut self, metrics: Option<Arc<dyn Metrics>>) {
+        self.reader.metrics = metrics;
+    }
+
+    pub fn metrics(&self) -> Option<&dyn Metrics> {
+        self.reader.metrics.as_deref()
+    }
+
+    /// Set before handing out readers, like `set_metrics`.
+    pub fn set_cache_policy(&mut self, policy: CachePolicy) {
+        self.reader.policy = policy;
+    }
+
+    /// Make an AHA array that does not match its branch an error for
+    /// `try_cow_clean` instead of a fallback to reading the children.
+    /// Either way the mismatch is reported to the metrics hook.
+    ///
+    /// Strict mode also stops recycling AHA slots: a recycled slot holds
+    /// another branch's array, which would fail every older root that
+    /// still refers to it, although nothing is damaged.
+    pub fn set_strict_aha(&mut self, strict: bool) {
+        self.strict_aha = strict;
+        if let Some(aha) = &mut self.aha {
+            aha.set_recycle(!strict);
         }
-        let len = u16::from_le_bytes(len_buf.try_into().unwrap());
-        let data = self
-            .backend
-            .read(ptr + size_of::<EncodedLen>() as CleanPtr, len as usize);
-        Node::decode(&data)
+    }
+
+    /// Make `Merkle::finish_commit` write the nodes of each depth sorted by
+    /// reference item instead of in slot order; see
+    /// `DBConfig::deterministic_layout`.
+    pub fn set_deterministic_layout(&mut self, deterministic: bool) {
+        self.deterministic_layout = deterministic;
+    }
+
+    pub fn deterministic_layout(&self) -> bool {
+        self.deterministic_layout
+    }
+
+    /// Memory held by the clean-node cache, in bytes.
+    pub fn node_cache_bytes(&self) -> usize {
+        self.reader.cache.current_size()
+    }
+
+    /// Memory held by the page caches of the node and blob files.
+    pub fn page_cache_bytes(&self) -> usize {
+        let blobs = self.reader.blobs.as_ref();
+        self.reader.backend.lock().unwrap().cached_bytes()
+            + blobs.map_or(0, |blobs| blobs.lock().unwrap().cached_bytes())
+    }
+
+    /// Keep values longer than `inline_threshold` bytes in `blobs`, with only
+    /// a reference in their value node, and resolve such references on
+    /// read. Hashes still cover the values themselves, so roots do not
+    /// depend on where values are kept. A store whose node file holds
+    /// references must be given its blob store again when reopened; pass
+    /// `usize::MAX` to only read existing blobs. Set before handing out
+    /// readers, like `set_metrics`.
+    pub fn set_blob_store(&mut self, blobs: Box<dyn Backend>, inline_threshold: usize) {
+        self.reader.blobs = Some(Arc::new(Mutex::new(blobs)));
+        self.inline_threshold = inline_threshold;
+    }
+
+    /// A handle for reading committed nodes without locking this store.
+    pub fn reader(&self) -> NodeReader {
+        self.reader.clone()
+    }
+
+    pub fn get_node(&mut self, ptr: CleanPtr) -> Result<Node, Error> {
+        self.reader.get_node(ptr)
     }
 
     pub fn add_node(&mut self, node: Node) -> CleanPtr {
         #[cfg(feature = "stats")]   
         let encode_timer = Instant::now();
-        let encoded = node.encode();
+        let encoded = match (node.get_inner(), &self.reader.blobs) {
+            (NodeType::Value(vnode), Some(blobs)) if vnode.value.len() > self.inline_threshold => {
+                let mut blobs = blobs.lock().unwrap();
+                let blob_ptr = blobs.tail();
+                blobs.write(blob_ptr, &vnode.value);
+                Node::encode_blob_value(blob_ptr, vnode.value.len(), &vnode.extra)
+            }
+            _ => node.encode(),
+        };
         #[cfg(feature = "stats")] {
             self.stats.t_encode += encode_timer.elapsed().as_secs_f64();
         }
-        let mut buf = (encoded.len() as EncodedLen).to_le_bytes().to_vec();
+        let mut buf = Vec::with_capacity(MAX_VARINT_LEN + encoded.len());
+        utils::encode_varint(encoded.len() as u64, &mut buf);
         buf.extend(encoded);
-        let cptr = self.backend.tail();
-        self.backend.write(cptr, &buf);
-        let _ = self.clean.insert(cptr, node);
+        let mut backend = self.reader.backend.lock().unwrap();
+        let cptr = backend.tail();
+        backend.write(cptr, &buf);
+        drop(backend);
+        if let Some(m) = &self.reader.metrics {
+            m.on_node_write(buf.len());
+        }
+        self.reader.cache.insert(cptr, Arc::new(node));
         cptr
     }
 
     // ===== cache =====
-    pub fn get_clean(&mut self, cptr: CleanPtr) -> &Node {
-        if !self.clean.contains(&cptr) {
-            #[cfg(feature = "stats")]
-            let load_timer = Instant::now();
-            let node = self.get_node(cptr).unwrap();
-            let _ = self.clean.insert(cptr, node);
-            #[cfg(feature = "stats")]
-            {
-                self.stats.node_miss += 1;
-                self.stats.node_load += load_timer.elapsed().as_secs_f64();
-            }
+    pub fn get_clean(&mut self, cptr: CleanPtr) -> Arc<Node> {
+        self.try_get_clean(cptr).unwrap()
+    }
+
+    /// Like `get_clean`, but returns an error for an unreadable node.
+    pub fn try_get_clean(&mut self, cptr: CleanPtr) -> Result<Arc<Node>, Error> {
+        #[cfg(feature = "stats")]
+        let load_timer = Instant::now();
+        let (node, _hit) = self.reader.load(cptr)?;
+        #[cfg(feature = "stats")]
+        if _hit {
+            self.stats.node_hit += 1;
         } else {
-            #[cfg(feature = "stats")]
-            {
-                self.stats.node_hit += 1;
-            }
+            self.stats.node_miss += 1;
+            self.stats.node_load += load_timer.elapsed().as_secs_f64();
         }
-        self.clean.get(&cptr).unwrap()
+        Ok(node)
+    }
+
+    /// Describe the committed node at `cptr`. `None` if the node or one of
+    /// its children cannot be read. A node file holds its format header at
+    /// offset 0, so no node lives there: 0 is the empty root, and inspecting
+    /// it gives `None`.
+    pub fn inspect_node(&mut self, cptr: CleanPtr) -> Option<NodeView> {
+        let node = self.try_get_clean(cptr).ok()?;
+        let mut view_child = |child: &Child| {
+            let NodePtr::Clean(ptr) = child.ptr() else {
+                unreachable!("committed nodes only have clean children");
+            };
+            let hash = match child {
+                Child::Hash(_, hash) => hash.clone(),
+                Child::Ptr(_) => self.try_get_clean(ptr).ok()?.hash(),
+            };
+            Some(ChildView { ptr, hash })
+        };
+        Some(match node.get_inner() {
+            NodeType::Branch(bnode) => NodeView::Branch {
+                children: bnode
+                    .children
+                    .iter()
+                    .map(|child| match child {
+                        Some(child) => view_child(child).map(Some),
+                        None => Some(None),
+                    })
+                    .collect::<Option<_>>()?,
+            },
+            NodeType::Short(snode) => NodeView::Short {
+                path: NibblePath::from_nibbles(snode.path.clone()),
+                child: view_child(&snode.child)?,
+            },
+            NodeType::Value(vnode) => NodeView::Value {
+                value: vnode.value.clone(),
+                extra: vnode.extra.clone(),
+            },
+        })
     }
 
     pub fn take_clean(&mut self, cptr: CleanPtr) -> Node {
-        match self.clean.remove(&cptr) {
+        self.try_take_clean(cptr).unwrap()
+    }
+
+    /// Like `take_clean`, but returns an error for an unreadable node.
+    pub fn try_take_clean(&mut self, cptr: CleanPtr) -> Result<Node, Error> {
+        Ok(match self.reader.cache.remove(cptr) {
             Some(node) => {
                 #[cfg(feature = "stats")]
                 {
                     self.stats.node_hit += 1;
                 }
-                node
+                self.reader.count_cache(true);
+                // a reader may still hold it
+                Arc::unwrap_or_clone(node)
             }
             None => {
                 #[cfg(feature = "stats")]
                 let load_timer = Instant::now();
-                let node = self.get_node(cptr).unwrap();
+                let node = self.get_node(cptr)?;
                 #[cfg(feature = "stats")]
                 {
                     self.stats.node_miss += 1;
                     self.stats.node_load += load_timer.elapsed().as_secs_f64();
                 }
+                self.reader.count_cache(false);
                 node
             }
-        }
+        })
     }
 
     pub fn get_dirty(&mut self, dptr: DirtyPtr) -> Option<&Node> {
@@ -125,29 +361,97 @@ impl NodeStore {
     }
 
     pub fn add_dirty(&mut self, n: Option<Node>) -> DirtyPtr {
+        if let Some(dptr) = self.free.pop() {
+            self.dirty[dptr] = n;
+            return dptr;
+        }
         self.dirty.push(n);
         self.dirty.len() - 1
     }
 
+    /// Vacate `dptr` so a later `add_dirty` can reuse it.
+    ///
+    /// Only call this for a slot that nothing points to anymore. Dirty nodes
+    /// form a tree per trie, since `Merkle::fork` copies instead of sharing,
+    /// so every slot has exactly one referent: its parent's child pointer or
+    /// the trie's root. Once that pointer is overwritten, as when `insert`
+    /// replaces a dirty value node, the slot is dead and safe to hand out.
+    pub fn free_dirty(&mut self, dptr: DirtyPtr) {
+        self.dirty[dptr] = None;
+        self.free.push(dptr);
+    }
+
     pub fn put_dirty(&mut self, dptr: DirtyPtr, n: Option<Node>) {
         self.dirty[dptr] = n;
     }
 
     pub fn cow_clean(&mut self, cptr: CleanPtr) -> DirtyPtr {
-        #[cfg(not(feature = "lru"))] 
-        let mut node = self.take_clean(cptr);
-        #[cfg(feature = "lru")]
-        let mut node = self.get_clean(cptr).clone();
-        self.load_aha(&mut node);
-        self.add_dirty(Some(node))
+        self.try_cow_clean(cptr).unwrap()
     }
 
-    pub fn commit(&mut self) {
-        #[cfg(feature = "stats")]
-        let timer = Instant::now();
+    /// Like `cow_clean`, but returns an error, adding nothing, for an
+    /// unreadable node.
+    pub fn try_cow_clean(&mut self, cptr: CleanPtr) -> Result<DirtyPtr, Error> {
+        let mut node = match self.reader.policy {
+            CachePolicy::Take => self.try_take_clean(cptr)?,
+            CachePolicy::Clone => Node::clone(&*self.try_get_clean(cptr)?),
+        };
+        self.load_aha(&mut node)?;
+        Ok(self.add_dirty(Some(node)))
+    }
+
+    /// Mark the current end of the dirty arena, for a later `rollback`.
+    pub fn checkpoint(&self) -> usize {
+        self.dirty.len()
+    }
+
+    /// Drop every dirty node added since `checkpoint`. Any `DirtyPtr` at or
+    /// above the mark, including a root or a child pointer into that range,
+    /// is invalid afterwards and must not be used again. Nodes below the mark
+    /// that were changed in place, or added into reused slots, are not
+    /// restored.
+    pub fn rollback(&mut self, checkpoint: usize) {
+        self.dirty.truncate(checkpoint);
+        self.free.retain(|dptr| *dptr < checkpoint);
+    }
+
+    /// A trie now holds dirty nodes in the arena.
+    pub fn acquire_dirty(&mut self) {
+        self.dirty_tries += 1;
+    }
+
+    /// Number of occupied slots in the dirty arena.
+    pub fn dirty_count(&self) -> usize {
+        self.dirty.iter().filter(|n| n.is_some()).count()
+    }
+
+    /// Number of tries currently holding dirty nodes.
+    pub fn dirty_tries(&self) -> usize {
+        self.dirty_tries
+    }
+
+    /// A trie no longer holds dirty nodes, after committing or being dropped.
+    pub fn release_dirty(&mut self) {
+        self.dirty_tries -= 1;
+    }
+
+    /// Empty the dirty arena and its free list, once no trie holds dirty
+    /// nodes, and give back half the arena's capacity.
+    pub fn clear_dirty(&mut self) {
+        debug_assert!(self.dirty_tries == 0);
         self.dirty.clear();
+        self.free.clear();
         let cap = self.dirty.capacity();
         self.dirty.shrink_to(cap / 2);
+    }
+
+    pub fn commit(&mut self) {
+        #[cfg(feature = "stats")]
+        let timer = Instant::now();
+        // Slots of other dirty tries must stay where they are.
+        if self.dirty_tries == 0 {
+            self.clear_dirty();
+        }
         if let Some(aha) = &mut self.aha {
             aha.commit();
         }
@@ -161,7 +465,25 @@ impl NodeStore {
         if let Some(aha) = &mut self.aha {
             aha.flush();
         }
-        self.backend.flush();
+        // before the nodes that reference them
+        if let Some(blobs) = &self.reader.blobs {
+            blobs.lock().unwrap().flush();
+        }
+        self.reader.backend.lock().unwrap().flush();
+    }
+
+    /// Sync flushed node and AHA bytes; see `SyncMode`.
+    pub fn sync(&mut self, mode: SyncMode) -> std::io::Result<()> {
+        if mode == SyncMode::None {
+            return Ok(());
+        }
+        if let Some(aha) = &mut self.aha {
+            aha.sync(mode)?;
+        }
+        if let Some(blobs) = &self.reader.blobs {
+            blobs.lock().unwrap().sync(mode)?;
+        }
+        self.reader.backend.lock().unwrap().sync(mode)
     }
 
     // ===== node operations =====
@@ -193,7 +515,11 @@ impl NodeStore {
         }
     }
 
-    pub fn load_aha(&mut self, node: &mut Node) {
+    /// Fill in the child hashes of a branch from its AHA array, after
+    /// checking that they reproduce the branch hash. Children the array
+    /// cannot vouch for are left to `load_children_hash`; in strict mode
+    /// that is an error.
+    pub fn load_aha(&mut self, node: &mut Node) -> Result<(), Error> {
         #[cfg(feature = "stats")]
         let timer = Instant::now();
         if let Some(aha) = &mut self.aha {
@@ -204,36 +530,48 @@ impl NodeStore {
                     .filter(|c| matches!(c, None | Some(Child::Ptr(NodePtr::Clean(_)))))
                     .count();
                 if bnode.aha_len > 0 && cnt_needed > 0 {
-                    let mut hashs = aha.read_aha(bnode.aha_len, bnode.aha_ptr);
-                    assert!(hashs.len() == bnode.aha_len as usize);
-                    let mut validate_bnode = bnode.clone();
+                    // An unreadable array is treated like a failed validation.
+                    if let Ok(mut hashs) = aha.read_aha(bnode.aha_len, bnode.aha_ptr) {
+                        assert!(hashs.len() == bnode.aha_len as usize);
+                        let mut validate_bnode = bnode.clone();
 
-                    for i in 0..NBRANCH + 1 {
-                        if let Some(Child::Ptr(NodePtr::Clean(cptr))) = &validate_bnode.children[i]
-                        {
-                            let h = hashs.remove(0);
-                            validate_bnode.children[i] = Some(Child::Hash(*cptr, h));
-                        } else if let Some(Child::Hash(_, _)) = &validate_bnode.children[i] {
-                            //panic!("child is already loaded");
-                            let _ = hashs.remove(0);
+                        for i in 0..NBRANCH + 1 {
+                            if let Some(Child::Ptr(NodePtr::Clean(cptr))) =
+                                &validate_bnode.children[i]
+                            {
+                                let h = hashs.remove(0);
+                                validate_bnode.children[i] = Some(Child::Hash(*cptr, h));
+                            } else if let Some(Child::Hash(_, _)) = &validate_bnode.children[i] {
+                                //panic!("child is already loaded");
+                                let _ = hashs.remove(0);
+                            }
                         }
-                    }
-                    assert!(hashs.is_empty());
-                    // validate the children hashes are valid
-                    if bnode.hash == validate_bnode.calc_hash().unwrap() {
-                        bnode.children = validate_bnode.children.clone();
-                        #[cfg(feature = "stats")]
-                        {
-                            self.stats.aha_hit += 1;
-                            self.stats.t_hash_load += timer.elapsed().as_secs_f64();
+                        assert!(hashs.is_empty());
+                        // validate the children hashes are valid
+                        if bnode.hash == validate_bnode.calc_hash(self.hasher.as_ref()).unwrap() {
+                            bnode.children = validate_bnode.children.clone();
+                            #[cfg(feature = "stats")]
+                            {
+                                self.stats.aha_hit += 1;
+                                self.stats.t_hash_load += timer.elapsed().as_secs_f64();
+                            }
+                            return Ok(());
                         }
-                        return;
                     }
                     // if validation failed, fallback to load children hash from backend
                     #[cfg(feature = "stats")]
                     {
                         self.stats.aha_miss += 1;
                     }
+                    if let Some(m) = &self.reader.metrics {
+                        m.on_aha_mismatch();
+                    }
+                    if self.strict_aha {
+                        return Err(Error::new(
+                            ErrorKind::InvalidData,
+                            "AHA array does not match its branch",
+                        ));
+                    }
                 }
             }
         }
@@ -241,6 +579,7 @@ impl NodeStore {
         {
             self.stats.t_hash_load += timer.elapsed().as_secs_f64();
         }
+        Ok(())
     }
 
     pub fn write_aha(&mut self, node: &mut Node) {
@@ -256,10 +595,20 @@ impl NodeStore {
                 }
                 let old_len = bnode.aha_len;
                 let old_ptr = bnode.aha_ptr;
-                bnode.aha_len = hashs.len() as u8;
+                let len = hashs.len() as u8;
                 #[cfg(feature = "stats")]
                 let write_timer = Instant::now();
-                bnode.aha_ptr = aha.write_aha(hashs, old_len, old_ptr);
+                (bnode.aha_len, bnode.aha_ptr) = match aha.write_aha(hashs, old_len, old_ptr) {
+                    Some(ptr) => (len, ptr),
+                    None => {
+                        if len > 0
+                            && let Some(m) = &self.reader.metrics
+                        {
+                            m.on_aha_overflow();
+                        }
+                        (0, 0)
+                    }
+                };
                 #[cfg(feature = "stats")]
                 {
                     self.stats.t_aha_write += write_timer.elapsed().as_secs_f64();
@@ -270,13 +619,112 @@ impl NodeStore {
 
     #[cfg(feature = "stats")]
     pub fn print_stats(&mut self) {
-        self.stats.cache_size = self.clean.current_size();
+        self.stats.cache_size = self.reader.cache.current_size();
         self.stats.print_stats();
         self.stats.reset();
         if let Some(aha) = &mut self.aha {
             aha.print_stats();
         }
         println!("[store backend]");
-        self.backend.print_stats();
+        self.reader.backend.lock().unwrap().print_stats();
+    }
+}
+
+/// Read path to committed nodes, shared by a `NodeStore` and the tries over
+/// it.
+///
+/// Committed nodes are immutable, so lookups of clean nodes need not take
+/// the store's lock, which also guards the dirty arena. A cache hit locks
+/// only its cache shard. A miss also locks the backend: `Backend::read`
+/// takes `&mut self` because page caches and decoders update on every read,
+/// so an `RwLock` around it would still admit one reader at a time. Misses
+/// therefore stay serialized, and hot working sets that fit in the cache are
+/// what scale with threads.
+#[derive(Clone)]
+pub struct NodeReader {
+    cache: Arc<ShardedCache>,
+    backend: Arc<Mutex<Box<dyn Backend>>>,
+    blobs: Option<Arc<Mutex<Box<dyn Backend>>>>,
+    metrics: Option<Arc<dyn Metrics>>,
+    policy: CachePolicy,
+}
+
+impl NodeReader {
+    pub fn cache_policy(&self) -> CachePolicy {
+        self.policy
+    }
+
+    fn count_cache(&self, hit: bool) {
+        if let Some(m) = &self.metrics {
+            if hit {
+                m.on_cache_hit()
+            } else {
+                m.on_cache_miss()
+            }
+        }
+    }
+
+    // ===== store =====
+    // A stored node is a LEB128 varint length followed by the encoded node.
+
+    pub fn get_node(&self, ptr: CleanPtr) -> Result<Node, Error> {
+        let mut backend = self.backend.lock().unwrap();
+        // Read the length prefix first: the encoded node length and the size
+        // of the prefix itself.
+        let avail = backend.tail().saturating_sub(ptr);
+        let len_buf = backend.try_read(ptr, (MAX_VARINT_LEN as CleanPtr).min(avail) as usize)?;
+        let (len, prefix_len) = match utils::decode_varint(&len_buf) {
+            Some((len, prefix_len)) => (len as usize, prefix_len),
+            None => return Err(Error::new(ErrorKind::Other, "Invalid encoded length")),
+        };
+        // A length past the end of the store means a torn or corrupt node;
+        // don't try to read (and allocate) it.
+        let body_avail = avail - prefix_len as CleanPtr;
+        if len as u64 > body_avail {
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                format!("Truncated node: {len} bytes encoded, {body_avail} in store"),
+            ));
+        }
+        let data = backend.try_read(ptr + prefix_len as CleanPtr, len)?;
+        drop(backend);
+        if data.len() != len {
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                format!("Truncated node: {len} bytes encoded, {} read", data.len()),
+            ));
+        }
+        if let Some(m) = &self.metrics {
+            m.on_node_read(prefix_len + data.len());
+        }
+        Node::decode_with_blobs(&data, |blob_ptr, len| match &self.blobs {
+            Some(blobs) => Ok(blobs.lock().unwrap().read(blob_ptr, len)),
+            None => Err(Error::new(
+                ErrorKind::InvalidData,
+                "value is in a blob store, but none is set",
+            )),
+        })
+    }
+
+    /// The node at `cptr`, from the cache or else the backend, and whether it
+    /// was a cache hit.
+    fn load(&self, cptr: CleanPtr) -> Result<(Arc<Node>, bool), Error> {
+        if let Some(node) = self.cache.get(cptr) {
+            self.count_cache(true);
+            return Ok((node, true));
+        }
+        let node = Arc::new(self.get_node(cptr)?);
+        self.cache.insert(cptr, node.clone());
+        self.count_cache(false);
+        Ok((node, false))
+    }
+
+    pub fn get_clean(&self, cptr: CleanPtr) -> Arc<Node> {
+        self.try_get_clean(cptr).unwrap()
+    }
+
+    /// Like `get_clean`, but returns an error for an unreadable node.
+    pub fn try_get_clean(&self, cptr: CleanPtr) -> Result<Arc<Node>, Error> {
+        Ok(self.load(cptr)?.0)
     }
 }
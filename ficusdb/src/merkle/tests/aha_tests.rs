@@ -1,8 +1,11 @@
-use super::memstore::MemStore;
 use crate::merkle::aha::AggregatedHashArray;
 use crate::merkle::backend::Backend;
-use crate::merkle::node::{Branch, Child, Node, NodePtr, NodeType};
+use crate::merkle::hasher::Keccak256Hasher;
+use crate::merkle::memstore::MemStore;
+use crate::merkle::merkle::Merkle;
+use crate::merkle::node::{Branch, Child, Node, NodePtr, NodeType, Value};
 use crate::merkle::store::NodeStore;
+use crate::metrics::Metrics;
 
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
@@ -44,11 +47,14 @@ fn aha_selects_backend_by_array_len() {
     let b1 = Arc::new(Mutex::new(MemStore::new()));
     let b2 = Arc::new(Mutex::new(MemStore::new()));
 
-    let mut aha = AggregatedHashArray::new(vec![
-        (8, Box::new(SharedMemBackend(b0.clone()))),
-        (12, Box::new(SharedMemBackend(b1.clone()))),
-        (16, Box::new(SharedMemBackend(b2.clone()))),
-    ]);
+    let mut aha = AggregatedHashArray::new(
+        vec![
+            (8, Box::new(SharedMemBackend(b0.clone()))),
+            (12, Box::new(SharedMemBackend(b1.clone()))),
+            (16, Box::new(SharedMemBackend(b2.clone()))),
+        ],
+        32,
+    );
 
     let tails = || {
         (
@@ -77,49 +83,143 @@ fn aha_roundtrips_hash_arrays() {
     let b1 = Arc::new(Mutex::new(MemStore::new()));
     let b2 = Arc::new(Mutex::new(MemStore::new()));
 
-    let mut aha = AggregatedHashArray::new(vec![
-        (8, Box::new(SharedMemBackend(b0))),
-        (12, Box::new(SharedMemBackend(b1))),
-        (16, Box::new(SharedMemBackend(b2))),
-    ]);
+    let mut aha = AggregatedHashArray::new(
+        vec![
+            (8, Box::new(SharedMemBackend(b0))),
+            (12, Box::new(SharedMemBackend(b1))),
+            (16, Box::new(SharedMemBackend(b2))),
+        ],
+        32,
+    );
 
     // Mix variable hash byte-lengths (<=32) to validate the length-prefix encoding.
     let hashes: Vec<Vec<u8>> = vec![make_hash(0x10, 0), make_hash(0x20, 7), make_hash(0x30, 32)];
-    let ptr = aha.write_aha(hashes.clone(), 0, 0);
-    let got = aha.read_aha(hashes.len() as u8, ptr);
+    let ptr = aha.write_aha(hashes.clone(), 0, 0).unwrap();
+    let got = aha.read_aha(hashes.len() as u8, ptr).unwrap();
     assert_eq!(got, hashes);
 }
 
+#[test]
+fn aha_sizes_entries_for_wide_hashes() {
+    let b0 = Arc::new(Mutex::new(MemStore::new()));
+    let mut aha = AggregatedHashArray::new(vec![(8, Box::new(SharedMemBackend(b0.clone())))], 64);
+
+    // RLP of a 64-byte digest is 66 bytes (0xb8 0x40 + hash).
+    let wide = |seed: u8| rlp::encode(&make_hash(seed, 64).as_slice()).to_vec();
+    let hashes1: Vec<Vec<u8>> = (0..8).map(wide).collect();
+    let hashes2: Vec<Vec<u8>> = (8..16).map(wide).collect();
+    assert_eq!(hashes1[0].len(), 66);
+
+    let p0 = aha.write_aha(hashes1.clone(), 0, 0).unwrap();
+    let p1 = aha.write_aha(hashes2.clone(), 0, 0).unwrap();
+    assert_eq!(p1, 1);
+    assert_eq!(b0.lock().unwrap().tail(), 2 * 8 * (1 + 66));
+    assert_eq!(aha.read_aha(8, p0).unwrap(), hashes1);
+    assert_eq!(aha.read_aha(8, p1).unwrap(), hashes2);
+}
+
+#[test]
+fn aha_read_rejects_truncated_arrays() {
+    let b0 = Arc::new(Mutex::new(MemStore::new()));
+    let mut aha = AggregatedHashArray::new(vec![(8, Box::new(SharedMemBackend(b0.clone())))], 32);
+    let hashes: Vec<Vec<u8>> = (0..8).map(|i| make_hash(i, 32)).collect();
+    let ptr = aha.write_aha(hashes.clone(), 0, 0).unwrap();
+    assert_eq!(aha.read_aha(8, ptr).unwrap(), hashes);
+
+    // Cut the file in the middle of the fourth entry.
+    b0.lock().unwrap().write(3 * 33 + 10, &[]);
+    assert!(aha.read_aha(8, ptr).is_err());
+    assert_eq!(aha.read_aha(3, ptr).unwrap(), hashes[..3]);
+    // Past the end, and longer than any tier.
+    assert!(aha.read_aha(1, 1024).is_err());
+    assert!(aha.read_aha(9, ptr).is_err());
+}
+
 #[test]
 fn aha_recycles_after_commit() {
     let b0 = Arc::new(Mutex::new(MemStore::new()));
 
-    let mut aha = AggregatedHashArray::new(vec![(8, Box::new(SharedMemBackend(b0)))]);
+    let mut aha = AggregatedHashArray::new(vec![(8, Box::new(SharedMemBackend(b0)))], 32);
     let hashes1: Vec<Vec<u8>> = (0..8).map(|i| make_hash(i, 32)).collect();
     let hashes2: Vec<Vec<u8>> = (8..16).map(|i| make_hash(i, 32)).collect();
 
     // First write allocates at ptr=0.
-    let p0 = aha.write_aha(hashes1, 0, 0);
+    let p0 = aha.write_aha(hashes1, 0, 0).unwrap();
     assert_eq!(p0, 0);
 
     // Second write moves old ptr into pending recycle, allocates at tail.
-    let p1 = aha.write_aha(hashes2.clone(), 8, p0);
+    let p1 = aha.write_aha(hashes2.clone(), 8, p0).unwrap();
     assert_ne!(p1, p0);
 
     // Commit makes the old ptr available for reuse.
     aha.commit();
 
     // Third write should be able to reuse p0.
-    let p2 = aha.write_aha(hashes2, 8, p1);
+    let p2 = aha.write_aha(hashes2, 8, p1).unwrap();
     assert_eq!(p2, p0);
 }
 
 #[test]
-fn aha_returns_zero_when_array_len_exceeds_max() {
+fn aha_recycle_lists_survive_reopen() {
+    let b0 = Arc::new(Mutex::new(MemStore::new()));
+    let free = Arc::new(Mutex::new(MemStore::new()));
+    let open = || {
+        AggregatedHashArray::new(vec![(8, Box::new(SharedMemBackend(b0.clone())))], 32)
+            .with_recycle_store(Box::new(SharedMemBackend(free.clone())))
+    };
+    let hashes: Vec<Vec<u8>> = (0..8).map(|i| make_hash(i, 32)).collect();
+
+    let (p0, p1) = {
+        let mut aha = open();
+        let p0 = aha.write_aha(hashes.clone(), 0, 0).unwrap();
+        let p1 = aha.write_aha(hashes.clone(), 8, p0).unwrap();
+        aha.commit();
+        aha.flush();
+        (p0, p1)
+    };
+
+    // Slots freed before a reopen are reused instead of appending.
+    let mut aha = open();
+    assert_eq!(aha.write_aha(hashes.clone(), 8, p1), Some(p0));
+    aha.commit();
+    aha.flush();
+    drop(aha);
+    let mut aha = open();
+    assert_eq!(aha.write_aha(hashes.clone(), 0, 0), Some(p1));
+    drop(aha);
+
+    // A torn list is ignored.
+    let len = free.lock().unwrap().tail();
+    free.lock().unwrap().write(len - 1, &[]);
+    let tail = b0.lock().unwrap().tail();
+    let mut aha = open();
+    assert_eq!(aha.write_aha(hashes, 0, 0), Some(tail as u64 / (8 * 34)));
+}
+
+#[test]
+fn aha_slots_stay_aligned_in_unaligned_backends() {
     let b0 = Arc::new(Mutex::new(MemStore::new()));
-    let mut aha = AggregatedHashArray::new(vec![(8, Box::new(SharedMemBackend(b0)))]);
+    // A tier file that already holds bytes not written by the array.
+    b0.lock().unwrap().write(0, &[0xff; 5]);
+    let mut aha = AggregatedHashArray::new(vec![(8, Box::new(SharedMemBackend(b0.clone())))], 32);
+    let hashes1: Vec<Vec<u8>> = (0..8).map(|i| make_hash(i, 32)).collect();
+    let hashes2: Vec<Vec<u8>> = (8..16).map(|i| make_hash(i, 32)).collect();
+
+    let p0 = aha.write_aha(hashes1.clone(), 0, 0).unwrap();
+    let p1 = aha.write_aha(hashes2.clone(), 0, 0).unwrap();
+    assert_eq!((p0, p1), (1, 2));
+    assert_eq!(b0.lock().unwrap().tail(), 3 * 8 * 34);
+    assert_eq!(b0.lock().unwrap().read(0, 5), [0xff; 5]);
+    assert_eq!(aha.read_aha(8, p0).unwrap(), hashes1);
+    assert_eq!(aha.read_aha(8, p1).unwrap(), hashes2);
+}
+
+#[test]
+fn aha_returns_none_when_array_len_exceeds_max() {
+    let b0 = Arc::new(Mutex::new(MemStore::new()));
+    let mut aha = AggregatedHashArray::new(vec![(8, Box::new(SharedMemBackend(b0)))], 32);
     let hashes: Vec<Vec<u8>> = (0..9).map(|i| make_hash(i, 32)).collect();
-    assert_eq!(aha.write_aha(hashes, 0, 0), 0);
+    assert_eq!(aha.write_aha(hashes, 0, 0), None);
 }
 
 /// Backend wrapper that counts reads/writes, backed by `MemStore`.
@@ -182,8 +282,8 @@ fn store_load_children_hash_uses_aha_single_read() {
     let aha_backend: Box<dyn Backend> =
         Box::new(CountingMemBackend::new(aha_reads.clone(), aha_writes));
 
-    let aha = AggregatedHashArray::new(vec![(17, aha_backend)]);
-    let mut store = NodeStore::new(node_backend, 0, Some(aha));
+    let aha = AggregatedHashArray::new(vec![(17, aha_backend)], 32);
+    let mut store = NodeStore::new(node_backend, 0, Some(aha), Arc::new(Keccak256Hasher));
 
     // Build a branch node with 17 child reference items already loaded (Child::Hash).
     let mut b = Branch::new();
@@ -197,7 +297,7 @@ fn store_load_children_hash_uses_aha_single_read() {
     let mut node = Node(NodeType::Branch(b));
 
     // Compute the branch hash item and store the AHA blob.
-    node.calc_hash().unwrap();
+    node.calc_hash(&Keccak256Hasher).unwrap();
     store.write_aha(&mut node);
 
     // Simulate a decoded-on-disk branch: child pointers are Clean ptrs; hashes absent.
@@ -211,7 +311,7 @@ fn store_load_children_hash_uses_aha_single_read() {
     let mut persisted = Node(NodeType::Branch(persisted_bnode));
 
     // Act: load children hashes. It should read from AHA (once) and not touch node backend.
-    store.load_aha(&mut persisted);
+    store.load_aha(&mut persisted).unwrap();
 
     // Assert: AHA was used; node backend not used.
     assert_eq!(
@@ -245,8 +345,8 @@ fn store_write_aha_does_not_recycle_on_first_write() {
     // it will recycle ptr=0 on commit and the next write will wrongly reuse it.
     let node_backend: Box<dyn Backend> = Box::new(MemStore::new());
     let aha_backend: Box<dyn Backend> = Box::new(MemStore::new());
-    let aha = AggregatedHashArray::new(vec![(17, aha_backend)]);
-    let mut store = NodeStore::new(node_backend, 0, Some(aha));
+    let aha = AggregatedHashArray::new(vec![(17, aha_backend)], 32);
+    let mut store = NodeStore::new(node_backend, 0, Some(aha), Arc::new(Keccak256Hasher));
 
     let mut b = Branch::new();
     for i in 0..17 {
@@ -256,7 +356,7 @@ fn store_write_aha_does_not_recycle_on_first_write() {
         ));
     }
     let mut node = Node(NodeType::Branch(b));
-    node.calc_hash().unwrap();
+    node.calc_hash(&Keccak256Hasher).unwrap();
 
     store.write_aha(&mut node);
     let NodeType::Branch(b0) = node.get_inner() else {
@@ -280,3 +380,149 @@ fn store_write_aha_does_not_recycle_on_first_write() {
         "should not reuse first AHA pointer after initial commit"
     );
 }
+
+#[derive(Default)]
+struct OverflowCounter(AtomicUsize);
+
+impl Metrics for OverflowCounter {
+    fn on_aha_overflow(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[test]
+fn store_skips_aha_for_branches_wider_than_every_tier() {
+    let nodes = Arc::new(Mutex::new(MemStore::new()));
+    let ahas: Vec<_> = (0..4)
+        .map(|_| Arc::new(Mutex::new(MemStore::new())))
+        .collect();
+    let open = || {
+        let tiers = [4, 8, 12, 16]
+            .into_iter()
+            .zip(&ahas)
+            .map(|(len, b)| {
+                (
+                    len,
+                    Box::new(SharedMemBackend(b.clone())) as Box<dyn Backend>,
+                )
+            })
+            .collect();
+        let aha = AggregatedHashArray::new(tiers, 32);
+        let store = NodeStore::new(
+            Box::new(SharedMemBackend(nodes.clone())),
+            0,
+            Some(aha),
+            Arc::new(Keccak256Hasher),
+        );
+        Arc::new(Mutex::new(store))
+    };
+
+    // The empty key fills the value slot and one key per first nibble fills
+    // the others, so the root branch has 17 children.
+    let mut keys: Vec<Vec<u8>> = (0..16u8).map(|n| vec![n << 4, n]).collect();
+    keys.push(Vec::new());
+    let value = |k: &[u8]| Value::new([k, &[0xaa; 40]].concat(), Vec::new());
+
+    let store = open();
+    let overflows = Arc::new(OverflowCounter::default());
+    store.lock().unwrap().set_metrics(Some(overflows.clone()));
+    let mut merkle = Merkle::new(store.clone(), 0);
+    for k in &keys {
+        merkle.insert(k, value(k));
+    }
+    let root = merkle.commit();
+    store.lock().unwrap().flush();
+    assert_eq!(overflows.0.load(Ordering::Relaxed), 1);
+    let NodeType::Branch(bnode) = store.lock().unwrap().get_clean(root).get_inner().clone() else {
+        panic!("root is not a branch");
+    };
+    assert_eq!(bnode.children.iter().flatten().count(), 17);
+    assert_eq!((bnode.aha_len, bnode.aha_ptr), (0, 0));
+
+    // Without an array the hashes come from the children themselves.
+    let mut expected = Merkle::new(
+        Arc::new(Mutex::new(NodeStore::new(
+            Box::new(MemStore::new()),
+            0,
+            None,
+            Arc::new(Keccak256Hasher),
+        ))),
+        0,
+    );
+    for k in &keys {
+        expected.insert(k, value(k));
+    }
+    expected.commit();
+    let reopened = Merkle::new(open(), root);
+    assert_eq!(reopened.hash(), expected.hash());
+    for k in &keys {
+        assert_eq!(reopened.find(k).unwrap().value, value(k).value);
+    }
+}
+
+#[derive(Default)]
+struct MismatchCounter(AtomicUsize);
+
+impl Metrics for MismatchCounter {
+    fn on_aha_mismatch(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[test]
+fn store_reports_corrupt_aha_and_falls_back_unless_strict() {
+    let aha_backend = Arc::new(Mutex::new(MemStore::new()));
+    let aha = AggregatedHashArray::new(
+        vec![(17, Box::new(SharedMemBackend(aha_backend.clone())))],
+        32,
+    );
+    let mut store = NodeStore::new(
+        Box::new(MemStore::new()),
+        0,
+        Some(aha),
+        Arc::new(Keccak256Hasher),
+    );
+    let mismatches = Arc::new(MismatchCounter::default());
+    store.set_metrics(Some(mismatches.clone()));
+
+    let mut b = Branch::new();
+    for i in 0..17 {
+        b.children[i] = Some(Child::Hash(
+            i as crate::merkle::CleanPtr + 1,
+            rlp_child_ref(i as u8),
+        ));
+    }
+    let mut node = Node(NodeType::Branch(b));
+    node.calc_hash(&Keccak256Hasher).unwrap();
+    store.write_aha(&mut node);
+    // Flip a byte of the first stored child reference.
+    aha_backend.lock().unwrap().write(1, &[0xee]);
+
+    let persisted = || {
+        let NodeType::Branch(mut bnode) = node.get_inner().clone() else {
+            unreachable!();
+        };
+        for i in 0..17 {
+            bnode.children[i] = Some(Child::Ptr(NodePtr::Clean(i as u64 + 1)));
+        }
+        Node(NodeType::Branch(bnode))
+    };
+
+    // The children are left for `load_children_hash` to read.
+    let mut loaded = persisted();
+    store.load_aha(&mut loaded).unwrap();
+    let NodeType::Branch(bnode) = loaded.get_inner() else {
+        unreachable!();
+    };
+    assert!(
+        bnode
+            .children
+            .iter()
+            .all(|c| matches!(c, Some(Child::Ptr(NodePtr::Clean(_)))))
+    );
+    assert_eq!(mismatches.0.load(Ordering::Relaxed), 1);
+
+    store.set_strict_aha(true);
+    assert!(store.load_aha(&mut persisted()).is_err());
+    assert_eq!(mismatches.0.load(Ordering::Relaxed), 2);
+}
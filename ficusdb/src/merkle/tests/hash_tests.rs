@@ -1,6 +1,7 @@
 use super::eth_merkle::MPT;
-use super::memstore::MemStore;
 use crate::merkle::backend::Backend;
+use crate::merkle::hasher::{Hasher, Keccak256Hasher};
+use crate::merkle::memstore::MemStore;
 use crate::merkle::merkle::Merkle;
 use crate::merkle::node::Value;
 use crate::merkle::store::NodeStore;
@@ -41,6 +42,7 @@ fn new_merkle(shared: Arc<Mutex<MemStore>>, root_ptr: crate::merkle::CleanPtr) -
         Box::new(SharedMemBackend(shared)),
         TEST_CACHE_SIZE,
         None,
+        Arc::new(Keccak256Hasher),
     )));
     Merkle::new(store, root_ptr)
 }
@@ -53,6 +55,40 @@ fn merkle_hash_empty_tree_matches_reference() {
     let got = merkle.hash();
     let expected = MPT::new().root_hash();
     assert_eq!(got, expected);
+    assert_eq!(merkle.empty_root(), expected);
+}
+
+/// Keccak-256 with an all-zero empty root.
+struct ZeroEmptyHasher;
+
+impl Hasher for ZeroEmptyHasher {
+    fn digest(&self, data: &[u8]) -> Vec<u8> {
+        Keccak256Hasher.digest(data)
+    }
+
+    fn empty_node_hash(&self) -> Vec<u8> {
+        vec![0; 32]
+    }
+}
+
+#[test]
+fn merkle_empty_root_follows_the_hasher() {
+    let store = Arc::new(Mutex::new(NodeStore::new(
+        Box::new(MemStore::new()),
+        TEST_CACHE_SIZE,
+        None,
+        Arc::new(ZeroEmptyHasher),
+    )));
+    let mut merkle = Merkle::new(store, 0);
+    assert_eq!(merkle.empty_root(), vec![0; 32]);
+    assert_eq!(merkle.hash(), vec![0; 32]);
+
+    merkle.insert(b"k", Value::new(b"v".to_vec(), Vec::new()));
+    merkle.commit();
+    assert_ne!(merkle.hash(), vec![0; 32]);
+    assert!(merkle.delete(b"k"));
+    assert_eq!(merkle.commit(), 0);
+    assert_eq!(merkle.hash(), vec![0; 32]);
 }
 
 #[test]
@@ -245,3 +281,91 @@ fn merkle_hash_delete_to_empty_matches_reference_empty() {
     assert_eq!(merkle.hash(), mpt.root_hash());
     assert_eq!(merkle.hash(), MPT::new().root_hash());
 }
+
+struct Blake3Hasher;
+
+impl Hasher for Blake3Hasher {
+    fn digest(&self, data: &[u8]) -> Vec<u8> {
+        blake3::hash(data).as_bytes().to_vec()
+    }
+}
+
+fn new_blake3_merkle(shared: Arc<Mutex<MemStore>>, root_ptr: crate::merkle::CleanPtr) -> Merkle {
+    let store = Arc::new(Mutex::new(NodeStore::new(
+        Box::new(SharedMemBackend(shared)),
+        TEST_CACHE_SIZE,
+        None,
+        Arc::new(Blake3Hasher),
+    )));
+    Merkle::new(store, root_ptr)
+}
+
+#[test]
+fn merkle_blake3_hasher_roundtrip_and_stable_root() {
+    let mut rng = XorShift64::new(0xb1a3_0000_0000_0003);
+    let mut kvs = Vec::new();
+    for _ in 0..200 {
+        let key = rand_bytes(&mut rng, 32);
+        let vlen = 1 + (rng.next_u64() as usize % 64);
+        let value = rand_bytes(&mut rng, vlen);
+        kvs.push((key, value));
+    }
+
+    let shared = Arc::new(Mutex::new(MemStore::new()));
+    let mut merkle = new_blake3_merkle(shared.clone(), 0);
+    assert_eq!(merkle.hash(), Blake3Hasher.digest(&[0x80]));
+
+    for (k, v) in &kvs {
+        merkle.insert(k, Value::new(v.clone(), Vec::new()));
+    }
+    let root_ptr = merkle.commit();
+    for (k, v) in &kvs {
+        assert_eq!(merkle.find(k).unwrap().value, *v);
+    }
+    let root = merkle.hash();
+    assert_eq!(root.len(), 32);
+
+    // Same content inserted in reverse order yields the same root.
+    let mut reversed = new_blake3_merkle(Arc::new(Mutex::new(MemStore::new())), 0);
+    for (k, v) in kvs.iter().rev() {
+        reversed.insert(k, Value::new(v.clone(), Vec::new()));
+    }
+    reversed.commit();
+    assert_eq!(reversed.hash(), root);
+
+    // Reopening the committed root reproduces the hash.
+    let reopened = new_blake3_merkle(shared, root_ptr);
+    assert_eq!(reopened.hash(), root);
+
+    // The hash function actually changes the root.
+    let mut mpt = MPT::new();
+    for (k, v) in &kvs {
+        mpt.insert(k, v);
+    }
+    assert_ne!(root, mpt.root_hash());
+}
+
+#[test]
+fn merkle_commit_layout_is_reproducible() {
+    // Levels this wide are hashed across threads; the node file must still
+    // come out byte for byte the same.
+    let commit_bytes = || {
+        let shared = Arc::new(Mutex::new(MemStore::new()));
+        let mut merkle = new_merkle(shared.clone(), 0);
+        let mut rng = XorShift64::new(0x0ddb_a11c_0ffe_e000);
+        for _ in 0..5_000 {
+            let key = rand_bytes(&mut rng, 20);
+            let value = rand_bytes(&mut rng, 40);
+            merkle.insert(&key, Value::new(value, Vec::new()));
+        }
+        merkle.commit();
+        let hash = merkle.hash();
+        let mut store = shared.lock().unwrap();
+        let tail = store.tail();
+        (hash, store.read(0, tail))
+    };
+    let (hash, bytes) = commit_bytes();
+    for _ in 0..3 {
+        assert_eq!(commit_bytes(), (hash.clone(), bytes.clone()));
+    }
+}
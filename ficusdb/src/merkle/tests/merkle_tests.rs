@@ -1,8 +1,16 @@
-use super::memstore::MemStore;
+use crate::backend::SyncMode;
+use crate::merkle::AggregatedHashArray;
+use crate::merkle::IntegrityError;
+use crate::merkle::NBRANCH;
 use crate::merkle::backend::Backend;
+use crate::merkle::cursor::Cursor;
+use crate::merkle::hasher::{Hasher, Keccak256Hasher};
+use crate::merkle::memstore::MemStore;
 use crate::merkle::merkle::Merkle;
-use crate::merkle::node::Value;
+use crate::merkle::node::{Child, Node, NodePtr, NodeType, Short, Value};
 use crate::merkle::store::NodeStore;
+use crate::merkle::utils;
+use crate::merkle::verify_range_proof;
 
 use std::collections::{HashMap, HashSet};
 use std::sync::{Arc, Mutex};
@@ -43,6 +51,7 @@ fn new_merkle(shared: Arc<Mutex<MemStore>>, root_ptr: super::super::CleanPtr) ->
         Box::new(SharedMemBackend(shared)),
         TEST_CACHE_SIZE,
         None,
+        Arc::new(Keccak256Hasher),
     )));
     Merkle::new(store, root_ptr)
 }
@@ -94,6 +103,73 @@ fn merkle_delete_removes_key_and_preserves_others() {
     assert!(!merkle.delete(b"missing"));
 }
 
+#[test]
+fn merkle_delete_collapses_dirty_and_clean_paths_alike() {
+    let val = |k: &[u8]| Value::new(k.to_vec(), Vec::new());
+    let keys: Vec<Vec<u8>> = (0..300u32)
+        .map(|i| i.wrapping_mul(2654435761).to_be_bytes().to_vec())
+        .collect();
+    let (gone, kept) = keys.split_at(200);
+
+    // delete from uncommitted nodes, then from committed ones
+    let mut roots = Vec::new();
+    for commit_first in [false, true] {
+        let mut merkle = new_merkle(Arc::new(Mutex::new(MemStore::new())), 0);
+        for k in &keys {
+            merkle.insert(k, val(k));
+        }
+        if commit_first {
+            merkle.commit();
+        }
+        for k in gone {
+            assert!(merkle.delete(k));
+        }
+        merkle.commit();
+        assert_eq!(merkle.verify_integrity(), Ok(()));
+        roots.push(merkle.hash());
+    }
+
+    let mut fresh = new_merkle(Arc::new(Mutex::new(MemStore::new())), 0);
+    for k in kept {
+        fresh.insert(k, val(k));
+    }
+    fresh.commit();
+    assert_eq!(roots, vec![fresh.hash(), fresh.hash()]);
+}
+
+#[test]
+fn merkle_delete_batch_matches_sequential_deletes() {
+    let val = |k: &[u8]| Value::new(k.to_vec(), Vec::new());
+    let keys: Vec<Vec<u8>> = (0..400u32)
+        .map(|i| i.wrapping_mul(2654435761).to_be_bytes().to_vec())
+        .collect();
+    // every other key, unsorted, plus a repeat and a missing key
+    let mut gone: Vec<&[u8]> = keys.iter().step_by(2).map(Vec::as_slice).collect();
+    gone.push(&keys[0]);
+    gone.push(b"missing");
+
+    let mut sequential = new_merkle(Arc::new(Mutex::new(MemStore::new())), 0);
+    let mut batch = new_merkle(Arc::new(Mutex::new(MemStore::new())), 0);
+    for merkle in [&mut sequential, &mut batch] {
+        for k in &keys {
+            merkle.insert(k, val(k));
+        }
+        merkle.commit();
+    }
+    for k in &gone {
+        sequential.delete(k);
+    }
+    assert_eq!(batch.delete_batch(&gone), 200);
+    sequential.commit();
+    batch.commit();
+    assert_eq!(batch.verify_integrity(), Ok(()));
+    assert_eq!(batch.hash(), sequential.hash());
+
+    let rest: Vec<&[u8]> = keys.iter().map(Vec::as_slice).collect();
+    assert_eq!(batch.delete_batch(&rest), 200);
+    assert_eq!(batch.commit(), 0);
+}
+
 #[test]
 fn merkle_delete_then_commit_reopens_as_empty() {
     let shared = Arc::new(Mutex::new(MemStore::new()));
@@ -260,3 +336,881 @@ fn merkle_fuzzy_batch_commits_and_reopen_by_root_ptr() {
         }
     }
 }
+
+#[test]
+fn merkle_insert_sorted_matches_insert_root() {
+    let mut rng = XorShift64::new(0x0bad_cafe_f00d_1234);
+    let mut entries: Vec<(Vec<u8>, Value)> = Vec::new();
+    for _ in 0..2000 {
+        // Keys from a small alphabet share long prefixes and repeat.
+        let key: Vec<u8> = (0..6).map(|_| (rng.next_u64() % 4) as u8).collect();
+        let vlen = 1 + (rng.next_u64() % 48) as usize;
+        let val: Vec<u8> = (0..vlen).map(|_| rng.next_u64() as u8).collect();
+        entries.push((key, Value::new(val, Vec::new())));
+    }
+
+    let mut expected = new_merkle(Arc::new(Mutex::new(MemStore::new())), 0);
+    for (k, v) in &entries {
+        expected.insert(k, v.clone());
+    }
+    expected.commit();
+
+    // A stable sort keeps the insertion order of repeated keys, so the last
+    // write still wins.
+    entries.sort_by(|a, b| a.0.cmp(&b.0));
+    let shared = Arc::new(Mutex::new(MemStore::new()));
+    let mut bulk = new_merkle(shared.clone(), 0);
+    let root = bulk.insert_sorted(&entries);
+    assert_eq!(bulk.hash(), expected.hash());
+
+    let reopened = new_merkle(shared, root);
+    assert_eq!(reopened.hash(), expected.hash());
+    for (k, _) in &entries {
+        assert_eq!(
+            reopened.find(k).unwrap().value,
+            expected.find(k).unwrap().value
+        );
+    }
+}
+
+#[test]
+fn merkle_root_for_ignores_insertion_order() {
+    let mut rng = XorShift64::new(0x5eed_0f0d_de00_0001);
+    let mut entries: Vec<(Vec<u8>, Value)> = (0..500u32)
+        .map(|i| {
+            let key = i.wrapping_mul(2654435761).to_be_bytes().to_vec();
+            (key, Value::new(i.to_le_bytes().to_vec(), Vec::new()))
+        })
+        .collect();
+
+    let mut expected = new_merkle(Arc::new(Mutex::new(MemStore::new())), 0);
+    for (k, v) in &entries {
+        expected.insert(k, v.clone());
+    }
+    expected.commit();
+    assert_eq!(Merkle::root_for(&entries), expected.hash());
+
+    // Fisher-Yates shuffle
+    for i in (1..entries.len()).rev() {
+        let j = (rng.next_u64() % (i as u64 + 1)) as usize;
+        entries.swap(i, j);
+    }
+    assert_eq!(Merkle::root_for(&entries), expected.hash());
+    entries.reverse();
+    assert_eq!(Merkle::root_for(&entries), expected.hash());
+
+    assert_eq!(Merkle::root_for(&[]), Keccak256Hasher.empty_node_hash());
+}
+
+#[test]
+fn varint_roundtrips_and_rejects_truncation() {
+    for v in [
+        0u64,
+        1,
+        127,
+        128,
+        300,
+        16383,
+        16384,
+        u32::MAX as u64,
+        u64::MAX,
+    ] {
+        let mut buf = Vec::new();
+        utils::encode_varint(v, &mut buf);
+        assert_eq!(utils::decode_varint(&buf), Some((v, buf.len())));
+        assert_eq!(utils::decode_varint(&buf[..buf.len() - 1]), None);
+    }
+    // Small nodes only need a single length byte.
+    let mut buf = Vec::new();
+    utils::encode_varint(100, &mut buf);
+    assert_eq!(buf.len(), 1);
+
+    // A length prefix cut off by the end of the store is an error, not a panic.
+    let mut backend = MemStore::new();
+    backend.write(0, &[0x80, 0x80]);
+    let mut store = NodeStore::new(Box::new(backend), 0, None, Arc::new(Keccak256Hasher));
+    assert!(store.get_node(0).is_err());
+}
+
+/// Store a hand-built branch node body at the start of a fresh store.
+fn store_with_branch(children: usize, aha_len: u8) -> NodeStore {
+    let mut s = rlp::RlpStream::new_list(2);
+    s.append(&0u8); // branch node type
+    s.begin_list(4)
+        .append_list::<u8, u8>(&[])
+        .append_list::<Option<u64>, Option<u64>>(&vec![None; children])
+        .append(&aha_len)
+        .append(&0u64);
+    let body = s.out().to_vec();
+    let mut buf = Vec::new();
+    utils::encode_varint(body.len() as u64, &mut buf);
+    buf.extend(body);
+
+    let mut backend = MemStore::new();
+    backend.write(0, &buf);
+    NodeStore::new(Box::new(backend), 0, None, Arc::new(Keccak256Hasher))
+}
+
+#[test]
+fn store_rejects_corrupt_branch_nodes() {
+    assert!(store_with_branch(17, 17).get_node(0).is_ok());
+
+    let err = store_with_branch(16, 0).get_node(0).err().unwrap();
+    assert!(err.to_string().contains("RlpIncorrectListLen"), "{err}");
+    assert!(store_with_branch(18, 0).get_node(0).is_err());
+
+    let err = store_with_branch(17, 18).get_node(0).err().unwrap();
+    assert!(err.to_string().contains("aha_len"), "{err}");
+}
+
+#[test]
+fn store_rejects_truncated_node_bodies() {
+    // the prefix says 120 bytes, but the store ends after 50
+    let mut backend = MemStore::new();
+    let mut buf = Vec::new();
+    utils::encode_varint(120, &mut buf);
+    buf.extend([0u8; 50]);
+    backend.write(0, &buf);
+    let mut store = NodeStore::new(Box::new(backend), 0, None, Arc::new(Keccak256Hasher));
+    let err = store.get_node(0).err().unwrap();
+    assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+    assert!(err.to_string().contains("Truncated node"), "{err}");
+
+    // a corrupt prefix claiming a huge node fails without reading it
+    let mut backend = MemStore::new();
+    let mut buf = Vec::new();
+    utils::encode_varint(u64::MAX, &mut buf);
+    backend.write(0, &buf);
+    let mut store = NodeStore::new(Box::new(backend), 0, None, Arc::new(Keccak256Hasher));
+    assert!(store.get_node(0).is_err());
+}
+
+#[test]
+fn merkle_contains_matches_find() {
+    let shared = Arc::new(Mutex::new(MemStore::new()));
+    let mut merkle = new_merkle(shared.clone(), 0);
+    assert!(!merkle.contains(b"dog"));
+
+    merkle.insert(b"dog", Value::new(vec![0xaa; 128], Vec::new()));
+    merkle.insert(b"doe", Value::new(b"deer".to_vec(), Vec::new()));
+    // uncommitted keys are visible
+    assert!(merkle.contains(b"dog"));
+
+    let root = merkle.commit();
+    let merkle = new_merkle(shared, root);
+    for key in [&b"dog"[..], b"doe", b"do", b"dogs", b"cat", b""] {
+        assert_eq!(merkle.contains(key), merkle.find(key).is_some(), "{key:?}");
+    }
+    assert!(merkle.contains(b"dog"));
+    assert!(!merkle.contains(b"do"));
+}
+
+#[test]
+fn merkle_len_counts_committed_keys() {
+    let shared = Arc::new(Mutex::new(MemStore::new()));
+    let mut merkle = new_merkle(shared.clone(), 0);
+    assert_eq!(merkle.len(), 0);
+    assert!(merkle.is_empty());
+
+    let mut rng = XorShift64::new(0x51ed_270b_1dd3_a1e5);
+    let mut keys = HashSet::new();
+    while keys.len() < 1000 {
+        keys.insert(rng.next_u64().to_be_bytes().to_vec());
+    }
+    for k in &keys {
+        merkle.insert(k, Value::new(k.clone(), Vec::new()));
+    }
+    // only committed keys count
+    assert_eq!(merkle.len(), 0);
+    merkle.commit();
+    assert_eq!(merkle.len(), keys.len());
+
+    // overwrites don't add keys, deletes remove them
+    for k in keys.iter().take(100) {
+        merkle.insert(k, Value::new(b"new".to_vec(), Vec::new()));
+    }
+    let removed: Vec<_> = keys.iter().skip(100).take(10).collect();
+    for k in &removed {
+        assert!(merkle.delete(k));
+    }
+    let root = merkle.commit();
+    assert_eq!(merkle.len(), keys.len() - removed.len());
+    assert_eq!(new_merkle(shared, root).len(), keys.len() - removed.len());
+}
+
+#[test]
+fn merkle_diff_lists_changed_keys() {
+    let shared = Arc::new(Mutex::new(MemStore::new()));
+    let mut merkle = new_merkle(shared, 0);
+    let val = |v: &[u8]| Value::new(v.to_vec(), Vec::new());
+    let key = |i: u32| (i * 7919).to_be_bytes().to_vec();
+    for i in 0..200 {
+        merkle.insert(&key(i), val(b"a"));
+    }
+    let root_a = merkle.commit();
+
+    // overwrite, delete, add, and rewrite a value unchanged
+    type Change = (Vec<u8>, Option<Vec<u8>>, Option<Vec<u8>>);
+    let mut expected: Vec<Change> = Vec::new();
+    for i in [3, 50, 51, 199] {
+        merkle.insert(&key(i), val(b"b"));
+        expected.push((key(i), Some(b"a".to_vec()), Some(b"b".to_vec())));
+    }
+    for i in [10, 120] {
+        assert!(merkle.delete(&key(i)));
+        expected.push((key(i), Some(b"a".to_vec()), None));
+    }
+    for i in [200, 1000] {
+        merkle.insert(&key(i), val(b"c"));
+        expected.push((key(i), None, Some(b"c".to_vec())));
+    }
+    merkle.insert(&key(7), val(b"a"));
+    let root_b = merkle.commit();
+    expected.sort();
+
+    let got: Vec<_> = merkle
+        .diff(root_a, root_b)
+        .into_iter()
+        .map(|(k, old, new)| (k, old.map(|v| v.value), new.map(|v| v.value)))
+        .collect();
+    assert_eq!(got, expected);
+
+    let reversed = merkle.diff(root_b, root_a);
+    assert_eq!(reversed.len(), expected.len());
+    assert!(merkle.diff(root_a, root_a).is_empty());
+    assert_eq!(merkle.diff(0, root_a).len(), 200);
+}
+
+#[test]
+fn merkle_verify_integrity_reports_corruption() {
+    let shared = Arc::new(Mutex::new(MemStore::new()));
+    let mut merkle = new_merkle(shared.clone(), 0);
+    for i in 0..100u32 {
+        let val = format!("value-{i:04}").into_bytes();
+        merkle.insert(&i.to_be_bytes(), Value::new(val, Vec::new()));
+    }
+    let root = merkle.commit();
+    assert_eq!(merkle.verify_integrity(), Ok(()));
+    assert_eq!(new_merkle(shared.clone(), root).verify_integrity(), Ok(()));
+    assert_eq!(new_merkle(shared.clone(), 0).verify_integrity(), Ok(()));
+
+    // Flip a byte inside one stored value.
+    {
+        let mut mem = shared.lock().unwrap();
+        let tail = mem.tail();
+        let mut data = mem.read(0, tail);
+        let at = data.windows(10).position(|w| w == b"value-0042").unwrap();
+        data[at + 9] = b'3';
+        mem.write(0, &data);
+    }
+    let err = new_merkle(shared.clone(), root)
+        .verify_integrity()
+        .err()
+        .unwrap();
+    assert!(matches!(err, IntegrityError::HashMismatch { .. }), "{err}");
+    assert_ne!(err.ptr(), root);
+
+    // A pointer into the middle of a node does not decode.
+    let tail = shared.lock().unwrap().tail() as super::super::CleanPtr;
+    let err = new_merkle(shared, tail - 1)
+        .verify_integrity()
+        .err()
+        .unwrap();
+    assert!(
+        matches!(err, IntegrityError::Unreadable { ptr, .. } if ptr == tail - 1),
+        "{err}"
+    );
+}
+
+#[test]
+fn merkle_iter_rev_reverses_iter() {
+    let shared = Arc::new(Mutex::new(MemStore::new()));
+    let mut merkle = new_merkle(shared.clone(), 0);
+    assert!(merkle.iter_rev().next().is_none());
+
+    let mut rng = XorShift64::new(0x2545_f491_4f6c_dd1d);
+    let mut keys = HashSet::new();
+    while keys.len() < 500 {
+        let len = 1 + (rng.next_u64() % 8) as usize;
+        keys.insert(rng.next_u64().to_be_bytes()[..len].to_vec());
+    }
+    // insert asserts on keys that prefix one another
+    let keys: Vec<Vec<u8>> = keys
+        .iter()
+        .filter(|k| !keys.iter().any(|o| o != *k && o.starts_with(k)))
+        .cloned()
+        .collect();
+    for k in &keys {
+        merkle.insert(k, Value::new(k.clone(), Vec::new()));
+    }
+    let mut sorted = keys.clone();
+    sorted.sort();
+
+    // dirty trie, committed trie, and the same root reopened
+    let check = |merkle: &Merkle| {
+        let fwd: Vec<Vec<u8>> = merkle.iter().map(|(k, _)| k).collect();
+        let mut rev: Vec<Vec<u8>> = merkle
+            .iter_rev()
+            .map(|(k, v)| {
+                assert_eq!(k, v.value);
+                k
+            })
+            .collect();
+        assert_eq!(fwd, sorted);
+        rev.reverse();
+        assert_eq!(rev, fwd);
+    };
+    check(&merkle);
+    let root = merkle.commit();
+    check(&merkle);
+    check(&new_merkle(shared, root));
+}
+
+#[test]
+fn cursor_seeks_and_steps_both_ways() {
+    let shared = Arc::new(Mutex::new(MemStore::new()));
+    let mut merkle = new_merkle(shared.clone(), 0);
+    let mut rng = XorShift64::new(0x9e37_79b9_7f4a_7c15);
+    let mut keys = HashSet::new();
+    while keys.len() < 300 {
+        keys.insert(rng.next_u64().to_be_bytes()[..4].to_vec());
+    }
+    let mut sorted: Vec<Vec<u8>> = keys.into_iter().collect();
+    sorted.sort();
+    for k in &sorted {
+        merkle.insert(k, Value::new(k.clone(), Vec::new()));
+    }
+    let root = merkle.commit();
+    let store = Arc::new(Mutex::new(NodeStore::new(
+        Box::new(SharedMemBackend(shared)),
+        TEST_CACHE_SIZE,
+        None,
+        Arc::new(Keccak256Hasher),
+    )));
+
+    let all: Vec<Vec<u8>> = Cursor::new(store.clone(), root).map(|(k, _)| k).collect();
+    assert_eq!(all, sorted);
+
+    let mut probes: Vec<Vec<u8>> = (0..50)
+        .map(|_| rng.next_u64().to_be_bytes()[..3].to_vec())
+        .collect();
+    probes.extend(sorted.iter().step_by(37).cloned());
+    probes.push(vec![]);
+    probes.push(vec![0xff; 5]);
+    for probe in probes {
+        let idx = sorted.partition_point(|k| k < &probe);
+        let mut cursor = Cursor::new(store.clone(), root);
+        cursor.seek(&probe);
+        let next: Vec<_> = (&mut cursor).take(3).map(|(k, _)| k).collect();
+        assert_eq!(next, sorted[idx..(idx + 3).min(sorted.len())]);
+
+        let mut cursor = Cursor::new(store.clone(), root);
+        cursor.seek(&probe);
+        let mut prev = Vec::new();
+        while let Some((k, v)) = cursor.prev() {
+            assert_eq!(k, v);
+            prev.push(k);
+        }
+        prev.reverse();
+        assert_eq!(prev, sorted[..idx]);
+        // past the start, next resumes from the first key
+        assert_eq!(cursor.next().map(|(k, _)| k), sorted.first().cloned());
+    }
+
+    // turning around returns the same entry
+    let mut cursor = Cursor::new(store.clone(), root);
+    cursor.seek(&sorted[100]);
+    assert_eq!(cursor.next().unwrap().0, sorted[100]);
+    assert_eq!(cursor.next().unwrap().0, sorted[101]);
+    assert_eq!(cursor.prev().unwrap().0, sorted[101]);
+    assert_eq!(cursor.prev().unwrap().0, sorted[100]);
+    assert_eq!(cursor.prev().unwrap().0, sorted[99]);
+
+    let mut empty = Cursor::new(store, 0);
+    empty.seek(b"a");
+    assert!(empty.next().is_none());
+    assert!(empty.prev().is_none());
+}
+
+#[test]
+fn merkle_fork_mutates_independently_of_parent() {
+    let val = |k: &[u8], v: &[u8]| Value::new(v.to_vec(), k.to_vec());
+    let key = |i: u32| i.wrapping_mul(2654435761).to_be_bytes().to_vec();
+    let shared = Arc::new(Mutex::new(MemStore::new()));
+    let mut parent = new_merkle(shared, 0);
+    for i in 0..100 {
+        parent.insert(&key(i), val(&key(i), b"base"));
+    }
+    parent.commit();
+
+    // uncommitted parent changes are carried into the fork
+    for i in 100..150 {
+        parent.insert(&key(i), val(&key(i), b"parent"));
+    }
+    assert!(parent.delete(&key(5)));
+    let mut fork = parent.fork();
+    for i in 150..200 {
+        fork.insert(&key(i), val(&key(i), b"fork"));
+    }
+    assert!(fork.delete(&key(10)));
+    assert!(fork.delete(&key(120)));
+    fork.insert(&key(20), val(&key(20), b"fork"));
+
+    let check_parent = |parent: &Merkle| {
+        assert!(parent.find(&key(5)).is_none());
+        assert!(parent.find(&key(150)).is_none());
+        assert_eq!(parent.find(&key(10)).unwrap().value, b"base".to_vec());
+        assert_eq!(parent.find(&key(20)).unwrap().value, b"base".to_vec());
+        assert_eq!(parent.find(&key(120)).unwrap().value, b"parent".to_vec());
+    };
+    check_parent(&parent);
+    assert!(fork.find(&key(5)).is_none());
+    assert!(fork.find(&key(10)).is_none());
+    assert!(fork.find(&key(120)).is_none());
+    assert_eq!(fork.find(&key(130)).unwrap().value, b"parent".to_vec());
+    assert_eq!(fork.find(&key(170)).unwrap().value, b"fork".to_vec());
+
+    // committing the fork must leave the parent's dirty nodes in place
+    fork.commit();
+    check_parent(&parent);
+    parent.commit();
+    check_parent(&parent);
+
+    let expected = |keep: &dyn Fn(u32) -> Option<&'static [u8]>| {
+        let mut merkle = new_merkle(Arc::new(Mutex::new(MemStore::new())), 0);
+        for i in 0..200 {
+            if let Some(v) = keep(i) {
+                merkle.insert(&key(i), val(&key(i), v));
+            }
+        }
+        merkle.commit();
+        merkle.hash()
+    };
+    let parent_hash = expected(&|i| match i {
+        5 | 150.. => None,
+        100.. => Some(b"parent"),
+        _ => Some(b"base"),
+    });
+    let fork_hash = expected(&|i| match i {
+        5 | 10 | 120 => None,
+        20 | 150.. => Some(b"fork"),
+        100.. => Some(b"parent"),
+        _ => Some(b"base"),
+    });
+    assert_eq!(parent.hash(), parent_hash);
+    assert_eq!(fork.hash(), fork_hash);
+}
+
+#[test]
+fn merkle_discard_rolls_back_dirty_arena() {
+    let val = |k: &[u8]| Value::new(k.to_vec(), Vec::new());
+    let key = |i: u32| i.wrapping_mul(2654435761).to_be_bytes().to_vec();
+    let store = Arc::new(Mutex::new(NodeStore::new(
+        Box::new(MemStore::new()),
+        TEST_CACHE_SIZE,
+        None,
+        Arc::new(Keccak256Hasher),
+    )));
+    let mut merkle = Merkle::new(store.clone(), 0);
+    for i in 0..50 {
+        merkle.insert(&key(i), val(&key(i)));
+    }
+    merkle.commit();
+    let base_hash = merkle.hash();
+    let mark = store.lock().unwrap().checkpoint();
+
+    for i in 50..100 {
+        merkle.insert(&key(i), val(&key(i)));
+    }
+    assert!(merkle.delete(&key(3)));
+    assert!(store.lock().unwrap().checkpoint() > mark);
+    merkle.discard();
+    assert!(!merkle.is_dirty());
+    assert_eq!(store.lock().unwrap().checkpoint(), mark);
+    assert!(merkle.find(&key(60)).is_none());
+    assert!(merkle.find(&key(3)).is_some());
+
+    // a fork still holds slots above the mark, so they are not reclaimed
+    merkle.insert(&key(60), val(&key(60)));
+    let mut fork = merkle.fork();
+    merkle.discard();
+    assert!(store.lock().unwrap().checkpoint() > mark);
+    assert!(merkle.find(&key(60)).is_none());
+    assert!(fork.find(&key(60)).is_some());
+    fork.commit();
+
+    assert_eq!(merkle.commit(), merkle.root_cptr());
+    assert_eq!(merkle.hash(), base_hash);
+
+    // the last dirty trie to go reclaims the whole arena, including slots
+    // of a trie committed while it held nodes and slots it reused from
+    // that trie's free list
+    merkle.insert(&key(70), val(&key(70)));
+    let mut fork = merkle.fork();
+    merkle.commit();
+    assert!(store.lock().unwrap().dirty_count() > 0);
+    assert!(fork.delete(&key(70)));
+    fork.insert(&key(80), val(b"new"));
+    fork.discard();
+    assert_eq!(store.lock().unwrap().dirty_count(), 0);
+    assert_eq!(store.lock().unwrap().checkpoint(), 0);
+    assert!(fork.find(&key(80)).is_none());
+}
+
+/// Records every `sync` that reaches the backend.
+struct SyncRecorder(MemStore, Arc<Mutex<Vec<SyncMode>>>);
+
+impl Backend for SyncRecorder {
+    fn tail(&self) -> super::super::CleanPtr {
+        Backend::tail(&self.0)
+    }
+
+    fn read(&mut self, ptr: super::super::CleanPtr, len: usize) -> Vec<u8> {
+        Backend::read(&mut self.0, ptr, len)
+    }
+
+    fn write(&mut self, ptr: super::super::CleanPtr, data: &[u8]) {
+        Backend::write(&mut self.0, ptr, data);
+    }
+
+    fn flush(&mut self) {
+        Backend::flush(&mut self.0);
+    }
+
+    fn sync(&mut self, mode: SyncMode) -> std::io::Result<()> {
+        self.1.lock().unwrap().push(mode);
+        Ok(())
+    }
+
+    #[cfg(feature = "stats")]
+    fn print_stats(&mut self) {}
+}
+
+#[test]
+fn store_sync_reaches_node_and_aha_backends() {
+    let syncs = Arc::new(Mutex::new(Vec::new()));
+    let recorder =
+        || -> Box<dyn Backend> { Box::new(SyncRecorder(MemStore::new(), syncs.clone())) };
+    let aha = AggregatedHashArray::new(vec![(4, recorder()), (16, recorder())], 32);
+    let store = Arc::new(Mutex::new(NodeStore::new(
+        recorder(),
+        TEST_CACHE_SIZE,
+        Some(aha),
+        Arc::new(Keccak256Hasher),
+    )));
+    let mut merkle = Merkle::new(store.clone(), 0);
+    for i in 0..50u32 {
+        merkle.insert(&i.to_be_bytes(), Value::new(vec![1], Vec::new()));
+    }
+    merkle.commit();
+
+    let mut store = store.lock().unwrap();
+    store.flush();
+    store.sync(SyncMode::None).unwrap();
+    assert!(syncs.lock().unwrap().is_empty());
+    store.sync(SyncMode::Full).unwrap();
+    assert_eq!(*syncs.lock().unwrap(), vec![SyncMode::Full; 3]);
+}
+
+#[test]
+fn merkle_dirty_count_tracks_uncommitted_nodes() {
+    let shared = Arc::new(Mutex::new(MemStore::new()));
+    let mut merkle = new_merkle(shared, 0);
+    assert_eq!(merkle.dirty_count(), 0);
+    merkle.insert(b"a", Value::new(vec![1], Vec::new()));
+    let one = merkle.dirty_count();
+    assert!(one > 0);
+    for i in 0..50u8 {
+        merkle.insert(&[b'k', i], Value::new(vec![i], Vec::new()));
+    }
+    assert!(merkle.dirty_count() > one);
+    merkle.commit();
+    assert_eq!(merkle.dirty_count(), 0);
+
+    merkle.insert(b"a", Value::new(vec![2], Vec::new()));
+    assert!(merkle.dirty_count() > 0);
+    merkle.discard();
+    assert_eq!(merkle.dirty_count(), 0);
+}
+
+#[test]
+fn merkle_overwrites_reuse_dirty_slots() {
+    let key = |i: u32| i.wrapping_mul(2654435761).to_be_bytes().to_vec();
+    let store = Arc::new(Mutex::new(NodeStore::new(
+        Box::new(MemStore::new()),
+        TEST_CACHE_SIZE,
+        None,
+        Arc::new(Keccak256Hasher),
+    )));
+    let mut merkle = Merkle::new(store.clone(), 0);
+    for i in 0..200 {
+        merkle.insert(&key(i), Value::new(vec![0], Vec::new()));
+    }
+    let len = store.lock().unwrap().checkpoint();
+
+    // every overwrite frees the value node it replaces
+    for round in 1..=50u8 {
+        for i in 0..200 {
+            merkle.insert(&key(i), Value::new(vec![round], Vec::new()));
+        }
+    }
+    assert!(store.lock().unwrap().checkpoint() <= len + 1);
+
+    let mut expected = new_merkle(Arc::new(Mutex::new(MemStore::new())), 0);
+    for i in 0..200 {
+        assert_eq!(merkle.find(&key(i)).unwrap().value, vec![50]);
+        expected.insert(&key(i), Value::new(vec![50], Vec::new()));
+    }
+    merkle.commit();
+    expected.commit();
+    assert_eq!(merkle.hash(), expected.hash());
+}
+
+#[test]
+fn merkle_to_dot_renders_committed_nodes() {
+    let shared = Arc::new(Mutex::new(MemStore::new()));
+    let mut merkle = new_merkle(shared, 0);
+    assert_eq!(
+        merkle.to_dot(usize::MAX),
+        "digraph trie {\n    node [fontname=monospace];\n}\n"
+    );
+
+    merkle.insert(b"dog", Value::new(b"puppy".to_vec(), Vec::new()));
+    merkle.insert(b"doe", Value::new(b"deer".to_vec(), Vec::new()));
+    merkle.insert(b"doge", Value::new(vec![0xab; 20], Vec::new()));
+    // uncommitted nodes are not drawn
+    assert!(!merkle.to_dot(usize::MAX).contains("->"));
+    merkle.commit();
+
+    let dot = merkle.to_dot(usize::MAX);
+    assert!(dot.starts_with("digraph trie {") && dot.ends_with("}\n"));
+    // "do" is shared, then "g"/"e" split at nibble 6 and 5
+    assert!(dot.contains("label=\"short 646f6\"]"), "{dot}");
+    assert!(
+        dot.contains("[label=\"7\"]") && dot.contains("[label=\"5\"]"),
+        "{dot}"
+    );
+    assert!(dot.contains("[label=\"v\"]"), "{dot}");
+    assert!(dot.contains(&format!("0x{}\"", hex::encode(b"puppy"))));
+    assert!(dot.contains(&format!("0x{}...\"", hex::encode([0xab; 8]))));
+    assert!(!dot.contains("label=\"...\""));
+
+    // depth 0 keeps only the root and elides its child
+    let shallow = merkle.to_dot(0);
+    assert_eq!(shallow.matches("->").count(), 1, "{shallow}");
+    assert!(shallow.contains("label=\"...\""));
+    assert!(!shallow.contains("0x"));
+}
+
+#[test]
+fn merkle_overwrites_key_that_prefixes_another() {
+    let mut merkle = new_merkle(Arc::new(Mutex::new(MemStore::new())), 0);
+    merkle.insert(b"ab", Value::new(vec![1], Vec::new()));
+    merkle.insert(b"abc", Value::new(vec![2], Vec::new()));
+    merkle.insert(b"ab", Value::new(vec![3], Vec::new()));
+    assert_eq!(merkle.find(b"ab").unwrap().value, vec![3]);
+    assert_eq!(merkle.find(b"abc").unwrap().value, vec![2]);
+}
+
+#[test]
+fn merkle_reports_corrupt_nodes_as_errors() {
+    let shared = Arc::new(Mutex::new(MemStore::new()));
+    let mut store = NodeStore::new(
+        Box::new(SharedMemBackend(shared.clone())),
+        0,
+        None,
+        Arc::new(Keccak256Hasher),
+    );
+    let vptr = store.add_node(Node(NodeType::Value(Value::new(vec![1], Vec::new()))));
+    let short = |path: Vec<u8>| {
+        Node(NodeType::Short(Short::new(
+            path,
+            Child::Ptr(NodePtr::Clean(vptr)),
+        )))
+    };
+    // a value node before the terminator of [0x12]
+    let early = store.add_node(short(vec![1, 2]));
+    // a short node running past the terminator
+    let late = store.add_node(short(vec![1, 2, NBRANCH as u8, 3]));
+    store.flush();
+    drop(store);
+
+    let mut merkle = new_merkle(shared.clone(), early);
+    let err = merkle.try_find(&[0x12]).err().unwrap();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    assert!(err.to_string().contains("corrupt trie"), "{err}");
+    assert!(
+        merkle
+            .try_insert(&[0x12], Value::new(vec![2], Vec::new()))
+            .is_err()
+    );
+    // keys off the corrupt path still work
+    merkle.insert(&[0x34], Value::new(vec![3], Vec::new()));
+    assert_eq!(merkle.find(&[0x34]).unwrap().value, vec![3]);
+
+    let mut merkle = new_merkle(shared.clone(), late);
+    assert!(merkle.try_find(&[0x12]).unwrap().is_none());
+    assert!(
+        merkle
+            .try_insert(&[0x12], Value::new(vec![2], Vec::new()))
+            .is_err()
+    );
+
+    // a root past the end of the store
+    let tail = shared.lock().unwrap().tail() as super::super::CleanPtr;
+    let mut merkle = new_merkle(shared, tail + 100);
+    assert!(merkle.try_find(&[0x12]).is_err());
+    assert!(
+        merkle
+            .try_insert(&[0x12], Value::new(vec![2], Vec::new()))
+            .is_err()
+    );
+}
+
+#[test]
+fn merkle_replace_returns_the_previous_value() {
+    let mut merkle = new_merkle(Arc::new(Mutex::new(MemStore::new())), 0);
+    let val = |v: u8| Value::new(vec![v], vec![v, v]);
+    let raw = |v: Option<Value>| v.map(|v| (v.value, v.extra));
+    assert_eq!(raw(merkle.replace(b"dog", val(1))), None);
+    // an uncommitted previous value
+    assert_eq!(raw(merkle.replace(b"dog", val(2))), raw(Some(val(1))));
+    // "do" ends at a branch, "dog" below a short node
+    assert_eq!(raw(merkle.replace(b"do", val(3))), None);
+    merkle.commit();
+
+    // committed previous values are read back
+    assert_eq!(raw(merkle.replace(b"dog", val(4))), raw(Some(val(2))));
+    assert_eq!(raw(merkle.replace(b"do", val(5))), raw(Some(val(3))));
+    assert_eq!(raw(merkle.replace(b"doe", val(6))), None);
+    assert_eq!(raw(merkle.find(b"dog")), raw(Some(val(4))));
+    assert_eq!(raw(merkle.find(b"do")), raw(Some(val(5))));
+    assert_eq!(raw(merkle.find(b"doe")), raw(Some(val(6))));
+}
+
+#[test]
+fn merkle_subtrie_roots_split_at_depth() {
+    let mut merkle = new_merkle(Arc::new(Mutex::new(MemStore::new())), 0);
+    assert!(merkle.subtrie_roots(1).is_empty());
+    let val = |v: &[u8]| Value::new(v.to_vec(), Vec::new());
+    merkle.insert(&[0x12, 0x34], val(b"a"));
+    merkle.insert(&[0x12, 0x56], val(b"b"));
+    merkle.insert(&[0x78, 0x9a], val(b"c"));
+    merkle.insert(&[0x12], val(b"d"));
+    merkle.commit();
+    let leaf = |v: &[u8]| rlp::encode(&v).to_vec();
+    let short = |path: Vec<u8>, v: &[u8]| {
+        Short::new(path, Child::Hash(0, leaf(v)))
+            .calc_hash(&Keccak256Hasher)
+            .unwrap()
+    };
+    let prefixes = |roots: &[(Vec<u8>, Vec<u8>)]| -> Vec<Vec<u8>> {
+        roots.iter().map(|(prefix, _)| prefix.clone()).collect()
+    };
+
+    let root_ref = rlp::encode(&merkle.hash().as_slice()).to_vec();
+    assert_eq!(merkle.subtrie_roots(0), vec![(vec![], root_ref.clone())]);
+
+    // The root branch rebuilt from the depth-1 references hashes to the root.
+    let roots = merkle.subtrie_roots(1);
+    assert_eq!(prefixes(&roots), vec![vec![1], vec![7]]);
+    let mut branch = crate::merkle::node::Branch::new();
+    for (prefix, reference) in roots {
+        branch.children[prefix[0] as usize] = Some(Child::Hash(0, reference));
+    }
+    assert_eq!(branch.calc_hash(&Keccak256Hasher).unwrap(), root_ref);
+
+    // 0x12 ends at depth 3; the short node above "c" is split.
+    let roots = merkle.subtrie_roots(3);
+    assert_eq!(
+        roots,
+        vec![
+            (vec![1, 2, 16], leaf(b"d")),
+            (vec![1, 2, 3], short(vec![4, 16], b"a")),
+            (vec![1, 2, 5], short(vec![6, 16], b"b")),
+            (vec![7, 8, 9], short(vec![10, 16], b"c")),
+        ]
+    );
+
+    // Past every key, each value is reported at its own path.
+    assert_eq!(
+        merkle.subtrie_roots(5),
+        vec![
+            (vec![1, 2, 16], leaf(b"d")),
+            (vec![1, 2, 3, 4, 16], leaf(b"a")),
+            (vec![1, 2, 5, 6, 16], leaf(b"b")),
+            (vec![7, 8, 9, 10, 16], leaf(b"c")),
+        ]
+    );
+}
+
+#[test]
+fn merkle_range_proof_verifies_complete_ranges_only() {
+    let mut merkle = new_merkle(Arc::new(Mutex::new(MemStore::new())), 0);
+    let key = |i: u16| i.to_be_bytes().to_vec();
+    let empty = Keccak256Hasher.empty_node_hash();
+    let (entries, proof) = merkle.range_proof(&key(0), &key(10));
+    assert!(entries.is_empty());
+    assert!(verify_range_proof(&Keccak256Hasher, &empty, &key(0), &key(10), &[], &proof).is_ok());
+
+    // Short values leave small leaves embedded in their parents; every fifth
+    // value is long enough to be hashed. Key [0x01] sits in a value slot.
+    for i in (0..900).step_by(3) {
+        let len = if i % 5 == 0 { 40 } else { 1 };
+        merkle.insert(&key(i), Value::new(vec![i as u8; len], Vec::new()));
+    }
+    merkle.insert(&[0x01], Value::new(b"prefix".to_vec(), Vec::new()));
+    merkle.commit();
+    let root = merkle.hash();
+    let check = |start: &[u8], end: &[u8], entries: &[(Vec<u8>, Vec<u8>)], proof: &[Vec<u8>]| {
+        verify_range_proof(&Keccak256Hasher, &root, start, end, entries, proof).is_ok()
+    };
+    let prove = |start: &[u8], end: &[u8]| {
+        let (entries, proof) = merkle.range_proof(start, end);
+        let entries: Vec<(Vec<u8>, Vec<u8>)> =
+            entries.into_iter().map(|(k, v)| (k, v.value)).collect();
+        (entries, proof)
+    };
+
+    for (start, end) in [
+        (key(30), key(300)),
+        (key(31), key(301)),
+        (vec![], vec![0xff; 3]),
+        (vec![0x01], key(0x0110)),
+        (key(2000), key(3000)),
+    ] {
+        let (entries, proof) = prove(&start, &end);
+        let expected: Vec<Vec<u8>> = merkle
+            .iter()
+            .map(|(k, _)| k)
+            .filter(|k| (start.as_slice()..=end.as_slice()).contains(&k.as_slice()))
+            .collect();
+        assert_eq!(
+            entries.iter().map(|(k, _)| k.clone()).collect::<Vec<_>>(),
+            expected
+        );
+        assert!(check(&start, &end, &entries, &proof));
+    }
+
+    let (start, end) = (key(30), key(300));
+    let (entries, proof) = prove(&start, &end);
+    // 91 multiples of three, and [0x01]
+    assert_eq!(entries.len(), 92);
+
+    let mut missing = entries.clone();
+    missing.remove(40);
+    assert!(!check(&start, &end, &missing, &proof));
+
+    let mut altered = entries.clone();
+    altered[10].1.push(0);
+    assert!(!check(&start, &end, &altered, &proof));
+
+    let mut extra = entries.clone();
+    extra.insert(1, (key(34), b"x".to_vec()));
+    assert!(!check(&start, &end, &extra, &proof));
+
+    // Claiming nothing lies in a non-empty range fails too.
+    let (_, empty_proof) = prove(&key(2000), &key(3000));
+    assert!(!check(&start, &end, &[], &empty_proof));
+    assert!(!check(&start, &end, &entries, &proof[..1]));
+}
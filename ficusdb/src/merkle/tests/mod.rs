@@ -1,5 +1,5 @@
 mod aha_tests;
 mod eth_merkle;
 mod hash_tests;
-mod memstore;
 mod merkle_tests;
+mod path_tests;
@@ -0,0 +1,61 @@
+use crate::merkle::path::NibblePath;
+
+#[test]
+fn nibble_path_round_trips_keys_with_a_terminator() {
+    let path = NibblePath::from_key(&[0x3a, 0x0f]);
+    assert!(path.is_terminal());
+    assert_eq!(path.nibbles(), &[3, 0xa, 0, 0xf]);
+    assert_eq!(path.as_slice(), &[3, 0xa, 0, 0xf, 16]);
+    assert_eq!(path.to_key(), Some(vec![0x3a, 0x0f]));
+    assert_eq!(path.to_string(), "3a0fT");
+    assert_eq!(format!("{path:?}"), "NibblePath(3a0fT)");
+
+    let empty = NibblePath::from_key(&[]);
+    assert!(empty.is_terminal());
+    assert_eq!(empty.to_key(), Some(Vec::new()));
+    assert_eq!(empty.to_string(), "T");
+}
+
+#[test]
+fn nibble_path_compact_encoding_covers_odd_and_even_lengths() {
+    // (nibbles, hex-prefix encoding) per the Ethereum yellow paper
+    let cases: [(&[u8], &[u8]); 6] = [
+        (&[1, 2, 3, 4, 5], &[0x11, 0x23, 0x45]),
+        (&[0, 1, 2, 3, 4, 5], &[0x00, 0x01, 0x23, 0x45]),
+        (&[0, 0xf, 1, 0xc, 0xb, 8, 16], &[0x20, 0x0f, 0x1c, 0xb8]),
+        (&[0xf, 1, 0xc, 0xb, 8, 16], &[0x3f, 0x1c, 0xb8]),
+        (&[], &[0x00]),
+        (&[16], &[0x20]),
+    ];
+    for (nibbles, compact) in cases {
+        let path = NibblePath::from_nibbles(nibbles.to_vec());
+        assert_eq!(path.to_compact(), compact, "{path}");
+        assert_eq!(NibblePath::from_compact(compact), path);
+    }
+
+    let odd = NibblePath::from_nibbles(vec![1, 2, 3, 16]);
+    assert!(odd.is_terminal());
+    assert_eq!(odd.to_key(), None);
+    let extension = NibblePath::from_nibbles(vec![1, 2]);
+    assert!(!extension.is_terminal());
+    assert_eq!(extension.to_key(), Some(vec![0x12]));
+    assert_eq!(extension.to_string(), "12");
+}
+
+#[test]
+fn nibble_path_common_prefix_stops_at_the_terminator() {
+    let a = NibblePath::from_key(&[0x12, 0x34]);
+    let b = NibblePath::from_key(&[0x12, 0x35]);
+    assert_eq!(a.common_prefix(&b), 3);
+    assert_eq!(a.common_prefix(&a), 5);
+    let prefix = NibblePath::from_nibbles(vec![1, 2, 3, 4]);
+    assert_eq!(a.common_prefix(&prefix), 4);
+    assert_eq!(prefix.common_prefix(&a), 4);
+}
+
+#[test]
+fn nibble_path_rejects_inner_terminators() {
+    let err = std::panic::catch_unwind(|| NibblePath::from_nibbles(vec![1, 16, 2])).unwrap_err();
+    let msg = err.downcast_ref::<&str>().unwrap();
+    assert!(msg.contains("nibbles are below 16"), "{msg}");
+}
@@ -78,3 +78,33 @@ pub fn from_compact(compact: &[u8]) -> Vec<u8> {
     let head = 2 - (nibbles[0] & 1) as usize;
     nibbles[head..].to_vec()
 }
+
+/// Longest LEB128 encoding of a `u64`.
+pub const MAX_VARINT_LEN: usize = 10;
+
+/// Append `v` to `buf` as an unsigned LEB128 varint.
+pub fn encode_varint(mut v: u64, buf: &mut Vec<u8>) {
+    while v >= 0x80 {
+        buf.push((v as u8) | 0x80);
+        v >>= 7;
+    }
+    buf.push(v as u8);
+}
+
+/// Decode an unsigned LEB128 varint from the front of `buf`, returning the
+/// value and the number of bytes consumed. `None` if `buf` ends before the
+/// varint does or the value overflows a `u64`.
+pub fn decode_varint(buf: &[u8]) -> Option<(u64, usize)> {
+    let mut v = 0u64;
+    for (i, b) in buf.iter().take(MAX_VARINT_LEN).enumerate() {
+        let bits = (*b & 0x7f) as u64;
+        if i == MAX_VARINT_LEN - 1 && bits > 1 {
+            return None;
+        }
+        v |= bits << (7 * i);
+        if b & 0x80 == 0 {
+            return Some((v, i + 1));
+        }
+    }
+    None
+}
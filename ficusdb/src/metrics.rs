@@ -0,0 +1,49 @@
+use crate::backend::SyncMode;
+use std::time::Duration;
+
+/// Hook for exporting storage counters, e.g. to Prometheus.
+///
+/// Every method defaults to a no-op, so an implementation only overrides the
+/// events it cares about. Set through `DBConfig::metrics` or
+/// `StateDBConfig::metrics`; when unset, nothing is called.
+pub trait Metrics: Send + Sync {
+    /// A node was read and decoded from the node file.
+    fn on_node_read(&self, _bytes: usize) {}
+
+    /// A node was appended to the node file.
+    fn on_node_write(&self, _bytes: usize) {}
+
+    /// A node lookup was served by the clean node cache.
+    fn on_cache_hit(&self) {}
+
+    /// A node lookup missed the clean node cache.
+    fn on_cache_miss(&self) {}
+
+    /// A file page was served from the page cache.
+    fn on_page_hit(&self) {}
+
+    /// A file page was loaded from disk.
+    fn on_page_miss(&self) {}
+
+    /// Dirty pages of a file were written back.
+    fn on_flush(&self, _dur: Duration) {}
+
+    /// A file was synced to stable storage with `mode`, which is never
+    /// `SyncMode::None`.
+    fn on_sync(&self, _mode: SyncMode, _dur: Duration) {}
+
+    /// A trie was committed to a new root, taking `dur`; committing a trie
+    /// without changes is not reported. A `StateDB` commit reports each
+    /// changed storage trie as well as the account trie, and since storage
+    /// tries hash in parallel, their durations overlap.
+    fn on_commit(&self, _dur: Duration) {}
+
+    /// A branch had more child hashes than the largest AHA tier holds, so
+    /// none were stored in the AHA and loading them reads every child.
+    fn on_aha_overflow(&self) {}
+
+    /// A branch's AHA array was unreadable or did not reproduce the branch
+    /// hash, so its child hashes were read from the children instead. This
+    /// points at corruption or a bug; see `DBConfig::strict_aha`.
+    fn on_aha_mismatch(&self) {}
+}
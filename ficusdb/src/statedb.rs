@@ -1,19 +1,32 @@
 #![allow(dead_code)]
 use crate::backend::PageCachedFile;
-use crate::merkle::{AggregatedHashArray, Backend, CleanPtr, Merkle, NodeStore, Value};
+use crate::merkle::{
+    AggregatedHashArray, Backend, CachePolicy, CleanPtr, Hasher, Keccak256Hasher, Merkle,
+    NodeStore, NodeView, Value, check_node_header,
+};
+use crate::metrics::Metrics;
 use lru_mem::{HeapSize, LruCache};
 use num_bigint::BigUint;
+use rayon::prelude::*;
 use rlp::{Decodable, DecoderError, Encodable, Rlp, RlpStream};
-use sha3::{Digest, Keccak256};
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
 use std::sync::{Arc, Mutex};
 use typed_builder::TypedBuilder;
 
 #[cfg(feature = "stats")]
 use crate::stats::StateDBStats;
-#[cfg(feature = "stats")]
 use std::time::Instant;
 
+/// (address, storage key) of a storage slot.
+type SlotKey = (Vec<u8>, Vec<u8>);
+
+/// An address, or one of its storage slots, in the access list.
+type AccessKey = (Vec<u8>, Option<Vec<u8>>);
+
+/// Storage tries kept for reads between commits before starting over.
+const STORAGE_TRIE_CACHE_LEN: usize = 1024;
+
 #[derive(TypedBuilder)]
 pub struct StateDBConfig {
     #[builder(default = false)]
@@ -28,6 +41,52 @@ pub struct StateDBConfig {
     pub aha_lens: Vec<u8>,
     #[builder(default = 16 * 1024 * 1024)]
     pub obj_cache_size: usize,
+    #[builder(default = Arc::new(Keccak256Hasher))]
+    pub hasher: Arc<dyn Hasher>,
+    /// Receives node, cache, page and commit events. Unset by default.
+    #[builder(default, setter(strip_option))]
+    pub metrics: Option<Arc<dyn Metrics>>,
+    /// Delete touched accounts that are empty (EIP-161) on commit.
+    #[builder(default = false)]
+    pub prune_empty: bool,
+    /// Take raw addresses and storage keys and hash them with `hasher`
+    /// into trie keys, as Ethereum's secure trie does. Off by default:
+    /// callers pass keys that are already hashed. Addresses returned from
+    /// the trie, as by `account_diff`, are trie keys either way.
+    #[builder(default = false)]
+    pub secure: bool,
+    /// How copy-on-write uses the clean-node cache; see `CachePolicy`.
+    #[builder(default)]
+    pub cache_policy: CachePolicy,
+    /// Let any write to an address without an account, even
+    /// `add_balance(addr, 0)`, create the account, as Ethereum did before
+    /// EIP-161. When off, such an account reads as absent and is only
+    /// written once a write makes it non-empty or `create_account` creates
+    /// it. `prune_empty` implies off. Off by default, as on Ethereum since
+    /// EIP-161; turn it on to replay blocks from before it.
+    #[builder(default = false)]
+    pub create_on_touch: bool,
+    /// Once the staged storage writes of accounts that aren't removed
+    /// exceed this many bytes, a `set_state` commits the storage tries of
+    /// every dirty account to new roots, keeping the accounts themselves
+    /// dirty until `commit` writes the account trie. The flush applies all
+    /// of those writes, so the next one waits for this many bytes again.
+    /// Dirty account records don't count, however many there are; bound
+    /// them with `pending_accounts`. `revert` still undoes flushed writes,
+    /// but `get_committed_state` then reads them as committed. 0, the
+    /// default, disables the limit.
+    #[builder(default = 0)]
+    pub max_dirty_bytes: usize,
+}
+
+/// The trie key of an address or storage key: its hash when `secure`,
+/// else the key itself.
+fn trie_key<'a>(secure: bool, hasher: &dyn Hasher, key: &'a [u8]) -> Cow<'a, [u8]> {
+    if secure {
+        Cow::Owned(hasher.digest(key))
+    } else {
+        Cow::Borrowed(key)
+    }
 }
 
 #[derive(Clone)]
@@ -45,16 +104,64 @@ impl HeapSize for Account {
 }
 
 impl Account {
-    fn new() -> Self {
+    fn new(hasher: &dyn Hasher) -> Self {
         Self {
             nonce: 0,
             balance: BigUint::from_bytes_be(&[0]),
-            roothash: Keccak256::digest(&[0x80u8]).to_vec(),
-            codehash: Keccak256::digest(b"").to_vec(),
+            roothash: hasher.empty_node_hash(),
+            codehash: hasher.digest(b""),
+        }
+    }
+
+    /// No nonce, balance, code or storage, as defined by EIP-161.
+    fn is_empty(&self, hasher: &dyn Hasher) -> bool {
+        self.nonce == 0
+            && self.balance == BigUint::from_bytes_be(&[0])
+            && self.codehash == hasher.digest(b"")
+            && self.roothash == hasher.empty_node_hash()
+    }
+}
+
+/// A point-in-time copy of an account's fields.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AccountInfo {
+    pub nonce: u64,
+    pub balance: BigUint,
+    pub roothash: Vec<u8>,
+    pub codehash: Vec<u8>,
+}
+
+impl From<&Account> for AccountInfo {
+    fn from(account: &Account) -> Self {
+        Self {
+            nonce: account.nonce,
+            balance: account.balance.clone(),
+            roothash: account.roothash.clone(),
+            codehash: account.codehash.clone(),
         }
     }
 }
 
+/// An account to create with `StateDB::apply_genesis`.
+#[derive(Debug, Clone, Default)]
+pub struct GenesisAccount {
+    pub balance: BigUint,
+    pub nonce: u64,
+    pub code: Vec<u8>,
+    /// Initial storage slots, written as with `set_state`.
+    pub storage: Option<HashMap<Vec<u8>, Vec<u8>>>,
+}
+
+/// How an account differs between two roots. `None` means the account does
+/// not exist on that side.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AccountChange {
+    pub addr: Vec<u8>,
+    pub old: Option<AccountInfo>,
+    pub new: Option<AccountInfo>,
+    pub storage_changed: bool,
+}
+
 impl Encodable for Account {
     fn rlp_append(&self, s: &mut RlpStream) {
         let balance = if self.balance > BigUint::from_bytes_be(&[0]) {
@@ -82,12 +189,33 @@ impl Decodable for Account {
     }
 }
 
+/// Returned by `StateDB::try_sub_balance` when the balance is too low.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InsufficientBalance {
+    pub balance: BigUint,
+    pub amount: BigUint,
+}
+
+impl std::fmt::Display for InsufficientBalance {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "insufficient balance: have {}, need {}",
+            self.balance, self.amount
+        )
+    }
+}
+
+impl std::error::Error for InsufficientBalance {}
+
 #[derive(Clone)]
 struct StateObject {
     account: Account,
     rootptr: CleanPtr,
     state_dirty: HashMap<Vec<u8>, Vec<u8>>,
     deleted: bool,
+    // in the trie, or made by `create_account`; see `is_phantom`
+    exists: bool,
 }
 
 impl StateObject {
@@ -97,17 +225,60 @@ impl StateObject {
             rootptr,
             state_dirty: HashMap::new(),
             deleted: false,
+            exists: true,
+        }
+    }
+
+    /// An empty account for an address that is not in the trie, made dirty
+    /// by a write such as `add_balance(addr, 0)`.
+    fn touched(account: Account) -> Self {
+        Self {
+            exists: false,
+            ..Self::new(account, 0)
         }
     }
 
-    fn set_state(&mut self, key: &[u8], val: &[u8]) {
-        self.state_dirty.insert(key.to_vec(), val.to_vec());
+    /// Touched without existing and still empty: such an account reads as
+    /// absent and is not written on commit. Its storage starts empty, so
+    /// staged deletions, as from `set_state(addr, key, &[])`, leave it so.
+    fn is_phantom(&self, hasher: &dyn Hasher) -> bool {
+        !self.exists
+            && self.state_dirty.values().all(|v| v.is_empty())
+            && self.account.is_empty(hasher)
+    }
+
+    /// Stage a storage write, returning the value it replaces in
+    /// `state_dirty`.
+    fn set_state(&mut self, key: &[u8], val: &[u8]) -> Option<Vec<u8>> {
+        self.state_dirty.insert(key.to_vec(), val.to_vec())
+    }
+
+    /// Bytes held by the dirty account at `addr`: its key, the account and
+    /// its staged storage writes.
+    fn dirty_bytes(&self, addr: &[u8]) -> usize {
+        addr.len() + self.heap_size() + self.staged_bytes()
+    }
+
+    fn staged_bytes(&self) -> usize {
+        self.state_dirty
+            .iter()
+            .map(|(k, v)| k.len() + v.len())
+            .sum()
+    }
+
+    /// Staged storage bytes a flush would apply: none for a removed
+    /// account, whose storage writes commit drops.
+    fn flushable_bytes(&self) -> usize {
+        if self.deleted { 0 } else { self.staged_bytes() }
     }
 }
 
 impl HeapSize for StateObject {
     fn heap_size(&self) -> usize {
-        self.account.heap_size() + self.rootptr.heap_size() + self.deleted.heap_size()
+        self.account.heap_size()
+            + self.rootptr.heap_size()
+            + self.deleted.heap_size()
+            + self.exists.heap_size()
     }
 }
 
@@ -158,6 +329,18 @@ impl StateDBRoots {
         self.roots.get(root_hash).cloned()
     }
 
+    fn len(&self) -> usize {
+        (self.root_file.tail() / 40) as usize
+    }
+
+    fn get_version_ptr(&mut self, version: usize) -> Option<CleanPtr> {
+        if version >= self.len() {
+            return None;
+        }
+        let buf = self.root_file.read(version as u64 * 40 + 32, 8);
+        Some(CleanPtr::from_le_bytes(buf.try_into().unwrap()))
+    }
+
     fn add_root_ptr(&mut self, root_hash: Vec<u8>, cptr: CleanPtr) {
         let mut buf = root_hash.clone();
         buf.resize(32, 0);
@@ -167,17 +350,167 @@ impl StateDBRoots {
         self.root_file.flush();
         let _ = self.roots.insert(root_hash.clone(), cptr);
     }
+
+    /// Drop every record after the latest one for `cptr`. Returns false if
+    /// there is none.
+    fn truncate_to(&mut self, cptr: CleanPtr) -> bool {
+        let Some(version) = (0..self.len())
+            .rev()
+            .find(|&version| self.get_version_ptr(version) == Some(cptr))
+        else {
+            return false;
+        };
+        self.root_file.truncate((version as u64 + 1) * 40);
+        // hashes of dropped roots must no longer resolve
+        self.roots.clear();
+        self.cur_cptr = self.root_file.tail();
+        true
+    }
+
+    fn flush(&mut self) {
+        self.root_file.flush();
+    }
+}
+
+/// Contract code blobs, content-addressed by codehash.
+///
+/// The code file is a sequence of records
+/// `[hash_len: u8][hash][code_len: u32 LE][code]`; the index from codehash
+/// to the record's code bytes is rebuilt by scanning the headers on open.
+struct CodeStore {
+    code_file: PageCachedFile,
+    index: HashMap<Vec<u8>, (u64, usize)>,
+}
+
+impl CodeStore {
+    fn new(mut code_file: PageCachedFile) -> Self {
+        let mut index = HashMap::new();
+        let mut ptr = 0;
+        while ptr < code_file.tail() {
+            let hash_len = code_file.read(ptr, 1)[0] as u64;
+            let hash = code_file.read(ptr + 1, hash_len as usize);
+            let buf = code_file.read(ptr + 1 + hash_len, 4);
+            let code_len = u32::from_le_bytes(buf.try_into().unwrap()) as usize;
+            let code_ptr = ptr + 1 + hash_len + 4;
+            index.insert(hash, (code_ptr, code_len));
+            ptr = code_ptr + code_len as u64;
+        }
+        Self { code_file, index }
+    }
+
+    fn get(&mut self, codehash: &[u8]) -> Option<Vec<u8>> {
+        let (ptr, len) = *self.index.get(codehash)?;
+        Some(self.code_file.read(ptr, len))
+    }
+
+    fn put(&mut self, codehash: Vec<u8>, code: &[u8]) {
+        if self.index.contains_key(&codehash) {
+            return;
+        }
+        let mut buf = Vec::with_capacity(1 + codehash.len() + 4 + code.len());
+        buf.push(codehash.len() as u8);
+        buf.extend(&codehash);
+        buf.extend(&(code.len() as u32).to_le_bytes());
+        buf.extend(code);
+        let tail = self.code_file.tail();
+        self.code_file.write(tail, &buf);
+        let code_ptr = tail + buf.len() as u64 - code.len() as u64;
+        self.index.insert(codehash, (code_ptr, code.len()));
+    }
+
+    fn flush(&mut self) {
+        self.code_file.flush();
+    }
+}
+
+/// A read-only view of the state at one committed root.
+///
+/// The view reads through its own `Merkle` over the shared node store, so
+/// reads need only `&self`, touch none of the `StateDB`'s caches and can run
+/// on another thread while the `StateDB` keeps executing and committing.
+/// Only committed data is visible: uncommitted changes of the `StateDB` are
+/// not, and neither are later commits.
+pub struct StateReadView {
+    store: Arc<Mutex<NodeStore>>,
+    merkle: Merkle,
+    hasher: Arc<dyn Hasher>,
+    secure: bool,
+}
+
+impl StateReadView {
+    /// The committed root this view reads.
+    pub fn root(&self) -> CleanPtr {
+        self.merkle.root_cptr()
+    }
+
+    fn account(&self, addr: &[u8]) -> Option<(Account, CleanPtr)> {
+        let val = self
+            .merkle
+            .find(&trie_key(self.secure, self.hasher.as_ref(), addr))?;
+        Some((
+            rlp::decode(&val.value).unwrap(),
+            rlp::decode(&val.extra).unwrap(),
+        ))
+    }
+
+    pub fn get_balance(&self, addr: &[u8]) -> BigUint {
+        self.account(addr)
+            .map_or_else(|| BigUint::from(0u32), |(account, _)| account.balance)
+    }
+
+    pub fn get_nonce(&self, addr: &[u8]) -> u64 {
+        self.account(addr).map_or(0, |(account, _)| account.nonce)
+    }
+
+    /// The stored value of a storage slot, as `StateDB::get_committed_state`
+    /// returns it.
+    pub fn get_state(&self, addr: &[u8], key: &[u8]) -> Vec<u8> {
+        let Some((_, rootptr)) = self.account(addr) else {
+            return Vec::new();
+        };
+        Merkle::new(self.store.clone(), rootptr)
+            .find(&trie_key(self.secure, self.hasher.as_ref(), key))
+            .map(|v| v.value)
+            .unwrap_or_default()
+    }
 }
 
 pub struct StateDB {
     roots: StateDBRoots,
+    code: CodeStore,
     store: Arc<Mutex<NodeStore>>,
     merkle: Arc<Mutex<Merkle>>,
+    // hash of the committed root; cleared when the root changes
+    root_hash: Mutex<Option<Vec<u8>>>,
 
     obj_clean: LruCache<Vec<u8>, StateObject>,
     obj_dirty: HashMap<Vec<u8>, StateObject>,
+    // sum of `StateObject::dirty_bytes` over `obj_dirty`
+    dirty_bytes: usize,
+    // sum of `StateObject::flushable_bytes` over `obj_dirty`, which
+    // `max_dirty_bytes` bounds
+    flushable_bytes: usize,
+    max_dirty_bytes: usize,
+    // storage flushed by `max_dirty_bytes` since the last commit
+    storage_flushed: bool,
     state_clean: LruCache<Vec<u8>, Vec<u8>>,
+    // storage tries read since the last commit, with their roots pinned
+    storage_tries: HashMap<Vec<u8>, Merkle>,
     deltas: Vec<HashMap<Vec<u8>, Option<StateObject>>>,
+    // EIP-1153 transient storage; never committed
+    transient: HashMap<SlotKey, Vec<u8>>,
+    // prior transient values per snapshot, indexed like `deltas`
+    transient_deltas: Vec<HashMap<SlotKey, Option<Vec<u8>>>>,
+    // EIP-2929 access list of the current transaction
+    warm_addrs: HashSet<Vec<u8>>,
+    warm_slots: HashSet<SlotKey>,
+    // entries first warmed after each snapshot, indexed like `deltas`
+    access_deltas: Vec<Vec<AccessKey>>,
+    hasher: Arc<dyn Hasher>,
+    prune_empty: bool,
+    // `StateDBConfig::create_on_touch`, off under `prune_empty`
+    create_on_touch: bool,
+    secure: bool,
     #[cfg(feature = "stats")]
     stats: Arc<Mutex<StateDBStats>>,
 }
@@ -189,40 +522,73 @@ impl StateDB {
         }
         let _ = std::fs::create_dir_all(path);
         let node_path = format!("{}/node", path);
-        let node_file = PageCachedFile::new(&node_path, cfg.page_cache_size);
+        let mut node_file = PageCachedFile::new(&node_path, cfg.page_cache_size);
+        node_file.set_metrics(cfg.metrics.clone());
         let aha = if cfg.aha_lens.is_empty() {
             None
         } else {
             let mut ahas: Vec<(u8, Box<dyn Backend>)> = Vec::new();
             for len in cfg.aha_lens {
                 let aha_path = format!("{}/aha_{}", path, len);
-                let aha_file = PageCachedFile::new(&aha_path, cfg.aha_cache_size);
+                let mut aha_file = PageCachedFile::new(&aha_path, cfg.aha_cache_size);
+                aha_file.set_metrics(cfg.metrics.clone());
                 ahas.push((len, Box::new(aha_file)));
             }
-            Some(AggregatedHashArray::new(ahas))
+            let free_path = format!("{}/aha_free", path);
+            let free_file = PageCachedFile::new(&free_path, cfg.aha_cache_size);
+            Some(
+                AggregatedHashArray::new(ahas, cfg.hasher.output_len())
+                    .with_recycle_store(Box::new(free_file)),
+            )
         };
+        let mut node_backend: Box<dyn Backend> = Box::new(node_file);
+        check_node_header(&mut node_backend).unwrap_or_else(|e| panic!("{}: {}", node_path, e));
         let node_store = Arc::new(Mutex::new(NodeStore::new(
-            Box::new(node_file),
+            node_backend,
             cfg.cache_size,
             aha,
+            cfg.hasher.clone(),
         )));
+        node_store.lock().unwrap().set_metrics(cfg.metrics);
+        node_store
+            .lock()
+            .unwrap()
+            .set_cache_policy(cfg.cache_policy);
 
         let root_path = format!("{}/root", path);
         let root_file = PageCachedFile::new(&root_path, cfg.aha_cache_size);
         let (roots, root_cptr) = StateDBRoots::new(root_file, cfg.aha_cache_size / 1024);
         let merkle = Merkle::new(node_store.clone(), root_cptr);
+        let code_path = format!("{}/code", path);
+        let code = CodeStore::new(PageCachedFile::new(&code_path, cfg.page_cache_size));
         let obj_clean = LruCache::new(cfg.obj_cache_size);
         let obj_dirty = HashMap::new();
         let state_clean = LruCache::new(cfg.obj_cache_size);
         let deltas = Vec::new();
         Self {
             roots,
+            code,
             store: node_store,
             merkle: Arc::new(Mutex::new(merkle)),
+            root_hash: Mutex::new(None),
             obj_clean,
             obj_dirty,
+            dirty_bytes: 0,
+            flushable_bytes: 0,
+            max_dirty_bytes: cfg.max_dirty_bytes,
+            storage_flushed: false,
             state_clean,
+            storage_tries: HashMap::new(),
             deltas,
+            transient: HashMap::new(),
+            transient_deltas: Vec::new(),
+            warm_addrs: HashSet::new(),
+            warm_slots: HashSet::new(),
+            access_deltas: Vec::new(),
+            hasher: cfg.hasher,
+            prune_empty: cfg.prune_empty,
+            create_on_touch: cfg.create_on_touch && !cfg.prune_empty,
+            secure: cfg.secure,
             #[cfg(feature = "stats")]
             stats: Arc::new(Mutex::new(StateDBStats::new())),
         }
@@ -234,10 +600,15 @@ impl StateDB {
             return;
         }
         *self.merkle.lock().unwrap() = Merkle::new(self.store.clone(), root);
+        *self.root_hash.lock().unwrap() = None;
         self.obj_clean.clear();
         self.obj_dirty.clear();
+        self.dirty_bytes = 0;
+        self.flushable_bytes = 0;
+        self.storage_flushed = false;
         self.state_clean.clear();
-        self.deltas.clear();
+        self.storage_tries.clear();
+        self.finalise();
     }
 
     pub fn open_root_hash(&mut self, root_hash: &Vec<u8>) {
@@ -249,13 +620,101 @@ impl StateDB {
         }
     }
 
+    /// A read-only view of the current committed root. See
+    /// `StateReadView`.
+    pub fn read_view(&self) -> StateReadView {
+        let root = self.merkle.lock().unwrap().root_cptr();
+        StateReadView {
+            store: self.store.clone(),
+            merkle: Merkle::new(self.store.clone(), root),
+            hasher: self.hasher.clone(),
+            secure: self.secure,
+        }
+    }
+
+    /// Number of accounts with uncommitted changes, for deciding when to
+    /// commit before buffered changes grow too large.
+    pub fn pending_accounts(&self) -> usize {
+        self.obj_dirty.len()
+    }
+
+    /// Approximate memory held by uncommitted changes: the dirty accounts
+    /// and their staged storage keys and values. Only the storage part is
+    /// bounded, by `StateDBConfig::max_dirty_bytes` when set.
+    pub fn pending_bytes(&self) -> usize {
+        self.dirty_bytes
+    }
+
+    /// Drop every version committed after the latest one at `root` and
+    /// switch to it, discarding uncommitted changes. Returns false, changing
+    /// nothing, if `root` was never committed. Nodes of the dropped versions
+    /// stay in the node file, unreferenced.
+    pub fn rollback_to(&mut self, root: CleanPtr) -> bool {
+        if !self.roots.truncate_to(root) {
+            return false;
+        }
+        self.reset();
+        self.open_root(root);
+        true
+    }
+
+    /// Number of roots published by commits so far.
+    pub fn version_count(&self) -> usize {
+        self.roots.len()
+    }
+
+    /// Open the root of the `version`-th commit (0-based). Returns the root
+    /// opened, or `None` if there is no such version.
+    pub fn open_version(&mut self, version: usize) -> Option<CleanPtr> {
+        let cptr = self.roots.get_version_ptr(version)?;
+        self.open_root(cptr);
+        Some(cptr)
+    }
+
+    /// Open the root committed `n` versions before the latest one; `n = 0`
+    /// is the latest.
+    pub fn open_version_from_tip(&mut self, n: usize) -> Option<CleanPtr> {
+        let version = self.version_count().checked_sub(n + 1)?;
+        self.open_version(version)
+    }
+
+    /// Load the given accounts, and the root nodes of their storage tries,
+    /// into the caches ahead of use. A hint only: results are unchanged.
+    pub fn prefetch(&mut self, addrs: &[&[u8]]) {
+        let mut rootptrs = Vec::new();
+        {
+            let merkle = self.merkle.lock().unwrap();
+            for addr in addrs {
+                if self.obj_dirty.contains_key(*addr) || self.obj_clean.contains(*addr) {
+                    continue;
+                }
+                if let Some(val) = merkle.find(&self.trie_key(addr)) {
+                    let obj = StateObject::new(
+                        rlp::decode(&val.value).unwrap(),
+                        rlp::decode(&val.extra).unwrap(),
+                    );
+                    rootptrs.push(obj.rootptr);
+                    let _ = self.obj_clean.insert(addr.to_vec(), obj);
+                }
+            }
+        }
+        let mut store = self.store.lock().unwrap();
+        for rootptr in rootptrs.into_iter().filter(|&ptr| ptr != 0) {
+            let _ = store.try_get_clean(rootptr);
+        }
+    }
+
+    fn trie_key<'a>(&self, key: &'a [u8]) -> Cow<'a, [u8]> {
+        trie_key(self.secure, self.hasher.as_ref(), key)
+    }
+
     fn get_obj(&mut self, addr: &[u8]) -> Option<&StateObject> {
         match self.obj_dirty.get(addr) {
-            Some(obj) => Some(obj),
+            Some(obj) => (!obj.is_phantom(self.hasher.as_ref())).then_some(obj),
             None => {
                 if !self.obj_clean.contains(addr) {
                     let merkle = self.merkle.lock().unwrap();
-                    if let Some(val) = merkle.find(addr) {
+                    if let Some(val) = merkle.find(&self.trie_key(addr)) {
                         let _ = self.obj_clean.insert(
                             addr.to_vec(),
                             StateObject::new(
@@ -270,13 +729,22 @@ impl StateDB {
         }
     }
 
+    /// Move the account into `obj_dirty` for mutation. The first time an
+    /// account is touched within the innermost snapshot, its prior dirty
+    /// state (`None` if it was not dirty) is recorded so `revert` can
+    /// restore it. An address that is not in the trie gets an empty
+    /// account, which without `create_on_touch` commit drops unless a write
+    /// makes it non-empty.
     fn ensure_dirty_obj(&mut self, addr: &[u8]) -> &mut StateObject {
         if !self.obj_dirty.contains_key(addr) {
+            if let Some(delta) = self.deltas.last_mut() {
+                delta.entry(addr.to_vec()).or_insert(None);
+            }
             let obj = match self.obj_clean.remove(addr) {
                 Some(obj) => Some(obj),
                 None => {
                     let merkle = self.merkle.lock().unwrap();
-                    if let Some(val) = merkle.find(addr) {
+                    if let Some(val) = merkle.find(&self.trie_key(addr)) {
                         Some(StateObject::new(
                             rlp::decode(&val.value).unwrap(),
                             rlp::decode(&val.extra).unwrap(),
@@ -286,25 +754,23 @@ impl StateDB {
                     }
                 }
             };
-            match obj {
-                Some(obj) => {
-                    if let Some(delta) = self.deltas.last_mut() {
-                        delta.entry(addr.to_vec()).or_insert(Some(obj.clone()));
-                    }
-                    self.obj_dirty.insert(addr.to_vec(), obj);
+            let obj = obj.unwrap_or_else(|| {
+                let account = Account::new(self.hasher.as_ref());
+                if self.create_on_touch {
+                    StateObject::new(account, 0)
+                } else {
+                    StateObject::touched(account)
                 }
-                None => {
-                    if let Some(delta) = self.deltas.last_mut() {
-                        delta.entry(addr.to_vec()).or_insert(None);
-                    }
-                    self.obj_dirty
-                        .insert(addr.to_vec(), StateObject::new(Account::new(), 0));
-                }
-            }
+            });
+            self.dirty_bytes += obj.dirty_bytes(addr);
+            self.flushable_bytes += obj.flushable_bytes();
+            self.obj_dirty.insert(addr.to_vec(), obj);
         }
         let obj = self.obj_dirty.get_mut(addr).unwrap();
         if let Some(delta) = self.deltas.last_mut() {
-            delta.entry(addr.to_vec()).or_insert(Some(obj.clone()));
+            delta
+                .entry(addr.to_vec())
+                .or_insert_with(|| Some(obj.clone()));
         }
         obj
     }
@@ -314,10 +780,55 @@ impl StateDB {
         obj.account.balance += amount;
     }
 
+    /// Subtract `amount` from the balance, leaving it unchanged when the
+    /// balance is too low. Use `try_sub_balance` to detect that case.
     pub fn sub_balance(&mut self, addr: &[u8], amount: BigUint) {
+        let _ = self.try_sub_balance(addr, amount);
+    }
+
+    /// Subtract `amount` from the balance, failing if the balance is lower
+    /// than `amount`. The account is touched either way.
+    pub fn try_sub_balance(
+        &mut self,
+        addr: &[u8],
+        amount: BigUint,
+    ) -> Result<(), InsufficientBalance> {
         let obj = self.ensure_dirty_obj(addr);
-        if amount <= obj.account.balance {
-            obj.account.balance -= amount;
+        if amount > obj.account.balance {
+            return Err(InsufficientBalance {
+                balance: obj.account.balance.clone(),
+                amount,
+            });
+        }
+        obj.account.balance -= amount;
+        Ok(())
+    }
+
+    /// Apply signed balance changes in order: positive amounts are added,
+    /// negative ones subtracted, saturating at zero. Each account is made
+    /// dirty, and recorded for `revert`, once however often it appears.
+    pub fn apply_balance_deltas(&mut self, deltas: &[(Vec<u8>, i128)]) {
+        self.ensure_dirty_objs(deltas.iter().map(|(addr, _)| addr));
+        for (addr, delta) in deltas {
+            let balance = &mut self.obj_dirty.get_mut(addr).unwrap().account.balance;
+            let amount = BigUint::from(delta.unsigned_abs());
+            if *delta >= 0 {
+                *balance += amount;
+            } else if amount > *balance {
+                *balance = BigUint::from(0u8);
+            } else {
+                *balance -= amount;
+            }
+        }
+    }
+
+    /// `ensure_dirty_obj` for each distinct address in `addrs`.
+    fn ensure_dirty_objs<'a>(&mut self, addrs: impl Iterator<Item = &'a Vec<u8>>) {
+        let mut seen = HashSet::new();
+        for addr in addrs {
+            if seen.insert(addr) {
+                self.ensure_dirty_obj(addr);
+            }
         }
     }
 
@@ -328,11 +839,82 @@ impl StateDB {
         }
     }
 
+    /// All fields of the account, or `None` if it does not exist or has
+    /// been removed.
+    pub fn get_account(&mut self, addr: &[u8]) -> Option<AccountInfo> {
+        match self.get_obj(addr) {
+            Some(obj) if !obj.deleted => Some(AccountInfo::from(&obj.account)),
+            _ => None,
+        }
+    }
+
+    /// Describe the committed node at `ptr`, in the account trie or in a
+    /// storage trie. `None` for an unreadable pointer. A root of 0 is the
+    /// empty trie and should not be inspected.
+    pub fn inspect_node(&self, ptr: CleanPtr) -> Option<NodeView> {
+        self.store.lock().unwrap().inspect_node(ptr)
+    }
+
+    /// Accounts that differ between two committed roots, in ascending
+    /// address order.
+    pub fn account_diff(&mut self, old_root: CleanPtr, new_root: CleanPtr) -> Vec<AccountChange> {
+        let decode = |val: Option<Value>| {
+            val.map(|val| AccountInfo::from(&rlp::decode::<Account>(&val.value).unwrap()))
+        };
+        // a missing account has empty storage
+        let empty_root = self.hasher.empty_node_hash();
+        let storage_root = |info: &Option<AccountInfo>| {
+            info.as_ref()
+                .map_or(empty_root.clone(), |info| info.roothash.clone())
+        };
+        let merkle = self.merkle.lock().unwrap();
+        merkle
+            .diff(old_root, new_root)
+            .into_iter()
+            .map(|(addr, old, new)| {
+                let (old, new) = (decode(old), decode(new));
+                let storage_changed = storage_root(&old) != storage_root(&new);
+                AccountChange {
+                    addr,
+                    old,
+                    new,
+                    storage_changed,
+                }
+            })
+            .collect()
+    }
+
+    /// Addresses whose account differs between `base_root` and the current
+    /// root, in ascending order, for a follower that fetches the accounts
+    /// itself. Like `account_diff`, subtrees with the same reference hash
+    /// are skipped, but accounts are not decoded, and an account whose
+    /// storage trie only moved to other nodes is not reported.
+    pub fn changed_accounts_since(&mut self, base_root: CleanPtr) -> Vec<Vec<u8>> {
+        let merkle = self.merkle.lock().unwrap();
+        merkle
+            .diff(base_root, merkle.root_cptr())
+            .into_iter()
+            .filter(|(_, old, new)| {
+                old.as_ref().map(|v| &v.value) != new.as_ref().map(|v| &v.value)
+            })
+            .map(|(addr, _, _)| addr)
+            .collect()
+    }
+
     pub fn set_nonce(&mut self, addr: &[u8], nonce: u64) {
         let obj = self.ensure_dirty_obj(addr);
         obj.account.nonce = nonce;
     }
 
+    /// `set_nonce` for each pair in order, making each account dirty once
+    /// like `apply_balance_deltas`.
+    pub fn set_nonces(&mut self, nonces: &[(Vec<u8>, u64)]) {
+        self.ensure_dirty_objs(nonces.iter().map(|(addr, _)| addr));
+        for (addr, nonce) in nonces {
+            self.obj_dirty.get_mut(addr).unwrap().account.nonce = *nonce;
+        }
+    }
+
     pub fn get_nonce(&mut self, addr: &[u8]) -> u64 {
         match self.get_obj(addr) {
             Some(obj) => obj.account.nonce,
@@ -352,12 +934,145 @@ impl StateDB {
         }
     }
 
+    /// Store `code` for the account and set its codehash. Identical code is
+    /// stored once and shared between accounts.
+    pub fn set_code(&mut self, addr: &[u8], code: Vec<u8>) {
+        let codehash = self.hasher.digest(&code);
+        self.code.put(codehash.clone(), &code);
+        self.set_codehash(addr, codehash);
+    }
+
+    /// The account's code, or empty if it has none or it was never stored
+    /// via `set_code`.
+    pub fn get_code(&mut self, addr: &[u8]) -> Vec<u8> {
+        let codehash = self.get_codehash(addr);
+        self.code.get(&codehash).unwrap_or_default()
+    }
+
     pub fn set_state(&mut self, addr: &[u8], key: &[u8], val: &[u8]) {
         let obj = self.ensure_dirty_obj(addr);
-        obj.set_state(key, val);
+        let deleted = obj.deleted;
+        let old = obj.set_state(key, val);
+        let replaced = old.map_or(0, |old| key.len() + old.len());
+        self.dirty_bytes = self.dirty_bytes + key.len() + val.len() - replaced;
+        if !deleted {
+            self.flushable_bytes = self.flushable_bytes + key.len() + val.len() - replaced;
+        }
+        if self.max_dirty_bytes > 0 && self.flushable_bytes > self.max_dirty_bytes {
+            self.commit_storage(false);
+            self.storage_flushed = true;
+        }
     }
 
+    /// The current value of a storage slot, including uncommitted writes.
     pub fn get_state(&mut self, addr: &[u8], key: &[u8]) -> Vec<u8> {
+        if let Some(val) = self
+            .obj_dirty
+            .get(addr)
+            .and_then(|obj| obj.state_dirty.get(key))
+        {
+            // stored leaves are RLP(value_bytes); an empty value is a deletion
+            return if val.is_empty() {
+                Vec::new()
+            } else {
+                rlp::encode(val).to_vec()
+            };
+        }
+        self.get_committed_state(addr, key)
+    }
+
+    /// `get_state` for several slots of one account. The account is resolved
+    /// once and every slot missing from the caches is read from the same
+    /// storage trie.
+    pub fn get_states(&mut self, addr: &[u8], keys: &[&[u8]]) -> Vec<Vec<u8>> {
+        let rootptr = self.get_obj(addr).map(|obj| obj.rootptr);
+        let mut vals = Vec::with_capacity(keys.len());
+        for key in keys {
+            if let Some(val) = self
+                .obj_dirty
+                .get(addr)
+                .and_then(|obj| obj.state_dirty.get(*key))
+            {
+                vals.push(if val.is_empty() {
+                    Vec::new()
+                } else {
+                    rlp::encode(val).to_vec()
+                });
+                continue;
+            }
+            let ckey = [addr, key].concat();
+            if let Some(val) = self.state_clean.get(&ckey) {
+                vals.push(val.to_vec());
+                continue;
+            }
+            let Some(rootptr) = rootptr else {
+                vals.push(Vec::new());
+                continue;
+            };
+            let tkey = self.trie_key(key);
+            let val = self
+                .storage_trie(addr, rootptr)
+                .find(&tkey)
+                .map(|v| v.value)
+                .unwrap_or_default();
+            let _ = self.state_clean.insert(ckey, val.clone());
+            vals.push(val);
+        }
+        vals
+    }
+
+    /// Write a transient storage slot (EIP-1153 `TSTORE`). Transient slots
+    /// are journaled like regular storage, so `revert` undoes them, but they
+    /// never reach the trie and are wiped by `finalise`. An empty value
+    /// clears the slot.
+    pub fn set_transient(&mut self, addr: &[u8], key: &[u8], val: &[u8]) {
+        let tkey = (addr.to_vec(), key.to_vec());
+        if let Some(delta) = self.transient_deltas.last_mut() {
+            delta
+                .entry(tkey.clone())
+                .or_insert_with(|| self.transient.get(&tkey).cloned());
+        }
+        if val.is_empty() {
+            self.transient.remove(&tkey);
+        } else {
+            self.transient.insert(tkey, val.to_vec());
+        }
+    }
+
+    /// Read a transient storage slot (EIP-1153 `TLOAD`); empty if unset.
+    pub fn get_transient(&mut self, addr: &[u8], key: &[u8]) -> Vec<u8> {
+        self.transient
+            .get(&(addr.to_vec(), key.to_vec()))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Record an access to `addr`, or to its storage slot `key`, for EIP-2929
+    /// gas accounting. Returns whether it was already warm in this
+    /// transaction. Addresses and slots are tracked separately: warming a
+    /// slot does not warm its address. `revert` un-warms what was first
+    /// accessed after the snapshot, and `finalise` clears everything. Nothing
+    /// here touches the trie.
+    pub fn mark_access(&mut self, addr: &[u8], key: Option<&[u8]>) -> bool {
+        let newly = match key {
+            None => self.warm_addrs.insert(addr.to_vec()),
+            Some(key) => self.warm_slots.insert((addr.to_vec(), key.to_vec())),
+        };
+        if newly && let Some(delta) = self.access_deltas.last_mut() {
+            delta.push((addr.to_vec(), key.map(|k| k.to_vec())));
+        }
+        !newly
+    }
+
+    fn clear_access_list(&mut self) {
+        self.warm_addrs.clear();
+        self.warm_slots.clear();
+        self.access_deltas.clear();
+    }
+
+    /// The value of a storage slot as of the last commit, ignoring pending
+    /// writes.
+    pub fn get_committed_state(&mut self, addr: &[u8], key: &[u8]) -> Vec<u8> {
         let ckey = [addr, key].concat();
         if !self.state_clean.contains(&ckey) {
             let rootptr = if let Some(obj) = self.get_obj(addr) {
@@ -365,42 +1080,119 @@ impl StateDB {
             } else {
                 return Vec::new();
             };
-            let subtree = Merkle::new(self.store.clone(), rootptr);
-            let val = subtree.find(key).map(|v| v.value).unwrap_or_default();
+            let tkey = self.trie_key(key);
+            let val = self
+                .storage_trie(addr, rootptr)
+                .find(&tkey)
+                .map(|v| v.value)
+                .unwrap_or_default();
             let _ = self.state_clean.insert(ckey.to_vec(), val);
         }
         self.state_clean.get(&ckey).unwrap().to_vec()
     }
 
+    /// The committed storage trie of `addr` at `rootptr`, kept across reads
+    /// of its slots until the account's storage root changes or the next
+    /// commit.
+    fn storage_trie(&mut self, addr: &[u8], rootptr: CleanPtr) -> &Merkle {
+        if self
+            .storage_tries
+            .get(addr)
+            .is_none_or(|trie| trie.root_cptr() != rootptr)
+        {
+            if self.storage_tries.len() >= STORAGE_TRIE_CACHE_LEN {
+                self.storage_tries.clear();
+            }
+            let mut trie = Merkle::new(self.store.clone(), rootptr);
+            trie.pin_root();
+            self.storage_tries.insert(addr.to_vec(), trie);
+        }
+        &self.storage_tries[addr]
+    }
+
+    /// The account's storage root hash including pending writes, without
+    /// committing them. Accounts with no storage (or no account at all) have
+    /// the empty trie hash.
+    pub fn storage_root(&mut self, addr: &[u8]) -> Vec<u8> {
+        let empty_root = self.hasher.empty_node_hash();
+        let store = self.store.clone();
+        let (secure, hasher) = (self.secure, self.hasher.clone());
+        let Some(obj) = self.get_obj(addr) else {
+            return empty_root;
+        };
+        if obj.deleted {
+            return empty_root;
+        }
+        if obj.state_dirty.is_empty() {
+            return obj.account.roothash.clone();
+        }
+        // Apply the writes as commit would, then hash the dirty nodes and
+        // drop them.
+        let mut subtree = Merkle::new(store, obj.rootptr);
+        for (key, val) in &obj.state_dirty {
+            let key = trie_key(secure, hasher.as_ref(), key);
+            if val.is_empty() {
+                subtree.delete(&key);
+            } else {
+                subtree.insert(&key, Value::new(rlp::encode(val).to_vec(), Vec::new()));
+            }
+        }
+        match subtree.prepare_commit() {
+            Some(mut pending) => {
+                pending.hash();
+                pending.root_hash()
+            }
+            None => empty_root,
+        }
+    }
+
+    /// Whether the account's storage root, including pending writes, is
+    /// `expected_storage_root`. Nothing is persisted; for pinning down a
+    /// storage root mismatch to a single account.
+    pub fn verify_account(&mut self, addr: &[u8], expected_storage_root: &[u8]) -> bool {
+        self.storage_root(addr) == expected_storage_root
+    }
+
     pub fn create_account(&mut self, addr: &[u8]) {
         self.ensure_dirty_obj(addr);
         let obj = self.obj_dirty.get_mut(addr).unwrap();
-        obj.account = Account::new();
+        obj.account = Account::new(self.hasher.as_ref());
+        self.flushable_bytes -= obj.flushable_bytes();
+        self.dirty_bytes -= obj.staged_bytes();
         obj.state_dirty.clear();
         obj.deleted = false;
+        obj.exists = true;
     }
 
     pub fn remove_account(&mut self, addr: &[u8]) {
-        if let Some(mut obj) = self.obj_dirty.remove(addr) {
-            obj.deleted = true;
-            obj.account.balance = BigUint::from_bytes_be(&[0]);
-            self.obj_dirty.insert(addr.to_vec(), obj);
+        if self.get_obj(addr).is_none() {
             return;
         }
-        if let Some(mut obj) = self.obj_clean.remove(addr) {
-            obj.deleted = true;
-            obj.account.balance = BigUint::from_bytes_be(&[0]);
-            self.obj_dirty.insert(addr.to_vec(), obj);
-            return;
+        let obj = self.ensure_dirty_obj(addr);
+        let flushable = obj.flushable_bytes();
+        obj.deleted = true;
+        obj.account.balance = BigUint::from_bytes_be(&[0]);
+        self.flushable_bytes -= flushable;
+    }
+
+    /// `remove_account` for each of `addrs`. The accounts leave the trie in
+    /// one batch at the next commit.
+    pub fn remove_accounts(&mut self, addrs: &[&[u8]]) {
+        for addr in addrs {
+            self.remove_account(addr);
         }
     }
 
     pub fn snapshot(&mut self) -> usize {
+        self.debug_assert_journals_aligned();
         self.deltas.push(HashMap::new());
+        self.transient_deltas.push(HashMap::new());
+        self.access_deltas.push(Vec::new());
         self.deltas.len() - 1
     }
 
     pub fn revert(&mut self, sid: usize) {
+        self.debug_assert_journals_aligned();
         for idx in (sid..self.deltas.len()).rev() {
             for (addr, obj) in self.deltas[idx].drain() {
                 match obj {
@@ -412,13 +1204,134 @@ impl StateDB {
                     }
                 };
             }
+            for (tkey, val) in self.transient_deltas[idx].drain() {
+                match val {
+                    Some(v) => {
+                        self.transient.insert(tkey, v);
+                    }
+                    None => {
+                        self.transient.remove(&tkey);
+                    }
+                };
+            }
+            for (addr, key) in self.access_deltas[idx].drain(..) {
+                match key {
+                    Some(key) => {
+                        self.warm_slots.remove(&(addr, key));
+                    }
+                    None => {
+                        self.warm_addrs.remove(&addr);
+                    }
+                };
+            }
+        }
+        self.recount_dirty_bytes();
+        // Slots read since a flush were cached from storage roots the
+        // reverted accounts may no longer have.
+        if self.storage_flushed {
+            self.state_clean.clear();
         }
     }
 
+    /// Snapshot ids index `deltas`, `transient_deltas` and `access_deltas`
+    /// alike.
+    fn debug_assert_journals_aligned(&self) {
+        debug_assert_eq!(self.transient_deltas.len(), self.deltas.len());
+        debug_assert_eq!(self.access_deltas.len(), self.deltas.len());
+    }
+
+    fn recount_dirty_bytes(&mut self) {
+        self.dirty_bytes = self
+            .obj_dirty
+            .iter()
+            .map(|(addr, obj)| obj.dirty_bytes(addr))
+            .sum();
+        self.flushable_bytes = self
+            .obj_dirty
+            .values()
+            .map(|obj| obj.flushable_bytes())
+            .sum();
+    }
+
     pub fn commit(&mut self) -> CleanPtr {
+        self.commit_with_hash().0
+    }
+
+    /// `commit`, also returning the new state root hash, which commit
+    /// computes anyway.
+    pub fn commit_with_hash(&mut self) -> (CleanPtr, Vec<u8>) {
         #[cfg(feature = "stats")]
         let timer = Instant::now();
+        self.commit_storage(true);
         let mut merkle = self.merkle.lock().unwrap();
+
+        #[cfg(feature = "stats")]
+        let merkle_write_timer = Instant::now();
+        let mut removed = Vec::new();
+        for (addr, mut obj) in self.obj_dirty.drain() {
+            if obj.is_phantom(self.hasher.as_ref()) {
+                continue;
+            }
+            if obj.deleted || (self.prune_empty && obj.account.is_empty(self.hasher.as_ref())) {
+                removed.push(trie_key(self.secure, self.hasher.as_ref(), &addr).into_owned());
+            } else {
+                let value = Value {
+                    value: rlp::encode(&obj.account).to_vec(),
+                    extra: rlp::encode(&obj.rootptr).to_vec(),
+                };
+                merkle.insert(&trie_key(self.secure, self.hasher.as_ref(), &addr), value);
+                assert!(obj.state_dirty.len() == 0);
+                obj.exists = true;
+                let _ = self.obj_clean.insert(addr, obj);
+            }
+        }
+        self.dirty_bytes = 0;
+        self.flushable_bytes = 0;
+        self.storage_flushed = false;
+        let removed: Vec<&[u8]> = removed.iter().map(Vec::as_slice).collect();
+        merkle.delete_batch(&removed);
+        #[cfg(feature = "stats")]
+        {
+            let mut stats = self.stats.lock().unwrap();
+            stats.t_merkle_write += merkle_write_timer.elapsed().as_secs_f64();
+        }
+        #[cfg(feature = "stats")]
+        let merkle_timer = Instant::now();
+        let cptr = merkle.commit();
+        #[cfg(feature = "stats")]
+        {
+            let mut stats = self.stats.lock().unwrap();
+            stats.t_merkle_commit += merkle_timer.elapsed().as_secs_f64();
+        }
+        self.storage_tries.clear();
+        // Code must be durable before a root referencing it is published.
+        self.code.flush();
+        let root_hash = merkle.hash();
+        self.roots.add_root_ptr(root_hash.clone(), cptr);
+        *self.root_hash.lock().unwrap() = Some(root_hash.clone());
+        self.store.lock().unwrap().flush();
+        #[cfg(feature = "stats")]
+        {
+            let mut stats = self.stats.lock().unwrap();
+            stats.t_commit += timer.elapsed().as_secs_f64();
+        }
+        drop(merkle);
+        self.finalise();
+        (cptr, root_hash)
+    }
+
+    /// Apply the staged storage writes of every dirty account to its
+    /// storage trie and commit it, moving the account to the new
+    /// `rootptr` and storage root. The account trie is left alone.
+    ///
+    /// The written slots go into `state_clean` if `cache_slots`. A flush
+    /// before `commit` drops them from it instead, since `revert` can still
+    /// move the account back to its old storage root.
+    fn commit_storage(&mut self, cache_slots: bool) {
+        // Storage writes go through the shared node store and are applied one
+        // account at a time; only hashing the independent storage tries runs
+        // in parallel, without holding the store lock.
+        let mut subtrees = Vec::new();
         for (addr, obj) in &mut self.obj_dirty {
             if obj.state_dirty.len() > 0 && !obj.deleted {
                 #[cfg(feature = "stats")]
@@ -427,10 +1340,15 @@ impl StateDB {
                 for (key, val) in obj.state_dirty.drain() {
                     let mut ckey = addr.to_vec();
                     ckey.extend(&key.to_vec());
+                    let key = trie_key(self.secure, self.hasher.as_ref(), &key);
                     if val.len() > 0 {
                         // Ethereum storage trie stores RLP(value_bytes) as the leaf value.
                         let enc = rlp::encode(&val).to_vec();
-                        let _ = self.state_clean.insert(ckey, enc.clone());
+                        if cache_slots {
+                            let _ = self.state_clean.insert(ckey, enc.clone());
+                        } else {
+                            self.state_clean.remove(&ckey);
+                        }
                         subtree.insert(&key, Value::new(enc, Vec::new()));
                     } else {
                         self.state_clean.remove(&ckey);
@@ -442,71 +1360,110 @@ impl StateDB {
                     let mut stats = self.stats.lock().unwrap();
                     stats.t_merkle_write += merkle_write_timer.elapsed().as_secs_f64();
                 }
-                #[cfg(feature = "stats")]
-                let merkle_timer = Instant::now();
-                let cptr = subtree.commit();
-                #[cfg(feature = "stats")]
-                {
-                    let mut stats = self.stats.lock().unwrap();
-                    stats.t_merkle_commit += merkle_timer.elapsed().as_secs_f64();
-                }
-                obj.rootptr = cptr;
-                let h = subtree.hash();
-                obj.account.roothash = h.as_slice().try_into().unwrap();
+                // Take the subtree's dirty nodes out of the store before the
+                // next account's writes start allocating dirty slots.
+                let started = Instant::now();
+                let pending = subtree.prepare_commit();
+                subtrees.push((addr.clone(), subtree, pending, started));
             }
         }
 
         #[cfg(feature = "stats")]
-        let merkle_write_timer = Instant::now();
-        for (addr, obj) in self.obj_dirty.drain() {
-            if obj.deleted {
-                merkle.delete(&addr);
-            } else {
-                let value = Value {
-                    value: rlp::encode(&obj.account).to_vec(),
-                    extra: rlp::encode(&obj.rootptr).to_vec(),
-                };
-                merkle.insert(&addr, value);
-                assert!(obj.state_dirty.len() == 0);
-                let _ = self.obj_clean.insert(addr, obj);
+        let merkle_timer = Instant::now();
+        subtrees.par_iter_mut().for_each(|(_, _, pending, _)| {
+            if let Some(pending) = pending {
+                pending.hash();
             }
+        });
+        for (addr, mut subtree, pending, started) in subtrees {
+            let cptr = match pending {
+                Some(pending) => subtree.finish_commit(pending),
+                // deleted to empty
+                None => {
+                    subtree.report_commit(started);
+                    subtree.root_cptr()
+                }
+            };
+            let obj = self.obj_dirty.get_mut(&addr).unwrap();
+            obj.rootptr = cptr;
+            let h = subtree.hash();
+            obj.account.roothash = h.as_slice().try_into().unwrap();
         }
         #[cfg(feature = "stats")]
-        {
-            let mut stats = self.stats.lock().unwrap();
-            stats.t_merkle_write += merkle_write_timer.elapsed().as_secs_f64();
-        }
-        #[cfg(feature = "stats")]
-        let merkle_timer = Instant::now();
-        let cptr = merkle.commit();
-        #[cfg(feature = "stats")]
         {
             let mut stats = self.stats.lock().unwrap();
             stats.t_merkle_commit += merkle_timer.elapsed().as_secs_f64();
         }
-        self.deltas.clear();
-        self.roots.add_root_ptr(merkle.hash(), cptr);
-        self.store.lock().unwrap().flush();
-        #[cfg(feature = "stats")]
-        {
-            let mut stats = self.stats.lock().unwrap();
-            stats.t_commit += timer.elapsed().as_secs_f64();
+
+        self.recount_dirty_bytes();
+    }
+
+    /// Create the given accounts and commit them as one block, returning
+    /// the new state root hash. Addresses are trie keys, as for the other
+    /// account methods. Every listed account is written, even an empty one,
+    /// whatever `create_on_touch` says.
+    pub fn apply_genesis(&mut self, alloc: &[(Vec<u8>, GenesisAccount)]) -> Vec<u8> {
+        for (addr, account) in alloc {
+            if self.get_account(addr).is_none() {
+                self.create_account(addr);
+            }
+            self.add_balance(addr, account.balance.clone());
+            self.set_nonce(addr, account.nonce);
+            self.set_code(addr, account.code.clone());
+            for (key, val) in account.storage.iter().flatten() {
+                self.set_state(addr, key, val);
+            }
         }
-        cptr
+        self.finalise();
+        self.commit_with_hash().1
     }
 
+    /// End the transaction: drop the revert journal, transient storage and
+    /// the access list. `commit` and `open_root` do this too, since
+    /// snapshots index all three journals alike.
     pub fn finalise(&mut self) {
         self.deltas.clear();
+        self.transient.clear();
+        self.transient_deltas.clear();
+        self.clear_access_list();
+    }
+
+    /// Abort the block: drop every uncommitted account and storage change,
+    /// along with what `finalise` drops, and go back to the last committed
+    /// state. Cached committed accounts and slots are kept, unless
+    /// `max_dirty_bytes` flushed storage since the commit. Code stored by
+    /// `set_code` stays in the code store, which is content-addressed.
+    pub fn reset(&mut self) {
+        self.merkle.lock().unwrap().discard();
+        self.obj_dirty.clear();
+        self.dirty_bytes = 0;
+        self.flushable_bytes = 0;
+        if std::mem::take(&mut self.storage_flushed) {
+            self.state_clean.clear();
+        }
+        self.finalise();
+    }
+
+    /// Write buffered pages of the code, node, AHA and root files to disk.
+    ///
+    /// `commit` already flushes what it writes; this is for a long-lived
+    /// `StateDB` that wants a checkpoint between commits, e.g. of code stored
+    /// by `set_code`. Uncommitted account and storage changes are not part
+    /// of any file until `commit` and stay in memory.
+    pub fn flush(&mut self) {
+        self.code.flush();
+        self.store.lock().unwrap().flush();
+        self.roots.flush();
     }
 
+    /// Hash of the committed root. Uncommitted changes are not included, so
+    /// the hash is computed once per root and kept until `commit` or
+    /// `open_root` moves to another one.
     pub fn hash(&self) -> Vec<u8> {
-        self.merkle
-            .lock()
-            .unwrap()
-            .hash()
-            .as_slice()
-            .try_into()
-            .unwrap()
+        let mut root_hash = self.root_hash.lock().unwrap();
+        root_hash
+            .get_or_insert_with(|| self.merkle.lock().unwrap().hash())
+            .clone()
     }
 
     #[cfg(feature = "stats")]
@@ -520,6 +1477,6 @@ impl StateDB {
 
 impl Drop for StateDB {
     fn drop(&mut self) {
-        self.store.lock().unwrap().flush();
+        self.flush();
     }
 }
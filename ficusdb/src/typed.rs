@@ -0,0 +1,87 @@
+use crate::db::{CommitError, DB, WriteBatch};
+use crate::merkle::CleanPtr;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::marker::PhantomData;
+
+/// A `DB` whose keys and values are serde types, stored as their bincode
+/// encodings.
+///
+/// This only encodes and decodes around the byte-level `DB`, so roots,
+/// `open_root` and snapshots behave exactly as on the raw bytes. Key order
+/// in the trie follows the encoded bytes, not `K`'s ordering.
+pub struct TypedDB<K, V> {
+    db: DB,
+    _types: PhantomData<fn(K) -> V>,
+}
+
+impl<K: Serialize, V: Serialize + DeserializeOwned> TypedDB<K, V> {
+    pub fn new(db: DB) -> Self {
+        Self {
+            db,
+            _types: PhantomData,
+        }
+    }
+
+    /// The underlying byte-level database, e.g. for `open_root`.
+    pub fn db(&mut self) -> &mut DB {
+        &mut self.db
+    }
+
+    pub fn into_inner(self) -> DB {
+        self.db
+    }
+
+    /// Panics if the stored bytes do not decode as `V`.
+    pub fn get(&mut self, k: &K) -> Option<V> {
+        self.db
+            .get(&encode(k))
+            .map(|v| bincode::deserialize(&v).expect("stored value does not decode"))
+    }
+
+    /// Write a single entry and commit it as a new root.
+    pub fn put(&mut self, k: &K, v: &V) -> CleanPtr {
+        let mut wb = self.new_writebatch();
+        wb.put(k, v);
+        wb.commit()
+            .expect("batch without compare_and_set cannot conflict")
+    }
+
+    pub fn new_writebatch(&self) -> TypedWriteBatch<K, V> {
+        TypedWriteBatch {
+            wb: self.db.new_writebatch(),
+            _types: PhantomData,
+        }
+    }
+}
+
+pub struct TypedWriteBatch<K, V> {
+    wb: WriteBatch,
+    _types: PhantomData<fn(K) -> V>,
+}
+
+impl<K: Serialize, V: Serialize> TypedWriteBatch<K, V> {
+    pub fn put(&mut self, k: &K, v: &V) {
+        self.wb.insert(&encode(k), &encode(v));
+    }
+
+    pub fn remove(&mut self, k: &K) {
+        self.wb.remove(&encode(k));
+    }
+
+    pub fn len(&self) -> usize {
+        self.wb.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.wb.is_empty()
+    }
+
+    pub fn commit(&mut self) -> Result<CleanPtr, CommitError> {
+        self.wb.commit()
+    }
+}
+
+fn encode<T: Serialize>(t: &T) -> Vec<u8> {
+    bincode::serialize(t).expect("value does not serialize")
+}
@@ -0,0 +1,111 @@
+use sha3::{Digest, Keccak256};
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::mem::size_of;
+use std::os::unix::fs::FileExt;
+
+use crate::backend::SyncMode;
+use crate::merkle::CleanPtr;
+
+// root_cptr, root file offset, node file tail, checksum; all u64 LE.
+const RECORD_SIZE: usize = 32;
+
+/// Single-record write-ahead log making root advances atomic.
+///
+/// Before a commit flushes anything, it logs the root it is about to
+/// publish, the root file offset it will be written at, and the node file
+/// length from before the commit. If the process dies before the root
+/// pointer is fully written, `recover` drops the torn root bytes and the
+/// nodes appended for the lost commit, so the DB reopens at the previous
+/// root. A record whose checksum does not match was itself torn, which means
+/// nothing else had been flushed yet.
+///
+/// The node file is cut back to its physical length, which is only a
+/// commit boundary when nodes are stored as appended bytes. An encrypted
+/// node file rewrites its last block in place, so `DB::try_open` refuses
+/// the WAL together with `encryption_key`.
+pub(crate) struct Wal {
+    file: File,
+    node_path: String,
+    root_path: String,
+}
+
+impl Wal {
+    pub fn open(path: &str) -> std::io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(format!("{}/wal", path))?;
+        Ok(Self {
+            file,
+            node_path: format!("{}/node", path),
+            root_path: format!("{}/root", path),
+        })
+    }
+
+    /// Log a commit of `root_cptr` at `root_offset` in the root file. Must be
+    /// called before the commit's nodes are flushed.
+    pub fn log(&mut self, root_cptr: CleanPtr, root_offset: u64) -> io::Result<()> {
+        let node_tail = std::fs::metadata(&self.node_path).map_or(0, |m| m.len());
+        let mut record = Vec::with_capacity(RECORD_SIZE);
+        record.extend(root_cptr.to_le_bytes());
+        record.extend(root_offset.to_le_bytes());
+        record.extend(node_tail.to_le_bytes());
+        record.extend(checksum(&record));
+        self.file.write_all_at(&record, 0)
+    }
+
+    pub fn sync(&self, mode: SyncMode) -> io::Result<()> {
+        mode.sync(&self.file)
+    }
+
+    /// Forget the logged commit, for when the root file is rewound past it
+    /// on purpose.
+    pub fn clear(&mut self) -> io::Result<()> {
+        self.file.set_len(0)
+    }
+
+    /// Roll back a commit that logged but did not finish writing its root.
+    /// Must run before the node and root files are opened.
+    pub fn recover(&mut self) -> io::Result<()> {
+        let mut record = [0u8; RECORD_SIZE];
+        if self.file.read_exact_at(&mut record, 0).is_err()
+            || checksum(&record[..24]) != record[24..]
+        {
+            return Ok(());
+        }
+        let root_cptr = &record[..8];
+        let root_offset = u64::from_le_bytes(record[8..16].try_into().unwrap());
+        let node_tail = u64::from_le_bytes(record[16..24].try_into().unwrap());
+
+        let root_file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&self.root_path)?;
+        let mut published = [0u8; size_of::<CleanPtr>()];
+        if root_file.read_exact_at(&mut published, root_offset).is_ok() && published == root_cptr {
+            return Ok(());
+        }
+        if root_file.metadata()?.len() > root_offset {
+            root_file.set_len(root_offset)?;
+        }
+        let node_file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&self.node_path)?;
+        if node_file.metadata()?.len() > node_tail {
+            node_file.set_len(node_tail)?;
+        }
+        // The record is resolved; don't let it touch the files again.
+        self.file.set_len(0)
+    }
+}
+
+fn checksum(data: &[u8]) -> [u8; 8] {
+    Keccak256::digest(data)[..8].try_into().unwrap()
+}
@@ -1,8 +1,16 @@
-use ficusdb::{DB, DBConfig};
+use ficusdb::{
+    CachePolicy, CommitError, DB, DBConfig, DbError, Hasher, Keccak256Hasher, Metrics,
+    NodeHeaderError, NodeView, OpenError, SyncMode,
+};
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::fs;
+use std::io::Write;
+use std::os::unix::fs::PermissionsExt;
 use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
 
 fn unique_temp_dir(name: &str) -> PathBuf {
     let mut p = std::env::temp_dir();
@@ -41,7 +49,7 @@ fn db_opens_latest_committed_root() {
         let mut wb = db.new_writebatch();
         wb.insert(b"a", b"1");
         wb.insert(b"b", b"2");
-        root1 = wb.commit();
+        root1 = wb.commit().unwrap();
     }
 
     // Reopen should automatically load the last root pointer and see the data.
@@ -74,17 +82,17 @@ fn db_can_open_historical_roots_and_cache_does_not_poison() {
         // Commit 1
         let mut wb = db.new_writebatch();
         wb.insert(b"k", b"v1");
-        root1 = wb.commit();
+        root1 = wb.commit().unwrap();
 
         // Commit 2: overwrite k
         let mut wb = db.new_writebatch();
         wb.insert(b"k", b"v2");
-        root2 = wb.commit();
+        root2 = wb.commit().unwrap();
 
         // Commit 3: add another key
         let mut wb = db.new_writebatch();
         wb.insert(b"x", b"xx");
-        root3 = wb.commit();
+        root3 = wb.commit().unwrap();
     }
 
     // Reopen and exercise historical lookups.
@@ -127,7 +135,7 @@ fn db_value_cache_eviction_does_not_change_results() {
     wb.insert(b"a", b"va");
     wb.insert(b"b", b"vb");
     wb.insert(b"c", b"vc");
-    let _ = wb.commit();
+    wb.commit().unwrap();
 
     // Interleave reads to force evictions; results must always be correct.
     assert_eq!(db.get(b"a"), Some(b"va".to_vec()));
@@ -217,7 +225,7 @@ fn db_fuzzy_large_scale_historical_and_latest() {
                 }
             }
 
-            let root = wb.commit();
+            let root = wb.commit().unwrap();
             roots.push(root);
             samples.push(touched);
         }
@@ -250,3 +258,2499 @@ fn db_fuzzy_large_scale_historical_and_latest() {
 
     let _ = fs::remove_dir_all(&dir);
 }
+
+#[test]
+fn db_snapshots_at_different_roots_coexist() {
+    let dir = unique_temp_dir("snapshot");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+
+    let mut db = DB::open(dir.to_str().unwrap(), default_cfg(true, 1024));
+    let mut wb = db.new_writebatch();
+    wb.insert(b"k", b"v1");
+    let root1 = wb.commit().unwrap();
+    let h1 = db.hash();
+
+    let mut wb = db.new_writebatch();
+    wb.insert(b"k", b"v2");
+    wb.insert(b"x", b"xx");
+    let root2 = wb.commit().unwrap();
+    let h2 = db.hash();
+
+    let snap1 = db.snapshot_at(root1);
+    let snap2 = db.snapshot_at(root2);
+
+    // Switching the DB's active root must not disturb existing snapshots.
+    db.open_root(root1);
+    assert_eq!(db.get(b"k"), Some(b"v1".to_vec()));
+    assert_eq!(snap2.get(b"k"), Some(b"v2".to_vec()));
+    assert_eq!(snap2.get(b"x"), Some(b"xx".to_vec()));
+    assert_eq!(snap1.get(b"x"), None);
+    assert_eq!(snap1.root_cptr(), root1);
+    assert_eq!(snap1.hash(), h1);
+    assert_eq!(snap2.hash(), h2);
+
+    // Snapshots can be read from other threads concurrently.
+    let handles: Vec<_> = [(snap1, b"v1".to_vec()), (snap2, b"v2".to_vec())]
+        .into_iter()
+        .map(|(snap, expected)| {
+            std::thread::spawn(move || {
+                for _ in 0..100 {
+                    assert_eq!(snap.get(b"k"), Some(expected.clone()));
+                }
+            })
+        })
+        .collect();
+    for h in handles {
+        h.join().unwrap();
+    }
+
+    drop(db);
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn db_scan_prefix_and_remove_prefix() {
+    let dir = unique_temp_dir("prefix");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+
+    let mut db = DB::open(dir.to_str().unwrap(), default_cfg(true, 1024));
+    let mut wb = db.new_writebatch();
+    wb.insert(b"ab", b"1");
+    wb.insert(b"abc", b"2");
+    wb.insert(b"abd", b"3");
+    wb.insert(b"xyz", b"4");
+    wb.commit().unwrap();
+
+    let kv = |k: &[u8], v: &[u8]| (k.to_vec(), v.to_vec());
+    let ab = vec![kv(b"ab", b"1"), kv(b"abc", b"2"), kv(b"abd", b"3")];
+    assert_eq!(db.scan_prefix(b"ab"), ab);
+    assert_eq!(db.scan_prefix(b"a"), ab);
+    assert_eq!(db.scan_prefix(b"abc"), vec![kv(b"abc", b"2")]);
+    assert_eq!(db.scan_prefix(b"x"), vec![kv(b"xyz", b"4")]);
+    assert_eq!(db.scan_prefix(b"abx"), Vec::new());
+    assert_eq!(db.scan_prefix(b"b"), Vec::new());
+    assert_eq!(db.scan_prefix(b"").len(), 4);
+
+    let mut wb = db.new_writebatch();
+    wb.remove_prefix(b"ab");
+    wb.commit().unwrap();
+    assert_eq!(db.get(b"ab"), None);
+    assert_eq!(db.get(b"abc"), None);
+    assert_eq!(db.get(b"abd"), None);
+    assert_eq!(db.get(b"xyz"), Some(b"4".to_vec()));
+    assert_eq!(db.scan_prefix(b""), vec![kv(b"xyz", b"4")]);
+
+    // The remaining trie must be identical to one that only ever held `xyz`.
+    let dir2 = unique_temp_dir("prefix-ref");
+    let db2 = DB::open(dir2.to_str().unwrap(), default_cfg(true, 1024));
+    let mut wb = db2.new_writebatch();
+    wb.insert(b"xyz", b"4");
+    wb.commit().unwrap();
+    assert_eq!(db.hash(), db2.hash());
+
+    drop(db);
+    drop(db2);
+    let _ = fs::remove_dir_all(&dir);
+    let _ = fs::remove_dir_all(&dir2);
+}
+
+#[test]
+fn db_value_cache_is_scoped_to_root() {
+    let dir = unique_temp_dir("cache-root");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+
+    let mut db = DB::open(dir.to_str().unwrap(), default_cfg(true, 1024));
+    let mut wb = db.new_writebatch();
+    wb.insert(b"k", b"v1");
+    let root1 = wb.commit().unwrap();
+    wb.insert(b"k", b"v2");
+    let root2 = wb.commit().unwrap();
+
+    // Stage a write while at root2, then switch away before committing it.
+    assert_eq!(db.get(b"k"), Some(b"v2".to_vec()));
+    let mut staged = db.new_writebatch();
+    staged.insert(b"k", b"v3");
+    db.open_root(root1);
+    assert_eq!(db.get(b"k"), Some(b"v1".to_vec()));
+
+    // The batch lands on top of root1; values cached at other roots must not
+    // be served at the new one, and vice versa.
+    let root3 = staged.commit().unwrap();
+    assert_eq!(db.get(b"k"), Some(b"v3".to_vec()));
+    for _ in 0..3 {
+        db.open_root(root1);
+        assert_eq!(db.get(b"k"), Some(b"v1".to_vec()));
+        db.open_root(root2);
+        assert_eq!(db.get(b"k"), Some(b"v2".to_vec()));
+        db.open_root(root3);
+        assert_eq!(db.get(b"k"), Some(b"v3".to_vec()));
+    }
+
+    drop(db);
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn db_get_or_insert_with_runs_closure_only_on_miss() {
+    let dir = unique_temp_dir("get-or-insert");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+
+    let mut db = DB::open(dir.to_str().unwrap(), default_cfg(true, 1024));
+    let mut wb = db.new_writebatch();
+    wb.insert(b"present", b"old");
+    let root = wb.commit().unwrap();
+
+    let mut calls = 0;
+    let mut compute = |v: &[u8]| {
+        calls += 1;
+        v.to_vec()
+    };
+    assert_eq!(
+        db.get_or_insert_with(b"present", || compute(b"new")),
+        b"old"
+    );
+    assert_eq!(
+        db.get_or_insert_with(b"missing", || compute(b"made")),
+        b"made"
+    );
+    assert_eq!(
+        db.get_or_insert_with(b"missing", || compute(b"again")),
+        b"made"
+    );
+    assert_eq!(calls, 1);
+
+    // The miss committed a new root; the old one is untouched.
+    assert_eq!(db.get(b"missing"), Some(b"made".to_vec()));
+    assert_eq!(db.snapshot_at(root).get(b"missing"), None);
+    drop(db);
+    let mut db = DB::open(dir.to_str().unwrap(), default_cfg(false, 1024));
+    assert_eq!(db.get(b"missing"), Some(b"made".to_vec()));
+
+    drop(db);
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn db_open_version_by_index() {
+    let dir = unique_temp_dir("open-version");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+
+    let mut roots = Vec::new();
+    {
+        let db = DB::open(dir.to_str().unwrap(), default_cfg(true, 1024));
+        for v in [b"v0", b"v1", b"v2"] {
+            let mut wb = db.new_writebatch();
+            wb.insert(b"k", v);
+            roots.push(wb.commit().unwrap());
+        }
+    }
+
+    let mut db = DB::open(dir.to_str().unwrap(), default_cfg(false, 1024));
+    assert_eq!(db.version_count(), 3);
+    assert_eq!(db.open_version(0), Some(roots[0]));
+    assert_eq!(db.get(b"k"), Some(b"v0".to_vec()));
+    assert_eq!(db.open_version_from_tip(1), Some(roots[1]));
+    assert_eq!(db.get(b"k"), Some(b"v1".to_vec()));
+    assert_eq!(db.open_version_from_tip(0), Some(roots[2]));
+    assert_eq!(db.get(b"k"), Some(b"v2".to_vec()));
+
+    // Out of range leaves the current root alone.
+    assert_eq!(db.open_version(3), None);
+    assert_eq!(db.open_version_from_tip(3), None);
+    assert_eq!(db.get(b"k"), Some(b"v2".to_vec()));
+
+    drop(db);
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn db_writebatch_len_and_clear() {
+    let dir = unique_temp_dir("wb-len");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+
+    let mut db = DB::open(dir.to_str().unwrap(), default_cfg(true, 1024));
+    let mut wb = db.new_writebatch();
+    assert!(wb.is_empty());
+    wb.insert(b"a", b"1");
+    wb.insert(b"b", b"2");
+    wb.insert(b"a", b"3");
+    wb.remove(b"c");
+    assert_eq!(wb.len(), 3);
+
+    wb.clear();
+    assert!(wb.is_empty());
+    assert_eq!(wb.staged_bytes(), 0);
+
+    // Still usable after clearing.
+    wb.insert(b"d", b"4");
+    assert_eq!(wb.len(), 1);
+    wb.commit().unwrap();
+    assert!(wb.is_empty());
+    assert_eq!(db.get(b"a"), None);
+    assert_eq!(db.get(b"d"), Some(b"4".to_vec()));
+
+    drop(db);
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn db_writebatch_auto_flushes_past_max_batch_bytes() {
+    let dir = unique_temp_dir("auto-flush");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+
+    let cfg = DBConfig::builder()
+        .truncate(true)
+        .cache_size(1024)
+        .page_cache_size(1 << 20)
+        .aha_cache_size(1 << 20)
+        .aha_lens(vec![])
+        .max_batch_bytes(64)
+        .build();
+    let mut db = DB::open(dir.to_str().unwrap(), cfg);
+    let mut wb = db.new_writebatch();
+    wb.insert(b"x", b"old");
+    assert_eq!(wb.staged_bytes(), 4);
+    wb.insert(b"x", b"older");
+    assert_eq!(wb.staged_bytes(), 6);
+
+    let mut expected = HashMap::new();
+    for i in 0..64u32 {
+        let key = format!("key-{i:03}").into_bytes();
+        wb.insert(&key, &i.to_le_bytes());
+        expected.insert(key, i.to_le_bytes().to_vec());
+        assert!(wb.staged_bytes() <= 64);
+    }
+    // `x` was applied to the trie by an auto-flush; the rewrite must win.
+    wb.insert(b"x", b"new");
+    let root = wb.commit().unwrap();
+    assert_eq!(wb.staged_bytes(), 0);
+
+    assert_eq!(db.get(b"x"), Some(b"new".to_vec()));
+    for (k, v) in &expected {
+        assert_eq!(db.get(k).as_ref(), Some(v));
+    }
+    assert_eq!(db.snapshot_at(root).get(b"x"), Some(b"new".to_vec()));
+
+    drop(db);
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[derive(Default)]
+struct CountingMetrics {
+    node_reads: AtomicUsize,
+    node_bytes_written: AtomicUsize,
+    cache_misses: AtomicUsize,
+    page_misses: AtomicUsize,
+    commits: AtomicUsize,
+    full_syncs: AtomicUsize,
+}
+
+impl Metrics for CountingMetrics {
+    fn on_node_read(&self, _bytes: usize) {
+        self.node_reads.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn on_node_write(&self, bytes: usize) {
+        self.node_bytes_written.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    fn on_cache_miss(&self) {
+        self.cache_misses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn on_page_miss(&self) {
+        self.page_misses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn on_commit(&self, _dur: Duration) {
+        self.commits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn on_sync(&self, mode: SyncMode, _dur: Duration) {
+        if mode == SyncMode::Full {
+            self.full_syncs.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+#[test]
+fn db_reports_to_metrics_hook() {
+    let dir = unique_temp_dir("metrics");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+
+    let metrics = Arc::new(CountingMetrics::default());
+    let cfg = || {
+        DBConfig::builder()
+            .cache_size(1024)
+            .page_cache_size(1 << 20)
+            .aha_cache_size(1 << 20)
+            .db_value_cache_size(0)
+            .metrics(metrics.clone())
+            .build()
+    };
+    {
+        let db = DB::open(dir.to_str().unwrap(), cfg());
+        let mut wb = db.new_writebatch();
+        for i in 0..100u32 {
+            wb.insert(&i.to_be_bytes(), b"value");
+        }
+        wb.commit().unwrap();
+        // Nothing changed, so there is no new root to report.
+        db.new_writebatch().commit().unwrap();
+    }
+    assert_eq!(metrics.commits.load(Ordering::Relaxed), 1);
+    let written = metrics.node_bytes_written.load(Ordering::Relaxed);
+    assert!(written > 0);
+    // The node file also holds its 16-byte format header.
+    assert_eq!(
+        written as u64 + 16,
+        fs::metadata(dir.join("node")).unwrap().len()
+    );
+
+    // A fresh handle has to load nodes through the page cache.
+    let mut db = DB::open(dir.to_str().unwrap(), cfg());
+    assert_eq!(db.get(&7u32.to_be_bytes()), Some(b"value".to_vec()));
+    assert!(metrics.node_reads.load(Ordering::Relaxed) > 0);
+    assert!(metrics.cache_misses.load(Ordering::Relaxed) > 0);
+    assert!(metrics.page_misses.load(Ordering::Relaxed) > 0);
+
+    // deleting every key commits the trie to empty
+    let mut wb = db.new_writebatch();
+    wb.remove_prefix(b"");
+    wb.commit().unwrap();
+    assert_eq!(db.len(), 0);
+    assert_eq!(metrics.commits.load(Ordering::Relaxed), 2);
+
+    drop(db);
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn db_prefetch_serves_later_gets_from_cache() {
+    let dir = unique_temp_dir("prefetch");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+
+    let keys: Vec<Vec<u8>> = (0..50u32).map(|i| i.to_be_bytes().to_vec()).collect();
+    {
+        let db = DB::open(dir.to_str().unwrap(), default_cfg(true, 1024));
+        let mut wb = db.new_writebatch();
+        for k in &keys {
+            wb.insert(k, k);
+        }
+        wb.commit().unwrap();
+    }
+
+    let metrics = Arc::new(CountingMetrics::default());
+    let cfg = DBConfig::builder()
+        .cache_size(1024)
+        .page_cache_size(1 << 20)
+        .aha_cache_size(1 << 20)
+        .aha_lens(vec![])
+        .metrics(metrics.clone())
+        .build();
+    let mut db = DB::open(dir.to_str().unwrap(), cfg);
+    let wanted: Vec<&[u8]> = keys[..10]
+        .iter()
+        .map(|k| k.as_slice())
+        .chain([b"missing".as_slice()])
+        .collect();
+    db.prefetch(&wanted);
+    let reads = metrics.node_reads.load(Ordering::Relaxed);
+    assert!(reads > 0);
+
+    for k in &keys[..10] {
+        assert_eq!(db.get(k), Some(k.clone()));
+    }
+    assert_eq!(db.get(b"missing"), None);
+    assert_eq!(metrics.node_reads.load(Ordering::Relaxed), reads);
+
+    drop(db);
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[cfg(feature = "compression")]
+#[test]
+fn db_compressed_node_file_resolves_after_reopen() {
+    let dir = unique_temp_dir("compressed");
+    let plain_dir = unique_temp_dir("compressed-plain");
+    let _ = fs::remove_dir_all(&dir);
+    let _ = fs::remove_dir_all(&plain_dir);
+    fs::create_dir_all(&dir).unwrap();
+    fs::create_dir_all(&plain_dir).unwrap();
+
+    let compressed_cfg = |truncate| {
+        DBConfig::builder()
+            .truncate(truncate)
+            .cache_size(1024)
+            .page_cache_size(1 << 20)
+            .aha_cache_size(1 << 20)
+            .db_value_cache_size(0)
+            .compression_level(3)
+            .build()
+    };
+    let plain = DB::open(plain_dir.to_str().unwrap(), default_cfg(true, 0));
+    let mut roots = Vec::new();
+    {
+        let db = DB::open(dir.to_str().unwrap(), compressed_cfg(true));
+        for round in 0..4u32 {
+            let mut wb = db.new_writebatch();
+            let mut plain_wb = plain.new_writebatch();
+            for i in 0..200u32 {
+                let k = i.wrapping_mul(2654435761).to_be_bytes();
+                let v = format!("value-{round}-{i}").into_bytes();
+                wb.insert(&k, &v);
+                plain_wb.insert(&k, &v);
+            }
+            roots.push(wb.commit().unwrap());
+            plain_wb.commit().unwrap();
+        }
+    }
+
+    let mut db = DB::open(dir.to_str().unwrap(), compressed_cfg(false));
+    assert_eq!(db.hash(), plain.hash());
+    for (round, root) in roots.iter().enumerate() {
+        db.open_root(*root);
+        for i in (0..200u32).step_by(17) {
+            let k = i.wrapping_mul(2654435761).to_be_bytes();
+            assert_eq!(db.get(&k), Some(format!("value-{round}-{i}").into_bytes()));
+        }
+    }
+
+    drop(db);
+    drop(plain);
+    let _ = fs::remove_dir_all(&dir);
+    let _ = fs::remove_dir_all(&plain_dir);
+}
+
+#[test]
+fn db_hasher_overrides_keccak_impl() {
+    struct CountingHasher(AtomicUsize);
+    impl Hasher for CountingHasher {
+        fn digest(&self, data: &[u8]) -> Vec<u8> {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            Keccak256Hasher.digest(data)
+        }
+    }
+    let dir = unique_temp_dir("hasher-override");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+
+    let db = DB::open(dir.to_str().unwrap(), default_cfg(true, 0));
+    assert_eq!(db.keccak_impl(), Some(ficusdb::KeccakImpl::Sha3));
+    drop(db);
+    let counting = Arc::new(CountingHasher(AtomicUsize::new(0)));
+    let mut cfg = default_cfg(true, 0);
+    cfg.hasher = Some(counting.clone());
+    let db = DB::open(dir.to_str().unwrap(), cfg);
+    assert_eq!(db.keccak_impl(), None);
+    let mut wb = db.new_writebatch();
+    wb.insert(b"k", b"v");
+    wb.commit().unwrap();
+    assert!(counting.0.load(Ordering::Relaxed) > 0);
+    assert_eq!(db.hash(), db.root_for(&[(b"k".to_vec(), b"v".to_vec())]));
+
+    drop(db);
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[cfg(feature = "tiny-keccak")]
+#[test]
+fn db_tiny_keccak_hashes_like_sha3() {
+    use ficusdb::KeccakImpl;
+    let dir = unique_temp_dir("tiny-keccak");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+
+    let tiny_cfg = |truncate| {
+        DBConfig::builder()
+            .truncate(truncate)
+            .cache_size(1024)
+            .page_cache_size(1 << 20)
+            .aha_cache_size(1 << 20)
+            .db_value_cache_size(0)
+            .keccak_impl(KeccakImpl::TinyKeccak)
+            .build()
+    };
+    let insert_round = |db: &DB, round: u32| {
+        let mut wb = db.new_writebatch();
+        for i in 0..200u32 {
+            let k = i.wrapping_mul(2654435761).to_be_bytes();
+            wb.insert(&k, format!("value-{round}-{i}").as_bytes());
+        }
+        wb.commit().unwrap();
+    };
+    let sha3_dir = unique_temp_dir("tiny-keccak-sha3");
+    let _ = fs::remove_dir_all(&sha3_dir);
+    fs::create_dir_all(&sha3_dir).unwrap();
+    let sha3 = DB::open(sha3_dir.to_str().unwrap(), default_cfg(true, 0));
+    insert_round(&sha3, 0);
+    insert_round(&sha3, 1);
+
+    {
+        let db = DB::open(dir.to_str().unwrap(), tiny_cfg(true));
+        insert_round(&db, 0);
+    }
+    // a DB written with one implementation opens with the other
+    let db = DB::open(dir.to_str().unwrap(), default_cfg(false, 0));
+    insert_round(&db, 1);
+    drop(db);
+    let db = DB::open(dir.to_str().unwrap(), tiny_cfg(false));
+    assert_eq!(db.hash(), sha3.hash());
+    assert_eq!(db.keccak_impl(), Some(KeccakImpl::TinyKeccak));
+    drop(db);
+    // the field is read when the DB opens, not when the config is built
+    let mut cfg = default_cfg(false, 0);
+    cfg.keccak_impl = KeccakImpl::TinyKeccak;
+    let db = DB::open(dir.to_str().unwrap(), cfg);
+    assert_eq!(db.keccak_impl(), Some(KeccakImpl::TinyKeccak));
+
+    drop(db);
+    drop(sha3);
+    let _ = fs::remove_dir_all(&dir);
+    let _ = fs::remove_dir_all(&sha3_dir);
+}
+
+#[cfg(feature = "encryption")]
+fn encrypted_cfg(truncate: bool, key: [u8; 32]) -> DBConfig {
+    DBConfig::builder()
+        .truncate(truncate)
+        .cache_size(1024)
+        .page_cache_size(1 << 20)
+        .aha_cache_size(1 << 20)
+        .db_value_cache_size(0)
+        .encryption_key(key)
+        .build()
+}
+
+#[cfg(feature = "encryption")]
+#[test]
+fn db_encrypted_reopens_with_key() {
+    let dir = unique_temp_dir("encrypted");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+
+    let key = [0x42; 32];
+    let hash = {
+        let db = DB::open(dir.to_str().unwrap(), encrypted_cfg(true, key));
+        let mut wb = db.new_writebatch();
+        for i in 0..300u32 {
+            wb.insert(&i.wrapping_mul(2654435761).to_be_bytes(), b"secret-value");
+        }
+        wb.commit().unwrap();
+        db.hash()
+    };
+    let raw = fs::read(dir.join("node")).unwrap();
+    assert!(!raw.windows(12).any(|w| w == b"secret-value"));
+
+    let mut db = DB::open(dir.to_str().unwrap(), encrypted_cfg(false, key));
+    assert_eq!(db.hash(), hash);
+    for i in 0..300u32 {
+        let k = i.wrapping_mul(2654435761).to_be_bytes();
+        assert_eq!(db.get(&k), Some(b"secret-value".to_vec()));
+    }
+
+    drop(db);
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[cfg(feature = "encryption")]
+#[test]
+fn db_encrypted_wrong_key_fails_authentication() {
+    let dir = unique_temp_dir("encrypted-wrong-key");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    {
+        let db = DB::open(dir.to_str().unwrap(), encrypted_cfg(true, [1; 32]));
+        let mut wb = db.new_writebatch();
+        wb.insert(b"k", b"v");
+        wb.commit().unwrap();
+    }
+
+    let err = DB::try_open(dir.to_str().unwrap(), encrypted_cfg(false, [2; 32]))
+        .err()
+        .unwrap();
+    assert!(matches!(err, OpenError::Decrypt { .. }), "{err}");
+    assert!(err.to_string().contains("failed authentication"), "{err}");
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[cfg(feature = "encryption")]
+#[test]
+fn db_encrypted_tampered_block_fails_try_get() {
+    let dir = unique_temp_dir("encrypted-tampered");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let key = [3; 32];
+    let keys: Vec<[u8; 4]> = (0..300u32)
+        .map(|i| i.wrapping_mul(2654435761).to_be_bytes())
+        .collect();
+    {
+        let db = DB::open(dir.to_str().unwrap(), encrypted_cfg(true, key));
+        let mut wb = db.new_writebatch();
+        for k in &keys {
+            wb.insert(k, &[7; 40]);
+        }
+        wb.commit().unwrap();
+    }
+
+    // Flip a ciphertext byte in the second block, past the node header;
+    // the root sits in the last block, so the DB still opens.
+    let node = dir.join("node");
+    let mut raw = fs::read(&node).unwrap();
+    // header + 4 KiB block + tag, the on-disk layout of EncryptedBackend
+    let stride = 12 + 4096 + 16;
+    assert!(raw.len() > 3 * stride);
+    raw[stride + 100] ^= 1;
+    fs::write(&node, raw).unwrap();
+
+    let mut db = DB::try_open(dir.to_str().unwrap(), encrypted_cfg(false, key)).unwrap();
+    let failed = keys.iter().filter(|k| db.try_get(&k[..]).is_err()).count();
+    assert!(failed > 0);
+
+    drop(db);
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn db_wal_rolls_back_torn_root_write() {
+    let dir = unique_temp_dir("wal");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let wal_cfg = |truncate| {
+        DBConfig::builder()
+            .truncate(truncate)
+            .cache_size(1024)
+            .page_cache_size(1 << 20)
+            .aha_cache_size(1 << 20)
+            .db_value_cache_size(0)
+            .aha_lens(vec![])
+            .wal(true)
+            .build()
+    };
+
+    let (root_a, node_len_a) = {
+        let db = DB::open(dir.to_str().unwrap(), wal_cfg(true));
+        let mut wb = db.new_writebatch();
+        wb.insert(b"a", b"1");
+        let root_a = wb.commit().unwrap();
+        let node_len_a = fs::metadata(dir.join("node")).unwrap().len();
+
+        let mut wb = db.new_writebatch();
+        wb.insert(b"a", b"2");
+        wb.insert(b"b", b"2");
+        wb.commit().unwrap();
+        (root_a, node_len_a)
+    };
+    assert!(fs::metadata(dir.join("node")).unwrap().len() > node_len_a);
+
+    // Tear the second root pointer as if the process died mid-write.
+    let root_file = fs::OpenOptions::new()
+        .write(true)
+        .open(dir.join("root"))
+        .unwrap();
+    // header, the first record, then a torn second pointer
+    root_file.set_len(8 + 40 + 3).unwrap();
+    drop(root_file);
+
+    {
+        let mut db = DB::open(dir.to_str().unwrap(), wal_cfg(false));
+        assert_eq!(db.version_count(), 1);
+        assert_eq!(db.version_root(0), Some(root_a));
+        assert_eq!(db.get(b"a"), Some(b"1".to_vec()));
+        assert_eq!(db.get(b"b"), None);
+        assert_eq!(fs::metadata(dir.join("root")).unwrap().len(), 8 + 40);
+        assert_eq!(fs::metadata(dir.join("node")).unwrap().len(), node_len_a);
+
+        // The recovered DB keeps committing normally.
+        let mut wb = db.new_writebatch();
+        wb.insert(b"c", b"3");
+        wb.commit().unwrap();
+    }
+
+    let mut db = DB::open(dir.to_str().unwrap(), wal_cfg(false));
+    assert_eq!(db.version_count(), 2);
+    assert_eq!(db.get(b"a"), Some(b"1".to_vec()));
+    assert_eq!(db.get(b"c"), Some(b"3".to_vec()));
+
+    drop(db);
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn db_wal_refuses_encryption_key() {
+    let dir = unique_temp_dir("wal-encrypted");
+    let _ = fs::remove_dir_all(&dir);
+
+    // Rolling back a torn commit would cut the encrypted tail block short.
+    let cfg = DBConfig::builder()
+        .truncate(true)
+        .cache_size(1024)
+        .page_cache_size(1 << 20)
+        .aha_cache_size(1 << 20)
+        .db_value_cache_size(0)
+        .wal(true)
+        .encryption_key([9; 32])
+        .build();
+    let err = DB::try_open(dir.to_str().unwrap(), cfg).err().unwrap();
+    assert!(matches!(err, OpenError::Config(_)), "{err}");
+    assert!(!dir.exists());
+}
+
+#[test]
+fn db_last_returns_largest_key() {
+    let dir = unique_temp_dir("last");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+
+    let db = DB::open(dir.to_str().unwrap(), default_cfg(true, 0));
+    assert_eq!(db.last(), None);
+    for seq in [3u64, 250, 17, 4096, 9] {
+        let mut wb = db.new_writebatch();
+        wb.insert(&seq.to_be_bytes(), format!("entry-{seq}").as_bytes());
+        wb.commit().unwrap();
+    }
+    assert_eq!(
+        db.last(),
+        Some((4096u64.to_be_bytes().to_vec(), b"entry-4096".to_vec()))
+    );
+
+    drop(db);
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn db_cursor_paginates_from_seek_key() {
+    let dir = unique_temp_dir("cursor");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+
+    let mut db = DB::open(dir.to_str().unwrap(), default_cfg(true, 0));
+    let mut wb = db.new_writebatch();
+    for i in 0..100u32 {
+        wb.insert(&i.to_be_bytes(), &(i * 2).to_be_bytes());
+    }
+    wb.commit().unwrap();
+
+    // page through 30..60 in pages of 10, resuming after the last key seen
+    let mut pages = Vec::new();
+    let mut from = 30u32.to_be_bytes().to_vec();
+    for _ in 0..3 {
+        let mut cursor = db.cursor();
+        cursor.seek(&from);
+        let page: Vec<_> = cursor.take(10).collect();
+        from = page.last().unwrap().0.clone();
+        from.push(0);
+        pages.extend(page);
+    }
+    let expected: Vec<_> = (30..60u32)
+        .map(|i| (i.to_be_bytes().to_vec(), (i * 2).to_be_bytes().to_vec()))
+        .collect();
+    assert_eq!(pages, expected);
+
+    // the cursor keeps reading the root it was created at
+    let mut cursor = db.cursor();
+    let mut wb = db.new_writebatch();
+    wb.remove(&0u32.to_be_bytes());
+    wb.commit().unwrap();
+    assert_eq!(cursor.next().unwrap().0, 0u32.to_be_bytes().to_vec());
+    assert_eq!(db.get(&0u32.to_be_bytes()), None);
+    assert_eq!(cursor.prev().unwrap().0, 0u32.to_be_bytes().to_vec());
+    assert_eq!(cursor.prev(), None);
+
+    drop(db);
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn db_merge_adds_integers_across_batches() {
+    let dir = unique_temp_dir("merge");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+
+    let add = |old: Option<&[u8]>, operands: &[&[u8]]| {
+        let decode = |v: &[u8]| u64::from_le_bytes(v.try_into().unwrap());
+        let sum = operands
+            .iter()
+            .fold(old.map_or(0, decode), |sum, op| sum + decode(op));
+        sum.to_le_bytes().to_vec()
+    };
+    let cfg = DBConfig::builder()
+        .truncate(true)
+        .cache_size(1024)
+        .page_cache_size(1 << 20)
+        .aha_cache_size(1 << 20)
+        .db_value_cache_size(1024)
+        .aha_lens(vec![])
+        .max_batch_bytes(256)
+        .merge_fn(Arc::new(add))
+        .build();
+    let mut db = DB::open(dir.to_str().unwrap(), cfg);
+    let count = |db: &mut DB, key: &[u8]| {
+        db.get(key)
+            .map(|v| u64::from_le_bytes(v.try_into().unwrap()))
+    };
+
+    let mut wb = db.new_writebatch();
+    wb.merge(b"hits", &3u64.to_le_bytes());
+    wb.merge(b"hits", &4u64.to_le_bytes());
+    assert_eq!(wb.len(), 1);
+    assert_eq!(wb.get(b"hits"), Some(7u64.to_le_bytes().to_vec()));
+    wb.commit().unwrap();
+    assert_eq!(count(&mut db, b"hits"), Some(7));
+
+    // two batches opened before either commits both land
+    let mut first = db.new_writebatch();
+    let mut second = db.new_writebatch();
+    first.merge(b"hits", &10u64.to_le_bytes());
+    second.merge(b"hits", &100u64.to_le_bytes());
+    first.commit().unwrap();
+    second.commit().unwrap();
+    assert_eq!(count(&mut db, b"hits"), Some(117));
+
+    // an insert replaces pending operands; later operands fold into it
+    let mut wb = db.new_writebatch();
+    wb.merge(b"hits", &1u64.to_le_bytes());
+    wb.insert(b"hits", &50u64.to_le_bytes());
+    wb.merge(b"hits", &2u64.to_le_bytes());
+    wb.merge(b"misses", &5u64.to_le_bytes());
+    wb.commit().unwrap();
+    assert_eq!(count(&mut db, b"hits"), Some(52));
+    assert_eq!(count(&mut db, b"misses"), Some(5));
+
+    // operands count towards max_batch_bytes; an auto-flush folds them in
+    let mut wb = db.new_writebatch();
+    for i in 0..100u64 {
+        wb.merge(&(i % 10).to_be_bytes(), &i.to_le_bytes());
+        assert!(wb.staged_bytes() <= 256, "{}", wb.staged_bytes());
+    }
+    assert!(wb.staged_bytes() > 0);
+    assert_eq!(
+        wb.get(&3u64.to_be_bytes()),
+        Some(480u64.to_le_bytes().to_vec())
+    );
+    wb.commit().unwrap();
+    for k in 0..10u64 {
+        assert_eq!(count(&mut db, &k.to_be_bytes()), Some(450 + 10 * k));
+    }
+
+    drop(db);
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn db_compare_and_set_lets_only_first_racer_commit() {
+    let dir = unique_temp_dir("cas");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+
+    let mut db = DB::open(dir.to_str().unwrap(), default_cfg(true, 1024));
+    let mut wb = db.new_writebatch();
+    wb.insert(b"counter", b"1");
+    let root1 = wb.commit().unwrap();
+
+    // both batches read counter = 1 and try to bump it
+    let mut first = db.new_writebatch();
+    let mut second = db.new_writebatch();
+    first.compare_and_set(b"counter", Some(b"1"), b"2");
+    first.insert(b"owner", b"first");
+    second.compare_and_set(b"counter", Some(b"1"), b"2");
+    second.insert(b"owner", b"second");
+
+    let root2 = first.commit().unwrap();
+    assert!(matches!(
+        second.commit(),
+        Err(CommitError::CasConflict { key }) if key == b"counter"
+    ));
+    assert!(second.is_empty());
+    assert_eq!(db.version_count(), 2);
+    assert_eq!(db.open_version_from_tip(0), Some(root2));
+    assert_eq!(db.get(b"counter"), Some(b"2".to_vec()));
+    assert_eq!(db.get(b"owner"), Some(b"first".to_vec()));
+
+    // expecting absence, and retrying with the fresh value
+    let mut wb = db.new_writebatch();
+    wb.compare_and_set(b"lock", None, b"held");
+    wb.compare_and_set(b"counter", Some(b"2"), b"3");
+    wb.commit().unwrap();
+    let mut wb = db.new_writebatch();
+    wb.compare_and_set(b"lock", None, b"stolen");
+    assert!(wb.commit().is_err());
+    assert_eq!(db.get(b"lock"), Some(b"held".to_vec()));
+    assert_eq!(db.get(b"counter"), Some(b"3".to_vec()));
+    assert_ne!(root1, root2);
+
+    drop(db);
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn db_export_import_roundtrips_root_hash() {
+    let src_dir = unique_temp_dir("export-src");
+    let dst_dir = unique_temp_dir("export-dst");
+    let _ = fs::remove_dir_all(&src_dir);
+    let _ = fs::remove_dir_all(&dst_dir);
+    fs::create_dir_all(&src_dir).unwrap();
+    fs::create_dir_all(&dst_dir).unwrap();
+
+    let mut rng = XorShift64::new(0x6a09_e667_f3bc_c908);
+    let mut src = DB::open(src_dir.to_str().unwrap(), default_cfg(true, 0));
+    let mut keys = Vec::new();
+    for _ in 0..3 {
+        let mut wb = src.new_writebatch();
+        for _ in 0..200 {
+            let key = rng.next_u64().to_be_bytes().to_vec();
+            let len = (rng.next_u64() % 64) as usize + 1;
+            wb.insert(&key, &rand_bytes(&mut rng, len));
+            keys.push(key);
+        }
+        for key in keys.iter().step_by(7) {
+            wb.remove(key);
+        }
+        wb.commit().unwrap();
+    }
+    let mut dump = Vec::new();
+    src.export(&mut dump).unwrap();
+
+    let root = {
+        let mut dst = DB::open(dst_dir.to_str().unwrap(), default_cfg(true, 0));
+        let root = dst.import(&mut dump.as_slice()).unwrap();
+        assert_eq!(dst.hash(), src.hash());
+        root
+    };
+    let mut dst = DB::open(dst_dir.to_str().unwrap(), default_cfg(false, 0));
+    assert_eq!(dst.version_count(), 1);
+    assert_eq!(dst.version_root(0), Some(root));
+    assert_eq!(dst.hash(), src.hash());
+    for key in &keys {
+        assert_eq!(dst.get(key), src.get(key));
+    }
+
+    // a truncated or foreign stream is rejected without publishing a root
+    assert!(dst.import(&mut &dump[..dump.len() - 1]).is_err());
+    assert!(dst.import(&mut &b"not an export"[..]).is_err());
+    assert_eq!(dst.version_count(), 1);
+
+    // a frame length past the end of the stream fails without reserving it
+    let mut forged = dump[..12].to_vec();
+    forged.push(1);
+    forged.extend(u32::MAX.to_le_bytes());
+    forged.extend(b"key");
+    let err = dst.import(&mut forged.as_slice()).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+    drop(dst);
+
+    // small chunks build the same trie, in one commit writing the same
+    // nodes as a single chunk
+    let import_with = |max_batch_bytes| {
+        let metrics = Arc::new(CountingMetrics::default());
+        let mut cfg = default_cfg(true, 0);
+        cfg.max_batch_bytes = max_batch_bytes;
+        cfg.metrics = Some(metrics.clone());
+        let mut db = DB::open(dst_dir.to_str().unwrap(), cfg);
+        db.import(&mut dump.as_slice()).unwrap();
+        (db, metrics)
+    };
+    let (whole, whole_metrics) = import_with(0);
+    drop(whole);
+    let (mut chunked, metrics) = import_with(1024);
+    assert_eq!(chunked.hash(), src.hash());
+    for key in &keys {
+        assert_eq!(chunked.get(key), src.get(key));
+    }
+    assert_eq!(metrics.commits.load(Ordering::Relaxed), 1);
+    assert_eq!(
+        metrics.node_bytes_written.load(Ordering::Relaxed),
+        whole_metrics.node_bytes_written.load(Ordering::Relaxed)
+    );
+    drop(chunked);
+
+    drop(src);
+    let _ = fs::remove_dir_all(&src_dir);
+    let _ = fs::remove_dir_all(&dst_dir);
+}
+
+#[test]
+fn db_full_sync_commits_survive_reopen() {
+    let dir = unique_temp_dir("sync-full");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let metrics = Arc::new(CountingMetrics::default());
+    let sync_cfg = |truncate| {
+        DBConfig::builder()
+            .truncate(truncate)
+            .cache_size(1024)
+            .page_cache_size(1 << 20)
+            .aha_cache_size(1 << 20)
+            .db_value_cache_size(0)
+            .wal(true)
+            .sync_mode(SyncMode::Full)
+            .metrics(metrics.clone())
+            .build()
+    };
+    let full_syncs = || metrics.full_syncs.load(Ordering::Relaxed);
+
+    let root = {
+        let mut db = DB::open(dir.to_str().unwrap(), sync_cfg(true));
+        let mut wb = db.new_writebatch();
+        for i in 0..100u32 {
+            wb.insert(&i.to_be_bytes(), b"v");
+        }
+        let root = wb.commit().unwrap();
+        // the node file and every AHA file
+        let after_commit = full_syncs();
+        assert!(after_commit > 1, "{after_commit}");
+
+        let mut wb = db.new_writebatch();
+        wb.insert(b"later", b"v");
+        wb.commit_nosync().unwrap();
+        assert_eq!(full_syncs(), after_commit);
+        db.sync();
+        assert_eq!(full_syncs(), 2 * after_commit);
+        db.rollback_to(root);
+        root
+    };
+    let mut db = DB::open(dir.to_str().unwrap(), sync_cfg(false));
+    assert_eq!(db.version_root(0), Some(root));
+    assert_eq!(db.get(&7u32.to_be_bytes()), Some(b"v".to_vec()));
+
+    drop(db);
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn db_insert_with_extra_roundtrips_extra_bytes() {
+    let dir = unique_temp_dir("extra");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+
+    {
+        let mut db = DB::open(dir.to_str().unwrap(), default_cfg(true, 1 << 20));
+        let mut wb = db.new_writebatch();
+        wb.insert_with_extra(b"k1", b"v1", b"side");
+        wb.insert(b"k2", b"v2");
+        wb.commit().unwrap();
+
+        assert_eq!(
+            db.get_with_extra(b"k1"),
+            Some((b"v1".to_vec(), b"side".to_vec()))
+        );
+        assert_eq!(db.get(b"k1"), Some(b"v1".to_vec()));
+        assert_eq!(db.get_with_extra(b"k2"), Some((b"v2".to_vec(), Vec::new())));
+        assert_eq!(db.get_with_extra(b"missing"), None);
+
+        // a plain insert replaces the extra bytes too
+        let mut wb = db.new_writebatch();
+        wb.insert(b"k1", b"v1");
+        wb.commit().unwrap();
+        assert_eq!(db.get_with_extra(b"k1"), Some((b"v1".to_vec(), Vec::new())));
+
+        let mut wb = db.new_writebatch();
+        wb.insert_with_extra(b"k1", b"v3", b"again");
+        wb.commit().unwrap();
+    }
+    let mut db = DB::open(dir.to_str().unwrap(), default_cfg(false, 0));
+    assert_eq!(
+        db.get_with_extra(b"k1"),
+        Some((b"v3".to_vec(), b"again".to_vec()))
+    );
+
+    drop(db);
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn db_snapshots_read_from_many_threads() {
+    let dir = unique_temp_dir("parallel-get");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let db = DB::open(dir.to_str().unwrap(), default_cfg(true, 0));
+    let mut wb = db.new_writebatch();
+    for i in 0..2000u32 {
+        wb.insert(&i.to_be_bytes(), &(i * 2).to_le_bytes());
+    }
+    let root = wb.commit().unwrap();
+
+    let snapshots: Vec<_> = (0..4).map(|_| db.snapshot_at(root)).collect();
+    std::thread::scope(|s| {
+        for (t, snapshot) in snapshots.iter().enumerate() {
+            s.spawn(move || {
+                for i in (t as u32..2000).step_by(3) {
+                    let v = snapshot.get(&i.to_be_bytes());
+                    assert_eq!(v, Some((i * 2).to_le_bytes().to_vec()));
+                }
+                assert!(snapshot.get(&5000u32.to_be_bytes()).is_none());
+            });
+        }
+    });
+
+    drop(snapshots);
+    drop(db);
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn db_inspect_node_walks_the_committed_trie() {
+    let dir = unique_temp_dir("inspect");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let db = DB::open(dir.to_str().unwrap(), default_cfg(true, 0));
+    assert!(db.inspect_node(0).is_none());
+    assert!(db.inspect_node(1 << 20).is_none());
+
+    let mut wb = db.new_writebatch();
+    let mut expected = Vec::new();
+    for i in 0..300u32 {
+        let key = i.wrapping_mul(2654435761).to_be_bytes().to_vec();
+        wb.insert(&key, &i.to_le_bytes());
+        expected.push((key, i.to_le_bytes().to_vec()));
+    }
+    let root = wb.commit().unwrap();
+    expected.sort();
+
+    // rebuild every key from the nibbles on its path
+    fn walk(db: &DB, ptr: u64, nibbles: &mut Vec<u8>, out: &mut Vec<(Vec<u8>, Vec<u8>)>) {
+        match db.inspect_node(ptr).unwrap() {
+            NodeView::Branch { children } => {
+                assert_eq!(children.len(), 17);
+                // the value slot sorts before the nibbles
+                for slot in [16].into_iter().chain(0..16) {
+                    if let Some(child) = &children[slot] {
+                        assert!(!child.hash.is_empty());
+                        nibbles.push(slot as u8);
+                        walk(db, child.ptr, nibbles, out);
+                        nibbles.pop();
+                    }
+                }
+            }
+            NodeView::Short { path, child } => {
+                nibbles.extend(path.as_slice());
+                walk(db, child.ptr, nibbles, out);
+                nibbles.truncate(nibbles.len() - path.as_slice().len());
+            }
+            NodeView::Value { value, extra } => {
+                assert!(extra.is_empty());
+                assert_eq!(nibbles.last(), Some(&16));
+                let key = nibbles[..nibbles.len() - 1]
+                    .chunks(2)
+                    .map(|pair| pair[0] << 4 | pair[1])
+                    .collect();
+                out.push((key, value));
+            }
+        }
+    }
+    let mut found = Vec::new();
+    walk(&db, root, &mut Vec::new(), &mut found);
+    assert_eq!(found, expected);
+
+    drop(db);
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn db_root_for_matches_committed_root_without_writing() {
+    let dir = unique_temp_dir("root-for");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let mut db = DB::open(dir.to_str().unwrap(), default_cfg(true, 0));
+    let empty = db.hash();
+    assert_eq!(db.root_for(&[]), empty);
+
+    let entries: Vec<(Vec<u8>, Vec<u8>)> = (0..100u32)
+        .map(|i| (i.to_be_bytes().to_vec(), vec![i as u8; 3]))
+        .collect();
+    let expected = db.root_for(&entries);
+    assert_eq!(db.hash(), empty);
+    assert_eq!(db.get(&0u32.to_be_bytes()), None);
+
+    let mut wb = db.new_writebatch();
+    for (k, v) in entries.iter().rev() {
+        wb.insert(k, v);
+    }
+    wb.commit().unwrap();
+    assert_eq!(db.hash(), expected);
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn db_open_root_hash_finds_published_roots() {
+    let dir = unique_temp_dir("open-root-hash");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.to_str().unwrap();
+
+    let mut hashes = Vec::new();
+    let mut roots = Vec::new();
+    {
+        let mut db = DB::open(path, default_cfg(true, 0));
+        for i in 0..3u8 {
+            let mut wb = db.new_writebatch();
+            wb.insert(b"k", &[i]);
+            roots.push(wb.commit().unwrap());
+            hashes.push(db.hash());
+        }
+        assert_eq!(db.open_root_hash(&hashes[0]), Some(roots[0]));
+        assert_eq!(db.get(b"k"), Some(vec![0]));
+        assert_eq!(db.open_root_hash(&[0xab; 32]), None);
+        assert_eq!(db.get(b"k"), Some(vec![0]));
+    }
+    let root_file = fs::read(dir.join("root")).unwrap();
+    assert_eq!(root_file.len(), 8 + 3 * 40);
+
+    // Rewrite the root file in the old format of bare pointers.
+    let legacy: Vec<u8> = root_file[8..]
+        .chunks(40)
+        .flat_map(|record| record[..8].to_vec())
+        .collect();
+    fs::write(dir.join("root"), legacy).unwrap();
+    let mut db = DB::open(path, default_cfg(false, 0));
+    assert_eq!(db.version_count(), 3);
+    assert_eq!(db.get(b"k"), Some(vec![2]));
+    assert_eq!(db.open_root_hash(&hashes[1]), Some(roots[1]));
+    assert_eq!(db.get(b"k"), Some(vec![1]));
+
+    // New commits keep the old format.
+    let mut wb = db.new_writebatch();
+    wb.insert(b"k", &[3]);
+    let root = wb.commit().unwrap();
+    let hash = db.hash();
+    db.open_root(roots[0]);
+    assert_eq!(db.open_root_hash(&hash), Some(root));
+    drop(db);
+    assert_eq!(fs::metadata(dir.join("root")).unwrap().len(), 4 * 8);
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn db_value_size_histogram_buckets_committed_values() {
+    let dir = unique_temp_dir("value-histogram");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let mut db = DB::open(dir.to_str().unwrap(), default_cfg(true, 0));
+    assert!(db.value_size_histogram().is_empty());
+
+    let mut wb = db.new_writebatch();
+    for (i, size) in [0usize, 1, 2, 3, 4, 7, 8, 100, 1000]
+        .into_iter()
+        .enumerate()
+    {
+        wb.insert(&[i as u8], &vec![0xab; size]);
+    }
+    wb.commit().unwrap();
+    // uncommitted writes are not counted
+    let mut wb = db.new_writebatch();
+    wb.insert(b"staged", &[1; 5000]);
+
+    let expected = BTreeMap::from([(0, 1), (1, 1), (2, 2), (4, 2), (8, 1), (64, 1), (512, 1)]);
+    assert_eq!(db.value_size_histogram(), expected);
+    let expected = BTreeMap::from([(0, 4), (4, 3), (100, 2)]);
+    assert_eq!(db.value_size_histogram_with(&[4, 100]), expected);
+
+    drop(wb);
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn db_inline_threshold_moves_large_values_to_blobs() {
+    let inline_dir = unique_temp_dir("blobs-inline");
+    let blob_dir = unique_temp_dir("blobs");
+    for dir in [&inline_dir, &blob_dir] {
+        let _ = fs::remove_dir_all(dir);
+        fs::create_dir_all(dir).unwrap();
+    }
+    let blob_cfg = |truncate| {
+        DBConfig::builder()
+            .truncate(truncate)
+            .cache_size(1024)
+            .page_cache_size(1 << 20)
+            .db_value_cache_size(0)
+            .aha_lens(vec![])
+            .inline_threshold(64)
+            .build()
+    };
+
+    let mut hashes = Vec::new();
+    for (dir, cfg) in [
+        (&inline_dir, default_cfg(true, 0)),
+        (&blob_dir, blob_cfg(true)),
+    ] {
+        let db = DB::open(dir.to_str().unwrap(), cfg);
+        let mut wb = db.new_writebatch();
+        for i in 0..20u8 {
+            wb.insert_with_extra(&[i], &vec![i; 1000], &[i; 2]);
+        }
+        wb.insert(b"small", &[7; 64]);
+        wb.commit().unwrap();
+        hashes.push(db.hash());
+    }
+    assert_eq!(hashes[0], hashes[1]);
+    assert!(!inline_dir.join("blobs").exists());
+    assert!(fs::metadata(blob_dir.join("blobs")).unwrap().len() >= 20 * 1000);
+    let node_len = |dir: &PathBuf| fs::metadata(dir.join("node")).unwrap().len();
+    assert!(node_len(&blob_dir) * 10 < node_len(&inline_dir));
+
+    // blob references resolve with or without the threshold
+    for cfg in [blob_cfg(false), default_cfg(false, 0)] {
+        let mut db = DB::open(blob_dir.to_str().unwrap(), cfg);
+        assert_eq!(db.hash(), hashes[0]);
+        assert_eq!(db.get_with_extra(&[3]), Some((vec![3; 1000], vec![3; 2])));
+        assert_eq!(db.get(b"small"), Some(vec![7; 64]));
+        assert_eq!(
+            db.value_size_histogram_with(&[1000]),
+            BTreeMap::from([(0, 1), (1000, 20)])
+        );
+    }
+
+    for dir in [&inline_dir, &blob_dir] {
+        let _ = fs::remove_dir_all(dir);
+    }
+}
+
+#[test]
+fn db_writebatch_get_reads_its_own_writes() {
+    let dir = unique_temp_dir("writebatch-get");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let mut db = DB::open(dir.to_str().unwrap(), default_cfg(true, 0));
+    let mut wb = db.new_writebatch();
+    wb.insert(b"a", b"1");
+    wb.insert(b"b", b"2");
+    wb.commit().unwrap();
+
+    let mut wb = db.new_writebatch();
+    assert_eq!(wb.get(b"a"), Some(b"1".to_vec()));
+    wb.insert(b"a", b"10");
+    wb.remove(b"b");
+    wb.insert(b"c", b"3");
+    assert_eq!(wb.get(b"a"), Some(b"10".to_vec()));
+    assert_eq!(wb.get(b"b"), None);
+    assert_eq!(wb.get(b"c"), Some(b"3".to_vec()));
+    assert_eq!(wb.get(b"d"), None);
+
+    // a dependent write built from a staged value
+    let c = wb.get(b"c").unwrap();
+    wb.insert(b"d", &[c, b"!".to_vec()].concat());
+    wb.commit().unwrap();
+    assert_eq!(db.get(b"d"), Some(b"3!".to_vec()));
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn db_resize_value_cache_evicts_down_to_the_new_size() {
+    let dir = unique_temp_dir("resize-value-cache");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let mut cfg = default_cfg(true, 1 << 20);
+    cfg.cache_size = 1 << 20;
+    let mut db = DB::open(dir.to_str().unwrap(), cfg);
+    let mut wb = db.new_writebatch();
+    for i in 0..200u32 {
+        wb.insert(&i.to_be_bytes(), &[7; 32]);
+    }
+    wb.commit().unwrap();
+    for i in 0..200u32 {
+        assert!(db.get(&i.to_be_bytes()).is_some());
+    }
+    let stats = db.cache_stats();
+    assert_eq!(stats.value_cache_entries, 200);
+    assert!(stats.clean_nodes_bytes > 0);
+    assert!(stats.page_cache_bytes > 0);
+
+    db.resize_value_cache(1024);
+    let shrunk = db.cache_stats().value_cache_entries;
+    assert!(shrunk > 0 && shrunk < 200);
+    // the most recently read values are the ones kept
+    for i in 200 - shrunk as u32..200 {
+        assert!(db.get(&i.to_be_bytes()).is_some());
+    }
+    assert_eq!(db.cache_stats().value_cache_entries, shrunk);
+    db.resize_value_cache(0);
+    assert_eq!(db.cache_stats().value_cache_entries, 0);
+    drop(db);
+
+    // a DB opened without a value cache gets one
+    let mut db = DB::open(dir.to_str().unwrap(), default_cfg(false, 0));
+    assert!(db.get(&0u32.to_be_bytes()).is_some());
+    assert_eq!(db.cache_stats().value_cache_entries, 0);
+    db.resize_value_cache(1 << 20);
+    assert!(db.get(&0u32.to_be_bytes()).is_some());
+    assert_eq!(db.cache_stats().value_cache_entries, 1);
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn db_overlay_reads_through_shadows_and_discards() {
+    let dir = unique_temp_dir("overlay");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let mut db = DB::open(dir.to_str().unwrap(), default_cfg(true, 1 << 20));
+    let mut wb = db.new_writebatch();
+    wb.insert(b"a", b"1");
+    wb.insert(b"b", b"2");
+    let base = wb.commit().unwrap();
+
+    // read-through and shadowing
+    let mut overlay = db.overlay();
+    assert_eq!(overlay.base_root(), base);
+    assert_eq!(overlay.get(b"a"), Some(b"1".to_vec()));
+    overlay.insert(b"a", b"10");
+    overlay.remove(b"b");
+    overlay.insert(b"c", b"3");
+    assert_eq!(overlay.get(b"a"), Some(b"10".to_vec()));
+    assert_eq!(overlay.get(b"b"), None);
+    assert_eq!(overlay.get(b"c"), Some(b"3".to_vec()));
+    assert_eq!(overlay.len(), 3);
+    // the DB doesn't see any of it
+    assert_eq!(db.get(b"a"), Some(b"1".to_vec()));
+    assert_eq!(db.get(b"c"), None);
+
+    // discard
+    drop(overlay);
+    assert_eq!(db.get(b"b"), Some(b"2".to_vec()));
+    assert_eq!(db.version_count(), 1);
+
+    // flatten into a real commit
+    let mut overlay = db.overlay();
+    overlay.insert(b"a", b"10");
+    overlay.remove(b"b");
+    let expected = db.root_for(&[(b"a".to_vec(), b"10".to_vec())]);
+    let root = overlay.commit();
+    assert_ne!(root, base);
+    assert_eq!(db.hash(), expected);
+    assert_eq!(db.get(b"a"), Some(b"10".to_vec()));
+    assert_eq!(db.get(b"b"), None);
+    assert_eq!(db.version_count(), 2);
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn db_rollback_to_drops_later_versions() {
+    let dir = unique_temp_dir("rollback-to");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.to_str().unwrap();
+    let mut cfg = default_cfg(true, 1 << 20);
+    cfg.wal = true;
+    let mut db = DB::open(path, cfg);
+    let mut roots = Vec::new();
+    for i in 0..3u8 {
+        let mut wb = db.new_writebatch();
+        wb.insert(&[i], &[i]);
+        roots.push(wb.commit().unwrap());
+    }
+    let first_hash = db.root_for(&[(vec![0], vec![0])]);
+    let root_len = || fs::metadata(dir.join("root")).unwrap().len();
+    assert_eq!(root_len(), 8 + 3 * 40);
+
+    assert!(!db.rollback_to(0xdead_beef));
+    assert_eq!(db.version_count(), 3);
+    assert!(db.rollback_to(roots[0]));
+    assert_eq!(root_len(), 8 + 40);
+    assert_eq!(db.version_count(), 1);
+    assert_eq!(db.hash(), first_hash);
+    assert_eq!(db.get(&[0]), Some(vec![0]));
+    assert_eq!(db.get(&[1]), None);
+    assert_eq!(db.get(&[2]), None);
+    assert_eq!(db.open_version(1), None);
+
+    // history goes on from the rolled-back root
+    let mut wb = db.new_writebatch();
+    wb.insert(b"next", b"1");
+    let next = wb.commit().unwrap();
+    assert_eq!(db.version_count(), 2);
+    drop(db);
+
+    let mut cfg = default_cfg(false, 0);
+    cfg.wal = true;
+    let mut db = DB::open(path, cfg);
+    assert_eq!(db.version_count(), 2);
+    assert_eq!(db.open_version(1), Some(next));
+    assert_eq!(db.get(b"next"), Some(b"1".to_vec()));
+    assert_eq!(db.get(&[0]), Some(vec![0]));
+    assert_eq!(db.get(&[2]), None);
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn db_key_hashes_follow_commits_with_and_without_summary() {
+    let dir = unique_temp_dir("key-summary");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.to_str().unwrap();
+    let summary_cfg = |truncate| {
+        let mut cfg = default_cfg(truncate, 0);
+        cfg.key_summary = true;
+        cfg
+    };
+    let expected = |keys: &[Vec<u8>]| {
+        let mut hashes: Vec<[u8; 8]> = keys
+            .iter()
+            .map(|key| Keccak256Hasher.digest(key)[..8].try_into().unwrap())
+            .collect();
+        hashes.sort();
+        hashes
+    };
+
+    let mut db = DB::open(path, summary_cfg(true));
+    assert!(db.key_hashes().is_empty());
+    let mut wb = db.new_writebatch();
+    for i in 0..50u32 {
+        wb.insert(&i.to_be_bytes(), b"v");
+    }
+    let first = wb.commit().unwrap();
+    let summary_len = || fs::metadata(dir.join("key_summary")).unwrap().len();
+    let first_len = summary_len();
+    let mut wb = db.new_writebatch();
+    for i in 0..10u32 {
+        wb.remove(&i.to_be_bytes());
+        wb.insert(&(i + 20).to_be_bytes(), b"overwritten");
+        wb.insert(&(i + 100).to_be_bytes(), b"new");
+    }
+    wb.commit().unwrap();
+    let keys: Vec<Vec<u8>> = (10..50u32)
+        .chain(100..110)
+        .map(|i| i.to_be_bytes().to_vec())
+        .collect();
+    assert_eq!(db.key_hashes(), expected(&keys));
+    // the second commit keeps the first root's record and logs the 20
+    // hashes it changed, not all 50
+    let grown = summary_len() - first_len;
+    assert!(grown > 0 && grown < 50 * 8);
+
+    // older roots are read back from the log
+    db.open_root(first);
+    let first_keys: Vec<Vec<u8>> = (0..50u32).map(|i| i.to_be_bytes().to_vec()).collect();
+    assert_eq!(db.key_hashes(), expected(&first_keys));
+    drop(db);
+
+    // the saved log is reused, a record cut short is dropped, and a log
+    // without the latest root gets a snapshot of it
+    let mut db = DB::open(path, summary_cfg(false));
+    assert_eq!(db.key_hashes(), expected(&keys));
+    db.open_root(first);
+    assert_eq!(db.key_hashes(), expected(&first_keys));
+    drop(db);
+    let full_len = summary_len();
+    fs::OpenOptions::new()
+        .append(true)
+        .open(dir.join("key_summary"))
+        .unwrap()
+        .write_all(&[100, 0, 0, 0, 1])
+        .unwrap();
+    let db = DB::open(path, summary_cfg(false));
+    assert_eq!(summary_len(), full_len);
+    assert_eq!(db.key_hashes(), expected(&keys));
+    drop(db);
+    fs::write(dir.join("key_summary"), [0u8; 16]).unwrap();
+    let db = DB::open(path, summary_cfg(false));
+    assert_eq!(db.key_hashes(), expected(&keys));
+    drop(db);
+    let db = DB::open(path, default_cfg(false, 0));
+    assert_eq!(db.key_hashes(), expected(&keys));
+    drop(db);
+
+    // every root of a run of small commits, deltas and snapshots alike,
+    // is rebuilt from the log after a reopen
+    let db = DB::open(path, summary_cfg(false));
+    let mut live: BTreeSet<u32> = keys
+        .iter()
+        .map(|k| u32::from_be_bytes(k[..].try_into().unwrap()))
+        .collect();
+    let mut history = Vec::new();
+    for round in 0..30u32 {
+        let mut wb = db.new_writebatch();
+        for i in 0..3 {
+            let key = 200 + round * 3 + i;
+            wb.insert(&key.to_be_bytes(), b"v");
+            live.insert(key);
+        }
+        let gone = *live.iter().nth(round as usize % live.len()).unwrap();
+        wb.remove(&gone.to_be_bytes());
+        live.remove(&gone);
+        let root = wb.commit().unwrap();
+        let keys: Vec<Vec<u8>> = live.iter().map(|i| i.to_be_bytes().to_vec()).collect();
+        history.push((root, expected(&keys)));
+    }
+    drop(db);
+    let mut db = DB::open(path, summary_cfg(false));
+    for (root, hashes) in &history {
+        db.open_root(*root);
+        assert_eq!(db.key_hashes(), *hashes);
+    }
+    drop(db);
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn db_open_at_starts_from_the_given_root() {
+    let dir = unique_temp_dir("open-at");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.to_str().unwrap();
+    let mut roots = Vec::new();
+    {
+        let db = DB::open(path, default_cfg(true, 0));
+        for i in 0..2u8 {
+            let mut wb = db.new_writebatch();
+            wb.insert(&[i], &[i]);
+            roots.push(wb.commit().unwrap());
+        }
+    }
+
+    let mut db = DB::open_at(path, default_cfg(false, 0), 0);
+    assert_eq!(db.get(&[0]), None);
+    assert_eq!(db.get(&[1]), None);
+    // the published roots are still there
+    assert_eq!(db.version_count(), 2);
+    assert_eq!(db.version_root(1), Some(roots[1]));
+    db.open_root(roots[1]);
+    assert_eq!(db.get(&[0]), Some(vec![0]));
+    assert_eq!(db.get(&[1]), Some(vec![1]));
+    drop(db);
+
+    let mut db = DB::open_at(path, default_cfg(false, 0), roots[0]);
+    assert_eq!(db.get(&[0]), Some(vec![0]));
+    assert_eq!(db.get(&[1]), None);
+    // commits build on the opened root
+    let mut wb = db.new_writebatch();
+    wb.insert(&[2], &[2]);
+    wb.commit().unwrap();
+    assert_eq!(db.get(&[1]), None);
+    assert_eq!(db.get(&[2]), Some(vec![2]));
+    assert_eq!(db.version_count(), 3);
+    drop(db);
+
+    let mut db = DB::open(path, default_cfg(false, 0));
+    assert_eq!(db.get(&[1]), None);
+    assert_eq!(db.get(&[2]), Some(vec![2]));
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn db_clones_share_the_store_and_current_root() {
+    let dir = unique_temp_dir("clone");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let mut db = DB::open(dir.to_str().unwrap(), default_cfg(true, 1 << 20));
+    let mut wb = db.new_writebatch();
+    wb.insert(b"a", b"1");
+    let first = wb.commit().unwrap();
+
+    let readers: Vec<_> = (0..4)
+        .map(|_| {
+            let mut db = db.clone();
+            std::thread::spawn(move || db.get(b"a"))
+        })
+        .collect();
+    for reader in readers {
+        assert_eq!(reader.join().unwrap(), Some(b"1".to_vec()));
+    }
+
+    // commits through one handle are seen by the others
+    let mut other = db.clone();
+    let mut wb = other.new_writebatch();
+    wb.insert(b"a", b"2");
+    wb.commit().unwrap();
+    assert_eq!(db.get(b"a"), Some(b"2".to_vec()));
+
+    // so is open_root, unlike a snapshot
+    let snapshot = db.snapshot_at(db.version_root(1).unwrap());
+    other.open_root(first);
+    assert_eq!(db.get(b"a"), Some(b"1".to_vec()));
+    assert_eq!(snapshot.get(b"a"), Some(b"2".to_vec()));
+    drop(other);
+    assert_eq!(db.get(b"a"), Some(b"1".to_vec()));
+    drop(db);
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn db_cache_policies_give_identical_results() {
+    let mut runs = Vec::new();
+    for policy in [CachePolicy::Take, CachePolicy::Clone] {
+        let dir = unique_temp_dir("cache-policy");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let mut cfg = default_cfg(true, 0);
+        cfg.cache_size = 1 << 20;
+        cfg.cache_policy = policy;
+        let mut db = DB::open(dir.to_str().unwrap(), cfg);
+        let mut run = Vec::new();
+        for round in 0..4u32 {
+            let mut wb = db.new_writebatch();
+            for i in 0..200u32 {
+                let key = (i * 7919 % 1000).to_be_bytes();
+                if (i + round) % 5 == 0 {
+                    wb.remove(&key);
+                } else {
+                    wb.insert(&key, &(i + round).to_le_bytes());
+                }
+            }
+            wb.commit().unwrap();
+            run.push(db.hash());
+            for i in (0..1000u32).step_by(37) {
+                run.push(db.get(&i.to_be_bytes()).unwrap_or_default());
+            }
+        }
+        runs.push(run);
+        drop(db);
+        let _ = fs::remove_dir_all(&dir);
+    }
+    assert_eq!(runs[0], runs[1]);
+}
+
+#[test]
+fn db_get_arc_shares_cached_values() {
+    let dir = unique_temp_dir("get-arc");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let mut db = DB::open(dir.to_str().unwrap(), default_cfg(true, 1 << 20));
+    let mut wb = db.new_writebatch();
+    wb.insert(b"hot", &[7; 4096]);
+    wb.commit().unwrap();
+
+    let first = db.get_arc(b"hot").unwrap();
+    let second = db.get_arc(b"hot").unwrap();
+    assert!(Arc::ptr_eq(&first, &second));
+    assert_eq!(db.get(b"hot"), Some(first.to_vec()));
+    assert_eq!(db.get_arc(b"cold"), None);
+
+    // a new root does not see the old value, which its holders keep
+    let mut wb = db.new_writebatch();
+    wb.insert(b"hot", &[8; 16]);
+    wb.commit().unwrap();
+    assert_eq!(*db.get_arc(b"hot").unwrap(), vec![8; 16]);
+    assert_eq!(*first, vec![7; 4096]);
+
+    drop(db);
+
+    // without the value cache, reads are not shared
+    let mut db = DB::open(dir.to_str().unwrap(), default_cfg(false, 0));
+    let (a, b) = (db.get_arc(b"hot").unwrap(), db.get_arc(b"hot").unwrap());
+    assert!(!Arc::ptr_eq(&a, &b));
+    assert_eq!(a, b);
+    drop(db);
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn db_on_commit_reports_changed_keys_after_publishing() {
+    let dir = unique_temp_dir("on-commit");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let mut cfg = default_cfg(true, 0);
+    cfg.max_batch_bytes = 64;
+    let mut db = DB::open(dir.to_str().unwrap(), cfg);
+    let commits = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let seen = commits.clone();
+    let root_path = dir.join("root");
+    db.on_commit(Box::new(move |root, keys: &[Vec<u8>]| {
+        // the root is published before the callback runs
+        let versions = (fs::metadata(&root_path).unwrap().len() - 8) / 40;
+        seen.lock().unwrap().push((versions, root, keys.to_vec()));
+    }));
+
+    let mut wb = db.new_writebatch();
+    wb.insert(b"b", b"1");
+    wb.insert(b"a", b"1");
+    let first = wb.commit().unwrap();
+    let mut wb = db.new_writebatch();
+    wb.remove(b"a");
+    // auto-flushed writes are reported too
+    wb.insert(b"c", &[0; 100]);
+    wb.insert(b"d", b"1");
+    let second = wb.commit().unwrap();
+    // a rejected batch reports nothing
+    let mut wb = db.new_writebatch();
+    wb.compare_and_set(b"b", Some(b"2"), b"3");
+    assert!(wb.commit().is_err());
+
+    let keys = |ks: &[&[u8]]| ks.iter().map(|k| k.to_vec()).collect::<Vec<_>>();
+    assert_eq!(
+        *commits.lock().unwrap(),
+        vec![
+            (1, first, keys(&[b"a", b"b"])),
+            (2, second, keys(&[b"a", b"c", b"d"])),
+        ]
+    );
+
+    // an import reports what differs from the previous root
+    let mut dump = Vec::new();
+    db.export(&mut dump).unwrap();
+    let mut wb = db.new_writebatch();
+    wb.insert(b"b", b"2");
+    wb.insert(b"e", b"1");
+    wb.commit().unwrap();
+    commits.lock().unwrap().clear();
+    let imported = db.import(&mut dump.as_slice()).unwrap();
+    assert_eq!(
+        *commits.lock().unwrap(),
+        vec![(4, imported, keys(&[b"b", b"e"]))]
+    );
+
+    // commits without a sync are reported once `sync` makes them durable
+    commits.lock().unwrap().clear();
+    let mut wb = db.new_writebatch();
+    wb.insert(b"f", b"1");
+    let third = wb.commit_nosync().unwrap();
+    let mut wb = db.new_writebatch();
+    wb.insert(b"g", b"1");
+    let fourth = wb.commit_nosync().unwrap();
+    assert!(commits.lock().unwrap().is_empty());
+    db.sync();
+    assert_eq!(
+        *commits.lock().unwrap(),
+        vec![(6, third, keys(&[b"f"])), (6, fourth, keys(&[b"g"]))]
+    );
+    drop(db);
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn db_get_at_reads_historical_roots_without_switching() {
+    let dir = unique_temp_dir("get-at");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let mut db = DB::open(dir.to_str().unwrap(), default_cfg(true, 1 << 20));
+    let mut roots = Vec::new();
+    for v in 0..4u8 {
+        let mut wb = db.new_writebatch();
+        if v == 2 {
+            wb.remove(b"k");
+        } else {
+            wb.insert(b"k", &[v]);
+        }
+        roots.push(wb.commit().unwrap());
+    }
+    assert_eq!(db.get(b"k"), Some(vec![3]));
+
+    let expected = [Some(vec![0]), Some(vec![1]), None, Some(vec![3])];
+    for i in [3, 0, 2, 1, 0, 3, 2] {
+        assert_eq!(db.get_at(roots[i], b"k"), expected[i]);
+        // the current root and its cached value are unaffected
+        assert_eq!(db.get(b"k"), Some(vec![3]));
+    }
+    assert_eq!(db.get_at(0, b"k"), None);
+    drop(db);
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn db_open_rejects_files_of_another_format_version() {
+    let dir = unique_temp_dir("format-version");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    {
+        let db = DB::open(dir.to_str().unwrap(), default_cfg(true, 0));
+        let mut wb = db.new_writebatch();
+        wb.insert(b"k", b"v");
+        wb.commit().unwrap();
+    }
+    let open_err = |dir: &PathBuf| {
+        let path = dir.to_str().unwrap().to_string();
+        let err = std::panic::catch_unwind(move || DB::open(&path, default_cfg(false, 0)))
+            .err()
+            .unwrap();
+        err.downcast_ref::<String>().unwrap().clone()
+    };
+
+    let node = fs::read(dir.join("node")).unwrap();
+    let mut future = node.clone();
+    future[8..12].copy_from_slice(&99u32.to_le_bytes());
+    fs::write(dir.join("node"), &future).unwrap();
+    let msg = open_err(&dir);
+    assert!(msg.contains("format version 99 is not supported"), "{msg}");
+    fs::write(dir.join("node"), &node[16..]).unwrap();
+    let msg = open_err(&dir);
+    assert!(msg.contains("no format header"), "{msg}");
+    fs::write(dir.join("node"), &node).unwrap();
+
+    let root = fs::read(dir.join("root")).unwrap();
+    let mut future = root.clone();
+    future[7] = b'2';
+    fs::write(dir.join("root"), &future).unwrap();
+    let msg = open_err(&dir);
+    assert!(msg.contains("root file format version 2"), "{msg}");
+    fs::write(dir.join("root"), &root).unwrap();
+
+    let mut db = DB::open(dir.to_str().unwrap(), default_cfg(false, 0));
+    assert_eq!(db.get(b"k"), Some(b"v".to_vec()));
+    drop(db);
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn db_try_open_reports_uncreatable_paths() {
+    let dir = unique_temp_dir("try-open-create");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+
+    // a DB path under a regular file cannot be created, even by root
+    fs::write(dir.join("file"), b"").unwrap();
+    let under_file = dir.join("file").join("db");
+    let err = DB::try_open(under_file.to_str().unwrap(), default_cfg(false, 0)).err();
+    match err {
+        Some(OpenError::Create { path, .. }) => assert_eq!(path, under_file.to_str().unwrap()),
+        other => panic!("unexpected {other:?}"),
+    }
+
+    let read_only = dir.join("read-only");
+    fs::create_dir_all(&read_only).unwrap();
+    fs::set_permissions(&read_only, fs::Permissions::from_mode(0o555)).unwrap();
+    // permission bits do not bind a privileged user
+    if fs::write(read_only.join("probe"), b"").is_err() {
+        let err = DB::try_open(read_only.to_str().unwrap(), default_cfg(false, 0)).err();
+        match err {
+            Some(OpenError::Create { path, source }) => {
+                assert_eq!(path, read_only.join("node").to_str().unwrap());
+                assert_eq!(source.kind(), std::io::ErrorKind::PermissionDenied);
+            }
+            other => panic!("unexpected {other:?}"),
+        }
+    }
+    fs::set_permissions(&read_only, fs::Permissions::from_mode(0o755)).unwrap();
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn db_try_open_reports_unopenable_side_files() {
+    let dir = unique_temp_dir("try-open-side-files");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.to_str().unwrap();
+
+    // a directory where the changelog file goes
+    fs::create_dir_all(dir.join("changelog")).unwrap();
+    let cfg = DBConfig::builder()
+        .cache_size(1024)
+        .page_cache_size(1 << 20)
+        .aha_cache_size(1 << 20)
+        .db_value_cache_size(0)
+        .changelog(true)
+        .build();
+    match DB::try_open(path, cfg).err() {
+        Some(OpenError::Create { path, .. }) => {
+            assert_eq!(path, dir.join("changelog").to_str().unwrap())
+        }
+        other => panic!("unexpected {other:?}"),
+    }
+
+    // and one where the key summary goes
+    fs::create_dir_all(dir.join("key_summary")).unwrap();
+    let cfg = DBConfig::builder()
+        .cache_size(1024)
+        .page_cache_size(1 << 20)
+        .aha_cache_size(1 << 20)
+        .db_value_cache_size(0)
+        .key_summary(true)
+        .build();
+    match DB::try_open(path, cfg).err() {
+        Some(OpenError::Io { path, .. }) => {
+            assert_eq!(path, dir.join("key_summary").to_str().unwrap())
+        }
+        other => panic!("unexpected {other:?}"),
+    }
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[cfg(not(feature = "encryption"))]
+#[test]
+fn db_try_open_refuses_encryption_key_without_the_feature() {
+    let dir = unique_temp_dir("try-open-no-encryption");
+    let _ = fs::remove_dir_all(&dir);
+    let cfg = DBConfig::builder()
+        .cache_size(1024)
+        .page_cache_size(1 << 20)
+        .aha_cache_size(1 << 20)
+        .db_value_cache_size(0)
+        .encryption_key([1; 32])
+        .build();
+    let err = DB::try_open(dir.to_str().unwrap(), cfg).err().unwrap();
+    assert!(matches!(err, OpenError::Config(_)), "{err}");
+    assert!(err.to_string().contains("`encryption` feature"), "{err}");
+    assert!(!dir.exists());
+}
+
+#[cfg(not(feature = "compression"))]
+#[test]
+fn db_try_open_refuses_compression_level_without_the_feature() {
+    let dir = unique_temp_dir("try-open-no-compression");
+    let _ = fs::remove_dir_all(&dir);
+    let cfg = DBConfig::builder()
+        .cache_size(1024)
+        .page_cache_size(1 << 20)
+        .aha_cache_size(1 << 20)
+        .db_value_cache_size(0)
+        .compression_level(3)
+        .build();
+    let err = DB::try_open(dir.to_str().unwrap(), cfg).err().unwrap();
+    assert!(matches!(err, OpenError::Config(_)), "{err}");
+    assert!(err.to_string().contains("`compression` feature"), "{err}");
+    assert!(!dir.exists());
+}
+
+#[test]
+fn db_try_open_reports_malformed_files() {
+    let dir = unique_temp_dir("try-open-malformed");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    {
+        let db = DB::open(dir.to_str().unwrap(), default_cfg(true, 0));
+        let mut wb = db.new_writebatch();
+        wb.insert(b"k", b"v");
+        wb.commit().unwrap();
+    }
+    let path = dir.to_str().unwrap();
+
+    // header, one record, then 3 bytes of a second
+    let root = fs::read(dir.join("root")).unwrap();
+    assert_eq!(root.len(), 8 + 40);
+    let mut torn = root.clone();
+    torn.extend([1, 2, 3]);
+    fs::write(dir.join("root"), &torn).unwrap();
+    match DB::try_open(path, default_cfg(false, 0)).err() {
+        Some(OpenError::RootFileLength { len, record, .. }) => {
+            assert_eq!((len, record), (51, 40));
+        }
+        other => panic!("unexpected {other:?}"),
+    }
+    let err = std::panic::catch_unwind(|| DB::open(path, default_cfg(false, 0)))
+        .err()
+        .unwrap();
+    let msg = err.downcast_ref::<String>().unwrap();
+    assert!(
+        msg.contains("not a whole number of 40-byte records"),
+        "{msg}"
+    );
+    fs::write(dir.join("root"), &root).unwrap();
+
+    let node = fs::read(dir.join("node")).unwrap();
+    let mut future = node.clone();
+    future[8..12].copy_from_slice(&99u32.to_le_bytes());
+    fs::write(dir.join("node"), &future).unwrap();
+    match DB::try_open(path, default_cfg(false, 0)).err() {
+        Some(OpenError::Version {
+            file,
+            found,
+            expected,
+            ..
+        }) => assert_eq!(
+            (file, found.as_str(), expected.as_str()),
+            ("node file", "99", "2")
+        ),
+        other => panic!("unexpected {other:?}"),
+    }
+    fs::write(dir.join("node"), &node[..10]).unwrap();
+    match DB::try_open(path, default_cfg(false, 0)).err() {
+        Some(OpenError::NodeFile { source, .. }) => assert_eq!(source, NodeHeaderError::Torn),
+        other => panic!("unexpected {other:?}"),
+    }
+    fs::write(dir.join("node"), &node).unwrap();
+
+    let mut db = DB::try_open(path, default_cfg(false, 0)).unwrap();
+    assert_eq!(db.get(b"k"), Some(b"v".to_vec()));
+    drop(db);
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn db_txn_reads_its_writes_and_commits_one_root() {
+    let dir = unique_temp_dir("txn");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let mut db = DB::open(dir.to_str().unwrap(), default_cfg(true, 1 << 20));
+    let mut wb = db.new_writebatch();
+    wb.insert(b"old", b"0");
+    wb.insert(b"gone", b"0");
+    wb.commit().unwrap();
+    let versions = db.version_count();
+
+    let mut txn = db.begin();
+    txn.insert(b"a", b"1");
+    txn.remove(b"gone");
+    assert_eq!(txn.get(b"a"), Some(b"1".to_vec()));
+    assert_eq!(txn.get(b"gone"), None);
+    txn.stage();
+    assert_eq!(txn.get(b"a"), Some(b"1".to_vec()));
+    assert_eq!(txn.get(b"old"), Some(b"0".to_vec()));
+    txn.insert(b"a", b"2");
+    txn.stage();
+    let mut wb = db.new_writebatch();
+    wb.insert(b"b", b"3");
+    txn.append(wb);
+    assert_eq!(txn.get(b"a"), Some(b"2".to_vec()));
+    assert_eq!(txn.get(b"b"), Some(b"3".to_vec()));
+    assert_eq!(db.version_count(), versions);
+
+    let root = txn.commit().unwrap();
+    assert_eq!(db.version_count(), versions + 1);
+    assert_eq!(db.version_root(versions), Some(root));
+    assert_eq!(db.get(b"a"), Some(b"2".to_vec()));
+    assert_eq!(db.get(b"b"), Some(b"3".to_vec()));
+    assert_eq!(db.get(b"old"), Some(b"0".to_vec()));
+    assert_eq!(db.get(b"gone"), None);
+    drop(db);
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn db_commit_nosync_groups_roots_until_sync() {
+    let dir = unique_temp_dir("commit-nosync");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let node_len = |dir: &PathBuf| fs::metadata(dir.join("node")).unwrap().len();
+
+    let synced = {
+        let mut db = DB::open(dir.to_str().unwrap(), default_cfg(true, 0));
+        let mut wb = db.new_writebatch();
+        wb.insert(b"k", b"0");
+        let synced = wb.commit().unwrap();
+        let len = node_len(&dir);
+        for i in 1..=3u8 {
+            let mut wb = db.new_writebatch();
+            wb.insert(b"k", &[b'0' + i]);
+            wb.commit_nosync().unwrap();
+        }
+        assert_eq!(db.version_count(), 4);
+        assert_eq!(db.get(b"k"), Some(b"3".to_vec()));
+        assert_eq!(node_len(&dir), len);
+        // A crash: nothing from the group reaches the files.
+        std::mem::forget(db);
+        synced
+    };
+
+    let mut db = DB::open(dir.to_str().unwrap(), default_cfg(false, 0));
+    assert_eq!(db.version_count(), 1);
+    assert_eq!(db.version_root(0), Some(synced));
+    assert_eq!(db.get(b"k"), Some(b"0".to_vec()));
+    for i in 1..=3u8 {
+        let mut wb = db.new_writebatch();
+        wb.insert(b"k", &[b'0' + i]);
+        wb.commit_nosync().unwrap();
+    }
+    db.sync();
+    std::mem::forget(db);
+
+    let mut db = DB::open(dir.to_str().unwrap(), default_cfg(false, 0));
+    assert_eq!(db.version_count(), 4);
+    assert_eq!(db.get(b"k"), Some(b"3".to_vec()));
+    for v in 0..4 {
+        let root = db.version_root(v).unwrap();
+        assert_eq!(db.get_at(root, b"k"), Some(vec![b'0' + v as u8]));
+    }
+    drop(db);
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn db_changelog_replays_leader_commits_on_a_follower() {
+    let leader_dir = unique_temp_dir("changelog-leader");
+    let follower_dir = unique_temp_dir("changelog-follower");
+    let leader_path = leader_dir.to_str().unwrap();
+    let mut leader_cfg = default_cfg(true, 0);
+    leader_cfg.changelog = true;
+    // A small batch limit makes commits include auto-flushed writes.
+    leader_cfg.max_batch_bytes = 64;
+    let mut leader = DB::open(leader_path, leader_cfg);
+    // The follower lays its nodes out differently.
+    let mut follower_cfg = default_cfg(true, 0);
+    follower_cfg.aha_lens = vec![4, 8];
+    follower_cfg.inline_threshold = Some(8);
+    let mut follower = DB::open(follower_dir.to_str().unwrap(), follower_cfg);
+
+    for round in 0..3u8 {
+        let mut batch = leader.new_writebatch();
+        for i in 0..20u8 {
+            batch.insert(&[round, i], &[i; 16]);
+        }
+        if round > 0 {
+            batch.remove(&[round - 1, 3]);
+            batch.insert(&[0, 5], &[round; 4]);
+        }
+        batch.commit().unwrap();
+    }
+    assert_eq!(
+        follower
+            .replay_changelog(leader_path, &follower.hash())
+            .unwrap(),
+        3
+    );
+    assert_eq!(follower.hash(), leader.hash());
+    assert_eq!(follower.get(&[0, 5]), Some(vec![2; 4]));
+    assert_eq!(follower.get(&[1, 3]), None);
+
+    // Only the records after the follower's root are applied.
+    let mut batch = leader.new_writebatch();
+    batch.remove(&[2, 0]);
+    batch.commit().unwrap();
+    assert_eq!(
+        follower
+            .replay_changelog(leader_path, &follower.hash())
+            .unwrap(),
+        1
+    );
+    assert_eq!(follower.get(&[2, 0]), None);
+
+    // A rollback is logged as the changes back to the older root.
+    let v3 = leader.version_root(2).unwrap();
+    assert!(leader.rollback_to(v3));
+    assert_eq!(
+        follower
+            .replay_changelog(leader_path, &follower.hash())
+            .unwrap(),
+        1
+    );
+    assert_eq!(follower.hash(), leader.hash());
+    assert_eq!(follower.get(&[2, 0]), Some(vec![0; 16]));
+    assert_eq!(
+        follower
+            .replay_changelog(leader_path, &follower.hash())
+            .unwrap(),
+        0
+    );
+
+    let err = follower
+        .replay_changelog(leader_path, &[7; 32])
+        .unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+
+    // A record cut short by a crash ends the log.
+    let log = leader_dir.join("changelog");
+    let len = fs::metadata(&log).unwrap().len();
+    fs::OpenOptions::new()
+        .append(true)
+        .open(&log)
+        .unwrap()
+        .set_len(len + 3)
+        .unwrap();
+    assert_eq!(
+        follower
+            .replay_changelog(leader_path, &follower.hash())
+            .unwrap(),
+        0
+    );
+
+    let _ = fs::remove_dir_all(&leader_dir);
+    let _ = fs::remove_dir_all(&follower_dir);
+}
+
+#[test]
+fn db_try_get_reports_undecodable_nodes() {
+    let dir = unique_temp_dir("decode-error");
+    let _ = fs::remove_dir_all(&dir);
+    let path = dir.to_str().unwrap().to_string();
+    let bad = {
+        let db = DB::open(&path, default_cfg(true, 0));
+        let mut wb = db.new_writebatch();
+        wb.insert(&[0x10], b"kept");
+        wb.insert(&[0x20], b"lost");
+        let root = wb.commit().unwrap();
+        let Some(NodeView::Branch { children }) = db.inspect_node(root) else {
+            panic!("two keys under one root branch");
+        };
+        children[2].as_ref().unwrap().ptr
+    };
+
+    // Keep the length prefix, garble the body.
+    let mut node = fs::read(dir.join("node")).unwrap();
+    let bad_at = bad as usize;
+    let len = node[bad_at] as usize;
+    node[bad_at + 1..bad_at + 1 + len].fill(0xff);
+    fs::write(dir.join("node"), &node).unwrap();
+
+    let mut db = DB::open(&path, default_cfg(false, 1 << 20));
+    assert_eq!(db.try_get(&[0x10]), Ok(Some(b"kept".to_vec())));
+    assert_eq!(db.try_get(&[0x20]), Err(DbError::Decode { ptr: bad }));
+    assert_eq!(db.try_get(&[0x20]), Err(DbError::Decode { ptr: bad }));
+    assert_eq!(db.get(&[0x10]), Some(b"kept".to_vec()));
+
+    let err = std::panic::catch_unwind(move || db.get(&[0x20]))
+        .err()
+        .unwrap();
+    let msg = err.downcast_ref::<String>().unwrap();
+    assert!(msg.contains(&format!("cannot decode node {bad}")), "{msg}");
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn db_deterministic_layout_writes_identical_node_files() {
+    let run = |name: &str, deterministic: bool| {
+        let dir = unique_temp_dir(name);
+        let mut cfg = default_cfg(true, 0);
+        cfg.deterministic_layout = deterministic;
+        cfg.aha_lens = vec![4, 8];
+        let db = DB::open(dir.to_str().unwrap(), cfg);
+        let mut wb = db.new_writebatch();
+        for g in 0..=255u8 {
+            wb.insert(&[g, 0x11], b"a");
+            wb.insert(&[g, 0x12], b"b");
+        }
+        wb.commit().unwrap();
+        // Removing [g, 0x12] first collapses the branch above [g, 0x11] and
+        // inserting [g, 0x13] splits it again, copying the untouched leaf;
+        // inserting first leaves the leaf where it is.
+        let mut wb = db.new_writebatch();
+        for g in 0..=255u8 {
+            wb.remove(&[g, 0x12]);
+            wb.insert(&[g, 0x13], b"c");
+        }
+        wb.commit().unwrap();
+        let files = (
+            db.hash(),
+            fs::read(dir.join("node")).unwrap(),
+            fs::read(dir.join("aha_4")).unwrap(),
+        );
+        let _ = fs::remove_dir_all(&dir);
+        files
+    };
+    let first = run("deterministic-a", true);
+    let second = run("deterministic-b", true);
+    assert_eq!(first.0, second.0);
+    assert!(first.1 == second.1, "node files differ");
+    assert!(first.2 == second.2, "AHA files differ");
+
+    // the layout never changes the hashes
+    let (a, b) = (run("layout-a", false), run("layout-b", false));
+    assert_eq!(a.0, first.0);
+    assert_eq!(b.0, first.0);
+}
+
+#[test]
+fn db_strict_aha_aborts_writes_over_a_damaged_aha() {
+    let dir = unique_temp_dir("strict-aha");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let aha_cfg = |truncate, strict_aha| {
+        DBConfig::builder()
+            .truncate(truncate)
+            .cache_size(1024)
+            .page_cache_size(1 << 20)
+            .aha_cache_size(1 << 20)
+            .db_value_cache_size(0)
+            .strict_aha(strict_aha)
+            .build()
+    };
+    let key = |i: u32| i.wrapping_mul(2654435761).to_be_bytes();
+    {
+        let db = DB::open(dir.to_str().unwrap(), aha_cfg(true, false));
+        let mut wb = db.new_writebatch();
+        for i in 0..256u32 {
+            wb.insert(&key(i), b"v");
+        }
+        wb.commit().unwrap();
+    }
+    let mut damaged = false;
+    for entry in fs::read_dir(&dir).unwrap() {
+        let path = entry.unwrap().path();
+        let name = path.file_name().unwrap().to_str().unwrap().to_string();
+        if name.starts_with("aha_") && name != "aha_free" {
+            let len = fs::metadata(&path).unwrap().len() as usize;
+            damaged |= len > 0;
+            fs::write(&path, vec![0xa5; len]).unwrap();
+        }
+    }
+    assert!(damaged);
+
+    {
+        let cfg = DBConfig {
+            max_batch_bytes: 100,
+            ..aha_cfg(false, true)
+        };
+        let mut db = DB::open(dir.to_str().unwrap(), cfg);
+        let hash = db.hash();
+        let mut wb = db.new_writebatch();
+        wb.insert(&key(0), b"w");
+        let err = wb.commit().unwrap_err();
+        assert!(matches!(err, CommitError::Corrupt(_)), "{err}");
+        assert!(
+            err.to_string()
+                .contains("AHA array does not match its branch"),
+            "{err}"
+        );
+
+        // a delete applied by an auto-flush fails its commit the same way
+        let mut wb = db.new_writebatch();
+        wb.remove(&key(1));
+        wb.insert(&[0; 64], &[0; 64]);
+        wb.insert(&[1; 64], &[0; 64]);
+        assert!(matches!(wb.commit(), Err(CommitError::Corrupt(_))));
+        assert_eq!(db.version_count(), 1);
+        assert_eq!(db.hash(), hash);
+        assert_eq!(db.get(&key(0)), Some(b"v".to_vec()));
+        assert_eq!(db.get(&key(1)), Some(b"v".to_vec()));
+    }
+
+    // without strict mode the hashes come from the node file instead
+    let mut db = DB::open(dir.to_str().unwrap(), aha_cfg(false, false));
+    let mut wb = db.new_writebatch();
+    wb.insert(&key(0), b"w");
+    wb.commit().unwrap();
+    assert_eq!(db.get(&key(0)), Some(b"w".to_vec()));
+    assert_eq!(db.get(&key(1)), Some(b"v".to_vec()));
+
+    drop(db);
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn db_strict_aha_accepts_writes_over_older_roots() {
+    let dir = unique_temp_dir("strict-aha-history");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let cfg = DBConfig::builder()
+        .truncate(true)
+        .cache_size(1024)
+        .page_cache_size(1 << 20)
+        .aha_cache_size(1 << 20)
+        .db_value_cache_size(0)
+        .strict_aha(true)
+        .build();
+    let mut db = DB::open(dir.to_str().unwrap(), cfg);
+    let key = |i: u32| i.wrapping_mul(2654435761).to_be_bytes();
+    let mut roots = Vec::new();
+    for round in 0..4u8 {
+        let mut wb = db.new_writebatch();
+        for i in 0..256u32 {
+            wb.insert(&key(i), &[round]);
+        }
+        roots.push(wb.commit().unwrap());
+    }
+
+    // Later commits replaced every branch of the first root; its AHA
+    // arrays must still be the ones it was written with.
+    db.open_root(roots[0]);
+    let mut wb = db.new_writebatch();
+    wb.insert(&key(0), b"fork");
+    wb.remove(&key(1));
+    wb.commit().unwrap();
+    assert_eq!(db.get(&key(0)), Some(b"fork".to_vec()));
+    assert_eq!(db.get(&key(2)), Some(vec![0]));
+
+    assert!(db.rollback_to(roots[1]));
+    let mut wb = db.new_writebatch();
+    wb.insert(&key(3), b"after rollback");
+    wb.commit().unwrap();
+    assert_eq!(db.get(&key(4)), Some(vec![1]));
+
+    drop(db);
+    let _ = fs::remove_dir_all(&dir);
+}
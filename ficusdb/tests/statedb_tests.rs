@@ -1,10 +1,15 @@
-use ficusdb::{StateDB, StateDBConfig};
+use ficusdb::{
+    AccountChange, AccountInfo, GenesisAccount, InsufficientBalance, Metrics, StateDB,
+    StateDBConfig, StateReadView,
+};
 use num_bigint::BigUint;
 use sha3::{Digest, Keccak256};
 
 use std::fs::File;
 use std::io::{BufRead, BufReader};
 use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::time::{SystemTime, UNIX_EPOCH};
 
 struct TempDir {
@@ -63,7 +68,11 @@ fn statedb_genesis_block() {
         p
     };
 
-    let cfg = StateDBConfig::builder().truncate(true).build();
+    // the ops predate EIP-161, when touching an account created it
+    let cfg = StateDBConfig::builder()
+        .truncate(true)
+        .create_on_touch(true)
+        .build();
     let mut statedb = StateDB::open(dir.path.to_str().unwrap(), cfg);
 
     let f = BufReader::new(File::open(ops_path).unwrap());
@@ -134,13 +143,10 @@ fn statedb_genesis_block() {
             }
             "commit" => {
                 // commit <blknum> <something> <expected_hash>
-                let _root = statedb.commit();
+                let (_root, hash) = statedb.commit_with_hash();
                 let expected = parse_hex_prefixed(parts[3]);
-                assert_eq!(
-                    statedb.hash().to_vec(),
-                    expected,
-                    "hash mismatch after commit line: {l}"
-                );
+                assert_eq!(hash, expected, "hash mismatch after commit line: {l}");
+                assert_eq!(statedb.hash(), hash);
             }
             "blocknum" => {
                 // ignored (workload bookkeeping)
@@ -168,3 +174,1264 @@ fn statedb_genesis_block() {
         }
     }
 }
+
+#[test]
+fn statedb_apply_genesis_matches_genesis_block() {
+    let dir = TempDir::new("prunusdb_statedb_apply_genesis");
+    let ops_path = {
+        let mut p = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        p.push("tests");
+        p.push("genesis.ops");
+        p
+    };
+
+    // Collect the allocation that genesis.ops builds op by op.
+    let mut alloc: Vec<(Vec<u8>, GenesisAccount)> = Vec::new();
+    let mut expected = Vec::new();
+    let f = BufReader::new(File::open(ops_path).unwrap());
+    for line in f.lines() {
+        let l = line.unwrap();
+        let parts: Vec<&str> = l.split_whitespace().collect();
+        match parts.first().copied() {
+            Some("addbalance") => alloc.push((
+                keccak32(&parse_hex_prefixed(parts[1])).to_vec(),
+                GenesisAccount {
+                    balance: parse_biguint(parts[2]),
+                    ..Default::default()
+                },
+            )),
+            Some("setnonce") => alloc.last_mut().unwrap().1.nonce = parts[2].parse().unwrap(),
+            Some("commit") => expected = parse_hex_prefixed(parts[3]),
+            _ => {}
+        }
+    }
+
+    let cfg = StateDBConfig::builder().truncate(true).build();
+    let mut statedb = StateDB::open(dir.path.to_str().unwrap(), cfg);
+    assert_eq!(statedb.apply_genesis(&alloc), expected);
+    assert_eq!(statedb.hash(), expected);
+}
+
+#[test]
+fn statedb_apply_genesis_seeds_code_and_storage() {
+    let dir = TempDir::new("prunusdb_statedb_genesis_storage");
+    let mut statedb = StateDB::open(dir.path.to_str().unwrap(), small_cfg());
+
+    let addr = keccak32(b"contract").to_vec();
+    let slot = keccak32(b"slot").to_vec();
+    let account = GenesisAccount {
+        balance: BigUint::from(5u8),
+        nonce: 1,
+        code: vec![0x60, 0x00],
+        storage: Some([(slot.clone(), vec![0x2a])].into_iter().collect()),
+    };
+    let root = statedb.apply_genesis(&[(addr.clone(), account)]);
+
+    let info = statedb.get_account(&addr).unwrap();
+    assert_eq!(info.nonce, 1);
+    assert_eq!(info.balance, BigUint::from(5u8));
+    assert_eq!(info.codehash, keccak32(&[0x60, 0x00]).to_vec());
+    assert_ne!(info.roothash, keccak32(&[0x80]).to_vec());
+    assert_eq!(statedb.get_code(&addr), vec![0x60, 0x00]);
+    assert_eq!(statedb.get_committed_state(&addr, &slot), vec![0x2a]);
+    assert_eq!(statedb.hash(), root);
+}
+
+fn small_cfg() -> StateDBConfig {
+    StateDBConfig::builder()
+        .truncate(true)
+        .cache_size(1 << 20)
+        .page_cache_size(1 << 20)
+        .aha_cache_size(1 << 20)
+        .obj_cache_size(1 << 20)
+        .build()
+}
+
+#[test]
+fn statedb_commit_many_storage_tries_matches_incremental_commits() {
+    const ACCOUNTS: u8 = 32;
+    const SLOTS: u8 = 16;
+    let slot_val = |a: u8, k: u8| vec![a, k, 1];
+
+    // All storage tries committed in one block.
+    let dir = TempDir::new("statedb_parallel_commit");
+    let mut statedb = StateDB::open(dir.path.to_str().unwrap(), small_cfg());
+    for a in 0..ACCOUNTS {
+        let addr = keccak32(&[a]);
+        statedb.add_balance(&addr, BigUint::from(a as u32 + 1));
+        for k in 0..SLOTS {
+            statedb.set_state(&addr, &keccak32(&[a, k]), &slot_val(a, k));
+        }
+    }
+    let _ = statedb.commit();
+    let root = statedb.hash();
+
+    // The same state, one account per block.
+    let dir2 = TempDir::new("statedb_serial_commit");
+    let mut serial = StateDB::open(dir2.path.to_str().unwrap(), small_cfg());
+    for a in 0..ACCOUNTS {
+        let addr = keccak32(&[a]);
+        serial.add_balance(&addr, BigUint::from(a as u32 + 1));
+        for k in 0..SLOTS {
+            serial.set_state(&addr, &keccak32(&[a, k]), &slot_val(a, k));
+        }
+        let _ = serial.commit();
+    }
+    assert_eq!(serial.hash(), root);
+
+    // Every slot is readable after reopening.
+    drop(statedb);
+    let cfg = StateDBConfig::builder()
+        .cache_size(1 << 20)
+        .page_cache_size(1 << 20)
+        .aha_cache_size(1 << 20)
+        .obj_cache_size(1 << 20)
+        .build();
+    let mut reopened = StateDB::open(dir.path.to_str().unwrap(), cfg);
+    assert_eq!(reopened.hash(), root);
+    for a in 0..ACCOUNTS {
+        let addr = keccak32(&[a]);
+        for k in 0..SLOTS {
+            let got = reopened.get_state(&addr, &keccak32(&[a, k]));
+            assert_eq!(got, rlp::encode(&slot_val(a, k)).to_vec());
+        }
+    }
+}
+
+#[test]
+fn statedb_try_sub_balance_reports_underflow() {
+    let dir = TempDir::new("statedb_sub_balance");
+    let mut statedb = StateDB::open(dir.path.to_str().unwrap(), small_cfg());
+    let addr = keccak32(b"alice");
+    statedb.add_balance(&addr, BigUint::from(100u32));
+
+    // Over-balance fails and leaves the balance unchanged.
+    assert_eq!(
+        statedb.try_sub_balance(&addr, BigUint::from(101u32)),
+        Err(InsufficientBalance {
+            balance: BigUint::from(100u32),
+            amount: BigUint::from(101u32),
+        })
+    );
+    assert_eq!(statedb.get_balance(&addr), BigUint::from(100u32));
+
+    // Zero is always fine, even for an account that does not exist yet.
+    assert_eq!(statedb.try_sub_balance(&addr, BigUint::from(0u32)), Ok(()));
+    assert_eq!(statedb.get_balance(&addr), BigUint::from(100u32));
+    let nobody = keccak32(b"nobody");
+    assert_eq!(
+        statedb.try_sub_balance(&nobody, BigUint::from(0u32)),
+        Ok(())
+    );
+    assert!(
+        statedb
+            .try_sub_balance(&nobody, BigUint::from(1u32))
+            .is_err()
+    );
+
+    // The exact balance drains the account.
+    assert_eq!(
+        statedb.try_sub_balance(&addr, BigUint::from(100u32)),
+        Ok(())
+    );
+    assert_eq!(statedb.get_balance(&addr), BigUint::from(0u32));
+}
+
+#[test]
+fn statedb_get_account_distinguishes_missing_from_empty() {
+    let dir = TempDir::new("statedb_get_account");
+    let mut statedb = StateDB::open(dir.path.to_str().unwrap(), small_cfg());
+    let addr = keccak32(b"alice");
+    let empty = keccak32(b"empty");
+    assert_eq!(statedb.get_account(&addr), None);
+
+    statedb.create_account(&empty);
+    statedb.add_balance(&addr, BigUint::from(7u32));
+    statedb.set_nonce(&addr, 3);
+    let _ = statedb.commit();
+
+    let info = statedb.get_account(&addr).unwrap();
+    assert_eq!(info.nonce, 3);
+    assert_eq!(info.balance, BigUint::from(7u32));
+    assert_eq!(info.codehash, keccak32(b"").to_vec());
+
+    // An all-zero account still exists.
+    let AccountInfo { nonce, balance, .. } = statedb.get_account(&empty).unwrap();
+    assert_eq!((nonce, balance), (0, BigUint::from(0u32)));
+
+    statedb.remove_account(&addr);
+    assert_eq!(statedb.get_account(&addr), None);
+}
+
+#[test]
+fn statedb_remove_accounts_matches_single_removes() {
+    let addrs: Vec<Vec<u8>> = (0..20u32)
+        .map(|i| keccak32(&i.to_be_bytes()).to_vec())
+        .collect();
+    let gone: Vec<&[u8]> = addrs[..12].iter().map(Vec::as_slice).collect();
+    // remove one by one, remove as a batch, never create
+    let mut hashes = Vec::new();
+    for mode in 0..3 {
+        let dir = TempDir::new("statedb_remove_accounts");
+        let mut statedb = StateDB::open(dir.path.to_str().unwrap(), small_cfg());
+        let start = if mode == 2 { gone.len() } else { 0 };
+        for (i, addr) in addrs.iter().enumerate().skip(start) {
+            statedb.add_balance(addr, BigUint::from(i as u32 + 1));
+            statedb.set_state(addr, b"slot", b"v");
+        }
+        let _ = statedb.commit();
+        match mode {
+            0 => gone.iter().for_each(|addr| statedb.remove_account(addr)),
+            1 => statedb.remove_accounts(&gone),
+            _ => {}
+        }
+        let _ = statedb.commit();
+        assert_eq!(statedb.get_account(&addrs[0]), None);
+        assert!(statedb.get_account(&addrs[12]).is_some());
+        hashes.push(statedb.hash());
+    }
+    assert_eq!(hashes[0], hashes[2]);
+    assert_eq!(hashes[1], hashes[2]);
+}
+
+#[test]
+fn statedb_hash_tracks_root_switches() {
+    let dir = TempDir::new("statedb_hash_cache");
+    let mut statedb = StateDB::open(dir.path.to_str().unwrap(), small_cfg());
+    let empty = statedb.hash();
+    let addr = keccak32(b"alice");
+
+    statedb.add_balance(&addr, BigUint::from(1u32));
+    // uncommitted changes are not part of the root hash
+    assert_eq!(statedb.hash(), empty);
+    let root_a = statedb.commit();
+    let hash_a = statedb.hash();
+    assert_ne!(hash_a, empty);
+
+    statedb.add_balance(&addr, BigUint::from(1u32));
+    let root_b = statedb.commit();
+    let hash_b = statedb.hash();
+    assert_ne!(hash_b, hash_a);
+
+    statedb.open_root(root_a);
+    assert_eq!(statedb.hash(), hash_a);
+    assert_eq!(statedb.get_balance(&addr), BigUint::from(1u32));
+    statedb.open_root_hash(&hash_b);
+    assert_eq!(statedb.hash(), hash_b);
+    assert_eq!(statedb.get_balance(&addr), BigUint::from(2u32));
+    statedb.open_root(0);
+    assert_eq!(statedb.hash(), empty);
+    statedb.open_root(root_b);
+    assert_eq!(statedb.hash(), hash_b);
+}
+
+#[test]
+fn statedb_code_roundtrips_and_dedupes() {
+    let dir = TempDir::new("statedb_code");
+    let code = b"\x60\x80\x60\x40\x52".to_vec();
+    let (a, b, c) = (keccak32(b"a"), keccak32(b"b"), keccak32(b"c"));
+    {
+        let mut statedb = StateDB::open(dir.path.to_str().unwrap(), small_cfg());
+        statedb.set_code(&a, code.clone());
+        statedb.set_code(&b, code.clone());
+        statedb.set_code(&c, b"other".to_vec());
+        assert_eq!(statedb.get_code(&a), code);
+        assert_eq!(statedb.get_codehash(&a), keccak32(&code).to_vec());
+        assert_eq!(statedb.get_code(&keccak32(b"none")), Vec::<u8>::new());
+        let _ = statedb.commit();
+    }
+
+    // The same code is written once.
+    let size = std::fs::metadata(dir.path.join("code")).unwrap().len();
+    let record = |code: &[u8]| (1 + 32 + 4 + code.len()) as u64;
+    assert_eq!(size, record(&code) + record(b"other"));
+
+    let cfg = StateDBConfig::builder()
+        .cache_size(1 << 20)
+        .page_cache_size(1 << 20)
+        .aha_cache_size(1 << 20)
+        .obj_cache_size(1 << 20)
+        .build();
+    let mut reopened = StateDB::open(dir.path.to_str().unwrap(), cfg);
+    assert_eq!(reopened.get_code(&a), code);
+    assert_eq!(reopened.get_code(&b), code);
+    assert_eq!(reopened.get_code(&c), b"other".to_vec());
+}
+
+#[test]
+fn statedb_flush_persists_without_commit_or_drop() {
+    let dir = TempDir::new("statedb_flush");
+    let code = b"\x60\x80\x60\x40\x52".to_vec();
+    let addr = keccak32(b"alice");
+    let mut statedb = StateDB::open(dir.path.to_str().unwrap(), small_cfg());
+    statedb.add_balance(&addr, BigUint::from(5u32));
+    statedb.set_code(&addr, code.clone());
+    let code_len = || std::fs::metadata(dir.path.join("code")).unwrap().len();
+    assert_eq!(code_len(), 0);
+
+    statedb.flush();
+    assert_eq!(code_len(), (1 + 32 + 4 + code.len()) as u64);
+    // uncommitted changes are still there
+    assert_eq!(statedb.get_balance(&addr), BigUint::from(5u32));
+    assert_eq!(statedb.get_code(&addr), code);
+
+    let _ = statedb.commit();
+    statedb.flush();
+    let cfg = StateDBConfig::builder()
+        .cache_size(1 << 20)
+        .page_cache_size(1 << 20)
+        .aha_cache_size(1 << 20)
+        .obj_cache_size(1 << 20)
+        .build();
+    let mut reopened = StateDB::open(dir.path.to_str().unwrap(), cfg);
+    assert_eq!(reopened.hash(), statedb.hash());
+    assert_eq!(reopened.get_balance(&addr), BigUint::from(5u32));
+    assert_eq!(reopened.get_code(&addr), code);
+}
+
+#[test]
+fn statedb_prune_empty_removes_emptied_accounts() {
+    let open = |dir: &TempDir, prune_empty: bool| {
+        let cfg = StateDBConfig::builder()
+            .truncate(true)
+            .cache_size(1 << 20)
+            .page_cache_size(1 << 20)
+            .aha_cache_size(1 << 20)
+            .obj_cache_size(1 << 20)
+            .prune_empty(prune_empty)
+            .create_on_touch(true)
+            .build();
+        StateDB::open(dir.path.to_str().unwrap(), cfg)
+    };
+    let (alice, bob) = (keccak32(b"alice"), keccak32(b"bob"));
+
+    let dir = TempDir::new("statedb_prune_empty");
+    let mut statedb = open(&dir, true);
+    let empty_root = statedb.hash();
+    statedb.add_balance(&alice, BigUint::from(5u32));
+    let _ = statedb.commit();
+    assert_ne!(statedb.hash(), empty_root);
+
+    statedb.sub_balance(&alice, BigUint::from(5u32));
+    let _ = statedb.commit();
+    assert_eq!(statedb.hash(), empty_root);
+    assert_eq!(statedb.get_account(&alice), None);
+
+    // A created account with a nonzero field is kept.
+    statedb.create_account(&bob);
+    statedb.set_nonce(&bob, 1);
+    let _ = statedb.commit();
+    assert_eq!(statedb.get_nonce(&bob), 1);
+
+    // Without pruning the emptied account stays in the trie.
+    let dir2 = TempDir::new("statedb_keep_empty");
+    let mut keep = open(&dir2, false);
+    keep.add_balance(&alice, BigUint::from(5u32));
+    keep.sub_balance(&alice, BigUint::from(5u32));
+    let _ = keep.commit();
+    assert_ne!(keep.hash(), empty_root);
+}
+
+#[test]
+fn statedb_committed_state_ignores_dirty_writes() {
+    let dir = TempDir::new("statedb_committed_state");
+    let mut statedb = StateDB::open(dir.path.to_str().unwrap(), small_cfg());
+    let (addr, slot) = (keccak32(b"contract"), keccak32(b"slot"));
+    statedb.set_state(&addr, &slot, &[1]);
+    let _ = statedb.commit();
+
+    statedb.set_state(&addr, &slot, &[2]);
+    assert_eq!(
+        statedb.get_committed_state(&addr, &slot),
+        rlp::encode(&vec![1u8]).to_vec()
+    );
+    assert_eq!(
+        statedb.get_state(&addr, &slot),
+        rlp::encode(&vec![2u8]).to_vec()
+    );
+
+    // Clearing a slot reads as empty until it is committed.
+    statedb.set_state(&addr, &slot, b"");
+    assert_eq!(statedb.get_state(&addr, &slot), Vec::<u8>::new());
+    assert_eq!(
+        statedb.get_committed_state(&addr, &slot),
+        rlp::encode(&vec![1u8]).to_vec()
+    );
+
+    let _ = statedb.commit();
+    assert_eq!(statedb.get_committed_state(&addr, &slot), Vec::<u8>::new());
+}
+
+#[test]
+fn statedb_nested_snapshots_revert_precisely() {
+    let dir = TempDir::new("statedb_nested_snapshots");
+    let mut statedb = StateDB::open(dir.path.to_str().unwrap(), small_cfg());
+    let (a, b, c) = (keccak32(b"a"), keccak32(b"b"), keccak32(b"c"));
+    statedb.add_balance(&a, BigUint::from(100u32));
+    statedb.add_balance(&b, BigUint::from(50u32));
+    let _ = statedb.commit();
+    let committed = statedb.hash();
+    let balances =
+        |s: &mut StateDB| [&a, &b, &c].map(|addr| s.get_account(addr).map(|info| info.balance));
+    let some = |n: u32| Some(BigUint::from(n));
+
+    // `a` is touched before the first snapshot and in every level.
+    statedb.add_balance(&a, BigUint::from(1u32));
+    let s0 = statedb.snapshot();
+    statedb.add_balance(&a, BigUint::from(10u32));
+    let s1 = statedb.snapshot();
+    statedb.sub_balance(&a, BigUint::from(5u32));
+    statedb.add_balance(&b, BigUint::from(5u32));
+    statedb.add_balance(&c, BigUint::from(7u32));
+    let s2 = statedb.snapshot();
+    statedb.add_balance(&a, BigUint::from(100u32));
+    statedb.remove_account(&b);
+    statedb.add_balance(&c, BigUint::from(1u32));
+    assert_eq!(balances(&mut statedb), [some(206), None, some(8)]);
+
+    statedb.revert(s2);
+    assert_eq!(balances(&mut statedb), [some(106), some(55), some(7)]);
+    statedb.revert(s1);
+    assert_eq!(balances(&mut statedb), [some(111), some(50), None]);
+    statedb.revert(s0);
+    assert_eq!(balances(&mut statedb), [some(101), some(50), None]);
+
+    // Undoing the last change leaves exactly the committed state.
+    statedb.sub_balance(&a, BigUint::from(1u32));
+    let _ = statedb.commit();
+    assert_eq!(statedb.hash(), committed);
+}
+
+#[test]
+fn statedb_account_diff_reports_changed_accounts() {
+    let dir = TempDir::new("statedb_account_diff");
+    let mut statedb = StateDB::open(dir.path.to_str().unwrap(), small_cfg());
+    let (a, b, c, d) = (
+        keccak32(b"a"),
+        keccak32(b"b"),
+        keccak32(b"c"),
+        keccak32(b"d"),
+    );
+    statedb.add_balance(&a, BigUint::from(10u32));
+    statedb.add_balance(&b, BigUint::from(20u32));
+    statedb.add_balance(&c, BigUint::from(30u32));
+    let root1 = statedb.commit();
+    let info = |s: &mut StateDB, addr: &[u8]| s.get_account(addr);
+    let (a1, b1, c1) = (
+        info(&mut statedb, &a),
+        info(&mut statedb, &b),
+        info(&mut statedb, &c),
+    );
+
+    statedb.set_nonce(&a, 1);
+    statedb.set_state(&b, &keccak32(b"slot"), &[1]);
+    statedb.remove_account(&c);
+    statedb.add_balance(&d, BigUint::from(40u32));
+    let root2 = statedb.commit();
+    let (a2, b2, d2) = (
+        info(&mut statedb, &a),
+        info(&mut statedb, &b),
+        info(&mut statedb, &d),
+    );
+
+    let mut expected = vec![
+        AccountChange {
+            addr: a.to_vec(),
+            old: a1,
+            new: a2,
+            storage_changed: false,
+        },
+        AccountChange {
+            addr: b.to_vec(),
+            old: b1,
+            new: b2,
+            storage_changed: true,
+        },
+        AccountChange {
+            addr: c.to_vec(),
+            old: c1,
+            new: None,
+            storage_changed: false,
+        },
+        AccountChange {
+            addr: d.to_vec(),
+            old: None,
+            new: d2,
+            storage_changed: false,
+        },
+    ];
+    expected.sort_by(|x, y| x.addr.cmp(&y.addr));
+    assert_eq!(statedb.account_diff(root1, root2), expected);
+    assert!(statedb.account_diff(root2, root2).is_empty());
+    let addrs: Vec<Vec<u8>> = expected.into_iter().map(|change| change.addr).collect();
+    assert_eq!(statedb.changed_accounts_since(root1), addrs);
+    assert!(statedb.changed_accounts_since(root2).is_empty());
+}
+
+#[test]
+fn statedb_open_version_by_index() {
+    let dir = TempDir::new("prunusdb_statedb_open_version");
+    let mut statedb = StateDB::open(dir.path.to_str().unwrap(), small_cfg());
+    let addr = keccak32(b"acct");
+
+    let mut hashes = Vec::new();
+    for nonce in 1..=3 {
+        statedb.set_nonce(&addr, nonce);
+        statedb.commit();
+        hashes.push(statedb.hash());
+    }
+    assert_eq!(statedb.version_count(), 3);
+
+    assert!(statedb.open_version(0).is_some());
+    assert_eq!(statedb.get_nonce(&addr), 1);
+    assert_eq!(statedb.hash(), hashes[0]);
+    statedb.open_version_from_tip(1).unwrap();
+    assert_eq!(statedb.get_nonce(&addr), 2);
+    statedb.open_version_from_tip(0).unwrap();
+    assert_eq!(statedb.hash(), hashes[2]);
+
+    assert_eq!(statedb.open_version(3), None);
+    assert_eq!(statedb.open_version_from_tip(3), None);
+    assert_eq!(statedb.hash(), hashes[2]);
+}
+
+#[derive(Default)]
+struct NodeReads(AtomicUsize);
+
+impl Metrics for NodeReads {
+    fn on_node_read(&self, _bytes: usize) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[derive(Default)]
+struct Commits(AtomicUsize);
+
+impl Metrics for Commits {
+    fn on_commit(&self, _dur: std::time::Duration) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[test]
+fn statedb_reports_storage_trie_commits_to_metrics() {
+    let dir = TempDir::new("statedb_commit_metrics");
+    let commits = Arc::new(Commits::default());
+    let mut cfg = small_cfg();
+    cfg.metrics = Some(commits.clone());
+    let mut statedb = StateDB::open(dir.path.to_str().unwrap(), cfg);
+    let (alice, bob, slot) = (keccak32(b"alice"), keccak32(b"bob"), keccak32(b"slot"));
+    let count = || commits.0.load(Ordering::Relaxed);
+
+    // the account trie and both storage tries
+    statedb.set_state(&alice, &slot, &[1]);
+    statedb.set_state(&bob, &slot, &[2]);
+    statedb.commit();
+    assert_eq!(count(), 3);
+    // clearing alice's only slot commits her storage trie to empty
+    statedb.set_state(&alice, &slot, &[]);
+    statedb.commit();
+    assert_eq!(count(), 5);
+    // nothing changed
+    statedb.commit();
+    assert_eq!(count(), 5);
+}
+
+#[test]
+fn statedb_prefetch_loads_accounts_ahead_of_use() {
+    let dir = TempDir::new("prunusdb_statedb_prefetch");
+    let addrs: Vec<[u8; 32]> = (0..20u8).map(|i| keccak32(&[i])).collect();
+    {
+        let mut statedb = StateDB::open(dir.path.to_str().unwrap(), small_cfg());
+        for (i, addr) in addrs.iter().enumerate() {
+            statedb.add_balance(addr, BigUint::from(i + 1));
+            statedb.set_state(addr, &keccak32(b"slot"), &[i as u8 + 1]);
+        }
+        statedb.commit();
+    }
+
+    let reads = Arc::new(NodeReads::default());
+    let cfg = StateDBConfig::builder()
+        .cache_size(1 << 20)
+        .page_cache_size(1 << 20)
+        .aha_cache_size(1 << 20)
+        .obj_cache_size(1 << 20)
+        .metrics(reads.clone())
+        .build();
+    let mut statedb = StateDB::open(dir.path.to_str().unwrap(), cfg);
+    let missing = keccak32(b"missing");
+    let wanted: Vec<&[u8]> = addrs[..5]
+        .iter()
+        .chain([&missing])
+        .map(|a| a.as_slice())
+        .collect();
+    statedb.prefetch(&wanted);
+    let after_prefetch = reads.0.load(Ordering::Relaxed);
+    assert!(after_prefetch > 0);
+
+    for (i, addr) in addrs[..5].iter().enumerate() {
+        assert_eq!(statedb.get_balance(addr), BigUint::from(i + 1));
+    }
+    assert_eq!(reads.0.load(Ordering::Relaxed), after_prefetch);
+    assert_eq!(statedb.get_account(&missing), None);
+    assert_eq!(
+        statedb.get_committed_state(&addrs[3], &keccak32(b"slot")),
+        vec![4]
+    );
+}
+
+#[test]
+fn statedb_storage_root_includes_pending_writes() {
+    let dir = TempDir::new("statedb_storage_root");
+    let mut statedb = StateDB::open(dir.path.to_str().unwrap(), small_cfg());
+    let addr = keccak32(b"contract");
+    let empty_root = Keccak256::digest(rlp::encode(&"")).to_vec();
+    assert_eq!(statedb.storage_root(&addr), empty_root);
+
+    for k in 0..20u8 {
+        statedb.set_state(&addr, &keccak32(&[k]), &[k + 1]);
+    }
+    let pending = statedb.storage_root(&addr);
+    assert_ne!(pending, empty_root);
+    // computing it leaves the pending writes in place
+    assert_eq!(
+        statedb.get_state(&addr, &keccak32(&[3])),
+        rlp::encode(&vec![4u8]).to_vec()
+    );
+    let _ = statedb.commit();
+    assert_eq!(statedb.get_account(&addr).unwrap().roothash, pending);
+    assert_eq!(statedb.storage_root(&addr), pending);
+
+    // overwrites and deletions mid-block
+    statedb.set_state(&addr, &keccak32(&[0]), &[9]);
+    statedb.set_state(&addr, &keccak32(&[1]), b"");
+    let pending = statedb.storage_root(&addr);
+    statedb.add_balance(&addr, BigUint::from(1u32));
+    let _ = statedb.commit();
+    assert_eq!(statedb.get_account(&addr).unwrap().roothash, pending);
+
+    // clearing every slot gives the empty trie hash
+    for k in 0..20u8 {
+        statedb.set_state(&addr, &keccak32(&[k]), b"");
+    }
+    assert_eq!(statedb.storage_root(&addr), empty_root);
+    let _ = statedb.commit();
+    assert_eq!(statedb.get_account(&addr).unwrap().roothash, empty_root);
+}
+
+#[test]
+fn statedb_transient_storage_never_reaches_the_root() {
+    let dir = TempDir::new("statedb_transient");
+    let mut statedb = StateDB::open(dir.path.to_str().unwrap(), small_cfg());
+    let addr = keccak32(b"contract");
+    statedb.add_balance(&addr, BigUint::from(1u32));
+    statedb.finalise();
+    let _ = statedb.commit();
+    let root = statedb.hash();
+
+    statedb.set_transient(&addr, b"lock", b"1");
+    assert_eq!(statedb.get_transient(&addr, b"lock"), b"1".to_vec());
+    assert!(statedb.get_state(&addr, b"lock").is_empty());
+
+    // journaled like regular storage
+    let sid = statedb.snapshot();
+    statedb.set_transient(&addr, b"lock", b"2");
+    statedb.set_transient(&addr, b"other", b"x");
+    statedb.revert(sid);
+    assert_eq!(statedb.get_transient(&addr, b"lock"), b"1".to_vec());
+    assert!(statedb.get_transient(&addr, b"other").is_empty());
+
+    assert_eq!(
+        statedb.storage_root(&addr),
+        statedb.get_account(&addr).unwrap().roothash
+    );
+    let _ = statedb.commit();
+    assert_eq!(statedb.hash(), root);
+
+    // gone at the end of the transaction
+    statedb.finalise();
+    assert!(statedb.get_transient(&addr, b"lock").is_empty());
+}
+
+#[test]
+fn statedb_revert_after_commit_undoes_transient_writes() {
+    let dir = TempDir::new("statedb_transient_after_commit");
+    let mut statedb = StateDB::open(dir.path.to_str().unwrap(), small_cfg());
+    let addr = keccak32(b"contract");
+    // a snapshot taken before the commit must not shift later ones
+    statedb.snapshot();
+    statedb.add_balance(&addr, BigUint::from(1u32));
+    let _ = statedb.commit();
+
+    let sid = statedb.snapshot();
+    statedb.set_transient(&addr, b"lock", b"1");
+    statedb.add_balance(&addr, BigUint::from(2u32));
+    statedb.revert(sid);
+    assert!(statedb.get_transient(&addr, b"lock").is_empty());
+    assert_eq!(statedb.get_balance(&addr), BigUint::from(1u32));
+}
+
+#[test]
+fn statedb_revert_after_commit_cools_warmed_entries() {
+    let dir = TempDir::new("statedb_access_after_commit");
+    let mut statedb = StateDB::open(dir.path.to_str().unwrap(), small_cfg());
+    let a = keccak32(b"a");
+    statedb.snapshot();
+    statedb.add_balance(&a, BigUint::from(1u32));
+    let _ = statedb.commit();
+
+    let sid = statedb.snapshot();
+    assert!(!statedb.mark_access(&a, None));
+    assert!(!statedb.mark_access(&a, Some(b"slot")));
+    statedb.revert(sid);
+    assert!(!statedb.mark_access(&a, None));
+    assert!(!statedb.mark_access(&a, Some(b"slot")));
+}
+
+#[test]
+fn statedb_access_list_tracks_warmth_across_snapshots() {
+    let dir = TempDir::new("statedb_access_list");
+    let mut statedb = StateDB::open(dir.path.to_str().unwrap(), small_cfg());
+    let a = keccak32(b"a");
+    let b = keccak32(b"b");
+
+    assert!(!statedb.mark_access(&a, None));
+    assert!(statedb.mark_access(&a, None));
+    // slots are tracked apart from their address
+    assert!(!statedb.mark_access(&a, Some(b"slot")));
+    assert!(statedb.mark_access(&a, Some(b"slot")));
+    assert!(!statedb.mark_access(&b, Some(b"slot")));
+
+    let sid = statedb.snapshot();
+    assert!(statedb.mark_access(&a, None));
+    assert!(!statedb.mark_access(&b, None));
+    assert!(!statedb.mark_access(&a, Some(b"other")));
+    let inner = statedb.snapshot();
+    assert!(!statedb.mark_access(&a, Some(b"inner")));
+    statedb.revert(inner);
+    assert!(statedb.mark_access(&a, Some(b"other")));
+    statedb.revert(sid);
+
+    // warmed before the snapshot: still warm; after it: cold again
+    assert!(statedb.mark_access(&a, None));
+    assert!(statedb.mark_access(&a, Some(b"slot")));
+    assert!(!statedb.mark_access(&b, None));
+    assert!(!statedb.mark_access(&a, Some(b"other")));
+
+    statedb.finalise();
+    assert!(!statedb.mark_access(&a, None));
+    assert!(!statedb.mark_access(&a, Some(b"slot")));
+}
+
+#[test]
+fn statedb_reset_returns_to_the_committed_state() {
+    let dir = TempDir::new("statedb_reset");
+    let mut statedb = StateDB::open(dir.path.to_str().unwrap(), small_cfg());
+    let alice = keccak32(b"alice");
+    let bob = keccak32(b"bob");
+    statedb.add_balance(&alice, BigUint::from(10u32));
+    statedb.set_state(&alice, b"slot", b"1");
+    let _ = statedb.commit();
+    let root = statedb.hash();
+    let slot = statedb.get_state(&alice, b"slot");
+
+    statedb.snapshot();
+    statedb.add_balance(&alice, BigUint::from(5u32));
+    statedb.set_state(&alice, b"slot", b"2");
+    statedb.set_state(&alice, b"new", b"3");
+    statedb.add_balance(&bob, BigUint::from(7u32));
+    statedb.set_transient(&alice, b"lock", b"1");
+    assert!(!statedb.mark_access(&bob, None));
+    statedb.reset();
+
+    assert_eq!(statedb.get_balance(&alice), BigUint::from(10u32));
+    assert_eq!(statedb.get_state(&alice, b"slot"), slot);
+    assert!(statedb.get_state(&alice, b"new").is_empty());
+    assert!(statedb.get_account(&bob).is_none());
+    assert!(statedb.get_transient(&alice, b"lock").is_empty());
+    assert!(!statedb.mark_access(&bob, None));
+    assert_eq!(statedb.hash(), root);
+
+    // a reset StateDB commits as if the aborted block never happened
+    statedb.add_balance(&bob, BigUint::from(1u32));
+    let _ = statedb.commit();
+    statedb.reset();
+    assert_eq!(statedb.get_balance(&bob), BigUint::from(1u32));
+    assert_eq!(statedb.get_balance(&alice), BigUint::from(10u32));
+}
+
+#[test]
+fn statedb_verify_account_checks_the_storage_root() {
+    let dir = TempDir::new("statedb_verify_account");
+    let mut statedb = StateDB::open(dir.path.to_str().unwrap(), small_cfg());
+    let addr = keccak32(b"contract");
+    let empty_root = Keccak256::digest(rlp::encode(&"")).to_vec();
+    assert!(statedb.verify_account(&addr, &empty_root));
+
+    // A trie with one slot is a single leaf: [hex-prefix(path), rlp(value)],
+    // with the even-length leaf flag 0x20 in front of the slot key.
+    let slot = keccak32(b"slot");
+    let leaf_root = |value: &[u8]| {
+        let mut path = vec![0x20];
+        path.extend_from_slice(&slot);
+        let mut leaf = rlp::RlpStream::new_list(2);
+        leaf.append(&path);
+        leaf.append(&rlp::encode(&value.to_vec()).to_vec());
+        Keccak256::digest(leaf.out()).to_vec()
+    };
+    statedb.set_state(&addr, &slot, &[0x2a]);
+    assert!(statedb.verify_account(&addr, &leaf_root(&[0x2a])));
+    assert!(!statedb.verify_account(&addr, &empty_root));
+
+    let _ = statedb.commit();
+    assert!(statedb.verify_account(&addr, &leaf_root(&[0x2a])));
+    statedb.set_state(&addr, &slot, &[0x01, 0x00]);
+    assert!(statedb.verify_account(&addr, &leaf_root(&[0x01, 0x00])));
+    assert!(!statedb.verify_account(&addr, &leaf_root(&[0x2a])));
+    // nothing was committed
+    assert_eq!(
+        statedb.get_account(&addr).unwrap().roothash,
+        leaf_root(&[0x2a])
+    );
+}
+
+#[test]
+fn statedb_read_view_sees_only_its_committed_root() {
+    let dir = TempDir::new("statedb_read_view");
+    let mut statedb = StateDB::open(dir.path.to_str().unwrap(), small_cfg());
+    let alice = keccak32(b"alice");
+    let slot = keccak32(b"slot");
+    statedb.add_balance(&alice, BigUint::from(10u32));
+    statedb.set_nonce(&alice, 1);
+    statedb.set_state(&alice, &slot, &[1]);
+    let _ = statedb.commit();
+    let committed = statedb.get_committed_state(&alice, &slot);
+
+    let view: StateReadView = statedb.read_view();
+    // pending changes of the StateDB are not visible
+    statedb.add_balance(&alice, BigUint::from(5u32));
+    statedb.set_state(&alice, &slot, &[2]);
+    assert_eq!(view.get_balance(&alice), BigUint::from(10u32));
+    assert_eq!(view.get_state(&alice, &slot), committed);
+
+    // nor are later commits, made while another thread reads the view
+    std::thread::scope(|s| {
+        let reader = s.spawn(|| {
+            for _ in 0..100 {
+                assert_eq!(view.get_balance(&alice), BigUint::from(10u32));
+                assert_eq!(view.get_nonce(&alice), 1);
+                assert_eq!(view.get_state(&alice, &slot), committed);
+            }
+        });
+        for i in 0..10u32 {
+            statedb.add_balance(&keccak32(&i.to_be_bytes()), BigUint::from(1u32));
+            let _ = statedb.commit();
+        }
+        reader.join().unwrap();
+    });
+    assert_eq!(statedb.get_balance(&alice), BigUint::from(15u32));
+    assert_eq!(view.get_balance(&keccak32(b"nobody")), BigUint::from(0u32));
+    assert!(view.get_state(&keccak32(b"nobody"), &slot).is_empty());
+
+    let latest = statedb.read_view();
+    assert_ne!(latest.root(), view.root());
+    assert_eq!(latest.get_balance(&alice), BigUint::from(15u32));
+    assert_eq!(
+        latest.get_state(&alice, &slot),
+        statedb.get_committed_state(&alice, &slot)
+    );
+}
+
+#[test]
+fn statedb_pending_accounts_counts_uncommitted_accounts() {
+    let dir = TempDir::new("statedb_pending_accounts");
+    let mut statedb = StateDB::open(dir.path.to_str().unwrap(), small_cfg());
+    assert_eq!(statedb.pending_accounts(), 0);
+    for i in 0..5u32 {
+        statedb.add_balance(&keccak32(&i.to_be_bytes()), BigUint::from(1u32));
+    }
+    assert_eq!(statedb.pending_accounts(), 5);
+    // reads don't count; another write to the same account doesn't either
+    statedb.get_balance(&keccak32(b"reader"));
+    statedb.set_nonce(&keccak32(&0u32.to_be_bytes()), 1);
+    assert_eq!(statedb.pending_accounts(), 5);
+    let _ = statedb.commit();
+    assert_eq!(statedb.pending_accounts(), 0);
+}
+
+#[test]
+fn statedb_max_dirty_bytes_flushes_storage_before_commit() {
+    const LIMIT: usize = 4096;
+    let dir = TempDir::new("statedb_max_dirty_bytes");
+    let plain_dir = TempDir::new("statedb_max_dirty_bytes_plain");
+    let cfg = StateDBConfig::builder()
+        .truncate(true)
+        .cache_size(1 << 20)
+        .page_cache_size(1 << 20)
+        .aha_cache_size(1 << 20)
+        .obj_cache_size(1 << 20)
+        .max_dirty_bytes(LIMIT)
+        .build();
+    let mut limited = StateDB::open(dir.path.to_str().unwrap(), cfg);
+    let mut plain = StateDB::open(plain_dir.path.to_str().unwrap(), small_cfg());
+    let addrs: Vec<[u8; 32]> = (0..4u8).map(|a| keccak32(&[a])).collect();
+
+    let (mut peak, mut records, mut flushes) = (0, 0, 0);
+    let mut sid = None;
+    for i in 0..500u32 {
+        if i == 400 {
+            sid = Some((limited.snapshot(), plain.snapshot()));
+        }
+        let addr = &addrs[i as usize % addrs.len()];
+        let key = keccak32(&i.to_be_bytes());
+        let val = (i % 97 + 1).to_be_bytes();
+        limited.set_state(addr, &key, &val);
+        plain.set_state(addr, &key, &val);
+        let pending = limited.pending_bytes();
+        if pending < peak {
+            // a flush leaves only the account records
+            records = pending;
+            flushes += 1;
+        }
+        peak = peak.max(pending);
+    }
+    assert!(flushes > 0 && peak - records <= LIMIT, "peak {peak}");
+    assert!(plain.pending_bytes() > 500 * 32);
+    let slot = keccak32(&450u32.to_be_bytes());
+    assert_eq!(
+        limited.get_state(&addrs[2], &slot),
+        plain.get_state(&addrs[2], &slot)
+    );
+
+    // writes flushed after the snapshot are still undone
+    let (limited_sid, plain_sid) = sid.unwrap();
+    limited.revert(limited_sid);
+    plain.revert(plain_sid);
+    assert_eq!(limited.get_state(&addrs[2], &slot), Vec::<u8>::new());
+    assert_eq!(
+        limited.storage_root(&addrs[1]),
+        plain.storage_root(&addrs[1])
+    );
+
+    limited.commit();
+    plain.commit();
+    assert_eq!(limited.pending_bytes(), 0);
+    assert_eq!(limited.hash(), plain.hash());
+}
+
+#[test]
+fn statedb_max_dirty_bytes_ignores_dirty_account_records() {
+    const LIMIT: usize = 4096;
+    let dir = TempDir::new("statedb_max_dirty_bytes_accounts");
+    let plain_dir = TempDir::new("statedb_max_dirty_bytes_accounts_plain");
+    let mut cfg = small_cfg();
+    cfg.max_dirty_bytes = LIMIT;
+    let mut limited = StateDB::open(dir.path.to_str().unwrap(), cfg);
+    let mut plain = StateDB::open(plain_dir.path.to_str().unwrap(), small_cfg());
+
+    // The account records alone outgrow the limit long before the storage
+    // writes do, which must not flush on every write.
+    let slot = keccak32(b"slot");
+    for a in 0..100u32 {
+        let addr = keccak32(&a.to_be_bytes());
+        limited.set_state(&addr, &slot, &[1]);
+        plain.set_state(&addr, &slot, &[1]);
+    }
+    assert!(plain.pending_bytes() > LIMIT);
+    assert_eq!(limited.pending_bytes(), plain.pending_bytes());
+
+    // Once the storage writes do exceed it, one flush empties them.
+    let (mut peak, mut flushes) = (limited.pending_bytes(), 0);
+    for i in 0..50u32 {
+        let addr = keccak32(&i.to_be_bytes());
+        let key = keccak32(&i.to_be_bytes());
+        limited.set_state(&addr, &key, &[2; 8]);
+        plain.set_state(&addr, &key, &[2; 8]);
+        let pending = limited.pending_bytes();
+        if pending < peak {
+            flushes += 1;
+        }
+        peak = pending;
+    }
+    assert_eq!(flushes, 1);
+    assert_eq!(limited.commit_with_hash().1, plain.commit_with_hash().1);
+}
+
+#[test]
+fn statedb_rollback_to_drops_later_versions() {
+    let dir = TempDir::new("statedb_rollback_to");
+    let mut statedb = StateDB::open(dir.path.to_str().unwrap(), small_cfg());
+    let alice = keccak32(b"alice");
+    let mut roots = Vec::new();
+    let mut hashes = Vec::new();
+    for i in 1..=3u32 {
+        statedb.add_balance(&alice, BigUint::from(i));
+        roots.push(statedb.commit());
+        hashes.push(statedb.hash());
+    }
+    let root_len = || std::fs::metadata(dir.path.join("root")).unwrap().len();
+    assert_eq!(root_len(), 3 * 40);
+
+    statedb.add_balance(&alice, BigUint::from(100u32));
+    assert!(!statedb.rollback_to(0xdead_beef));
+    assert!(statedb.rollback_to(roots[0]));
+    assert_eq!(root_len(), 40);
+    assert_eq!(statedb.version_count(), 1);
+    assert_eq!(statedb.hash(), hashes[0]);
+    assert_eq!(statedb.get_balance(&alice), BigUint::from(1u32));
+
+    // dropped roots can no longer be opened by hash
+    statedb.open_root_hash(&hashes[2]);
+    assert_eq!(statedb.hash(), hashes[0]);
+
+    statedb.add_balance(&alice, BigUint::from(1u32));
+    let _ = statedb.commit();
+    assert_eq!(statedb.version_count(), 2);
+    assert_eq!(statedb.get_balance(&alice), BigUint::from(2u32));
+}
+
+#[test]
+fn statedb_get_states_matches_get_state() {
+    let dir = TempDir::new("statedb_get_states");
+    let mut statedb = StateDB::open(dir.path.to_str().unwrap(), small_cfg());
+    let alice = keccak32(b"alice");
+    let slots: Vec<[u8; 32]> = (0..12u32).map(|i| keccak32(&i.to_be_bytes())).collect();
+    for (i, slot) in slots.iter().take(8).enumerate() {
+        statedb.set_state(&alice, slot, &[i as u8 + 1]);
+    }
+    let _ = statedb.commit();
+
+    // overwrite, delete and add slots without committing
+    statedb.set_state(&alice, &slots[1], &[0xaa]);
+    statedb.set_state(&alice, &slots[2], &[]);
+    statedb.set_state(&alice, &slots[9], &[0xbb]);
+
+    let keys: Vec<&[u8]> = slots.iter().map(|s| s.as_slice()).collect();
+    for addr in [alice, keccak32(b"nobody")] {
+        let expected: Vec<Vec<u8>> = keys.iter().map(|k| statedb.get_state(&addr, k)).collect();
+        assert_eq!(statedb.get_states(&addr, &keys), expected);
+    }
+    let vals = statedb.get_states(&alice, &keys);
+    assert_eq!(vals[0], rlp::encode(&vec![1u8]).to_vec());
+    assert_eq!(vals[1], rlp::encode(&vec![0xaau8]).to_vec());
+    assert!(vals[2].is_empty() && vals[8].is_empty());
+    assert_eq!(vals[9], rlp::encode(&vec![0xbbu8]).to_vec());
+    assert!(statedb.get_states(&alice, &[]).is_empty());
+}
+
+struct NodeLookups(AtomicUsize);
+
+impl Metrics for NodeLookups {
+    fn on_cache_hit(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn on_cache_miss(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[test]
+fn statedb_get_state_reuses_the_storage_trie_root() {
+    let dir = TempDir::new("statedb_storage_trie_cache");
+    let lookups = Arc::new(NodeLookups(AtomicUsize::new(0)));
+    let cfg = StateDBConfig::builder()
+        .truncate(true)
+        .cache_size(1 << 20)
+        .page_cache_size(1 << 20)
+        .aha_cache_size(1 << 20)
+        .obj_cache_size(1 << 20)
+        .metrics(lookups.clone())
+        .build();
+    let mut statedb = StateDB::open(dir.path.to_str().unwrap(), cfg);
+    let alice = keccak32(b"alice");
+    let slot = keccak32(b"slot");
+    statedb.set_state(&alice, &slot, &[1]);
+    let _ = statedb.commit();
+
+    assert_eq!(
+        statedb.get_state(&alice, &slot),
+        rlp::encode(&vec![1u8]).to_vec()
+    );
+    // alice's storage trie is a single short node: its root is loaded once
+    // and then resolves every missing slot
+    let before = lookups.0.load(Ordering::Relaxed);
+    for i in 0..100u32 {
+        assert!(
+            statedb
+                .get_state(&alice, &keccak32(&i.to_be_bytes()))
+                .is_empty()
+        );
+    }
+    assert_eq!(lookups.0.load(Ordering::Relaxed), before + 1);
+
+    // a new storage root replaces the cached trie
+    statedb.set_state(&alice, &slot, &[2]);
+    let _ = statedb.commit();
+    statedb.set_state(&alice, &keccak32(b"other"), &[3]);
+    let _ = statedb.commit();
+    assert_eq!(
+        statedb.get_state(&alice, &slot),
+        rlp::encode(&vec![2u8]).to_vec()
+    );
+    assert_eq!(
+        statedb.get_states(&alice, &[&slot, &keccak32(b"other")]),
+        vec![
+            rlp::encode(&vec![2u8]).to_vec(),
+            rlp::encode(&vec![3u8]).to_vec()
+        ]
+    );
+}
+
+#[test]
+fn statedb_secure_hashes_raw_keys_like_prehashed_callers() {
+    let plain_dir = TempDir::new("statedb_secure_plain");
+    let secure_dir = TempDir::new("statedb_secure_hashed");
+    let mut plain = StateDB::open(plain_dir.path.to_str().unwrap(), small_cfg());
+    let mut secure_cfg = small_cfg();
+    secure_cfg.secure = true;
+    let mut secure = StateDB::open(secure_dir.path.to_str().unwrap(), secure_cfg);
+
+    let (alice, bob, slot) = (b"alice".as_slice(), b"bob".as_slice(), b"slot".as_slice());
+    for (statedb, prehash) in [(&mut plain, true), (&mut secure, false)] {
+        let key = |k: &[u8]| {
+            if prehash {
+                keccak32(k).to_vec()
+            } else {
+                k.to_vec()
+            }
+        };
+        statedb.add_balance(&key(alice), BigUint::from(7u32));
+        statedb.set_nonce(&key(alice), 3);
+        statedb.set_state(&key(alice), &key(slot), &[0x2a]);
+        statedb.add_balance(&key(bob), BigUint::from(1u32));
+        assert_ne!(
+            statedb.storage_root(&key(alice)),
+            keccak32(&[0x80]).to_vec()
+        );
+        let _ = statedb.commit();
+        statedb.remove_account(&key(bob));
+        let _ = statedb.commit();
+    }
+    assert_eq!(secure.hash(), plain.hash());
+    assert_eq!(
+        secure.storage_root(alice),
+        plain.storage_root(&keccak32(alice))
+    );
+
+    // reads take the same keys as writes
+    assert_eq!(secure.get_balance(alice), BigUint::from(7u32));
+    assert_eq!(secure.get_nonce(alice), 3);
+    assert_eq!(
+        secure.get_state(alice, slot),
+        rlp::encode(&vec![0x2au8]).to_vec()
+    );
+    assert!(secure.get_account(bob).is_none());
+    // the hashed key is just another raw key to a secure StateDB
+    assert_eq!(secure.get_balance(&keccak32(alice)), BigUint::from(0u32));
+    assert_eq!(plain.get_balance(alice), BigUint::from(0u32));
+
+    let view = secure.read_view();
+    assert_eq!(view.get_balance(alice), BigUint::from(7u32));
+    assert_eq!(
+        view.get_state(alice, slot),
+        rlp::encode(&vec![0x2au8]).to_vec()
+    );
+}
+
+#[test]
+fn statedb_bulk_balance_and_nonce_updates_match_single_ops() {
+    let dir_a = TempDir::new("statedb_bulk_updates_a");
+    let dir_b = TempDir::new("statedb_bulk_updates_b");
+    let mut bulk = StateDB::open(dir_a.path.to_str().unwrap(), small_cfg());
+    let mut single = StateDB::open(dir_b.path.to_str().unwrap(), small_cfg());
+    let (alice, bob, carol) = (keccak32(b"alice"), keccak32(b"bob"), keccak32(b"carol"));
+    for statedb in [&mut bulk, &mut single] {
+        statedb.add_balance(&alice, BigUint::from(100u32));
+        statedb.set_nonce(&alice, 1);
+        statedb.commit();
+    }
+
+    let sid = bulk.snapshot();
+    bulk.apply_balance_deltas(&[
+        (alice.to_vec(), -30),
+        (bob.to_vec(), 50),
+        (alice.to_vec(), -500),
+        (alice.to_vec(), 7),
+        (carol.to_vec(), -1),
+    ]);
+    bulk.set_nonces(&[(alice.to_vec(), 2), (bob.to_vec(), 1), (alice.to_vec(), 3)]);
+    // the overdraft saturates at zero before the last credit
+    assert_eq!(bulk.get_balance(&alice), BigUint::from(7u32));
+    assert_eq!(bulk.get_balance(&bob), BigUint::from(50u32));
+    assert_eq!(bulk.get_balance(&carol), BigUint::from(0u32));
+    assert_eq!((bulk.get_nonce(&alice), bulk.get_nonce(&bob)), (3, 1));
+
+    single.sub_balance(&alice, BigUint::from(100u32));
+    single.add_balance(&bob, BigUint::from(50u32));
+    single.add_balance(&alice, BigUint::from(7u32));
+    single.sub_balance(&carol, BigUint::from(0u32));
+    single.set_nonce(&alice, 3);
+    single.set_nonce(&bob, 1);
+    assert_eq!(bulk.hash(), single.hash());
+
+    bulk.revert(sid);
+    assert_eq!(bulk.get_balance(&alice), BigUint::from(100u32));
+    assert_eq!(bulk.get_balance(&bob), BigUint::from(0u32));
+    assert_eq!(bulk.get_nonce(&alice), 1);
+    assert!(bulk.get_account(&carol).is_none());
+}
+
+#[test]
+fn statedb_touching_a_missing_account_does_not_create_it() {
+    let dir = TempDir::new("statedb_phantom_accounts");
+    let mut statedb = StateDB::open(dir.path.to_str().unwrap(), small_cfg());
+    let (alice, bob, carol) = (keccak32(b"alice"), keccak32(b"bob"), keccak32(b"carol"));
+    let empty_root = statedb.hash();
+
+    statedb.add_balance(&alice, BigUint::from(0u32));
+    statedb.sub_balance(&bob, BigUint::from(0u32));
+    // clearing a slot of a missing account leaves it missing too
+    statedb.set_state(&carol, &keccak32(b"slot"), &[]);
+    assert!(statedb.get_account(&alice).is_none());
+    assert!(statedb.get_account(&carol).is_none());
+    let (root, hash) = statedb.commit_with_hash();
+    assert_eq!(hash, empty_root);
+    assert!(statedb.get_account(&alice).is_none());
+    assert!(statedb.account_diff(0, root).is_empty());
+
+    // A touched account that receives value, and an explicitly created
+    // empty one, are written.
+    statedb.add_balance(&alice, BigUint::from(0u32));
+    statedb.add_balance(&alice, BigUint::from(5u32));
+    statedb.create_account(&carol);
+    statedb.commit();
+    assert_eq!(statedb.get_balance(&alice), BigUint::from(5u32));
+    assert!(statedb.get_account(&carol).is_some());
+    let written = statedb.hash();
+
+    // Once written, an account stays even when touched back to empty.
+    statedb.sub_balance(&alice, BigUint::from(5u32));
+    statedb.add_balance(&bob, BigUint::from(0u32));
+    statedb.commit();
+    assert_ne!(statedb.hash(), written);
+    assert_eq!(
+        statedb.get_account(&alice).map(|a| a.balance),
+        Some(BigUint::from(0u32))
+    );
+    assert!(statedb.get_account(&bob).is_none());
+}
+
+#[test]
+fn statedb_create_on_touch_creates_touched_accounts() {
+    let dir = TempDir::new("statedb_touch_creates");
+    let mut cfg = small_cfg();
+    cfg.create_on_touch = true;
+    let mut statedb = StateDB::open(dir.path.to_str().unwrap(), cfg);
+    let empty_root = statedb.hash();
+    let alice = keccak32(b"alice");
+    statedb.add_balance(&alice, BigUint::from(0u32));
+    assert!(statedb.get_account(&alice).is_some());
+    statedb.commit();
+    assert_ne!(statedb.hash(), empty_root);
+}
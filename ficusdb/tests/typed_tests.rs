@@ -0,0 +1,60 @@
+#![cfg(feature = "serde")]
+
+use ficusdb::{DB, DBConfig, TypedDB};
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+fn unique_temp_dir(name: &str) -> PathBuf {
+    let mut p = std::env::temp_dir();
+    let pid = std::process::id();
+    let n = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_nanos();
+    p.push(format!("ficusdb-typedtests-{name}-{pid}-{n}"));
+    p
+}
+
+fn open(dir: &Path) -> DB {
+    let cfg = DBConfig::builder()
+        .cache_size(1024)
+        .page_cache_size(1 << 20)
+        .aha_cache_size(1 << 20)
+        .aha_lens(vec![])
+        .build();
+    DB::open(dir.to_str().unwrap(), cfg)
+}
+
+#[test]
+fn typed_db_roundtrips_and_keeps_versions() {
+    let dir = unique_temp_dir("roundtrip");
+    let _ = fs::remove_dir_all(&dir);
+
+    let mut db: TypedDB<(u32, String), Vec<u64>> = TypedDB::new(open(&dir));
+    let k = |i: u32| (i, format!("user-{i}"));
+
+    let mut wb = db.new_writebatch();
+    for i in 0..50 {
+        wb.put(&k(i), &vec![i as u64; i as usize % 4]);
+    }
+    assert_eq!(wb.len(), 50);
+    let root1 = wb.commit().unwrap();
+    assert_eq!(db.get(&k(7)), Some(vec![7, 7, 7]));
+    assert_eq!(db.get(&(7, "other".to_string())), None);
+
+    let root2 = db.put(&k(7), &vec![1, 2]);
+    assert_ne!(root1, root2);
+    assert_eq!(db.get(&k(7)), Some(vec![1, 2]));
+
+    // Versioning works on the raw bytes underneath.
+    db.db().open_root(root1);
+    assert_eq!(db.get(&k(7)), Some(vec![7, 7, 7]));
+
+    drop(db.into_inner());
+    let mut db: TypedDB<(u32, String), Vec<u64>> = TypedDB::new(open(&dir));
+    assert_eq!(db.get(&k(7)), Some(vec![1, 2]));
+
+    drop(db);
+    let _ = fs::remove_dir_all(&dir);
+}